@@ -1,6 +1,49 @@
 /* STM32F723IEK6 */
 MEMORY
 {
-  FLASH : ORIGIN = 0x08000000, LENGTH = 512k
-  RAM : ORIGIN = 0x20000000, LENGTH = 256k
+  /* The last 128k sector holds the configuration store */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 384k
+  /* DTCM is never cached, so the stack and the DMA buffers on it live here */
+  DTCM : ORIGIN = 0x20000000, LENGTH = 64k
+  RAM : ORIGIN = 0x20010000, LENGTH = 176k
+  /* SRAM2 is made non-cacheable by `bsp::mpu::setup()`, for DMA buffers
+   * which can't live on the stack */
+  SRAM2 : ORIGIN = 0x2003C000, LENGTH = 16k
+  /* ITCM RAM starts at 0, skip the first bytes so no function or pointer is null */
+  ITCM : ORIGIN = 0x00000020, LENGTH = 16k - 0x20
 }
+
+/* Place the stack at the top of DTCM. Peripheral DMA buffers are owned by
+ * structs on the stack, or are statics in the .sram2 section, such as the DAP
+ * response buffers, so they stay coherent with D-cache enabled. Their sizes
+ * are accounted in firmware/src/buffers.rs. */
+_stack_start = ORIGIN(DTCM) + LENGTH(DTCM);
+
+/* Functions placed in the .itcm section run from ITCM RAM, avoiding flash
+ * wait states and cache misses. They are loaded into flash after .data and
+ * copied into ITCM by `bsp::itcm::init()` at boot. */
+SECTIONS
+{
+  .itcm : AT(LOADADDR(.data) + SIZEOF(.data)) ALIGN(4)
+  {
+    . = ALIGN(4);
+    __sitcm = .;
+    *(.itcm .itcm.*);
+    . = ALIGN(4);
+    __eitcm = .;
+  } > ITCM
+  __siitcm = LOADADDR(.itcm);
+} INSERT AFTER .data;
+
+/* The linker only checks .itcm fits in ITCM, not that its load image fits in flash. */
+ASSERT(LOADADDR(.itcm) + SIZEOF(.itcm) <= ORIGIN(FLASH) + LENGTH(FLASH),
+       "ERROR(memory.x): the .itcm load image does not fit in FLASH");
+
+/* Statics placed in the .sram2 section are not initialised at boot. */
+SECTIONS
+{
+  .sram2 (NOLOAD) : ALIGN(4)
+  {
+    *(.sram2 .sram2.*);
+  } > SRAM2
+} INSERT AFTER .bss;
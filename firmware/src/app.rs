@@ -1,15 +1,65 @@
-use crate::dap::DAPVersion;
-use crate::vcp::VcpConfig;
-use crate::{DAP1_PACKET_SIZE, DAP2_PACKET_SIZE, VCP_PACKET_SIZE};
+use crate::bsp::cortex_m::peripheral::DWT;
+use crate::calibration::Calibration;
+use crate::config::{ConfigStore, Key};
+use crate::dap::{self, DAPVersion};
+use crate::gdb::{self, GdbServer};
+use crate::led::ActivityLed;
+use crate::tunnel::{self, DapTunnel};
+use crate::usb::stats::STATS;
+use crate::vcp::{self, VcpConfig, VCP_STATS};
+use crate::{AVRISP_PACKET_SIZE, DAP2_PACKET_SIZE, DIRTYJTAG_PACKET_SIZE, VCP_PACKET_SIZE};
 use hs_probe_bsp as bsp;
 use hs_probe_bsp::rcc::CoreFrequency;
 
+/// Time between stack usage measurements, which scan the unused stack.
+const STACK_POLL_MS: u64 = 1000;
+
 #[allow(clippy::large_enum_variant)]
 pub enum Request {
     Suspend,
-    DAP1Command(([u8; DAP1_PACKET_SIZE as usize], usize)),
-    DAP2Command(([u8; DAP2_PACKET_SIZE as usize], usize)),
+    /// The host resumed the bus after suspending it.
+    Resume,
+    /// A DAPv1 request is waiting in the USB request buffer.
+    DAP1Command,
+    /// A DAPv2 request is waiting in the USB request buffer.
+    DAP2Command,
     VCPPacket(([u8; VCP_PACKET_SIZE as usize], usize)),
+    /// The host set the CDC line coding, which may be unchanged.
+    VCPLineCoding(VcpConfig),
+    /// The host set the CDC control line state.
+    VCPControlLines {
+        dtr: bool,
+        rts: bool,
+    },
+    #[cfg(not(feature = "hid-1024"))]
+    AVRISPCommand(([u8; AVRISP_PACKET_SIZE as usize], usize)),
+    #[cfg(not(feature = "hid-1024"))]
+    DirtyJTAGCommand(([u8; DIRTYJTAG_PACKET_SIZE as usize], usize)),
+}
+
+/// Something for the application to act on, checked for each main loop iteration.
+///
+/// New subsystems add a variant for each source of work and handle it in
+/// `App::process_event`, rather than polling from `App::poll` directly.
+enum Event {
+    /// A request received from the host over USB.
+    Usb(Request),
+    /// The VCP transmit DMA finished a transfer.
+    VcpTxComplete,
+    /// SWO data was received, or the trace endpoint is ready for more.
+    Swo,
+    /// VBUS appeared or disappeared, on boards which can sense it.
+    Vbus(bool),
+    /// The uptime reached a new millisecond, given in ms.
+    Tick(u64),
+}
+
+/// Use of the CDC serial port, selected by the baud rate the host sets.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum CdcMode {
+    Vcp,
+    DapTunnel,
+    Gdb,
 }
 
 pub struct App<'a> {
@@ -21,9 +71,24 @@ pub struct App<'a> {
     usb: &'a mut crate::usb::USB,
     dap: &'a mut crate::dap::DAP<'a>,
     vcp: &'a mut crate::vcp::VCP<'a>,
+    avrisp: &'a mut crate::avrisp::AVRISP<'a>,
     delay: &'a bsp::delay::Delay,
+    config: &'a ConfigStore<'a>,
+    activity_led: ActivityLed<'a>,
+    /// Responses to AVR ISP and DirtyJTAG requests.
     resp_buf: [u8; DAP2_PACKET_SIZE as usize],
+    /// SWO data streamed to the host, and VCP data from the target, each with
+    /// their own buffer so neither waits for the other or for a response.
+    swo_buf: [u8; DAP2_PACKET_SIZE as usize],
+    vcp_buf: [u8; VCP_PACKET_SIZE as usize],
     vcp_config: VcpConfig,
+    vbus_present: bool,
+    last_tick_ms: u64,
+    last_stack_poll_ms: u64,
+    stack_warned: bool,
+    tunnel: DapTunnel,
+    gdb: GdbServer,
+    cdc_mode: CdcMode,
 }
 
 impl<'a> App<'a> {
@@ -37,7 +102,9 @@ impl<'a> App<'a> {
         usb: &'a mut crate::usb::USB,
         dap: &'a mut crate::dap::DAP<'a>,
         vcp: &'a mut crate::vcp::VCP<'a>,
+        avrisp: &'a mut crate::avrisp::AVRISP<'a>,
         delay: &'a bsp::delay::Delay,
+        config: &'a ConfigStore<'a>,
     ) -> Self {
         App {
             rcc,
@@ -48,22 +115,41 @@ impl<'a> App<'a> {
             usb,
             dap,
             vcp,
+            avrisp,
             delay,
+            config,
+            activity_led: ActivityLed::new(&pins.led_blue),
             resp_buf: [0; DAP2_PACKET_SIZE as usize],
+            swo_buf: [0; DAP2_PACKET_SIZE as usize],
+            vcp_buf: [0; VCP_PACKET_SIZE as usize],
             vcp_config: VcpConfig::default(),
+            vbus_present: true,
+            last_tick_ms: 0,
+            last_stack_poll_ms: 0,
+            stack_warned: false,
+            tunnel: DapTunnel::new(),
+            gdb: GdbServer::new(),
+            cdc_mode: CdcMode::Vcp,
         }
     }
 
     /// Unsafety: this function should be called from the main context.
     /// No other contexts should be active at the same time.
     pub unsafe fn setup(&mut self, serial: &'static str) {
-        // Configure system clock
+        // Protect flash and the bottom of the stack, and make SRAM2 non-cacheable
+        bsp::mpu::setup();
+
+        // Configure system clock, measuring HSE against the calibrated HSI
+        self.rcc
+            .set_hsi_trim(Calibration::load(self.config).hsi_trim);
         #[cfg(not(feature = "turbo"))]
         let clocks = self.rcc.setup(CoreFrequency::F72MHz);
         #[cfg(feature = "turbo")]
         let clocks = self.rcc.setup(CoreFrequency::F216MHz);
 
+        info!("HSE measured as {=u32} Hz", clocks.hse());
         self.delay.set_sysclk(&clocks);
+        self.delay.enable_uptime();
 
         // Configure DMA for SPI1, SPI2, USART1 and USART2 transfers
         self.dma.setup();
@@ -71,6 +157,9 @@ impl<'a> App<'a> {
         // Configure GPIOs
         self.pins.setup();
         self.pins.high_impedance_mode();
+        if !self.pins.lock_critical() {
+            error!("Failed to lock USB and power pin configuration");
+        }
 
         self.swd_spi.set_base_clock(&clocks);
         self.swd_spi.disable();
@@ -84,93 +173,282 @@ impl<'a> App<'a> {
         // Configure VCP clocks & pins
         self.vcp.setup(&clocks);
 
-        // Configure USB peripheral and connect to host
-        self.usb.setup(&clocks, serial);
+        // Apply the last line coding set by the host, so the VCP works
+        // before a terminal on the host opens the port again.
+        let mut coding = [0; 7];
+        let stored = self.config.read(Key::VcpLineCoding, &mut coding);
+        if let Some(config) = stored.and_then(|len| VcpConfig::from_bytes(&coding[..len])) {
+            self.vcp.set_config(config);
+            self.vcp.start();
+        }
+
+        // Configure USB peripheral and connect to host, naming it with the
+        // stored label so several probes can be told apart.
+        let mut label = [0; crate::usb::names::MAX_LABEL_LEN];
+        let len = self.config.read(Key::UsbLabel, &mut label).unwrap_or(0);
+        self.usb.setup(&clocks, serial, &label[..len]);
 
         self.pins.led_red.set_low();
         // self.pins.t5v_en.set_high();
     }
 
+    /// Handle the events of one main loop iteration, then move streamed data.
     pub fn poll(&mut self) {
         // we need to inform the usb mod if we would be ready to receive
         // new acm data would there be some available.
-        if let Some(req) = self.usb.interrupt(self.vcp.is_tx_idle()) {
-            self.process_request(req);
+        let cdc_idle = match self.cdc_mode {
+            CdcMode::Vcp => self.vcp.is_tx_ready(),
+            CdcMode::DapTunnel => self.tunnel.is_idle(),
+            CdcMode::Gdb => self.gdb.is_idle(),
+        };
+        if let Some(req) = self.usb.interrupt(cdc_idle) {
+            self.process_event(Event::Usb(req));
+        }
+
+        if vcp::take_tx_complete() {
+            self.process_event(Event::VcpTxComplete);
+        }
+
+        if dap::take_swo_pending() {
+            self.process_event(Event::Swo);
         }
 
+        if let Some(present) = self.vbus_change() {
+            self.process_event(Event::Vbus(present));
+        }
+
+        let now = self.delay.uptime_ms();
+        if now != self.last_tick_ms {
+            self.last_tick_ms = now;
+            self.process_event(Event::Tick(now));
+        }
+
+        self.poll_streams();
+    }
+
+    fn process_event(&mut self, event: Event) {
+        match event {
+            Event::Usb(req) => self.process_request(req),
+            Event::VcpTxComplete => self.vcp.poll_tx(),
+            Event::Swo => self.stream_swo(),
+            Event::Vbus(true) => info!("VBUS restored"),
+            Event::Vbus(false) => {
+                warn!("VBUS lost, turning off target power");
+                self.power_down();
+            }
+            Event::Tick(now) => {
+                self.dap.poll_reset_sense();
+                self.dap.poll_host_status();
+                self.dap.poll_brownout();
+                self.dap.poll_restart();
+                self.activity_led.poll(now);
+                if now - self.last_stack_poll_ms >= STACK_POLL_MS {
+                    self.last_stack_poll_ms = now;
+                    self.poll_stack();
+                }
+            }
+        }
+    }
+
+    /// Measure stack usage, warning once it comes close to overflowing.
+    fn poll_stack(&mut self) {
+        let used = bsp::stack::measure();
+        let size = bsp::stack::size();
+        if used > size / 10 * 9 && !self.stack_warned {
+            self.stack_warned = true;
+            warn!("Stack usage {=u32} of {=u32} bytes", used, size);
+        }
+    }
+
+    /// Send received SWO data to the host, when streaming is enabled and the
+    /// trace endpoint is ready to transmit more data.
+    ///
+    /// Once a packet has been sent, the endpoint completing raises another
+    /// `Event::Swo`, so data left in the buffer is sent without waiting for more.
+    fn stream_swo(&mut self) {
         if self.dap.is_swo_streaming() && !self.usb.dap2_swo_is_busy() {
-            // Poll for new UART data when streaming is enabled and
-            // the SWO endpoint is ready to transmit more data.
-            let len = self.dap.read_swo(&mut self.resp_buf);
+            let size = crate::usb::max_packet_size(DAP2_PACKET_SIZE) as usize;
+            let len = self.dap.read_swo_stream(&mut self.swo_buf[..size]);
 
             if len > 0 {
-                self.usb.dap2_stream_swo(&self.resp_buf[0..len]);
+                self.usb.dap2_stream_swo(&self.swo_buf[0..len]);
+                self.activity_led.activity(self.delay.uptime_ms());
             }
         }
+    }
 
-        // Compare potentially new line encoding for vcp
-        // There is probably a better way to do it but i could
-        // not find a way to be informed of a new encoding by the
-        // acm usb stack.
-        let new_line_coding = self.usb.serial_line_encoding();
-        let config = VcpConfig {
-            stop_bits: new_line_coding.stop_bits(),
-            data_bits: new_line_coding.data_bits(),
-            parity_type: new_line_coding.parity_type(),
-            data_rate: new_line_coding.data_rate(),
-        };
-        if config != self.vcp_config {
-            self.vcp_config = config;
-            self.vcp.stop();
-            self.vcp.set_config(self.vcp_config);
-            self.vcp.start();
+    /// Move data which flows without a request for each transfer: DAP or GDB
+    /// traffic on the CDC port, and VCP data from the target.
+    fn poll_streams(&mut self) {
+        if self.cdc_mode == CdcMode::DapTunnel {
+            let dap = &mut self.dap;
+            let usb = &mut self.usb;
+            self.tunnel.poll(
+                |report, resp| {
+                    let start = DWT::cycle_count();
+                    let len = dap.process_command(report, resp, DAPVersion::V2);
+                    STATS.command(DWT::cycle_count().wrapping_sub(start));
+                    len
+                },
+                |data| usb.serial_return(data),
+            );
+        } else if self.cdc_mode == CdcMode::Gdb {
+            let usb = &mut self.usb;
+            self.gdb.poll(self.dap, self.delay.uptime_ms(), |data| {
+                usb.serial_return(data)
+            });
         }
 
+        self.vcp.poll_errors();
+
         // check if there are bytes available in the uart rx buffer
         let vcp_rx_len = self.vcp.rx_bytes_available();
         if vcp_rx_len > 0 {
             // read them and get potentially new length of bytes
-            let len = self.vcp.read(&mut self.resp_buf);
+            let len = self.vcp.read(&mut self.vcp_buf);
             // transfer those bytes to the usb host
-            self.usb.serial_return(&self.resp_buf[0..len]);
+            let sent = self.usb.serial_return(&self.vcp_buf[0..len]);
+            VCP_STATS.dropped(len - sent);
+            self.activity_led.activity(self.delay.uptime_ms());
         }
     }
 
     fn process_request(&mut self, req: Request) {
         match req {
-            Request::DAP1Command((report, n)) => {
-                let len = self.dap.process_command(
+            Request::DAP1Command => {
+                let (report, resp) = self.usb.dap1_buffers();
+                let start = DWT::cycle_count();
+                let len = if report.first() == Some(&dap::DAP_VENDOR_VCP_SELF_TEST) {
+                    self.vcp.process_self_test(report, resp)
+                } else {
+                    self.dap.process_command(report, resp, DAPVersion::V1)
+                };
+                STATS.command(DWT::cycle_count().wrapping_sub(start));
+                self.usb.dap1_reply(len);
+            }
+            Request::DAP2Command => {
+                let (report, resp) = self.usb.dap2_buffers();
+                let start = DWT::cycle_count();
+                let len = if report.first() == Some(&dap::DAP_VENDOR_VCP_SELF_TEST) {
+                    self.vcp.process_self_test(report, resp)
+                } else {
+                    self.dap.process_command(report, resp, DAPVersion::V2)
+                };
+                STATS.command(DWT::cycle_count().wrapping_sub(start));
+                self.usb.dap2_reply(len);
+            }
+            Request::VCPPacket((buffer, n)) if self.cdc_mode == CdcMode::DapTunnel => {
+                self.tunnel.push(&buffer[0..n]);
+            }
+            Request::VCPPacket((buffer, n)) if self.cdc_mode == CdcMode::Gdb => {
+                self.gdb.push(&buffer[0..n]);
+            }
+            Request::VCPPacket((buffer, n)) => {
+                self.vcp.write(&buffer[0..n]);
+                self.activity_led.activity(self.delay.uptime_ms());
+            }
+            Request::VCPLineCoding(config) => self.set_vcp_config(config),
+            Request::VCPControlLines { dtr, rts } => {
+                debug!("CDC control lines DTR {=bool} RTS {=bool}", dtr, rts);
+                if dtr != vcp::dtr() {
+                    if dtr {
+                        info!("CDC port opened");
+                    } else {
+                        info!("CDC port closed");
+                    }
+                }
+                vcp::set_control_lines(dtr, rts);
+                self.vcp.set_port_open(dtr);
+            }
+            #[cfg(not(feature = "hid-1024"))]
+            Request::AVRISPCommand((report, n)) => {
+                let dap = &*self.dap;
+                let len = self.avrisp.process_command(
                     &report[..n],
-                    &mut self.resp_buf[..DAP1_PACKET_SIZE as usize],
-                    DAPVersion::V1,
+                    &mut self.resp_buf,
+                    || dap.target_voltage(),
+                    dap.is_connected(),
                 );
 
                 if len > 0 {
-                    self.usb.dap1_reply(&self.resp_buf[..len]);
+                    self.usb.avrisp_reply(&self.resp_buf[..len]);
                 }
             }
-            Request::DAP2Command((report, n)) => {
-                let len =
-                    self.dap
-                        .process_command(&report[..n], &mut self.resp_buf, DAPVersion::V2);
+            #[cfg(not(feature = "hid-1024"))]
+            Request::DirtyJTAGCommand((report, n)) => {
+                let len = self.dap.process_dirtyjtag(&report[..n], &mut self.resp_buf);
 
                 if len > 0 {
-                    self.usb.dap2_reply(&self.resp_buf[..len]);
+                    self.usb.dirtyjtag_reply(&self.resp_buf[..len]);
                 }
             }
-            Request::VCPPacket((buffer, n)) => {
-                self.vcp.write(&buffer[0..n], n);
-            }
             Request::Suspend => {
-                self.pins.high_impedance_mode();
-                self.pins.led_red.set_high();
-                self.pins.led_blue.set_high();
-                self.pins.led_green.set_high();
-                self.pins.tvcc_en.set_low();
-                self.pins.t5v_en.set_low();
-                self.swd_spi.disable();
-                self.jtag_spi.disable();
+                if self.pins.vbus_present() == Some(false) {
+                    info!("USB cable disconnected");
+                }
+                // The control lines are cleared by a bus reset, without a request.
+                vcp::set_control_lines(false, false);
+                self.vcp.set_port_open(false);
+                self.dap.suspend();
+                self.power_down();
             }
+            Request::Resume => self.dap.resume(),
+        }
+    }
+
+    /// Apply a line coding set by the host, selecting the use of the CDC port by its baud rate.
+    fn set_vcp_config(&mut self, config: VcpConfig) {
+        if config == self.vcp_config {
+            return;
+        }
+        self.vcp_config = config;
+        self.vcp.stop();
+        if config.data_rate == tunnel::TUNNEL_BAUD {
+            info!("DAP tunnel opened on the CDC port");
+            self.tunnel.reset();
+            self.cdc_mode = CdcMode::DapTunnel;
+        } else if config.data_rate == gdb::GDB_BAUD {
+            info!("GDB server opened on the CDC port");
+            self.gdb.reset();
+            self.cdc_mode = CdcMode::Gdb;
+        } else {
+            self.cdc_mode = CdcMode::Vcp;
+            self.vcp.set_config(self.vcp_config);
+            self.vcp.start();
+            if self
+                .config
+                .write(Key::VcpLineCoding, &config.to_bytes())
+                .is_err()
+            {
+                warn!("Failed to store VCP line coding");
+            }
+        }
+    }
+
+    /// Release all target pins and turn off target power.
+    fn power_down(&mut self) {
+        self.pins.high_impedance_mode();
+        self.pins.led_red.set_high();
+        self.activity_led.off();
+        self.pins.led_green.set_high();
+        self.pins.tvcc_en.set_low();
+        self.pins.t5v_en.set_low();
+        self.swd_spi.disable();
+        self.jtag_spi.disable();
+    }
+
+    /// Check for VBUS appearing or disappearing, on boards which can sense it,
+    /// so target power is turned off as soon as it is lost.
+    ///
+    /// A suspended host keeps VBUS present, so this tells a pulled cable apart
+    /// from suspend before the USB stack notices the bus has gone idle.
+    fn vbus_change(&mut self) -> Option<bool> {
+        let present = self.pins.vbus_present()?;
+        if present != self.vbus_present {
+            self.vbus_present = present;
+            Some(present)
+        } else {
+            None
         }
     }
 }
@@ -0,0 +1,440 @@
+use crate::bsp::{
+    delay::Delay,
+    gpio::Pins,
+    spi::{SPIPrescaler, SPI},
+};
+
+/// STK500v2 command IDs, as used by the AVRISP mkII over USB.
+#[allow(unused)]
+mod command {
+    pub const SIGN_ON: u8 = 0x01;
+    pub const SET_PARAMETER: u8 = 0x02;
+    pub const GET_PARAMETER: u8 = 0x03;
+    pub const OSCCAL: u8 = 0x05;
+    pub const LOAD_ADDRESS: u8 = 0x06;
+    pub const ENTER_PROGMODE_ISP: u8 = 0x10;
+    pub const LEAVE_PROGMODE_ISP: u8 = 0x11;
+    pub const CHIP_ERASE_ISP: u8 = 0x12;
+    pub const PROGRAM_FLASH_ISP: u8 = 0x13;
+    pub const READ_FLASH_ISP: u8 = 0x14;
+    pub const PROGRAM_EEPROM_ISP: u8 = 0x15;
+    pub const READ_EEPROM_ISP: u8 = 0x16;
+    pub const PROGRAM_FUSE_ISP: u8 = 0x17;
+    pub const READ_FUSE_ISP: u8 = 0x18;
+    pub const PROGRAM_LOCK_ISP: u8 = 0x19;
+    pub const READ_LOCK_ISP: u8 = 0x1A;
+    pub const READ_SIGNATURE_ISP: u8 = 0x1B;
+    pub const READ_OSCCAL_ISP: u8 = 0x1C;
+    pub const SPI_MULTI: u8 = 0x1D;
+}
+
+#[allow(unused)]
+mod status {
+    pub const CMD_OK: u8 = 0x00;
+    pub const CMD_TOUT: u8 = 0x80;
+    pub const RDY_BSY_TOUT: u8 = 0x81;
+    pub const CMD_FAILED: u8 = 0xC0;
+    pub const CMD_UNKNOWN: u8 = 0xC9;
+}
+
+#[allow(unused)]
+mod param {
+    pub const BUILD_NUMBER_LOW: u8 = 0x80;
+    pub const BUILD_NUMBER_HIGH: u8 = 0x81;
+    pub const HW_VER: u8 = 0x90;
+    pub const SW_MAJOR: u8 = 0x91;
+    pub const SW_MINOR: u8 = 0x92;
+    pub const VTARGET: u8 = 0x94;
+    pub const SCK_DURATION: u8 = 0x98;
+    pub const RESET_POLARITY: u8 = 0x9E;
+    pub const CONTROLLER_INIT: u8 = 0x9F;
+}
+
+const SIGNATURE: &[u8] = b"AVRISP_MK2";
+
+/// VTARGET reported in 0.1V units on boards which can't measure tVCC.
+const DEFAULT_VTARGET: u8 = 33;
+
+/// SPI clock frequencies for the AVRISP mkII SCK_DURATION parameter values 0 to 6.
+const SCK_FREQUENCIES: [u32; 7] = [
+    8_000_000, 4_000_000, 2_000_000, 1_000_000, 500_000, 250_000, 125_000,
+];
+
+/// Maximum number of polls of the target RDY/BSY flag before timing out.
+const RDY_BSY_POLLS: u32 = 10_000;
+
+/// AVR In-System Programming over SPI2, speaking the STK500v2 protocol.
+///
+/// SPI2 SCK/MOSI/MISO drive the target SCK/MOSI/MISO lines on the
+/// TCK/TDI/TDO pins, and nRESET drives the target reset.
+#[allow(clippy::upper_case_acronyms)]
+pub struct AVRISP<'a> {
+    spi: &'a SPI,
+    pins: &'a Pins<'a>,
+    delay: &'a Delay,
+    address: u32,
+    sck_duration: u8,
+    reset_polarity: u8,
+    controller_init: u8,
+}
+
+impl<'a> AVRISP<'a> {
+    pub fn new(spi: &'a SPI, pins: &'a Pins, delay: &'a Delay) -> Self {
+        AVRISP {
+            spi,
+            pins,
+            delay,
+            address: 0,
+            sck_duration: 6,
+            reset_polarity: 1,
+            controller_init: 0,
+        }
+    }
+
+    /// Process a new STK500v2 command from `req`.
+    ///
+    /// `target_voltage` measures tVCC in mV, or returns None if the board can't,
+    /// and is only called to report the VTARGET parameter.
+    ///
+    /// `dap_connected` is true while a DAP connection owns SPI2 and the debug pins,
+    /// in which case programming mode is refused so the JTAG engine isn't disturbed.
+    ///
+    /// Returns number of bytes written to response buffer.
+    pub fn process_command(
+        &mut self,
+        req: &[u8],
+        resp: &mut [u8],
+        target_voltage: impl FnOnce() -> Option<u32>,
+        dap_connected: bool,
+    ) -> usize {
+        let (&cmd, args) = match req.split_first() {
+            Some(x) => x,
+            None => return 0,
+        };
+        resp[0] = cmd;
+        resp[1] = status::CMD_OK;
+
+        match cmd {
+            command::SIGN_ON => {
+                resp[2] = SIGNATURE.len() as u8;
+                resp[3..3 + SIGNATURE.len()].copy_from_slice(SIGNATURE);
+                3 + SIGNATURE.len()
+            }
+            command::SET_PARAMETER if args.len() >= 2 => {
+                self.set_parameter(args[0], args[1]);
+                2
+            }
+            command::GET_PARAMETER if !args.is_empty() => {
+                resp[2] = self.get_parameter(args[0], target_voltage);
+                3
+            }
+            command::OSCCAL => 2,
+            command::LOAD_ADDRESS if args.len() >= 4 => {
+                self.address = u32::from_be_bytes([args[0], args[1], args[2], args[3]]);
+                2
+            }
+            command::ENTER_PROGMODE_ISP if args.len() >= 11 => {
+                resp[1] = if dap_connected {
+                    status::CMD_FAILED
+                } else {
+                    self.enter_progmode(args)
+                };
+                2
+            }
+            command::LEAVE_PROGMODE_ISP if args.len() >= 2 => {
+                // Programming mode can't have been entered while DAP holds the pins.
+                if !dap_connected {
+                    self.leave_progmode(args[0], args[1]);
+                }
+                2
+            }
+            command::CHIP_ERASE_ISP if args.len() >= 6 => {
+                self.spi_command(&args[2..6]);
+                if args[1] == 0 {
+                    self.delay_ms(args[0] as u32);
+                } else {
+                    resp[1] = self.wait_ready();
+                }
+                2
+            }
+            command::PROGRAM_FLASH_ISP if args.len() >= 9 => {
+                resp[1] = self.program_memory(args, true);
+                2
+            }
+            command::PROGRAM_EEPROM_ISP if args.len() >= 9 => {
+                resp[1] = self.program_memory(args, false);
+                2
+            }
+            command::READ_FLASH_ISP if args.len() >= 3 => self.read_memory(args, resp, true),
+            command::READ_EEPROM_ISP if args.len() >= 3 => self.read_memory(args, resp, false),
+            command::PROGRAM_FUSE_ISP | command::PROGRAM_LOCK_ISP if args.len() >= 4 => {
+                self.spi_command(&args[..4]);
+                resp[2] = status::CMD_OK;
+                3
+            }
+            command::READ_FUSE_ISP
+            | command::READ_LOCK_ISP
+            | command::READ_SIGNATURE_ISP
+            | command::READ_OSCCAL_ISP
+                if args.len() >= 5 =>
+            {
+                // The first argument is the 1-based index of the returned byte.
+                let rx = self.spi_command(&args[1..5]);
+                resp[2] = rx[(args[0].max(1) as usize - 1) & 3];
+                resp[3] = status::CMD_OK;
+                4
+            }
+            command::SPI_MULTI if args.len() >= 3 => self.spi_multi(args, resp),
+            _ => {
+                resp[1] = status::CMD_UNKNOWN;
+                2
+            }
+        }
+    }
+
+    fn set_parameter(&mut self, id: u8, value: u8) {
+        match id {
+            param::SCK_DURATION => {
+                self.sck_duration = value;
+                self.set_sck();
+            }
+            param::RESET_POLARITY => self.reset_polarity = value,
+            param::CONTROLLER_INIT => self.controller_init = value,
+            _ => (),
+        }
+    }
+
+    fn get_parameter(&self, id: u8, target_voltage: impl FnOnce() -> Option<u32>) -> u8 {
+        match id {
+            param::HW_VER => 1,
+            param::SW_MAJOR => 1,
+            param::SW_MINOR => 0,
+            param::VTARGET => {
+                target_voltage().map_or(DEFAULT_VTARGET, |mv| ((mv + 50) / 100).min(255) as u8)
+            }
+            param::SCK_DURATION => self.sck_duration,
+            param::RESET_POLARITY => self.reset_polarity,
+            param::CONTROLLER_INIT => self.controller_init,
+            _ => 0,
+        }
+    }
+
+    /// Apply the SCK_DURATION parameter to the SPI2 prescaler.
+    fn set_sck(&self) {
+        let frequency = match SCK_FREQUENCIES.get(self.sck_duration as usize) {
+            Some(&f) => f,
+            None => 1_000_000 / (self.sck_duration as u32 + 1),
+        };
+        let prescaler = self
+            .spi
+            .calculate_prescaler(frequency)
+            .unwrap_or(SPIPrescaler::Div256);
+        self.spi.set_prescaler(prescaler);
+    }
+
+    /// Assert or release the target reset line, honouring the reset polarity.
+    fn set_reset(&self, asserted: bool) {
+        // Polarity 1 means active-low reset, as used by AVRs.
+        self.pins
+            .reset
+            .set_bool(asserted != (self.reset_polarity == 1));
+    }
+
+    fn enter_progmode(&mut self, args: &[u8]) -> u8 {
+        let timeout = args[0];
+        let stab_delay = args[1];
+        let cmd_exe_delay = args[2];
+        let synch_loops = args[3];
+        let poll_value = args[5];
+        let poll_index = args[6];
+        let cmd = &args[7..11];
+
+        self.spi.setup_avrisp();
+        self.set_sck();
+        self.pins.spi2_clk.set_low();
+        self.pins.avrisp_mode();
+
+        // Pulse reset with SCK held low, then wait for the target to stabilise.
+        self.set_reset(false);
+        self.delay.delay_us(100);
+        self.set_reset(true);
+        self.delay_ms(stab_delay as u32);
+
+        for _ in 0..synch_loops.max(1) {
+            let rx = self.spi_command(cmd);
+            self.delay_ms(cmd_exe_delay as u32);
+            if poll_index == 0 || rx[(poll_index as usize - 1) & 3] == poll_value {
+                return status::CMD_OK;
+            }
+
+            // Not in sync, pulse SCK to shift the target's bit alignment and retry.
+            self.pins.spi2_clk.set_mode_output();
+            self.pins.spi2_clk.set_high();
+            self.delay.delay_us(10);
+            self.pins.spi2_clk.set_low();
+            self.pins.spi2_clk.set_mode_alternate();
+            self.delay_ms(timeout as u32);
+        }
+
+        self.leave_progmode(0, 0);
+        status::CMD_FAILED
+    }
+
+    fn leave_progmode(&mut self, pre_delay: u8, post_delay: u8) {
+        self.delay_ms(pre_delay as u32);
+        self.spi.disable();
+        self.pins.high_impedance_mode();
+        self.set_reset(false);
+        self.delay_ms(post_delay as u32);
+    }
+
+    /// Write a page or series of words to flash (`flash` true) or EEPROM.
+    fn program_memory(&mut self, args: &[u8], flash: bool) -> u8 {
+        let nbytes = u16::from_be_bytes([args[0], args[1]]) as usize;
+        let mode = args[2];
+        let delay = args[3];
+        let cmd1 = args[4];
+        let cmd2 = args[5];
+        let poll1 = args[7];
+        let data = &args[9..];
+        if data.len() < nbytes {
+            return status::CMD_FAILED;
+        }
+
+        self.load_extended_address();
+        let page_address = self.address;
+        let page_mode = mode & 1 != 0;
+
+        for (i, &byte) in data[..nbytes].iter().enumerate() {
+            let cmd = if flash {
+                cmd1 | (((i & 1) as u8) << 3)
+            } else {
+                cmd1
+            };
+            let addr = self.address;
+            self.spi_command(&[cmd, (addr >> 8) as u8, addr as u8, byte]);
+
+            if !page_mode {
+                // Mode bit 3 selects RDY/BSY polling in word mode
+                let status = self.wait_write(mode & 0x08 != 0, delay, byte == poll1);
+                if status != status::CMD_OK {
+                    return status;
+                }
+            }
+
+            if !flash || i & 1 == 1 {
+                self.address = self.address.wrapping_add(1);
+                if self.address & 0xFFFF == 0 {
+                    self.load_extended_address();
+                }
+            }
+        }
+
+        // Commit the page if requested
+        if page_mode && mode & 0x80 != 0 {
+            self.spi_command(&[cmd2, (page_address >> 8) as u8, page_address as u8, 0]);
+            // Mode bit 6 selects RDY/BSY polling in page mode
+            return self.wait_write(mode & 0x40 != 0, delay, false);
+        }
+
+        status::CMD_OK
+    }
+
+    /// Wait for a memory write to complete, either by polling RDY/BSY or with a timed delay.
+    ///
+    /// Value polling is not supported and falls back to a timed delay, as does polling
+    /// after writing a byte equal to the poll value.
+    fn wait_write(&self, rdy_bsy: bool, delay: u8, poll_value_written: bool) -> u8 {
+        if rdy_bsy && !poll_value_written {
+            self.wait_ready()
+        } else {
+            self.delay_ms(delay as u32);
+            status::CMD_OK
+        }
+    }
+
+    /// Read `nbytes` from flash (`flash` true) or EEPROM into the response.
+    fn read_memory(&mut self, args: &[u8], resp: &mut [u8], flash: bool) -> usize {
+        let nbytes = u16::from_be_bytes([args[0], args[1]]) as usize;
+        let cmd1 = args[2];
+        if 3 + nbytes > resp.len() {
+            resp[1] = status::CMD_FAILED;
+            return 2;
+        }
+
+        self.load_extended_address();
+        for i in 0..nbytes {
+            let cmd = if flash {
+                cmd1 | (((i & 1) as u8) << 3)
+            } else {
+                cmd1
+            };
+            let addr = self.address;
+            resp[2 + i] = self.spi_command(&[cmd, (addr >> 8) as u8, addr as u8, 0])[3];
+
+            if !flash || i & 1 == 1 {
+                self.address = self.address.wrapping_add(1);
+                if self.address & 0xFFFF == 0 {
+                    self.load_extended_address();
+                }
+            }
+        }
+        resp[2 + nbytes] = status::CMD_OK;
+        3 + nbytes
+    }
+
+    /// Perform an arbitrary SPI exchange.
+    fn spi_multi(&self, args: &[u8], resp: &mut [u8]) -> usize {
+        let num_tx = args[0] as usize;
+        let num_rx = args[1] as usize;
+        let rx_start = args[2] as usize;
+        let tx = &args[3..];
+        if tx.len() < num_tx || 3 + num_rx > resp.len() {
+            resp[1] = status::CMD_FAILED;
+            return 2;
+        }
+
+        let mut rx_idx = 0;
+        for i in 0..core::cmp::max(num_tx, rx_start + num_rx) {
+            let rx = self.spi.exchange8(tx.get(i).copied().unwrap_or(0));
+            if i >= rx_start && rx_idx < num_rx {
+                resp[2 + rx_idx] = rx;
+                rx_idx += 1;
+            }
+        }
+        resp[2 + num_rx] = status::CMD_OK;
+        3 + num_rx
+    }
+
+    /// Send the Load Extended Address command for devices with more than 128kB flash.
+    fn load_extended_address(&self) {
+        if self.address & 0x8000_0000 != 0 {
+            self.spi_command(&[0x4D, 0x00, (self.address >> 16) as u8, 0x00]);
+        }
+    }
+
+    /// Poll the target RDY/BSY flag until it reports ready.
+    fn wait_ready(&self) -> u8 {
+        for _ in 0..RDY_BSY_POLLS {
+            if self.spi_command(&[0xF0, 0x00, 0x00, 0x00])[3] & 1 == 0 {
+                return status::CMD_OK;
+            }
+        }
+        status::RDY_BSY_TOUT
+    }
+
+    /// Exchange a four byte serial programming instruction.
+    fn spi_command(&self, cmd: &[u8]) -> [u8; 4] {
+        let mut rx = [0u8; 4];
+        for (tx, rx) in cmd.iter().zip(rx.iter_mut()) {
+            *rx = self.spi.exchange8(*tx);
+        }
+        rx
+    }
+
+    fn delay_ms(&self, ms: u32) {
+        for _ in 0..ms {
+            self.delay.delay_us(1000);
+        }
+    }
+}
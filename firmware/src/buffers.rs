@@ -0,0 +1,150 @@
+//! Sizes and placement of the large buffers, accounted for at compile time.
+//!
+//! Buffers a peripheral accesses by DMA are owned by structs on the stack,
+//! which memory.x places in DTCM, or are statics in the non-cacheable `.sram2`
+//! section, such as the DAP response buffers which DAP_JTAG_Sequence captures
+//! TDO into, so they stay coherent with D-cache enabled. Buffers only the CPU
+//! accesses are statics in RAM (SRAM1).
+//!
+//! `BUFFERS` lists the structs `main` keeps on the stack and every static
+//! larger than a few words, sized from their types, along with the endpoint
+//! FIFOs in the OTG_HS peripheral's own RAM. The total in each region is
+//! checked against its budget, so that a new buffer which would leave too
+//! little RAM fails to build instead of overflowing the stack at runtime.
+
+use crate::app::App;
+use crate::avrisp::AVRISP;
+use crate::dap::DAP;
+use crate::log::LogBuffer;
+use crate::usb::names::NameTable;
+use crate::usb::{
+    Dap1Requests, Dap1Response, Dap2Requests, Dap2Response, InitializedUSB, FIFO_DEPTH_WORDS,
+    FIFO_WORDS,
+};
+use crate::vcp::VCP;
+use crate::{SwoUart, VCP_PACKET_SIZE};
+use core::mem::size_of;
+
+/// SWO is received in bursts while the host polls, so it gets a large buffer.
+pub const SWO_BUFFER_SIZE: usize = 4096;
+/// USART1 transmits only for UPDI, so its spare transmitter gets a small buffer.
+pub const SWO_TX_BUFFER_SIZE: usize = 64;
+/// VCP data from the target is forwarded each main loop iteration.
+pub const VCP_RX_BUFFER_SIZE: usize = 512;
+/// VCP data from the host is queued with room for two packets, so one can
+/// be received while the other is sent.
+pub const VCP_TX_BUFFER_SIZE: usize = 2 * VCP_PACKET_SIZE as usize;
+/// Log data waiting to be sent over USB.
+pub const LOG_BUFFER_SIZE: usize = 4096;
+/// Words of memory for the USB endpoint buffers.
+pub const EP_MEMORY_WORDS: usize = 4096;
+
+type EpMemory = [u32; EP_MEMORY_WORDS];
+
+#[derive(Copy, Clone)]
+pub enum Region {
+    /// The stack, in DTCM. Never cached, so used for peripheral DMA buffers.
+    Stack,
+    /// SRAM1, cached. Only for buffers the CPU accesses.
+    Ram,
+    /// The `.sram2` section, non-cacheable and not initialised at boot.
+    Sram2,
+    /// FIFO RAM of the OTG_HS peripheral, outside the address space.
+    UsbFifo,
+}
+
+impl Region {
+    /// Bytes of the region which buffers may take.
+    pub const fn budget(self) -> usize {
+        match self {
+            // Leaves 48K of the 64K DTCM for stack frames.
+            Region::Stack => 16 * 1024,
+            // Leaves the rest of the 176K for other statics.
+            Region::Ram => 64 * 1024,
+            Region::Sram2 => 16 * 1024,
+            Region::UsbFifo => FIFO_DEPTH_WORDS * 4,
+        }
+    }
+}
+
+pub struct Buffer {
+    pub name: &'static str,
+    pub region: Region,
+    pub size: usize,
+}
+
+const fn buffer(name: &'static str, region: Region, size: usize) -> Buffer {
+    Buffer { name, region, size }
+}
+
+pub const BUFFERS: [Buffer; 14] = [
+    buffer("SWO UART", Region::Stack, size_of::<SwoUart<'static>>()),
+    buffer("VCP", Region::Stack, size_of::<VCP<'static>>()),
+    buffer("DAP", Region::Stack, size_of::<DAP<'static>>()),
+    buffer("AVR ISP", Region::Stack, size_of::<AVRISP<'static>>()),
+    buffer("App", Region::Stack, size_of::<App<'static>>()),
+    buffer("USB endpoints", Region::Ram, size_of::<EpMemory>()),
+    buffer(
+        "USB state",
+        Region::Ram,
+        size_of::<Option<InitializedUSB>>(),
+    ),
+    buffer("USB names", Region::Ram, size_of::<NameTable>()),
+    buffer("DAPv1 requests", Region::Ram, size_of::<Dap1Requests>()),
+    buffer("DAPv2 requests", Region::Ram, size_of::<Dap2Requests>()),
+    buffer("Log", Region::Ram, size_of::<LogBuffer>()),
+    buffer("DAPv1 response", Region::Sram2, size_of::<Dap1Response>()),
+    buffer("DAPv2 response", Region::Sram2, size_of::<Dap2Response>()),
+    buffer("USB FIFO", Region::UsbFifo, FIFO_WORDS * 4),
+];
+
+/// Total bytes of the buffers in `region`.
+pub const fn total(region: Region) -> usize {
+    let mut total = 0;
+    let mut i = 0;
+    while i < BUFFERS.len() {
+        if BUFFERS[i].region as u8 == region as u8 {
+            total += BUFFERS[i].size;
+        }
+        i += 1;
+    }
+    total
+}
+
+const _: () = assert!(
+    total(Region::Stack) <= Region::Stack.budget(),
+    "Buffers on the stack exceed their budget"
+);
+const _: () = assert!(
+    total(Region::Ram) <= Region::Ram.budget(),
+    "Buffers in RAM exceed their budget"
+);
+const _: () = assert!(
+    total(Region::Sram2) <= Region::Sram2.budget(),
+    "Buffers in SRAM2 exceed their budget"
+);
+const _: () = assert!(
+    total(Region::UsbFifo) <= Region::UsbFifo.budget(),
+    "USB FIFOs exceed the OTG_HS FIFO RAM"
+);
+
+/// Memory for the USB endpoint buffers, which the CPU copies packets through.
+pub static mut EP_MEMORY: EpMemory = [0; EP_MEMORY_WORDS];
+
+/// Log how much of each region's budget the buffers take.
+pub fn log_usage() {
+    debug!(
+        "Buffers: stack {=usize}/{=usize}, RAM {=usize}/{=usize}, SRAM2 {=usize}/{=usize}, USB FIFO {=usize}/{=usize} bytes",
+        total(Region::Stack),
+        Region::Stack.budget(),
+        total(Region::Ram),
+        Region::Ram.budget(),
+        total(Region::Sram2),
+        Region::Sram2.budget(),
+        total(Region::UsbFifo),
+        Region::UsbFifo.budget()
+    );
+    for buffer in BUFFERS.iter() {
+        trace!("{=str}: {=usize} bytes", buffer.name, buffer.size);
+    }
+}
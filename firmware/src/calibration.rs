@@ -0,0 +1,70 @@
+//! Per-probe calibration, written during production with the Calibration
+//! vendor command and kept in the config store.
+
+use crate::config::{ConfigStore, Key};
+use core::convert::TryInto;
+
+/// Size of the stored calibration.
+pub const CALIBRATION_LEN: usize = 5;
+
+/// Gain which leaves measurements unchanged, in units of 1/10000.
+const UNITY_GAIN: u16 = 10_000;
+
+/// HSITRIM value after reset.
+const DEFAULT_HSI_TRIM: u8 = 16;
+
+#[derive(Copy, Clone)]
+pub struct Calibration {
+    /// Added to the tVCC measurement after the gain, in mV.
+    pub tvcc_offset_mv: i16,
+    /// tVCC gain, in units of 1/10000.
+    pub tvcc_gain: u16,
+    /// HSITRIM value, from 0 to 31, applied before HSE is measured against HSI.
+    pub hsi_trim: u8,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Calibration {
+            tvcc_offset_mv: 0,
+            tvcc_gain: UNITY_GAIN,
+            hsi_trim: DEFAULT_HSI_TRIM,
+        }
+    }
+}
+
+impl Calibration {
+    /// Read the stored calibration, or the default if none is stored.
+    pub fn load(config: &ConfigStore) -> Self {
+        let mut bytes = [0; CALIBRATION_LEN];
+        config
+            .read(Key::Calibration, &mut bytes)
+            .and_then(|len| Calibration::from_bytes(&bytes[..len]))
+            .unwrap_or_default()
+    }
+
+    /// Encode as the i16 tVCC offset, u16 tVCC gain and u8 HSI trim.
+    pub fn to_bytes(&self) -> [u8; CALIBRATION_LEN] {
+        let offset = self.tvcc_offset_mv.to_le_bytes();
+        let gain = self.tvcc_gain.to_le_bytes();
+        [offset[0], offset[1], gain[0], gain[1], self.hsi_trim]
+    }
+
+    /// Decode a calibration written by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != CALIBRATION_LEN || bytes[4] > 31 {
+            return None;
+        }
+        Some(Calibration {
+            tvcc_offset_mv: i16::from_le_bytes(bytes[0..2].try_into().ok()?),
+            tvcc_gain: u16::from_le_bytes(bytes[2..4].try_into().ok()?),
+            hsi_trim: bytes[4],
+        })
+    }
+
+    /// Correct a tVCC measurement in mV.
+    pub fn tvcc_mv(&self, mv: u32) -> u32 {
+        let mv = mv as i64 * self.tvcc_gain as i64 / UNITY_GAIN as i64;
+        (mv + self.tvcc_offset_mv as i64).max(0) as u32
+    }
+}
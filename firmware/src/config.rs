@@ -0,0 +1,209 @@
+//! Persistent configuration, kept as a log of key/value records in the last flash sector.
+//!
+//! Each record is a header word holding the key, the value length and a
+//! checksum, followed by the value padded to whole words. Writing a key
+//! appends a new record, and the last valid record for each key wins. When
+//! the sector is full, the latest value of each key is copied to RAM, the
+//! sector is erased and the values are written back, so a power loss during
+//! that erase loses the stored configuration.
+
+use crate::bsp::flash::{self, Flash};
+
+/// Flash sector holding the store, which memory.x leaves out of FLASH.
+const SECTOR: u32 = 7;
+const SECTOR_ADDRESS: u32 = 0x0806_0000;
+const SECTOR_END: u32 = SECTOR_ADDRESS + 128 * 1024;
+
+/// Largest value which can be stored under one key.
+pub const MAX_VALUE_LEN: usize = 128;
+
+/// Words of RAM for the latest records of all keys while compacting.
+const COMPACT_WORDS: usize = 512;
+
+const ERASED: u32 = 0xFFFF_FFFF;
+
+/// Keys of stored values. 0xFF is never used, as a header of all ones marks erased flash.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Key {
+    /// Last CDC line coding set by the host for the VCP.
+    VcpLineCoding = 0x01,
+    /// Pulls of the target-facing pins, as a u32 in the TargetPulls option format.
+    TargetPulls = 0x02,
+    /// TargetVendor string reported by DAP_Info.
+    TargetVendor = 0x03,
+    /// TargetName string reported by DAP_Info.
+    TargetName = 0x04,
+    /// Label appended to the USB product and interface names.
+    UsbLabel = 0x05,
+    /// BootloaderLock option, as a u8.
+    BootloaderLock = 0x06,
+    /// Per-probe calibration, in the format of `Calibration::to_bytes`.
+    Calibration = 0x07,
+    /// SwjPinMap option, as a u32.
+    SwjPinMap = 0x08,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum Error {
+    Flash(flash::Error),
+    /// Value longer than `MAX_VALUE_LEN`.
+    TooLarge,
+    /// No space left, even after compacting.
+    Full,
+}
+
+impl From<flash::Error> for Error {
+    fn from(err: flash::Error) -> Self {
+        Error::Flash(err)
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Fletcher-16 checksum of the key, length and value of a record.
+fn checksum(key: u8, value: &[u8]) -> u16 {
+    let (mut a, mut b) = (0u16, 0u16);
+    for &byte in [key, value.len() as u8].iter().chain(value) {
+        a = (a + byte as u16) % 255;
+        b = (b + a) % 255;
+    }
+    (b << 8) | a
+}
+
+/// Number of words taken by a value of `len` bytes.
+fn words(len: usize) -> usize {
+    (len + 3) / 4
+}
+
+pub struct ConfigStore<'a> {
+    flash: &'a Flash,
+}
+
+impl<'a> ConfigStore<'a> {
+    pub fn new(flash: &'a Flash) -> Self {
+        ConfigStore { flash }
+    }
+
+    /// Read the stored value of `key` into `buf`, returning its length,
+    /// or None if it has never been written.
+    pub fn read(&self, key: Key, buf: &mut [u8]) -> Option<usize> {
+        let (address, len) = self.latest(key as u8)?;
+        let len = len.min(buf.len());
+        self.read_bytes(address + 4, &mut buf[..len]);
+        Some(len)
+    }
+
+    /// Store `value` for `key`, unless it is already the stored value.
+    pub fn write(&self, key: Key, value: &[u8]) -> Result<()> {
+        if value.len() > MAX_VALUE_LEN {
+            return Err(Error::TooLarge);
+        }
+
+        let mut current = [0; MAX_VALUE_LEN];
+        if let Some(len) = self.read(key, &mut current) {
+            if current[..len] == *value {
+                return Ok(());
+            }
+        }
+
+        let mut record = [ERASED; 1 + MAX_VALUE_LEN / 4];
+        let n = 1 + words(value.len());
+        record[0] =
+            key as u32 | (value.len() as u32) << 8 | (checksum(key as u8, value) as u32) << 16;
+        for (word, chunk) in record[1..n].iter_mut().zip(value.chunks(4)) {
+            let mut bytes = [0xFF; 4];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            *word = u32::from_le_bytes(bytes);
+        }
+
+        let mut end = self.end();
+        if !self.is_free(end, n) {
+            self.compact()?;
+            end = self.end();
+            if !self.is_free(end, n) {
+                return Err(Error::Full);
+            }
+        }
+        self.flash.program(end, &record[..n])?;
+        Ok(())
+    }
+
+    /// Call `f` with the address, key and value length of each valid record,
+    /// returning the address following the last record.
+    fn records(&self, mut f: impl FnMut(u32, u8, usize)) -> u32 {
+        let mut address = SECTOR_ADDRESS;
+        while address < SECTOR_END {
+            let header = self.flash.read_word(address);
+            if header == ERASED {
+                break;
+            }
+            let key = header as u8;
+            let len = (header >> 8) as u8 as usize;
+            let next = address + 4 + 4 * words(len) as u32;
+            if next > SECTOR_END {
+                break;
+            }
+            let mut value = [0; 255];
+            self.read_bytes(address + 4, &mut value[..len]);
+            if checksum(key, &value[..len]) == (header >> 16) as u16 {
+                f(address, key, len);
+            }
+            address = next;
+        }
+        address
+    }
+
+    /// Address and value length of the last valid record for `key`.
+    fn latest(&self, key: u8) -> Option<(u32, usize)> {
+        let mut latest = None;
+        self.records(|address, k, len| {
+            if k == key {
+                latest = Some((address, len));
+            }
+        });
+        latest
+    }
+
+    /// Address after the last record, where the next one is written.
+    fn end(&self) -> u32 {
+        self.records(|_, _, _| {})
+    }
+
+    /// Check `words` words at `address` are erased, as a write interrupted
+    /// by a power loss may have left data behind an erased header.
+    fn is_free(&self, address: u32, words: usize) -> bool {
+        address + 4 * words as u32 <= SECTOR_END
+            && (0..words as u32).all(|i| self.flash.read_word(address + 4 * i) == ERASED)
+    }
+
+    fn read_bytes(&self, address: u32, buf: &mut [u8]) {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            let word = self.flash.read_word(address + 4 * (i as u32 / 4));
+            *byte = word.to_le_bytes()[i % 4];
+        }
+    }
+
+    /// Erase the sector, keeping only the last record of each key.
+    fn compact(&self) -> Result<()> {
+        let mut live = [ERASED; COMPACT_WORDS];
+        let mut used = 0;
+        for key in 0..0xFF {
+            if let Some((address, len)) = self.latest(key) {
+                let n = 1 + words(len);
+                if used + n > COMPACT_WORDS {
+                    return Err(Error::Full);
+                }
+                for (i, word) in live[used..used + n].iter_mut().enumerate() {
+                    *word = self.flash.read_word(address + 4 * i as u32);
+                }
+                used += n;
+            }
+        }
+
+        info!("Compacting configuration store");
+        self.flash.erase_sector(SECTOR)?;
+        self.flash.program(SECTOR_ADDRESS, &live[..used])?;
+        Ok(())
+    }
+}
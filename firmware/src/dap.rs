@@ -1,13 +1,180 @@
 // Copyright 2019-2020 Adam Greig
 // Dual licensed under the Apache 2.0 and MIT licenses.
 
+use crate::calibration::Calibration;
+use crate::config::{ConfigStore, Key};
+use crate::usb::{self, stats::STATS};
+use crate::vcp::{self, VCP_STATS};
 use crate::{
-    bsp::{cortex_m, gpio::Pins, rcc::Clocks, uart::UART},
-    jtag, swd, DAP1_PACKET_SIZE, DAP2_PACKET_SIZE,
+    bsp::{
+        adc::ADC,
+        cortex_m,
+        delay::Delay,
+        gpio::Pins,
+        rcc::Clocks,
+        signature, stack,
+        tim::{Capture, Pwm},
+    },
+    dirtyjtag, itm, jtag, swd, swim, trace, updi, SwoUart, DAP1_PACKET_SIZE, DAP2_PACKET_SIZE,
 };
 use core::convert::{TryFrom, TryInto};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
+/// ID of the DAP_TransferAbort command, which the USB stack checks for on receipt.
+pub const DAP_TRANSFER_ABORT: u8 = Command::DAP_TransferAbort as u8;
+
+/// Command ID of the VCP self test, which is handled by the VCP rather than `DAP`.
+pub const DAP_VENDOR_VCP_SELF_TEST: u8 = Command::DAP_Vendor_VCPSelfTest as u8;
+
+/// tVCC below which the target is reported as unpowered, in mV.
+const TVCC_PRESENT_MV: u32 = 1000;
+
+/// Slowest SWJ clock in Hz. Slower requests are clamped to it, as a single
+/// bitbanged transfer would then take longer than host tools wait for a response.
+const MIN_SWJ_CLOCK: u32 = 1000;
+
+/// Check a TargetPulls value only uses the four pin fields, none set to the reserved 3.
+fn valid_target_pulls(pulls: u32) -> bool {
+    pulls >> 8 == 0 && (0..4).all(|i| (pulls >> (2 * i)) & 0b11 != 0b11)
+}
+
+/// What a bit of DAP_SWJ_Pins drives and reads, from its field in the SwjPinMap option.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum SwjPin {
+    /// The pin CMSIS-DAP assigns to the bit, or the status this firmware reports in it.
+    Standard,
+    /// Nothing: writes are ignored and the bit reads as 0.
+    Unused,
+    /// The spare pin with this index, as for the GPIO vendor command.
+    Spare(usize),
+}
+
+/// Find what `bit` of DAP_SWJ_Pins is mapped to, or None for a reserved field value.
+fn swj_pin(map: u32, bit: u8) -> Option<SwjPin> {
+    match (map >> (4 * bit)) & 0xF {
+        0 => Some(SwjPin::Standard),
+        1 => Some(SwjPin::Unused),
+        n @ 8..=15 => Some(SwjPin::Spare(n as usize - 8)),
+        _ => None,
+    }
+}
+
+/// Check a SwjPinMap value only maps bits to valid fields and spare pins the board has.
+fn valid_swj_pin_map(map: u32, spare_count: usize) -> bool {
+    (0..8).all(|bit| match swj_pin(map, bit) {
+        Some(SwjPin::Spare(n)) => n < spare_count,
+        Some(_) => true,
+        None => false,
+    })
+}
+
+/// Longest TargetVendor or TargetName string, which fits a 64 byte DAP_Info response.
+const MAX_TARGET_INFO_LEN: usize = 60;
+
+/// String reported by DAP_Info for the target, empty if unknown.
+struct TargetInfo {
+    buf: [u8; MAX_TARGET_INFO_LEN],
+    len: usize,
+}
+
+impl TargetInfo {
+    const fn new() -> Self {
+        TargetInfo {
+            buf: [0; MAX_TARGET_INFO_LEN],
+            len: 0,
+        }
+    }
+
+    /// Returns false, leaving the string unchanged, if `value` is too long.
+    fn set(&mut self, value: &[u8]) -> bool {
+        if value.len() > MAX_TARGET_INFO_LEN {
+            return false;
+        }
+        self.buf[..value.len()].copy_from_slice(value);
+        self.len = value.len();
+        true
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+/// Set when a DAP_TransferAbort request is received, and cleared once it is processed.
+static TRANSFER_ABORT: AtomicBool = AtomicBool::new(false);
+
+/// Abort the DAP_Transfer or DAP_TransferBlock currently being processed.
+///
+/// Called from the USB interrupt when a DAP_TransferAbort request is received,
+/// before the request itself is processed.
+pub fn abort_transfer() {
+    TRANSFER_ABORT.store(true, Ordering::Release);
+}
+
+fn transfer_aborted() -> bool {
+    TRANSFER_ABORT.load(Ordering::Acquire)
+}
+
+/// Set when there may be SWO data to stream, and cleared once the main loop checks.
+static SWO_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Note there may be SWO data to stream: more was received, or the trace
+/// endpoint finished sending the last packet.
+///
+/// Called from the SWO UART, DMA and USB interrupts.
+pub fn notify_swo() {
+    SWO_PENDING.store(true, Ordering::Release);
+}
+
+/// Check whether `notify_swo` was called since the last check.
+pub fn take_swo_pending() -> bool {
+    SWO_PENDING.swap(false, Ordering::Acquire)
+}
+
+/// Time budget for SWD and JTAG operations in each DAP command, in CPU cycles, or 0 for no limit.
+static COMMAND_BUDGET: AtomicU32 = AtomicU32::new(0);
+
+/// Cycle counter value when processing of the current DAP command started.
+static COMMAND_START: AtomicU32 = AtomicU32::new(0);
+
+/// Longest command timeout, so the 32-bit cycle counter can't wrap within it.
+const MAX_COMMAND_TIMEOUT_MS: u32 = 10_000;
+
+/// Transfer status reported when a transfer request runs out of time:
+/// no valid ACK, with the SWD protocol error bit set.
+const TRANSFER_TIMEOUT_STATUS: u8 = 0b1111;
+
+/// CMSIS-DAP protocol version reported by DAP_Info.
+const CMSIS_DAP_VERSION: &str = "2.1.0";
+
+/// Size of the header before each chunk of streamed SWO data, with SWOFraming enabled.
+const SWO_FRAME_HEADER_LEN: usize = 8;
+
+/// Time the green LED spends on and then off while the host reports the target running.
+const RUNNING_BLINK_MS: u64 = 250;
+
+/// Time the red LED spends on and then off after a tVCC brown-out.
+const BROWNOUT_BLINK_MS: u64 = 100;
+
+/// Time between tVCC measurements during a debug session.
+const TVCC_POLL_MS: u64 = 10;
+
+/// Time between replying to the Restart vendor command and restarting,
+/// for the host to read the response.
+const RESTART_DELAY_MS: u64 = 50;
+
+/// Time to confirm a request to restart into the bootloader.
+const BOOTLOAD_CONFIRM_MS: u64 = 10_000;
+
+/// Returns true if the current DAP command has exceeded its time budget,
+/// so SWD and JTAG operations should give up instead of continuing.
+pub fn command_expired() -> bool {
+    let budget = COMMAND_BUDGET.load(Ordering::Relaxed);
+    let start = COMMAND_START.load(Ordering::Relaxed);
+    budget != 0 && cortex_m::peripheral::DWT::cycle_count().wrapping_sub(start) > budget
+}
+
 #[derive(Copy, Clone)]
 pub enum DAPVersion {
     V1,
@@ -60,6 +227,26 @@ enum Command {
     // DAP_ExecuteCommands = 0x7F,
     // DAP_QueueCommands = 0x7E,
 
+    // Vendor Commands
+    DAP_Vendor_Config = 0x80,
+    DAP_Vendor_UPDI = 0x81,
+    DAP_Vendor_SWIM = 0x82,
+    DAP_Vendor_JTAG_Scan = 0x83,
+    DAP_Vendor_Info = 0x84,
+    DAP_Vendor_Trace = 0x85,
+    DAP_Vendor_Diagnostics = 0x86,
+    DAP_Vendor_Benchmark = 0x87,
+    DAP_Vendor_PowerCycle = 0x88,
+    DAP_Vendor_VCPSelfTest = 0x89,
+    DAP_Vendor_GPIO = 0x8A,
+    DAP_Vendor_PWM = 0x8B,
+    DAP_Vendor_Frequency = 0x8C,
+    DAP_Vendor_Probe = 0x8D,
+    DAP_Vendor_TargetInfo = 0x8E,
+    DAP_Vendor_UsbLabel = 0x8F,
+    DAP_Vendor_Restart = 0x90,
+    DAP_Vendor_Calibration = 0x91,
+
     // Unimplemented Command Response
     Unimplemented = 0xFF,
 }
@@ -82,8 +269,13 @@ enum DAPInfoID {
     FirmwareVersion = 0x04,
     TargetVendor = 0x05,
     TargetName = 0x06,
+    ProductFirmwareVersion = 0x09,
+    /// Vendor-defined: measured tVCC in mV.
+    TargetVoltage = 0x80,
     Capabilities = 0xF0,
     TestDomainTimer = 0xF1,
+    UARTRxBufferSize = 0xFB,
+    UARTTxBufferSize = 0xFC,
     SWOTraceBufferSize = 0xFD,
     MaxPacketCount = 0xFE,
     MaxPacketSize = 0xFF,
@@ -113,6 +305,102 @@ enum ConnectPortResponse {
     JTAG = 2,
 }
 
+#[derive(Copy, Clone, TryFromPrimitive)]
+#[repr(u8)]
+#[allow(clippy::upper_case_acronyms)]
+enum VendorConfigOption {
+    ConnectUnderReset = 0x00,
+    CJTAG = 0x01,
+    RTCK = 0x02,
+    SWDRecovery = 0x03,
+    WaitIdleCycles = 0x04,
+    WaitDelay = 0x05,
+    LogLevel = 0x06,
+    CommandTrace = 0x07,
+    ResetAssertTime = 0x08,
+    ResetReleaseDelay = 0x09,
+    ResetPushPull = 0x0A,
+    CommandTimeout = 0x0B,
+    ITMPorts = 0x0C,
+    SWOFraming = 0x0D,
+    SecondSWD = 0x0E,
+    SWDPort = 0x0F,
+    FaultRetry = 0x10,
+    ParityRetries = 0x11,
+    PinSpeed = 0x12,
+    TargetPulls = 0x13,
+    BrownoutThreshold = 0x14,
+    BootloaderLock = 0x15,
+    SwjPinMap = 0x16,
+}
+
+#[derive(Copy, Clone, TryFromPrimitive)]
+#[repr(u8)]
+#[allow(clippy::upper_case_acronyms)]
+enum VendorInfo {
+    SWDClock = 0x00,
+    JTAGClock = 0x01,
+    RequestedClock = 0x02,
+}
+
+#[derive(Copy, Clone, TryFromPrimitive)]
+#[repr(u8)]
+#[allow(clippy::upper_case_acronyms)]
+enum DiagnosticsGroup {
+    USB = 0x00,
+    System = 0x01,
+    Target = 0x02,
+    VCP = 0x03,
+    Device = 0x04,
+}
+
+#[derive(Copy, Clone, TryFromPrimitive)]
+#[repr(u8)]
+enum BenchmarkOp {
+    Read = 0x00,
+    Write = 0x01,
+}
+
+#[derive(Copy, Clone, TryFromPrimitive)]
+#[repr(u8)]
+enum RestartMode {
+    Firmware = 0x00,
+    Bootloader = 0x01,
+    ConfirmBootloader = 0x02,
+}
+
+#[derive(Copy, Clone, TryFromPrimitive)]
+#[repr(u8)]
+enum GPIOOp {
+    Direction = 0x00,
+    Write = 0x01,
+    Read = 0x02,
+}
+
+#[derive(Copy, Clone, TryFromPrimitive)]
+#[repr(u8)]
+#[allow(clippy::upper_case_acronyms)]
+enum UPDICommand {
+    Start = 0x00,
+    Stop = 0x01,
+    Break = 0x02,
+    LDCS = 0x03,
+    STCS = 0x04,
+    Transfer = 0x05,
+}
+
+#[derive(Copy, Clone, TryFromPrimitive)]
+#[repr(u8)]
+#[allow(clippy::upper_case_acronyms)]
+enum SWIMCommand {
+    Enter = 0x00,
+    Exit = 0x01,
+    SRST = 0x02,
+    Read = 0x03,
+    Write = 0x04,
+    Reset = 0x05,
+}
+
 #[derive(TryFromPrimitive)]
 #[repr(u8)]
 enum SWOTransport {
@@ -170,6 +458,10 @@ impl<'a> Request<'a> {
         value
     }
 
+    pub fn remaining_len(&self) -> usize {
+        self.data.len()
+    }
+
     pub fn rest(self) -> &'a [u8] {
         self.data
     }
@@ -242,46 +534,377 @@ impl<'a> ResponseWriter<'a> {
     }
 }
 
+#[derive(Copy, Clone)]
 #[allow(clippy::upper_case_acronyms)]
 enum DAPMode {
     SWD,
     JTAG,
 }
 
+/// Debug session state saved while the USB bus is suspended.
+#[derive(Copy, Clone)]
+struct SavedSession {
+    mode: Option<DAPMode>,
+    swo_active: bool,
+    tvcc_en: bool,
+    t5v_en: bool,
+}
+
 #[allow(clippy::upper_case_acronyms)]
 pub struct DAP<'a> {
     swd: swd::SWD<'a>,
+    swd2: swd::SWD<'a>,
+    swd_port: usize,
+    second_swd: bool,
     jtag: jtag::JTAG<'a>,
-    uart: &'a mut UART<'a>,
+    swim: swim::SWIM<'a>,
+    uart: &'a mut SwoUart<'a>,
     pins: &'a Pins<'a>,
     mode: Option<DAPMode>,
     swo_streaming: bool,
     match_retries: usize,
+    connect_under_reset: bool,
+    connect_reset_asserted: bool,
+    swd_recovery_threshold: u32,
+    swd_errors: u32,
+    /// OSPEEDR setting of the debug outputs.
+    pin_speed: u32,
+    target_pulls: u32,
+    /// SwjPinMap option, a 4 bit field per DAP_SWJ_Pins bit.
+    swj_pin_map: u32,
+    config: &'a ConfigStore<'a>,
+    trace: trace::Trace,
+    hclk: u32,
+    timclk1: u32,
+    timclk2: u32,
+    adc: &'a ADC,
+    delay: &'a Delay,
+    pwm: &'a Pwm,
+    capture: &'a Capture,
+    reset_assert_us: u32,
+    reset_release_delay_us: u32,
+    reset_push_pull: bool,
+    reset_held: bool,
+    reset_held_count: u32,
+    command_timeout_ms: u32,
+    itm_filter: itm::ItmFilter,
+    swo_framing: bool,
+    swo_sequence: u16,
+    /// Clock applied to both SWD ports by the last DAP_SWJ_Clock in SWD mode
+    /// or while not connected, after clamping.
+    swd_clock: Option<u32>,
+    /// Clock applied to JTAG by the last DAP_SWJ_Clock in JTAG mode
+    /// or while not connected, after clamping.
+    jtag_clock: Option<u32>,
+    /// Clock requested by the last DAP_SWJ_Clock, or 0 if none.
+    swj_clock_requested: u32,
+    saved_session: Option<SavedSession>,
+    host_running: bool,
+    host_connected: bool,
+    /// Brown-out threshold in mV, or 0 for 90% of tVCC at the start of the session.
+    brownout_threshold_mv: u32,
+    /// tVCC measured at the start of the current debug session.
+    session_tvcc_mv: Option<u32>,
+    last_tvcc_poll_ms: u64,
+    /// Set at a brown-out until the Target diagnostics are reset.
+    brownout: bool,
+    in_brownout: bool,
+    brownout_count: u32,
+    brownout_min_mv: u32,
+    target_vendor: TargetInfo,
+    target_name: TargetInfo,
+    /// Restart requested by the host, and the uptime in ms to perform it at.
+    restart: Option<(RestartMode, u64)>,
+    /// Nonce of a request to restart into the bootloader, and the uptime in ms
+    /// it must be confirmed by.
+    bootload_request: Option<(u32, u64)>,
+    bootloader_lock: bool,
+    calibration: Calibration,
 }
 
 impl<'a> DAP<'a> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         swd: swd::SWD<'a>,
+        swd2: swd::SWD<'a>,
         jtag: jtag::JTAG<'a>,
-        uart: &'a mut UART<'a>,
+        swim: swim::SWIM<'a>,
+        uart: &'a mut SwoUart<'a>,
         pins: &'a Pins,
+        adc: &'a ADC,
+        delay: &'a Delay,
+        pwm: &'a Pwm,
+        capture: &'a Capture,
+        config: &'a ConfigStore<'a>,
     ) -> Self {
         DAP {
             swd,
+            swd2,
+            swd_port: 0,
+            second_swd: false,
             jtag,
+            swim,
             uart,
             pins,
             mode: None,
             swo_streaming: false,
             match_retries: 5,
+            connect_under_reset: false,
+            connect_reset_asserted: false,
+            swd_recovery_threshold: 0,
+            swd_errors: 0,
+            pin_speed: 3,
+            target_pulls: 0,
+            swj_pin_map: 0,
+            config,
+            trace: trace::Trace::new(),
+            hclk: 0,
+            timclk1: 0,
+            timclk2: 0,
+            adc,
+            delay,
+            pwm,
+            capture,
+            reset_assert_us: 10_000,
+            reset_release_delay_us: 10_000,
+            reset_push_pull: false,
+            reset_held: false,
+            reset_held_count: 0,
+            command_timeout_ms: 1000,
+            itm_filter: itm::ItmFilter::new(),
+            swo_framing: false,
+            swo_sequence: 0,
+            swd_clock: None,
+            jtag_clock: None,
+            swj_clock_requested: 0,
+            saved_session: None,
+            host_running: false,
+            host_connected: false,
+            brownout_threshold_mv: 0,
+            session_tvcc_mv: None,
+            last_tvcc_poll_ms: 0,
+            brownout: false,
+            in_brownout: false,
+            brownout_count: 0,
+            brownout_min_mv: u32::MAX,
+            target_vendor: TargetInfo::new(),
+            target_name: TargetInfo::new(),
+            restart: None,
+            bootload_request: None,
+            bootloader_lock: false,
+            calibration: Calibration::default(),
         }
     }
 
     /// Call with the system clock speeds to configure peripherals that require timing information.
     ///
-    /// Currently this only configures the SWO USART baud rate calculation.
+    /// This configures the SWO USART baud rate calculation, and powers up
+    /// the ADC, which is clocked from PCLK2, for temperature readings.
+    /// Stored pin pulls are applied, so must be called after pin setup.
     pub fn setup(&mut self, clocks: &Clocks) {
         self.uart.setup(clocks);
+        self.hclk = clocks.hclk();
+        self.timclk1 = clocks.timclk1();
+        self.timclk2 = clocks.timclk2();
+        self.adc.setup();
+        if let Some(sense) = &self.pins.tvcc_sense {
+            self.adc.set_slow_sampling(sense.channel);
+        }
+        self.set_command_timeout(self.command_timeout_ms);
+
+        let mut pulls = [0; 4];
+        if self.config.read(Key::TargetPulls, &mut pulls) == Some(4) {
+            let pulls = u32::from_le_bytes(pulls);
+            if valid_target_pulls(pulls) {
+                self.target_pulls = pulls;
+                self.pins.set_target_pulls(pulls);
+            }
+        }
+
+        let mut map = [0; 4];
+        if self.config.read(Key::SwjPinMap, &mut map) == Some(4) {
+            let map = u32::from_le_bytes(map);
+            if valid_swj_pin_map(map, self.pins.spare_count()) {
+                self.swj_pin_map = map;
+            }
+        }
+
+        self.calibration = Calibration::load(self.config);
+
+        let mut lock = [0];
+        if self.config.read(Key::BootloaderLock, &mut lock) == Some(1) {
+            self.bootloader_lock = lock[0] != 0;
+            usb::set_detach_lock(self.bootloader_lock);
+        }
+
+        let mut value = [0; MAX_TARGET_INFO_LEN];
+        if let Some(len) = self.config.read(Key::TargetVendor, &mut value) {
+            self.target_vendor.set(&value[..len]);
+        }
+        if let Some(len) = self.config.read(Key::TargetName, &mut value) {
+            self.target_name.set(&value[..len]);
+        }
+    }
+
+    /// Save the debug session before target pins and power are released for USB suspend.
+    ///
+    /// The USB stack also reports bus resets as suspend, so only the state at
+    /// the first one is kept until the session is resumed or replaced.
+    pub fn suspend(&mut self) {
+        if self.saved_session.is_none() {
+            self.saved_session = Some(SavedSession {
+                mode: self.mode,
+                swo_active: self.uart.is_active(),
+                tvcc_en: self.pins.tvcc_en.is_set_high(),
+                t5v_en: self.pins.t5v_en.is_set_high(),
+            });
+        }
+        self.uart.stop();
+        self.host_running = false;
+    }
+
+    /// Restore the debug session saved by `suspend`, once the host resumes the bus.
+    ///
+    /// Target power, the connected port with the last SWJ clock, and SWO capture
+    /// are restored. Transfer configuration is kept in RAM across suspend.
+    pub fn resume(&mut self) {
+        let saved = match self.saved_session.take() {
+            Some(saved) => saved,
+            None => return,
+        };
+
+        self.pins.tvcc_en.set_bool(saved.tvcc_en);
+        self.pins.t5v_en.set_bool(saved.t5v_en);
+
+        self.restore_mode(saved.mode);
+
+        if saved.swo_active {
+            self.itm_filter.reset();
+            self.uart.start();
+        }
+        info!("Restored debug session after USB resume");
+    }
+
+    /// Place pins and SPI back into `mode` and restore its last clock.
+    fn restore_mode(&self, mode: Option<DAPMode>) {
+        match mode {
+            Some(DAPMode::SWD) => {
+                self.swd_port_mode();
+                self.restore_clock(DAPMode::SWD);
+            }
+            Some(DAPMode::JTAG) => {
+                self.jtag_port_mode();
+                if self.jtag.is_cjtag() {
+                    self.jtag.cjtag_activate();
+                }
+            }
+            None => {}
+        }
+    }
+
+    /// Apply the last clock set for `mode`, as enabling SPI resets its clock.
+    fn restore_clock(&self, mode: DAPMode) {
+        match mode {
+            DAPMode::SWD => {
+                if let Some(clock) = self.swd_clock {
+                    self.swd.set_clock(clock);
+                    self.swd2.set_clock(clock);
+                }
+            }
+            DAPMode::JTAG => {
+                if let Some(clock) = self.jtag_clock {
+                    self.jtag.set_clock(clock);
+                }
+            }
+        }
+    }
+
+    /// The SWD port selected for transfers.
+    fn swd(&self) -> &swd::SWD<'a> {
+        if self.swd_port == 1 {
+            &self.swd2
+        } else {
+            &self.swd
+        }
+    }
+
+    /// Place pins and SPI into JTAG mode and restore the last JTAG clock.
+    fn jtag_port_mode(&self) {
+        self.pins.jtag_mode();
+        self.jtag.spi_enable();
+        self.restore_clock(DAPMode::JTAG);
+    }
+
+    /// Place pins and SPI into SWD mode for the selected port.
+    fn swd_port_mode(&self) {
+        if self.swd_port == 1 {
+            self.pins.swd2_mode();
+            self.swd2.spi_enable();
+        } else {
+            self.pins.swd_mode();
+            if self.second_swd {
+                self.pins.swd2_park();
+            }
+            self.swd.spi_enable();
+        }
+    }
+
+    /// Select the SWD port used for transfers, switching the pins over if
+    /// connected in SWD mode, or only if `force` when it is already selected.
+    fn select_swd_port(&mut self, port: usize, force: bool) {
+        if port == self.swd_port && !force {
+            return;
+        }
+        self.swd_port = port;
+        if matches!(self.mode, Some(DAPMode::SWD)) {
+            self.swd_port_mode();
+            self.restore_clock(DAPMode::SWD);
+            debug!("Selected SWD port {=usize}", port);
+        }
+    }
+
+    /// Select the SWD port addressed by the DAP index of a transfer command,
+    /// returning false if there is no such port.
+    ///
+    /// The index is ignored unless the second SWD port is enabled, as
+    /// CMSIS-DAP only uses it to address devices in a JTAG chain.
+    fn select_dap_index(&mut self, idx: u8) -> bool {
+        if !self.second_swd {
+            return true;
+        }
+        match idx {
+            0 | 1 => {
+                self.select_swd_port(idx as usize, false);
+                true
+            }
+            _ => {
+                warn!("Transfer to unknown DAP index {=u8}", idx);
+                false
+            }
+        }
+    }
+
+    /// Set the time budget for SWD operations in each DAP command, with 0 for no limit.
+    fn set_command_timeout(&mut self, ms: u32) {
+        self.command_timeout_ms = ms.min(MAX_COMMAND_TIMEOUT_MS);
+        let cycles = self.command_timeout_ms * (self.hclk / 1000);
+        COMMAND_BUDGET.store(cycles, Ordering::Relaxed);
+    }
+
+    /// Process a packet of DirtyJTAG commands from `report`.
+    ///
+    /// The JTAG pins are switched to JTAG mode if they are not already.
+    /// Each packet has the same time budget as a DAP command.
+    ///
+    /// Returns number of bytes written to response buffer.
+    pub fn process_dirtyjtag(&mut self, report: &[u8], rbuf: &mut [u8]) -> usize {
+        COMMAND_START.store(cortex_m::peripheral::DWT::cycle_count(), Ordering::Relaxed);
+        if !matches!(self.mode, Some(DAPMode::JTAG)) {
+            self.swim.exit();
+            self.jtag_port_mode();
+            self.mode = Some(DAPMode::JTAG);
+        }
+        dirtyjtag::process_command(&self.jtag, self.pins, report, rbuf)
     }
 
     /// Process a new CMSIS-DAP command from `report`.
@@ -293,6 +916,21 @@ impl<'a> DAP<'a> {
         rbuf: &mut [u8],
         version: DAPVersion,
     ) -> usize {
+        let start = cortex_m::peripheral::DWT::cycle_count();
+        COMMAND_START.store(start, Ordering::Relaxed);
+        if !self.trace.is_enabled() {
+            return self.process_request(report, rbuf, version);
+        }
+
+        let len = self.process_request(report, rbuf, version);
+        // Reading the trace doesn't change it, so it can be read in several packets.
+        if !report.is_empty() && report[0] != Command::DAP_Vendor_Trace as u8 {
+            self.trace.record(report, &rbuf[..len], start);
+        }
+        len
+    }
+
+    fn process_request(&mut self, report: &[u8], rbuf: &mut [u8], version: DAPVersion) -> usize {
         let req = match Request::from_report(report) {
             Some(req) => req,
             None => return 0,
@@ -321,19 +959,206 @@ impl<'a> DAP<'a> {
             Command::DAP_SWO_Data => self.process_swo_data(req, resp),
             Command::DAP_JTAG_Sequence => self.process_jtag_sequence(req, resp),
             Command::DAP_TransferConfigure => self.process_transfer_configure(req, resp),
-            Command::DAP_Transfer => self.process_transfer(req, resp),
-            Command::DAP_TransferBlock => self.process_transfer_block(req, resp),
+            Command::DAP_Transfer => {
+                self.process_transfer(req, resp);
+                self.check_swd_recovery(resp.read_u8_at(2));
+            }
+            Command::DAP_TransferBlock => {
+                self.process_transfer_block(req, resp);
+                self.check_swd_recovery(resp.read_u8_at(3));
+            }
             Command::DAP_TransferAbort => {
                 self.process_transfer_abort();
                 // Do not send a response for transfer abort commands
                 return 0;
             }
-            Command::Unimplemented => {}
+            Command::DAP_Vendor_Config => self.process_vendor_config(req, resp),
+            Command::DAP_Vendor_UPDI => self.process_vendor_updi(req, resp),
+            Command::DAP_Vendor_SWIM => self.process_vendor_swim(req, resp),
+            Command::DAP_Vendor_JTAG_Scan => self.process_vendor_jtag_scan(req, resp),
+            Command::DAP_Vendor_Info => self.process_vendor_info(req, resp),
+            Command::DAP_Vendor_Trace => self.process_vendor_trace(req, resp),
+            Command::DAP_Vendor_Diagnostics => self.process_vendor_diagnostics(req, resp),
+            Command::DAP_Vendor_Benchmark => self.process_vendor_benchmark(req, resp),
+            Command::DAP_Vendor_PowerCycle => self.process_vendor_power_cycle(req, resp),
+            Command::DAP_Vendor_GPIO => self.process_vendor_gpio(req, resp),
+            Command::DAP_Vendor_PWM => self.process_vendor_pwm(req, resp),
+            Command::DAP_Vendor_Frequency => self.process_vendor_frequency(req, resp),
+            Command::DAP_Vendor_Probe => self.process_vendor_probe(req, resp),
+            Command::DAP_Vendor_TargetInfo => self.process_vendor_target_info(req, resp),
+            Command::DAP_Vendor_UsbLabel => self.process_vendor_usb_label(req, resp),
+            Command::DAP_Vendor_Restart => self.process_vendor_restart(req, resp),
+            Command::DAP_Vendor_Calibration => self.process_vendor_calibration(req, resp),
+            // Only reached if the caller did not pass the command to the VCP.
+            Command::DAP_Vendor_VCPSelfTest => resp.write_err(),
+            Command::Unimplemented => debug!("Unimplemented DAP command {=u8:#x}", report[0]),
         }
 
         resp.idx
     }
 
+    /// Sample nRESET, noting when the target starts being held in reset externally.
+    ///
+    /// Call regularly from the main loop.
+    pub fn poll_reset_sense(&mut self) {
+        let held = self.reset_held_externally();
+        if held && !self.reset_held {
+            self.reset_held_count = self.reset_held_count.wrapping_add(1);
+            info!("nRESET held low externally");
+        }
+        self.reset_held = held;
+    }
+
+    /// Blink the green LED while the host reports the target is running.
+    ///
+    /// Call regularly from the main loop.
+    pub fn poll_host_status(&mut self) {
+        if self.host_running {
+            if (self.delay.uptime_ms() / RUNNING_BLINK_MS) % 2 == 0 {
+                self.pins.led_green.set_low();
+            } else {
+                self.pins.led_green.set_high();
+            }
+        }
+    }
+
+    /// Perform a restart requested with the Restart vendor command, once the
+    /// host has had time to read the response.
+    ///
+    /// Call regularly from the main loop.
+    pub fn poll_restart(&mut self) {
+        let now = self.delay.uptime_ms();
+        if let Some((_, by)) = self.bootload_request {
+            if now >= by {
+                info!("Bootloader entry was not confirmed");
+                self.bootload_request = None;
+                usb::arm_detach(false);
+            }
+        }
+
+        if let Some((mode, at)) = self.restart {
+            if now < at {
+                return;
+            }
+            match mode {
+                RestartMode::Firmware => {
+                    info!("Restarting firmware");
+                    cortex_m::peripheral::SCB::sys_reset();
+                }
+                RestartMode::Bootloader | RestartMode::ConfirmBootloader => {
+                    info!("Restarting into the system bootloader");
+                    crate::bsp::bootload::bootload();
+                }
+            }
+        }
+    }
+
+    /// Watch tVCC for brown-outs during a debug session, latching the event and
+    /// blinking the red LED until the Target diagnostics are reset.
+    ///
+    /// Call regularly from the main loop.
+    pub fn poll_brownout(&mut self) {
+        let now = self.delay.uptime_ms();
+        if self.brownout {
+            self.pins
+                .led_red
+                .set_bool((now / BROWNOUT_BLINK_MS) % 2 != 0);
+        }
+        if now < self.last_tvcc_poll_ms + TVCC_POLL_MS {
+            return;
+        }
+        self.last_tvcc_poll_ms = now;
+
+        if self.mode.is_none() {
+            self.session_tvcc_mv = None;
+            self.in_brownout = false;
+            return;
+        }
+        let mv = match self.target_voltage() {
+            Some(mv) => mv,
+            None => return,
+        };
+        let session_mv = match self.session_tvcc_mv {
+            Some(session_mv) => session_mv,
+            // Wait for the target to be powered before watching it.
+            None => {
+                if mv >= TVCC_PRESENT_MV {
+                    self.session_tvcc_mv = Some(mv);
+                }
+                return;
+            }
+        };
+
+        let threshold = if self.brownout_threshold_mv != 0 {
+            self.brownout_threshold_mv
+        } else {
+            session_mv * 9 / 10
+        };
+        if mv < threshold {
+            if !self.in_brownout {
+                warn!("tVCC brown-out, {=u32} mV", mv);
+                self.in_brownout = true;
+                self.brownout = true;
+                self.brownout_count += 1;
+            }
+            self.brownout_min_mv = self.brownout_min_mv.min(mv);
+        } else {
+            self.in_brownout = false;
+        }
+    }
+
+    /// Clear a latched brown-out, returning the red LED to the host status.
+    fn clear_brownout(&mut self) {
+        if self.brownout {
+            self.pins.led_red.set_bool(self.host_connected);
+        }
+        self.brownout = false;
+        self.brownout_count = 0;
+        self.brownout_min_mv = u32::MAX;
+    }
+
+    /// Measure tVCC in mV, or None if the board can't.
+    pub fn target_voltage(&self) -> Option<u32> {
+        self.pins
+            .tvcc_sense
+            .as_ref()
+            .map(|sense| self.adc.read_millivolts(sense.channel) * sense.mul / sense.div.max(1))
+            .map(|mv| self.calibration.tvcc_mv(mv))
+    }
+
+    /// Returns true if tVCC is measured below `TVCC_PRESENT_MV`, so the target
+    /// is unpowered. Always false if the board can't measure tVCC.
+    fn tvcc_low(&self) -> bool {
+        self.target_voltage()
+            .map_or(false, |mv| mv < TVCC_PRESENT_MV)
+    }
+
+    /// Returns true if nRESET is low while the probe is not driving it low,
+    /// such as when a supervisor on the target is holding it in reset.
+    fn reset_held_externally(&self) -> bool {
+        self.pins.reset.is_set_high() && self.pins.reset.is_low()
+    }
+
+    /// Connect in SWD mode on the selected port unless already connected,
+    /// for the GDB server, returning the port.
+    pub fn connect_swd(&mut self) -> &swd::SWD<'a> {
+        if !matches!(self.mode, Some(DAPMode::SWD)) {
+            self.swim.exit();
+            self.swd_port_mode();
+            self.restore_clock(DAPMode::SWD);
+            self.mode = Some(DAPMode::SWD);
+            self.saved_session = None;
+            info!("Connected in SWD mode for GDB");
+        }
+        self.release_connect_reset();
+        self.swd()
+    }
+
+    /// Returns true if a DAP_Connect is active, meaning the SWD/JTAG port owns the pins.
+    pub fn is_connected(&self) -> bool {
+        self.mode.is_some()
+    }
+
     /// Returns true if SWO streaming is currently active.
     pub fn is_swo_streaming(&self) -> bool {
         self.uart.is_active() && self.swo_streaming
@@ -342,7 +1167,33 @@ impl<'a> DAP<'a> {
     /// Polls the UART buffer for new SWO data, returning
     /// number of bytes written to buffer.
     pub fn read_swo(&mut self, buf: &mut [u8]) -> usize {
-        self.uart.read(buf)
+        let len = self.uart.read(buf);
+        self.itm_filter.filter(&mut buf[..len])
+    }
+
+    /// Polls for new SWO data to send over the streaming endpoint,
+    /// returning number of bytes written to buffer.
+    ///
+    /// With the SWOFraming option enabled, the data is preceded by a
+    /// `SWO_FRAME_HEADER_LEN` byte header: a u16 sequence number, which
+    /// increments for each chunk and restarts when capture is started,
+    /// the u16 length of the data that follows, and the u32 probe uptime
+    /// in µs when the data was read from the SWO buffer.
+    pub fn read_swo_stream(&mut self, buf: &mut [u8]) -> usize {
+        if !self.swo_framing {
+            return self.read_swo(buf);
+        }
+
+        let len = self.read_swo(&mut buf[SWO_FRAME_HEADER_LEN..]);
+        if len == 0 {
+            return 0;
+        }
+        let timestamp = self.delay.uptime_us() as u32;
+        buf[0..2].copy_from_slice(&self.swo_sequence.to_le_bytes());
+        buf[2..4].copy_from_slice(&(len as u16).to_le_bytes());
+        buf[4..8].copy_from_slice(&timestamp.to_le_bytes());
+        self.swo_sequence = self.swo_sequence.wrapping_add(1);
+        SWO_FRAME_HEADER_LEN + len
     }
 
     fn process_info(&mut self, mut req: Request, resp: &mut ResponseWriter, version: DAPVersion) {
@@ -352,17 +1203,35 @@ impl<'a> DAP<'a> {
             Ok(DAPInfoID::VendorID) => resp.write_u8(0),
             Ok(DAPInfoID::ProductID) => resp.write_u8(0),
             Ok(DAPInfoID::SerialNumber) => resp.write_u8(0),
-            // Return git version as firmware version
             Ok(DAPInfoID::FirmwareVersion) => {
+                resp.write_u8(CMSIS_DAP_VERSION.len() as u8);
+                resp.write_slice(CMSIS_DAP_VERSION.as_bytes());
+            }
+            // Return git version as product firmware version
+            Ok(DAPInfoID::ProductFirmwareVersion) => {
                 resp.write_u8(crate::GIT_VERSION.len() as u8);
                 resp.write_slice(crate::GIT_VERSION.as_bytes());
             }
-            // Return 0-length string for TargetVendor and TargetName to indicate
+            // Empty unless set with the TargetInfo vendor command, to indicate
             // unknown target device.
-            Ok(DAPInfoID::TargetVendor) => resp.write_u8(0),
-            Ok(DAPInfoID::TargetName) => resp.write_u8(0),
+            Ok(DAPInfoID::TargetVendor) => {
+                resp.write_u8(self.target_vendor.len as u8);
+                resp.write_slice(self.target_vendor.as_bytes());
+            }
+            Ok(DAPInfoID::TargetName) => {
+                resp.write_u8(self.target_name.len as u8);
+                resp.write_slice(self.target_name.as_bytes());
+            }
+            // Empty if the board can't measure tVCC.
+            Ok(DAPInfoID::TargetVoltage) => match self.target_voltage() {
+                Some(mv) => {
+                    resp.write_u8(2);
+                    resp.write_u16(mv.min(u16::MAX as u32) as u16);
+                }
+                None => resp.write_u8(0),
+            },
             Ok(DAPInfoID::Capabilities) => {
-                resp.write_u8(1);
+                resp.write_u8(2);
                 // Bit 0: SWD supported
                 // Bit 1: JTAG supported
                 // Bit 2: SWO UART supported
@@ -370,7 +1239,19 @@ impl<'a> DAP<'a> {
                 // Bit 4: Atomic commands not supported
                 // Bit 5: Test Domain Timer not supported
                 // Bit 6: SWO Streaming Trace supported
+                // Bit 7: UART Communication Port not supported
                 resp.write_u8(0b0100_0111);
+                // Bit 0: USB COM Port supported
+                resp.write_u8(0b0000_0001);
+            }
+            // Buffer sizes of the VCP, which is the USB COM port.
+            Ok(DAPInfoID::UARTRxBufferSize) => {
+                resp.write_u8(4);
+                resp.write_u32(crate::buffers::VCP_RX_BUFFER_SIZE as u32);
+            }
+            Ok(DAPInfoID::UARTTxBufferSize) => {
+                resp.write_u8(4);
+                resp.write_u32(crate::buffers::VCP_TX_BUFFER_SIZE as u32);
             }
             Ok(DAPInfoID::SWOTraceBufferSize) => {
                 resp.write_u8(4);
@@ -385,12 +1266,12 @@ impl<'a> DAP<'a> {
                 resp.write_u8(2);
                 match version {
                     DAPVersion::V1 => {
-                        // Maximum of 64 bytes per packet
+                        // One HID report per packet, 64 bytes or 1024 with `hid-1024`
                         resp.write_u16(DAP1_PACKET_SIZE);
                     }
                     DAPVersion::V2 => {
-                        // Maximum of 512 bytes per packet
-                        resp.write_u16(DAP2_PACKET_SIZE);
+                        // Maximum of 512 bytes per packet at high speed, 64 at full speed
+                        resp.write_u16(usb::max_packet_size(DAP2_PACKET_SIZE));
                     }
                 }
             }
@@ -401,49 +1282,99 @@ impl<'a> DAP<'a> {
     fn process_host_status(&mut self, mut req: Request, resp: &mut ResponseWriter) {
         let status_type = req.next_u8();
         let status_status = req.next_u8();
-        // Use HostStatus to set our LED when host is connected to target
-        if let Ok(HostStatusType::Connect) = HostStatusType::try_from(status_type) {
-            match status_status {
+        // Use HostStatus to set our LED when host is connected to target,
+        // and blink it while the target is running.
+        match HostStatusType::try_from(status_type) {
+            Ok(HostStatusType::Connect) => match status_status {
                 0 => {
+                    self.host_running = false;
+                    self.host_connected = false;
                     self.pins.led_red.set_low();
                     self.pins.led_green.set_high();
                 }
                 1 => {
+                    self.host_connected = true;
                     self.pins.led_red.set_high();
                     self.pins.led_green.set_low();
                 }
                 _ => (),
-            }
+            },
+            Ok(HostStatusType::Running) => match status_status {
+                0 => {
+                    // Back to solid green, as the host only reports running while connected.
+                    self.host_running = false;
+                    self.pins.led_green.set_low();
+                }
+                1 => {
+                    self.host_running = true;
+                    self.pins.led_red.set_high();
+                }
+                _ => (),
+            },
+            Err(_) => (),
         }
         resp.write_u8(0);
     }
 
     fn process_connect(&mut self, mut req: Request, resp: &mut ResponseWriter) {
         let port = req.next_u8();
+        self.swim.exit();
         match ConnectPort::try_from(port) {
             Ok(ConnectPort::Default) | Ok(ConnectPort::SWD) => {
-                self.pins.swd_mode();
-                self.swd.spi_enable();
+                self.swd_port_mode();
+                self.restore_clock(DAPMode::SWD);
                 self.mode = Some(DAPMode::SWD);
+                self.saved_session = None;
+                info!("Connected in SWD mode");
                 resp.write_u8(ConnectPortResponse::SWD as u8);
             }
             Ok(ConnectPort::JTAG) => {
-                self.pins.jtag_mode();
-                self.jtag.spi_enable();
-                self.mode = Some(DAPMode::JTAG);
-                resp.write_u8(ConnectPortResponse::JTAG as u8);
+                self.jtag_port_mode();
+
+                // Pulse nTRST to reset TAPs which need a hardware test reset.
+                self.pins.ntrst.set_low();
+                self.delay.delay_us(20);
+                self.pins.ntrst.set_high();
+
+                if self.jtag.is_cjtag() {
+                    self.jtag.cjtag_activate();
+                }
+                self.mode = Some(DAPMode::JTAG);
+                self.saved_session = None;
+                info!("Connected in JTAG mode");
+                resp.write_u8(ConnectPortResponse::JTAG as u8);
             }
             _ => {
+                warn!("Connect to unsupported port {=u8}", port);
                 resp.write_u8(ConnectPortResponse::Failed as u8);
+                return;
             }
         }
+
+        // Hold the target in reset while the host sends the selection sequence,
+        // it is released again before the first transfer.
+        if self.connect_under_reset {
+            self.pins.reset.set_low();
+            self.connect_reset_asserted = true;
+        }
+    }
+
+    /// Release nRESET if it was asserted by DAP_Connect in connect-under-reset mode.
+    fn release_connect_reset(&mut self) {
+        if self.connect_reset_asserted {
+            self.release_reset();
+            self.connect_reset_asserted = false;
+        }
     }
 
     fn process_disconnect(&mut self, _req: Request, resp: &mut ResponseWriter) {
+        self.release_connect_reset();
         self.pins.high_impedance_mode();
         self.mode = None;
+        self.saved_session = None;
         self.swd.spi_disable();
         self.jtag.spi_disable();
+        info!("Disconnected");
         resp.write_ok();
     }
 
@@ -452,9 +1383,13 @@ impl<'a> DAP<'a> {
             resp.write_err();
             return;
         }
-        let _idx = req.next_u8();
+        let idx = req.next_u8();
         let word = req.next_u32();
-        match self.swd.write_dp(0x00, word) {
+        if !self.select_dap_index(idx) {
+            resp.write_err();
+            return;
+        }
+        match self.swd().write_dp(0x00, word) {
             Ok(_) => resp.write_ok(),
             Err(_) => resp.write_err(),
         }
@@ -466,22 +1401,59 @@ impl<'a> DAP<'a> {
         resp.write_ok();
     }
 
+    /// Reset the target with a pulse on nRESET, shaped by the reset configuration options.
     fn process_reset_target(&mut self, _req: Request, resp: &mut ResponseWriter) {
+        self.connect_reset_asserted = false;
+        self.pins.reset.set_low();
+        self.delay_us_long(self.reset_assert_us);
+        self.release_reset();
+        self.delay_us_long(self.reset_release_delay_us);
         resp.write_ok();
-        // "No device specific reset sequence is implemented"
-        resp.write_u8(0);
+        // Reset sequence executed
+        resp.write_u8(1);
+    }
+
+    /// Release nRESET, driving it high briefly first if push-pull edges are enabled.
+    fn release_reset(&self) {
+        if self.reset_push_pull {
+            // A strong rising edge for targets whose pull-ups are too weak or
+            // loaded for open-drain, after which the line is left to float high.
+            self.pins.reset.set_otype_pushpull();
+            self.pins.reset.set_high();
+            self.delay.delay_us(1);
+            self.pins.reset.set_otype_opendrain();
+        } else {
+            self.pins.reset.set_high();
+        }
+    }
+
+    /// Delay for `us` microseconds, which may be longer than a single `Delay::delay_us` call.
+    fn delay_us_long(&self, mut us: u32) {
+        while us > 0 {
+            let chunk = us.min(5_000);
+            self.delay.delay_us(chunk);
+            us -= chunk;
+        }
     }
 
     fn process_swj_pins(&mut self, mut req: Request, resp: &mut ResponseWriter) {
         let output = req.next_u8();
-        let mask = req.next_u8();
+        let requested_mask = req.next_u8();
         let wait = req.next_u32();
 
+        // Bits mapped to their standard pins, which are handled as usual.
+        let standard = (0..8)
+            .filter(|&bit| swj_pin(self.swj_pin_map, bit) == Some(SwjPin::Standard))
+            .fold(0u8, |bits, bit| bits | (1 << bit));
+        let mask = requested_mask & standard;
+
         const SWCLK_POS: u8 = 0;
         const SWDIO_POS: u8 = 1;
         const TDI_POS: u8 = 2;
         const TDO_POS: u8 = 3;
+        const TVCC_LOW_POS: u8 = 4;
         const NTRST_POS: u8 = 5;
+        const NRESET_HELD_POS: u8 = 6;
         const NRESET_POS: u8 = 7;
 
         match self.mode {
@@ -520,29 +1492,84 @@ impl<'a> DAP<'a> {
             None => (),
         };
 
-        // Always allow setting the nRESET pin, which is always in output open-drain mode.
+        // Always allow setting the nTRST and nRESET pins, which are always in output
+        // open-drain mode.
+        if mask & (1 << NTRST_POS) != 0 {
+            self.pins.ntrst.set_bool(output & (1 << NTRST_POS) != 0);
+        }
+        // An explicit request from the host takes over from connect-under-reset.
         if mask & (1 << NRESET_POS) != 0 {
-            self.pins.reset.set_bool(output & (1 << NRESET_POS) != 0);
+            self.connect_reset_asserted = false;
+            if output & (1 << NRESET_POS) != 0 {
+                self.release_reset();
+            } else {
+                self.pins.reset.set_low();
+            }
+        }
+
+        // Bits mapped to spare pins drive them as push-pull outputs.
+        for bit in 0..8 {
+            if let Some(SwjPin::Spare(n)) = swj_pin(self.swj_pin_map, bit) {
+                if requested_mask & (1 << bit) != 0 {
+                    if let Some(pin) = self.pins.spare.iter().flatten().nth(n) {
+                        pin.set_bool(output & (1 << bit) != 0);
+                        pin.set_otype_pushpull().set_mode_output();
+                    }
+                }
+            }
         }
 
         // Delay required time in µs (approximate delay).
         cortex_m::asm::delay(42 * wait);
 
         // Read and return pin state
-        let state = ((self.pins.spi1_clk.get_state() as u8) << SWCLK_POS)
+        let mut state = ((self.pins.spi1_clk.get_state() as u8) << SWCLK_POS)
             | ((self.pins.spi1_miso.get_state() as u8) << SWDIO_POS)
             | ((self.pins.spi2_mosi.get_state() as u8) << TDI_POS)
             | ((self.pins.spi2_miso.get_state() as u8) << TDO_POS)
-            | (1 << NTRST_POS)
+            | ((self.tvcc_low() as u8) << TVCC_LOW_POS)
+            | ((self.pins.ntrst.get_state() as u8) << NTRST_POS)
+            | ((self.reset_held_externally() as u8) << NRESET_HELD_POS)
             | ((self.pins.reset.get_state() as u8) << NRESET_POS);
+        state &= standard;
+        for bit in 0..8 {
+            if let Some(SwjPin::Spare(n)) = swj_pin(self.swj_pin_map, bit) {
+                if let Some(pin) = self.pins.spare.iter().flatten().nth(n) {
+                    state |= (pin.is_high() as u8) << bit;
+                }
+            }
+        }
         resp.write_u8(state);
     }
 
     fn process_swj_clock(&mut self, mut req: Request, resp: &mut ResponseWriter) {
-        let clock = req.next_u32();
+        let requested = req.next_u32();
+        if requested == 0 {
+            resp.write_err();
+            return;
+        }
 
-        self.jtag.set_clock(clock);
-        let valid = self.swd.set_clock(clock);
+        let clock = requested.max(MIN_SWJ_CLOCK);
+        if clock != requested {
+            warn!(
+                "SWJ clock {=u32} Hz is too slow, using {=u32} Hz",
+                requested, clock
+            );
+        }
+        self.swj_clock_requested = requested;
+
+        // Only the connected mode's clock changes, so each keeps its own.
+        let valid = match self.mode {
+            Some(DAPMode::SWD) => self.set_swd_clock(clock),
+            Some(DAPMode::JTAG) => {
+                self.set_jtag_clock(clock);
+                true
+            }
+            None => {
+                self.set_jtag_clock(clock);
+                self.set_swd_clock(clock)
+            }
+        };
         if valid {
             resp.write_ok();
         } else {
@@ -550,6 +1577,22 @@ impl<'a> DAP<'a> {
         }
     }
 
+    /// Set the clock of both SWD ports, to be restored on later SWD connections.
+    fn set_swd_clock(&mut self, clock: u32) -> bool {
+        self.swd2.set_clock(clock);
+        let valid = self.swd.set_clock(clock);
+        if valid {
+            self.swd_clock = Some(clock);
+        }
+        valid
+    }
+
+    /// Set the JTAG clock, to be restored on later JTAG connections.
+    fn set_jtag_clock(&mut self, clock: u32) {
+        self.jtag.set_clock(clock);
+        self.jtag_clock = Some(clock);
+    }
+
     fn process_swj_sequence(&mut self, mut req: Request, resp: &mut ResponseWriter) {
         let nbits: usize = match req.next_u8() {
             // CMSIS-DAP says 0 means 256 bits
@@ -569,7 +1612,7 @@ impl<'a> DAP<'a> {
 
         match self.mode {
             Some(DAPMode::SWD) => {
-                self.swd.tx_sequence(seq, nbits);
+                self.swd().tx_sequence(seq, nbits);
             }
             Some(DAPMode::JTAG) => {
                 self.jtag.tms_sequence(seq, nbits);
@@ -587,11 +1630,11 @@ impl<'a> DAP<'a> {
         let config = req.next_u8();
         let clk_period = config & 0b011;
         let always_data = (config & 0b100) != 0;
-        if clk_period == 0 && !always_data {
-            resp.write_ok();
-        } else {
-            resp.write_err();
+        for swd in [&mut self.swd, &mut self.swd2] {
+            swd.set_turnaround(clk_period as usize + 1);
+            swd.set_data_phase(always_data);
         }
+        resp.write_ok();
     }
 
     fn process_swo_transport(&mut self, mut req: Request, resp: &mut ResponseWriter) {
@@ -639,6 +1682,8 @@ impl<'a> DAP<'a> {
                 resp.write_ok();
             }
             Ok(SWOControl::Start) => {
+                self.itm_filter.reset();
+                self.swo_sequence = 0;
                 self.uart.start();
                 resp.write_ok();
             }
@@ -686,7 +1731,7 @@ impl<'a> DAP<'a> {
         }
 
         // Read data from UART
-        let len = self.uart.read(buf);
+        let len = self.read_swo(buf);
         resp.skip(len);
 
         // Go back and write length
@@ -702,11 +1747,19 @@ impl<'a> DAP<'a> {
             }
         }
 
+        self.release_connect_reset();
+
         resp.write_ok();
 
-        // Run requested JTAG sequences. Cannot fail.
-        let size = self.jtag.sequences(req.rest(), resp.remaining());
-        resp.skip(size);
+        // Run requested JTAG sequences, which only fail by running out of time.
+        match self.jtag.sequences(req.rest(), resp.remaining()) {
+            Some(size) => resp.skip(size),
+            None => {
+                warn!("DAP_JTAG_Sequence timed out");
+                resp.idx = 1;
+                resp.write_err();
+            }
+        }
     }
 
     fn process_transfer_configure(&mut self, mut req: Request, resp: &mut ResponseWriter) {
@@ -714,7 +1767,9 @@ impl<'a> DAP<'a> {
         let _idle_cycles = req.next_u8();
 
         // Send number of wait retries through to SWD
-        self.swd.set_wait_retries(req.next_u16() as usize);
+        let retries = req.next_u16() as usize;
+        self.swd.set_wait_retries(retries);
+        self.swd2.set_wait_retries(retries);
 
         // Store number of match retries
         self.match_retries = req.next_u16() as usize;
@@ -723,20 +1778,38 @@ impl<'a> DAP<'a> {
     }
 
     fn process_transfer(&mut self, mut req: Request, resp: &mut ResponseWriter) {
-        let _idx = req.next_u8();
+        let idx = req.next_u8();
         let ntransfers = req.next_u8();
         let mut match_mask = 0xFFFF_FFFFu32;
 
+        if !self.select_dap_index(idx) {
+            // No transfers executed, with no ACK.
+            resp.write_u8(0);
+            resp.write_u8(0);
+            return;
+        }
+
+        self.release_connect_reset();
+
         // Ensure SWD pins are in the right mode, in case they've been used as outputs
         // by the SWJ_Pins command.
-        self.pins.swd_clk_spi();
-        self.pins.swd_tx();
+        self.swd().spi_mode();
 
         // Skip two bytes in resp to reserve space for final status,
         // which we update while processing.
         resp.write_u16(0);
 
         for transfer_idx in 0..ntransfers {
+            // Stop before the next transfer if the host aborted this request
+            if transfer_aborted() {
+                break;
+            }
+            if command_expired() {
+                warn!("DAP_Transfer timed out");
+                resp.write_u8_at(2, TRANSFER_TIMEOUT_STATUS);
+                break;
+            }
+
             // Store how many transfers we execute in the response
             resp.write_u8_at(1, transfer_idx + 1);
 
@@ -759,16 +1832,16 @@ impl<'a> DAP<'a> {
                     // keep issuing new AP reads, but our reads are
                     // sufficiently fast that for now this is simpler.
                     let rdbuff = swd::DPRegister::RDBUFF.into();
-                    if self.swd.read_ap(a).check(resp.mut_at(2)).is_none() {
+                    if self.swd().read_ap(a).check(resp.mut_at(2)).is_none() {
                         break;
                     }
-                    match self.swd.read_dp(rdbuff).check(resp.mut_at(2)) {
+                    match self.swd().read_dp(rdbuff).check(resp.mut_at(2)) {
                         Some(v) => v,
                         None => break,
                     }
                 } else {
                     // Reads from DP are not posted, so directly read the register.
-                    match self.swd.read_dp(a).check(resp.mut_at(2)) {
+                    match self.swd().read_dp(a).check(resp.mut_at(2)) {
                         Some(v) => v,
                         None => break,
                     }
@@ -782,11 +1855,11 @@ impl<'a> DAP<'a> {
                     let mut match_tries = 0;
                     while (read_value & match_mask) != target_value {
                         match_tries += 1;
-                        if match_tries > self.match_retries {
+                        if match_tries > self.match_retries || command_expired() {
                             break;
                         }
 
-                        read_value = match self.swd.read(apndp.into(), a).check(resp.mut_at(2)) {
+                        read_value = match self.swd().read(apndp.into(), a).check(resp.mut_at(2)) {
                             Some(v) => v,
                             None => break,
                         }
@@ -814,7 +1887,7 @@ impl<'a> DAP<'a> {
                 // Otherwise issue register write
                 let write_value = req.next_u32();
                 if self
-                    .swd
+                    .swd()
                     .write(apndp.into(), a, write_value)
                     .check(resp.mut_at(2))
                     .is_none()
@@ -826,17 +1899,25 @@ impl<'a> DAP<'a> {
     }
 
     fn process_transfer_block(&mut self, mut req: Request, resp: &mut ResponseWriter) {
-        let _idx = req.next_u8();
+        let idx = req.next_u8();
         let ntransfers = req.next_u16();
         let transfer_req = req.next_u8();
         let apndp = (transfer_req & (1 << 0)) != 0;
         let rnw = (transfer_req & (1 << 1)) != 0;
         let a = (transfer_req & (3 << 2)) >> 2;
 
+        if !self.select_dap_index(idx) {
+            // No transfers executed, with no ACK.
+            resp.write_u16(0);
+            resp.write_u8(0);
+            return;
+        }
+
+        self.release_connect_reset();
+
         // Ensure SWD pins are in the right mode, in case they've been used as outputs
         // by the SWJ_Pins command.
-        self.pins.swd_clk_spi();
-        self.pins.swd_tx();
+        self.swd().spi_mode();
 
         // Skip three bytes in resp to reserve space for final status,
         // which we update while processing.
@@ -849,13 +1930,26 @@ impl<'a> DAP<'a> {
         let mut transfers = 0;
 
         // If reading an AP register, post first read early.
-        if rnw && apndp && self.swd.read_ap(a).check(resp.mut_at(3)).is_none() {
+        if rnw && apndp && self.swd().read_ap(a).check(resp.mut_at(3)).is_none() {
             // Quit early on error
             resp.write_u16_at(1, 1);
             return;
         }
 
         for transfer_idx in 0..ntransfers {
+            // Stop before the next transfer if the host aborted this request,
+            // reporting only the transfers which were executed.
+            if transfer_aborted() {
+                resp.write_u16_at(1, transfer_idx);
+                return;
+            }
+            if command_expired() {
+                warn!("DAP_TransferBlock timed out");
+                resp.write_u8_at(3, TRANSFER_TIMEOUT_STATUS);
+                resp.write_u16_at(1, transfer_idx);
+                return;
+            }
+
             transfers = transfer_idx;
             if rnw {
                 // Handle repeated reads
@@ -863,20 +1957,20 @@ impl<'a> DAP<'a> {
                     // For AP reads, the first read was posted, so on the final
                     // read we need to read RDBUFF instead of the AP register.
                     if transfer_idx < ntransfers - 1 {
-                        match self.swd.read_ap(a).check(resp.mut_at(3)) {
+                        match self.swd().read_ap(a).check(resp.mut_at(3)) {
                             Some(v) => v,
                             None => break,
                         }
                     } else {
                         let rdbuff = swd::DPRegister::RDBUFF.into();
-                        match self.swd.read_dp(rdbuff).check(resp.mut_at(3)) {
+                        match self.swd().read_dp(rdbuff).check(resp.mut_at(3)) {
                             Some(v) => v,
                             None => break,
                         }
                     }
                 } else {
                     // For DP reads, no special care required
-                    match self.swd.read_dp(a).check(resp.mut_at(3)) {
+                    match self.swd().read_dp(a).check(resp.mut_at(3)) {
                         Some(v) => v,
                         None => break,
                     }
@@ -887,7 +1981,7 @@ impl<'a> DAP<'a> {
             } else {
                 // Handle repeated register writes
                 let write_value = req.next_u32();
-                let result = self.swd.write(apndp.into(), a, write_value);
+                let result = self.swd().write(apndp.into(), a, write_value);
                 if result.check(resp.mut_at(3)).is_none() {
                     break;
                 }
@@ -898,10 +1992,1018 @@ impl<'a> DAP<'a> {
         resp.write_u16_at(1, transfers + 1);
     }
 
+    /// Count consecutive SWD transfer requests ending in FAULT or a protocol error,
+    /// and run the SWD recovery sequence once the configured threshold is reached.
+    fn check_swd_recovery(&mut self, status: u8) {
+        if self.swd_recovery_threshold == 0 || !matches!(self.mode, Some(DAPMode::SWD)) {
+            return;
+        }
+
+        match status & 0b111 {
+            4 | 7 => {
+                self.swd_errors += 1;
+                if self.swd_errors >= self.swd_recovery_threshold {
+                    self.swd_errors = 0;
+                    warn!("Recovering SWD after repeated transfer errors");
+                    // The host sees the original error either way, so the
+                    // result of the recovery attempt itself is not reported.
+                    self.swd().recover().ok();
+                }
+            }
+            1 => self.swd_errors = 0,
+            _ => (),
+        }
+    }
+
     fn process_transfer_abort(&mut self) {
-        // We'll only ever receive an abort request when we're not already
-        // processing anything else, since processing blocks checking for
-        // new requests. Therefore there's nothing to do here.
+        // The abort flag was set by the USB interrupt when this request was
+        // received, stopping any transfer in progress or queued before it.
+        TRANSFER_ABORT.store(false, Ordering::Release);
+        debug!("Transfer aborted");
+    }
+
+    /// Read or write a vendor configuration option.
+    ///
+    /// Request: option ID, followed by an optional u32 value to write.
+    /// Response: status, followed by the current u32 value of the option.
+    fn process_vendor_config(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        let option = match VendorConfigOption::try_from(req.next_u8()) {
+            Ok(option) => option,
+            Err(_) => {
+                resp.write_err();
+                return;
+            }
+        };
+
+        if req.remaining_len() >= 4 {
+            let value = req.next_u32();
+            self.set_config_option(option, value);
+        }
+
+        resp.write_ok();
+        resp.write_u32(self.get_config_option(option));
+    }
+
+    fn set_config_option(&mut self, option: VendorConfigOption, value: u32) {
+        match option {
+            VendorConfigOption::ConnectUnderReset => self.connect_under_reset = value != 0,
+            VendorConfigOption::CJTAG => self.jtag.set_cjtag(value != 0),
+            VendorConfigOption::RTCK => self.jtag.set_rtck(value != 0),
+            VendorConfigOption::SWDRecovery => {
+                self.swd_recovery_threshold = value;
+                self.swd_errors = 0;
+            }
+            VendorConfigOption::WaitIdleCycles => {
+                self.swd.set_wait_idle_cycles(value as usize);
+                self.swd2.set_wait_idle_cycles(value as usize);
+            }
+            VendorConfigOption::WaitDelay => {
+                self.swd.set_wait_delay_us(value);
+                self.swd2.set_wait_delay_us(value);
+            }
+            VendorConfigOption::LogLevel => {
+                if value > u8::MAX as u32 || !crate::log::set_level(value as u8) {
+                    warn!("Invalid log level {=u32}", value);
+                }
+            }
+            VendorConfigOption::CommandTrace => self.trace.set_enabled(value != 0),
+            VendorConfigOption::ResetAssertTime => self.reset_assert_us = value,
+            VendorConfigOption::ResetReleaseDelay => self.reset_release_delay_us = value,
+            VendorConfigOption::ResetPushPull => self.reset_push_pull = value != 0,
+            VendorConfigOption::CommandTimeout => self.set_command_timeout(value),
+            VendorConfigOption::ITMPorts => self.itm_filter.set_ports(value),
+            VendorConfigOption::SWOFraming => self.swo_framing = value != 0,
+            VendorConfigOption::SecondSWD => {
+                // Switch back to the first port, holding or releasing the second.
+                self.second_swd = value != 0;
+                self.select_swd_port(0, true);
+            }
+            VendorConfigOption::SWDPort => {
+                if value == 0 || (value == 1 && self.second_swd) {
+                    self.select_swd_port(value as usize, false);
+                } else {
+                    warn!("Invalid SWD port {=u32}", value);
+                }
+            }
+            VendorConfigOption::FaultRetry => {
+                self.swd.set_fault_retry(value != 0);
+                self.swd2.set_fault_retry(value != 0);
+            }
+            VendorConfigOption::ParityRetries => {
+                self.swd.set_parity_retries(value as usize);
+                self.swd2.set_parity_retries(value as usize);
+            }
+            VendorConfigOption::TargetPulls => {
+                if !valid_target_pulls(value) {
+                    warn!("Invalid target pulls {=u32:#x}", value);
+                } else if value != self.target_pulls {
+                    self.target_pulls = value;
+                    self.pins.set_target_pulls(value);
+                    if self
+                        .config
+                        .write(Key::TargetPulls, &value.to_le_bytes())
+                        .is_err()
+                    {
+                        warn!("Failed to store target pulls");
+                    }
+                }
+            }
+            VendorConfigOption::BrownoutThreshold => self.brownout_threshold_mv = value,
+            VendorConfigOption::BootloaderLock => {
+                let lock = value != 0;
+                if lock != self.bootloader_lock {
+                    self.bootloader_lock = lock;
+                    usb::set_detach_lock(lock);
+                    if self
+                        .config
+                        .write(Key::BootloaderLock, &[lock as u8])
+                        .is_err()
+                    {
+                        warn!("Failed to store bootloader lock");
+                    }
+                }
+            }
+            VendorConfigOption::SwjPinMap => {
+                if !valid_swj_pin_map(value, self.pins.spare_count()) {
+                    warn!("Invalid SWJ pin map {=u32:#x}", value);
+                } else if value != self.swj_pin_map {
+                    self.swj_pin_map = value;
+                    if self
+                        .config
+                        .write(Key::SwjPinMap, &value.to_le_bytes())
+                        .is_err()
+                    {
+                        warn!("Failed to store SWJ pin map");
+                    }
+                }
+            }
+            VendorConfigOption::PinSpeed => {
+                if value <= 3 {
+                    self.pins.set_debug_ospeed(value);
+                    self.pin_speed = value;
+                } else {
+                    warn!("Invalid pin speed {=u32}", value);
+                }
+            }
+        }
+    }
+
+    fn get_config_option(&self, option: VendorConfigOption) -> u32 {
+        match option {
+            VendorConfigOption::ConnectUnderReset => self.connect_under_reset as u32,
+            VendorConfigOption::CJTAG => self.jtag.is_cjtag() as u32,
+            VendorConfigOption::RTCK => self.jtag.is_rtck() as u32,
+            VendorConfigOption::SWDRecovery => self.swd_recovery_threshold,
+            VendorConfigOption::WaitIdleCycles => self.swd.wait_idle_cycles() as u32,
+            VendorConfigOption::WaitDelay => self.swd.wait_delay_us(),
+            VendorConfigOption::LogLevel => crate::log::level() as u32,
+            VendorConfigOption::CommandTrace => self.trace.is_enabled() as u32,
+            VendorConfigOption::ResetAssertTime => self.reset_assert_us,
+            VendorConfigOption::ResetReleaseDelay => self.reset_release_delay_us,
+            VendorConfigOption::ResetPushPull => self.reset_push_pull as u32,
+            VendorConfigOption::CommandTimeout => self.command_timeout_ms,
+            VendorConfigOption::ITMPorts => self.itm_filter.ports(),
+            VendorConfigOption::SWOFraming => self.swo_framing as u32,
+            VendorConfigOption::SecondSWD => self.second_swd as u32,
+            VendorConfigOption::SWDPort => self.swd_port as u32,
+            VendorConfigOption::FaultRetry => self.swd.fault_retry() as u32,
+            VendorConfigOption::ParityRetries => self.swd.parity_retries() as u32,
+            VendorConfigOption::PinSpeed => self.pin_speed,
+            VendorConfigOption::TargetPulls => self.target_pulls,
+            VendorConfigOption::BrownoutThreshold => self.brownout_threshold_mv,
+            VendorConfigOption::BootloaderLock => self.bootloader_lock as u32,
+            VendorConfigOption::SwjPinMap => self.swj_pin_map,
+        }
+    }
+
+    /// Read a piece of vendor-specific probe information.
+    ///
+    /// Request: info ID.
+    /// Response: status, followed by the u32 value.
+    fn process_vendor_info(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        let value = match VendorInfo::try_from(req.next_u8()) {
+            Ok(VendorInfo::SWDClock) => self.swd().frequency(),
+            Ok(VendorInfo::JTAGClock) => self.jtag.frequency(),
+            Ok(VendorInfo::RequestedClock) => self.swj_clock_requested,
+            Err(_) => {
+                resp.write_err();
+                return;
+            }
+        };
+        resp.write_ok();
+        resp.write_u32(value);
+    }
+
+    /// Read entries from the DAP command trace, oldest first.
+    ///
+    /// Request: index of the first entry to read.
+    /// Response: status, number of entries recorded, number of entries in this
+    /// response, then for each entry: u32 cycle counter at the start of processing,
+    /// u32 cycles spent processing, command ID, first three request bytes and
+    /// first two response bytes after the command ID.
+    fn process_vendor_trace(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        let first = if req.remaining_len() >= 1 {
+            req.next_u8() as usize
+        } else {
+            0
+        };
+        resp.write_ok();
+        resp.write_u8(self.trace.len() as u8);
+        let count_idx = resp.idx;
+        resp.skip(1);
+        let n = self.trace.read(first, resp.remaining());
+        resp.write_u8_at(count_idx, n as u8);
+        resp.skip(n * trace::ENTRY_SIZE);
+    }
+
+    /// Read a group of diagnostic counters.
+    ///
+    /// Request: group ID, followed by an optional u8 which resets the
+    /// group's counters after reading them if non-zero.
+    /// Response: status, number of values, then each u32 value.
+    fn process_vendor_diagnostics(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        let group = DiagnosticsGroup::try_from(req.next_u8());
+        let reset = req.remaining_len() >= 1 && req.next_u8() != 0;
+        match group {
+            Ok(DiagnosticsGroup::USB) => {
+                let values = STATS.values();
+                resp.write_ok();
+                resp.write_u8(values.len() as u8 + 2);
+                for &value in values.iter() {
+                    resp.write_u32(value);
+                }
+                resp.write_u32(usb::speed() as u32);
+                resp.write_u32(self.pins.vbus_present().map_or(2, |present| present as u32));
+                if reset {
+                    STATS.reset();
+                }
+            }
+            Ok(DiagnosticsGroup::System) => {
+                resp.write_ok();
+                resp.write_u8(5);
+                resp.write_u32(self.delay.uptime_ms() as u32);
+                resp.write_u32(self.adc.temperature() as u32);
+                resp.write_u32(self.hclk);
+                resp.write_u32(stack::high_water());
+                resp.write_u32(stack::size());
+            }
+            Ok(DiagnosticsGroup::Target) => {
+                resp.write_ok();
+                resp.write_u8(8);
+                resp.write_u32(self.reset_held_externally() as u32);
+                resp.write_u32(self.reset_held_count);
+                resp.write_u32(self.swd().select());
+                resp.write_u32(self.swd().select1());
+                resp.write_u32(self.target_voltage().unwrap_or(u32::MAX));
+                resp.write_u32(self.brownout as u32);
+                resp.write_u32(self.brownout_count);
+                resp.write_u32(self.brownout_min_mv);
+                if reset {
+                    self.reset_held_count = 0;
+                    self.clear_brownout();
+                }
+            }
+            Ok(DiagnosticsGroup::VCP) => {
+                let values = VCP_STATS.values();
+                resp.write_ok();
+                resp.write_u8(values.len() as u8 + 1);
+                for &value in values.iter() {
+                    resp.write_u32(value);
+                }
+                resp.write_u32(vcp::control_lines());
+                if reset {
+                    VCP_STATS.reset();
+                }
+            }
+            Ok(DiagnosticsGroup::Device) => {
+                resp.write_ok();
+                resp.write_u8(7);
+                resp.write_u32(signature::dev_id() as u32);
+                resp.write_u32(signature::rev_id() as u32);
+                resp.write_u32(signature::flash_size_kb() as u32);
+                resp.write_u32(signature::package() as u32);
+                for word in signature::unique_id().chunks(4) {
+                    resp.write_u32(u32::from_le_bytes(word.try_into().unwrap()));
+                }
+            }
+            Err(_) => resp.write_err(),
+        }
+    }
+
+    /// Time a burst of 32-bit MEM-AP accesses over SWD.
+    ///
+    /// The host must select the MEM-AP and configure CSW for 32-bit accesses with
+    /// address auto-increment beforehand, on ADIv6 selecting the bank at 0xD00 of
+    /// the MEM-AP which holds TAR and DRW. TAR is written at the start address and
+    /// at each 1KB boundary, and written data is the address of each word.
+    ///
+    /// Request: operation (0 read, 1 write), u32 start address, u32 number of words.
+    /// Response: status, u32 words transferred, u32 CPU cycles taken, u32 words per second.
+    fn process_vendor_benchmark(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        if !matches!(self.mode, Some(DAPMode::SWD)) || req.remaining_len() < 9 {
+            resp.write_err();
+            return;
+        }
+        let op = match BenchmarkOp::try_from(req.next_u8()) {
+            Ok(op) => op,
+            Err(_) => {
+                resp.write_err();
+                return;
+            }
+        };
+        let address = req.next_u32();
+        let words = req.next_u32();
+
+        self.release_connect_reset();
+        self.swd().spi_mode();
+
+        let start = cortex_m::peripheral::DWT::cycle_count();
+        let (done, result) = self.swd_benchmark(op, address, words);
+        let cycles = cortex_m::peripheral::DWT::cycle_count().wrapping_sub(start);
+
+        match result {
+            Ok(()) => resp.write_ok(),
+            Err(_) => resp.write_err(),
+        }
+        resp.write_u32(done);
+        resp.write_u32(cycles);
+        resp.write_u32((done as u64 * self.hclk as u64 / cycles.max(1) as u64) as u32);
+    }
+
+    /// Perform the accesses for `process_vendor_benchmark`, stopping at the first error.
+    ///
+    /// Returns the number of words transferred and the result.
+    fn swd_benchmark(&self, op: BenchmarkOp, address: u32, words: u32) -> (u32, swd::Result<()>) {
+        const TAR: u8 = 1;
+        const DRW: u8 = 3;
+        let mut done = 0;
+        while done < words && !transfer_aborted() {
+            let addr = address.wrapping_add(done * 4);
+            // TAR is only guaranteed to auto-increment within 1KB blocks.
+            if done == 0 || addr & 0x3FF == 0 {
+                if let Err(e) = self.swd().write(swd::APnDP::AP, TAR, addr) {
+                    return (done, Err(e));
+                }
+            }
+            let result = match op {
+                BenchmarkOp::Read => self.swd().read_ap(DRW).map(|_| ()),
+                BenchmarkOp::Write => self.swd().write(swd::APnDP::AP, DRW, addr),
+            };
+            if let Err(e) = result {
+                return (done, Err(e));
+            }
+            done += 1;
+        }
+        // AP reads are posted, so collect the last one from RDBUFF.
+        if let BenchmarkOp::Read = op {
+            if done > 0 {
+                if let Err(e) = self.swd().read_dp(swd::DPRegister::RDBUFF.into()) {
+                    return (done - 1, Err(e));
+                }
+            }
+        }
+        (done, Ok(()))
+    }
+
+    /// Power cycle the target through tVCC and attach to it over SWD.
+    ///
+    /// The probe must be connected in SWD mode.
+    ///
+    /// Request: u16 time in ms with power off, u16 time in ms to wait after power
+    /// is restored, u8 flags. Flag bit 0 holds nRESET low from before power is
+    /// restored until the first transfer, as for connect under reset.
+    /// Response: status, u32 DPIDR read after the attach sequence.
+    fn process_vendor_power_cycle(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        if !matches!(self.mode, Some(DAPMode::SWD)) || req.remaining_len() < 5 {
+            resp.write_err();
+            return;
+        }
+        let off_ms = req.next_u16() as u32;
+        let on_ms = req.next_u16() as u32;
+        let hold_reset = req.next_u8() & 1 != 0;
+
+        // The rail is switched off on purpose, so don't report it as a brown-out.
+        self.session_tvcc_mv = None;
+        self.in_brownout = false;
+        self.pins.tvcc_en.set_low();
+        if hold_reset {
+            self.pins.reset.set_low();
+            self.connect_reset_asserted = true;
+        }
+        self.delay_us_long(off_ms * 1000);
+        self.pins.tvcc_en.set_high();
+        self.delay_us_long(on_ms * 1000);
+
+        self.swd().spi_mode();
+        self.swd().jtag_to_swd();
+
+        match self.swd().read_dpidr() {
+            Ok(dpidr) => {
+                info!("Attached after power cycle, DPIDR {=u32:#010x}", dpidr);
+                resp.write_ok();
+                resp.write_u32(dpidr);
+            }
+            Err(e) => {
+                warn!("Attach after power cycle failed: {}", e);
+                resp.write_err();
+                resp.write_u32(0);
+            }
+        }
+    }
+
+    /// Check whether anything responds on the debug connector: switch the
+    /// target to SWD and read DPIDR on the selected SWD port, then optionally
+    /// switch it to JTAG and scan the chain. The connection is restored afterwards.
+    ///
+    /// Request: optional u8 flags, bit 0 set to also scan JTAG.
+    /// Response: status, u8 1 if SWD responded, u32 DPIDR or 0, u8 number of
+    /// JTAG devices, then a u32 IDCODE for each device starting nearest TDO.
+    fn process_vendor_probe(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        let scan_jtag = req.remaining_len() >= 1 && req.next_u8() & 1 != 0;
+        self.release_connect_reset();
+        self.swim.exit();
+
+        self.restore_mode(Some(DAPMode::SWD));
+        self.swd().spi_mode();
+        self.swd().jtag_to_swd();
+        let dpidr = match self.swd().read_dpidr() {
+            Ok(dpidr) => {
+                info!("Probe found SWD target, DPIDR {=u32:#010x}", dpidr);
+                Some(dpidr)
+            }
+            Err(e) => {
+                debug!("Probe found no SWD target: {}", e);
+                None
+            }
+        };
+
+        let mut ids = [0u32; 16];
+        let mut count = Some(0);
+        if scan_jtag {
+            self.swd.spi_disable();
+            self.swd2.spi_disable();
+            self.restore_mode(Some(DAPMode::JTAG));
+            self.jtag.swd_to_jtag();
+            let max = ids.len().min((resp.remaining().len() - 7) / 4);
+            count = self.jtag.scan_chain(&mut ids[..max]);
+            if command_expired() {
+                count = None;
+            }
+            info!("Probe found {=usize} JTAG devices", count.unwrap_or(max));
+        }
+
+        self.swd.spi_disable();
+        self.swd2.spi_disable();
+        self.jtag.spi_disable();
+        if self.mode.is_none() {
+            self.pins.high_impedance_mode();
+        }
+        self.restore_mode(self.mode);
+
+        let count = match count {
+            Some(count) => count,
+            None => {
+                warn!("Probe found too many JTAG devices");
+                resp.write_err();
+                return;
+            }
+        };
+        resp.write_ok();
+        resp.write_u8(dpidr.is_some() as u8);
+        resp.write_u32(dpidr.unwrap_or(0));
+        resp.write_u8(count as u8);
+        for &id in &ids[..count] {
+            resp.write_u32(id);
+        }
+    }
+
+    /// Read or set the TargetVendor or TargetName string reported by DAP_Info,
+    /// for a probe permanently wired to one target. Set strings are stored in flash.
+    ///
+    /// Request: u8 DAP_Info ID, 0x05 for TargetVendor or 0x06 for TargetName,
+    /// optionally followed by the new string, with no NUL terminator. A lone
+    /// 0x00 byte clears the string.
+    /// Response: status, u8 string length, then the current string.
+    fn process_vendor_target_info(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        if req.remaining_len() < 1 {
+            resp.write_err();
+            return;
+        }
+        let (info, key) = match DAPInfoID::try_from(req.next_u8()) {
+            Ok(DAPInfoID::TargetVendor) => (&mut self.target_vendor, Key::TargetVendor),
+            Ok(DAPInfoID::TargetName) => (&mut self.target_name, Key::TargetName),
+            _ => {
+                resp.write_err();
+                return;
+            }
+        };
+
+        let value = req.rest();
+        if !value.is_empty() {
+            let value = if value == [0] { &[][..] } else { value };
+            if !info.set(value) {
+                warn!(
+                    "Target info longer than {=usize} bytes",
+                    MAX_TARGET_INFO_LEN
+                );
+                resp.write_err();
+                return;
+            }
+            if self.config.write(key, value).is_err() {
+                warn!("Failed to store target info");
+            }
+        }
+
+        resp.write_ok();
+        resp.write_u8(info.len as u8);
+        resp.write_slice(info.as_bytes());
+    }
+
+    /// Read or set the label appended to the USB product and interface names,
+    /// to tell several probes apart. It is stored in flash and used from the
+    /// next time the probe is connected.
+    ///
+    /// Request: optionally the new label of printable ASCII, with no NUL
+    /// terminator. A lone 0x00 byte clears the label.
+    /// Response: status, u8 label length, then the stored label.
+    fn process_vendor_usb_label(&mut self, req: Request, resp: &mut ResponseWriter) {
+        let value = req.rest();
+        if !value.is_empty() {
+            let value = if value == [0] { &[][..] } else { value };
+            if !usb::names::valid_label(value) {
+                warn!(
+                    "USB label not printable ASCII or longer than {=usize} bytes",
+                    usb::names::MAX_LABEL_LEN
+                );
+                resp.write_err();
+                return;
+            }
+            if self.config.write(Key::UsbLabel, value).is_err() {
+                warn!("Failed to store USB label");
+                resp.write_err();
+                return;
+            }
+        }
+
+        let mut label = [0; usb::names::MAX_LABEL_LEN];
+        let len = self.config.read(Key::UsbLabel, &mut label).unwrap_or(0);
+        resp.write_ok();
+        resp.write_u8(len as u8);
+        resp.write_slice(&label[..len]);
+    }
+
+    /// Restart the probe's firmware, or restart into the system bootloader to
+    /// update it without using the DFU interface. The restart happens shortly
+    /// after the response is sent.
+    ///
+    /// Entering the bootloader takes two requests, so stray traffic cannot
+    /// take the probe away: the first returns a nonce, which must be sent back
+    /// within `BOOTLOAD_CONFIRM_MS`. Until then, DFU_DETACH is also accepted
+    /// when the BootloaderLock option is set.
+    ///
+    /// Request: u8 mode, 0x00 to restart the firmware, 0x01 to request the
+    /// bootloader or 0x02 to confirm it, followed by the u32 nonce.
+    /// Response: status, then for 0x01 the u32 nonce.
+    fn process_vendor_restart(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        if req.remaining_len() < 1 {
+            resp.write_err();
+            return;
+        }
+        let now = self.delay.uptime_ms();
+        match RestartMode::try_from(req.next_u8()) {
+            Ok(RestartMode::Firmware) => {
+                self.restart = Some((RestartMode::Firmware, now + RESTART_DELAY_MS));
+                resp.write_ok();
+            }
+            Ok(RestartMode::Bootloader) => {
+                let nonce = cortex_m::peripheral::DWT::cycle_count();
+                self.bootload_request = Some((nonce, now + BOOTLOAD_CONFIRM_MS));
+                usb::arm_detach(true);
+                info!("Bootloader entry requested");
+                resp.write_ok();
+                resp.write_u32(nonce);
+            }
+            Ok(RestartMode::ConfirmBootloader) => {
+                let nonce = if req.remaining_len() >= 4 {
+                    Some(req.next_u32())
+                } else {
+                    None
+                };
+                match self.bootload_request.take() {
+                    Some((expected, by)) if now < by && nonce == Some(expected) => {
+                        self.restart = Some((RestartMode::Bootloader, now + RESTART_DELAY_MS));
+                        resp.write_ok();
+                    }
+                    _ => {
+                        warn!("Bootloader entry not confirmed");
+                        usb::arm_detach(false);
+                        resp.write_err();
+                    }
+                }
+            }
+            Err(_) => resp.write_err(),
+        }
+    }
+
+    /// Read or write the probe's calibration, during production. It is stored
+    /// in flash, the tVCC calibration applies immediately and the HSI trim
+    /// from the next power-up.
+    ///
+    /// Request: optionally the new calibration: i16 tVCC offset in mV, u16
+    /// tVCC gain in units of 1/10000, then u8 HSITRIM value from 0 to 31.
+    /// A lone 0x00 byte restores the defaults.
+    /// Response: status, then the calibration in the same format.
+    fn process_vendor_calibration(&mut self, req: Request, resp: &mut ResponseWriter) {
+        let value = req.rest();
+        if !value.is_empty() {
+            let calibration = if value == [0] {
+                Some(Calibration::default())
+            } else {
+                Calibration::from_bytes(value)
+            };
+            match calibration {
+                Some(calibration) => self.calibration = calibration,
+                None => {
+                    warn!("Invalid calibration");
+                    resp.write_err();
+                    return;
+                }
+            }
+            let bytes = self.calibration.to_bytes();
+            if self.config.write(Key::Calibration, &bytes).is_err() {
+                warn!("Failed to store calibration");
+                resp.write_err();
+                return;
+            }
+        }
+
+        resp.write_ok();
+        resp.write_slice(&self.calibration.to_bytes());
+    }
+
+    /// Configure, drive or read the board's spare pins, bit n for spare pin n.
+    ///
+    /// Request: operation, then for Direction a mask of pins to make push-pull
+    /// outputs, the rest becoming inputs, or for Write a mask of pins to set
+    /// and their new levels.
+    /// Response: status, number of spare pins, then the level of each pin.
+    fn process_vendor_gpio(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        let count = self.pins.spare_count();
+        let valid = ((1u16 << count) - 1) as u8;
+        if req.remaining_len() < 1 {
+            resp.write_err();
+            return;
+        }
+        let op = match GPIOOp::try_from(req.next_u8()) {
+            Ok(op) => op,
+            Err(_) => {
+                resp.write_err();
+                return;
+            }
+        };
+
+        match op {
+            GPIOOp::Direction if req.remaining_len() >= 1 => {
+                let outputs = req.next_u8();
+                if outputs & !valid != 0 {
+                    resp.write_err();
+                    return;
+                }
+                for (i, pin) in self.pins.spare.iter().flatten().enumerate() {
+                    if outputs & (1 << i) != 0 {
+                        pin.set_otype_pushpull().set_mode_output();
+                    } else {
+                        pin.set_mode_input();
+                    }
+                }
+            }
+            GPIOOp::Write if req.remaining_len() >= 2 => {
+                let mask = req.next_u8();
+                let levels = req.next_u8();
+                if mask & !valid != 0 {
+                    resp.write_err();
+                    return;
+                }
+                for (i, pin) in self.pins.spare.iter().flatten().enumerate() {
+                    if mask & (1 << i) != 0 {
+                        pin.set_bool(levels & (1 << i) != 0);
+                    }
+                }
+            }
+            GPIOOp::Read => (),
+            _ => {
+                resp.write_err();
+                return;
+            }
+        }
+
+        let mut levels = 0;
+        for (i, pin) in self.pins.spare.iter().flatten().enumerate() {
+            if pin.is_high() {
+                levels |= 1 << i;
+            }
+        }
+        resp.write_ok();
+        resp.write_u8(count as u8);
+        resp.write_u8(levels);
+    }
+
+    /// Start or stop PWM output on the board's PWM pin.
+    ///
+    /// Request: u32 frequency in Hz, 0 to stop, and u16 duty cycle in
+    /// hundredths of a percent.
+    /// Response: status, then the u32 actual frequency in Hz.
+    fn process_vendor_pwm(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        if self.pins.pwm.is_none() || req.remaining_len() < 6 {
+            resp.write_err();
+            resp.write_u32(0);
+            return;
+        }
+        let frequency = req.next_u32();
+        let duty = req.next_u16();
+
+        if frequency == 0 {
+            self.pwm.stop();
+            self.pins.pwm_release();
+            resp.write_ok();
+            resp.write_u32(0);
+            return;
+        }
+
+        match self.pwm.start(self.timclk2, frequency, duty) {
+            Some(actual) => {
+                self.pins.pwm_mode();
+                resp.write_ok();
+                resp.write_u32(actual);
+            }
+            None => {
+                resp.write_err();
+                resp.write_u32(0);
+            }
+        }
+    }
+
+    /// Measure one period of the signal on the board's frequency measurement pin.
+    ///
+    /// Request: optional u16 time in ms to wait for two rising edges, default 1000.
+    /// Response: status, u32 frequency in Hz, u16 duty cycle in hundredths of a
+    /// percent, u32 period in timer clock cycles and u32 timer clock in Hz.
+    fn process_vendor_frequency(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        if self.pins.freq.is_none() {
+            resp.write_err();
+            return;
+        }
+        let timeout_ms = if req.remaining_len() >= 2 {
+            req.next_u16() as u64
+        } else {
+            1000
+        };
+
+        self.pins.freq_mode();
+        self.capture.start();
+        let deadline = self.delay.uptime_ms() + timeout_ms;
+        let mut edges = 0;
+        let mut measurement = None;
+        while self.delay.uptime_ms() < deadline {
+            // The first edge only starts the count.
+            if let Some(captured) = self.capture.capture() {
+                edges += 1;
+                if edges == 2 {
+                    measurement = Some(captured);
+                    break;
+                }
+            }
+        }
+        self.capture.stop();
+        self.pins.freq_release();
+
+        match measurement {
+            Some((period, high)) if period != 0 => {
+                let frequency = (self.timclk1 as u64 + period as u64 / 2) / period as u64;
+                let duty = high.min(period) as u64 * 10_000 / period as u64;
+                resp.write_ok();
+                resp.write_u32(frequency as u32);
+                resp.write_u16(duty as u16);
+                resp.write_u32(period);
+                resp.write_u32(self.timclk1);
+            }
+            _ => {
+                debug!("No signal found on frequency measurement pin");
+                resp.write_err();
+            }
+        }
+    }
+
+    /// Process a UPDI command, using the SWO pin as the single-wire UPDI line.
+    ///
+    /// Request: UPDI command ID, followed by command-specific arguments:
+    /// * Start: u32 baud rate. Response: STATUSA.
+    /// * Stop, Break: no arguments or response data.
+    /// * LDCS: register address. Response: register value.
+    /// * STCS: register address, value.
+    /// * Transfer: u8 number of bytes to receive, then bytes to transmit.
+    ///   Response: received bytes.
+    fn process_vendor_updi(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        if req.remaining_len() < 1 {
+            resp.write_err();
+            return;
+        }
+        let command = match UPDICommand::try_from(req.next_u8()) {
+            Ok(command) => command,
+            Err(_) => {
+                resp.write_err();
+                return;
+            }
+        };
+
+        // Check the arguments are all there before touching USART1.
+        let args_len = match command {
+            UPDICommand::Start => 4,
+            UPDICommand::LDCS | UPDICommand::Transfer => 1,
+            UPDICommand::STCS => 2,
+            UPDICommand::Stop | UPDICommand::Break => 0,
+        };
+        if req.remaining_len() < args_len {
+            resp.write_err();
+            return;
+        }
+
+        if let UPDICommand::Start = command {
+            // UPDI takes over USART1 from SWO capture.
+            self.uart.stop();
+            self.swo_streaming = false;
+            self.pins.usart1_half_duplex_mode();
+        } else if !self.uart.is_half_duplex() {
+            resp.write_err();
+            return;
+        }
+
+        let updi = updi::UPDI::new(self.uart, &self.pins.usart1_rx);
+        let result = match command {
+            UPDICommand::Start => {
+                let baud = req.next_u32();
+                updi.start(baud).map(|status| {
+                    resp.write_ok();
+                    resp.write_u8(status);
+                })
+            }
+            UPDICommand::Stop => {
+                updi.stop();
+                self.pins.usart1_rx_mode();
+                resp.write_ok();
+                Ok(())
+            }
+            UPDICommand::Break => {
+                updi.double_break();
+                resp.write_ok();
+                Ok(())
+            }
+            UPDICommand::LDCS => updi.ldcs(req.next_u8()).map(|value| {
+                resp.write_ok();
+                resp.write_u8(value);
+            }),
+            UPDICommand::STCS => {
+                let addr = req.next_u8();
+                let value = req.next_u8();
+                updi.stcs(addr, value).map(|_| resp.write_ok())
+            }
+            UPDICommand::Transfer => {
+                let rx_len = req.next_u8() as usize;
+                resp.write_ok();
+                if rx_len > resp.remaining().len() {
+                    resp.idx = 1;
+                    resp.write_err();
+                    return;
+                }
+                let rx = &mut resp.remaining()[..rx_len];
+                updi.transfer(req.rest(), rx).map(|_| resp.skip(rx_len))
+            }
+        };
+
+        if result.is_err() {
+            // Discard any partial response and report the error.
+            resp.idx = 1;
+            resp.write_err();
+        }
+    }
+
+    /// Reset the JTAG chain and read the IDCODE of every device on it.
+    ///
+    /// Response: status, u8 number of devices, then a u32 IDCODE for each
+    /// device starting nearest TDO, or 0 for devices in BYPASS.
+    fn process_vendor_jtag_scan(&mut self, _req: Request, resp: &mut ResponseWriter) {
+        match self.mode {
+            Some(DAPMode::JTAG) => {}
+            _ => {
+                resp.write_err();
+                return;
+            }
+        }
+
+        self.release_connect_reset();
+
+        let mut ids = [0u32; 32];
+        let max = core::cmp::min(ids.len(), (resp.remaining().len() - 2) / 4);
+        match self.jtag.scan_chain(&mut ids[..max]) {
+            _ if command_expired() => {
+                warn!("JTAG scan timed out");
+                resp.write_err();
+            }
+            Some(count) => {
+                info!("JTAG scan found {=usize} devices", count);
+                resp.write_ok();
+                resp.write_u8(count as u8);
+                for &id in &ids[..count] {
+                    resp.write_u32(id);
+                }
+            }
+            None => {
+                warn!("JTAG scan found more than {=usize} devices", max);
+                resp.write_err();
+            }
+        }
+    }
+
+    /// Process an STM8 SWIM command, using the SWDIO pin as the SWIM line.
+    ///
+    /// Request: SWIM command ID, followed by command-specific arguments:
+    /// * Enter, Exit, SRST: no arguments or response data.
+    /// * Read: u8 number of bytes, u32 address. Response: read bytes.
+    /// * Write: u32 address, then bytes to write.
+    /// * Reset: u8 1 to assert nRESET or 0 to release it.
+    fn process_vendor_swim(&mut self, mut req: Request, resp: &mut ResponseWriter) {
+        if req.remaining_len() < 1 {
+            resp.write_err();
+            return;
+        }
+        let command = match SWIMCommand::try_from(req.next_u8()) {
+            Ok(command) => command,
+            Err(_) => {
+                resp.write_err();
+                return;
+            }
+        };
+
+        // Check the arguments are all there before releasing SWD or JTAG.
+        let args_len = match command {
+            SWIMCommand::Read => 5,
+            SWIMCommand::Write => 4,
+            SWIMCommand::Reset => 1,
+            SWIMCommand::Enter | SWIMCommand::Exit | SWIMCommand::SRST => 0,
+        };
+        if req.remaining_len() < args_len {
+            resp.write_err();
+            return;
+        }
+
+        let result = match command {
+            SWIMCommand::Enter => {
+                // SWIM takes over the SWDIO pin from any SWD or JTAG connection.
+                self.release_connect_reset();
+                self.swd.spi_disable();
+                self.jtag.spi_disable();
+                self.mode = None;
+                self.swim.enter().map(|_| resp.write_ok())
+            }
+            SWIMCommand::Exit => {
+                self.swim.exit();
+                resp.write_ok();
+                Ok(())
+            }
+            SWIMCommand::SRST => self.swim.srst().map(|_| resp.write_ok()),
+            SWIMCommand::Read => {
+                let len = req.next_u8() as usize;
+                let addr = req.next_u32();
+                resp.write_ok();
+                if len > resp.remaining().len() {
+                    resp.idx = 1;
+                    resp.write_err();
+                    return;
+                }
+                let buf = &mut resp.remaining()[..len];
+                self.swim.read(addr, buf).map(|_| resp.skip(len))
+            }
+            SWIMCommand::Write => {
+                let addr = req.next_u32();
+                let data = req.rest();
+                if data.is_empty() || data.len() > 255 {
+                    resp.write_err();
+                    return;
+                }
+                self.swim.write(addr, data).map(|_| resp.write_ok())
+            }
+            SWIMCommand::Reset => {
+                self.pins.reset.set_bool(req.next_u8() == 0);
+                resp.write_ok();
+                Ok(())
+            }
+        };
+
+        if result.is_err() {
+            // Discard any partial response and report the error.
+            resp.idx = 1;
+            resp.write_err();
+        }
     }
 }
 
@@ -0,0 +1,186 @@
+use crate::bsp::gpio::Pins;
+use crate::dap;
+use crate::jtag::JTAG;
+use core::convert::TryFrom;
+use num_enum::TryFromPrimitive;
+
+#[derive(Copy, Clone, TryFromPrimitive)]
+#[repr(u8)]
+enum Command {
+    Stop = 0x00,
+    Info = 0x01,
+    Freq = 0x02,
+    Xfer = 0x03,
+    SetSig = 0x04,
+    GetSig = 0x05,
+    Clk = 0x06,
+    SetVoltage = 0x07,
+    GotoBootloader = 0x08,
+}
+
+/// Modifier bits in the upper part of each command byte.
+const CMD_MASK: u8 = 0x0F;
+const EXTEND_LENGTH: u8 = 0x40;
+const NO_READ: u8 = 0x80;
+const READOUT: u8 = 0x80;
+
+const SIG_TCK: u8 = 1 << 1;
+const SIG_TDI: u8 = 1 << 2;
+const SIG_TDO: u8 = 1 << 3;
+const SIG_TMS: u8 = 1 << 4;
+const SIG_TRST: u8 = 1 << 5;
+const SIG_SRST: u8 = 1 << 6;
+
+const INFO: &[u8] = b"DJTAG2\n\0";
+
+/// Largest XFER handled, limited by the 9 bit length field.
+const MAX_XFER_BITS: usize = 511;
+
+/// Process one packet of DirtyJTAG commands, mapping them onto `jtag`.
+///
+/// Commands are processed until CMD_STOP, the end of the packet, a command
+/// whose response doesn't fit in what is left of `resp`, which isn't run, or
+/// the packet running out of time, after which the last command may be cut short.
+/// Responses of all commands in the packet are concatenated into `resp`,
+/// so hosts should only queue one command with a response per packet.
+///
+/// Returns the number of bytes written to `resp`.
+pub fn process_command(jtag: &JTAG, pins: &Pins, mut req: &[u8], resp: &mut [u8]) -> usize {
+    let mut idx = 0;
+
+    while let Some((&cmd, args)) = req.split_first() {
+        if dap::command_expired() {
+            warn!("DirtyJTAG packet timed out");
+            break;
+        }
+        let used = match Command::try_from(cmd & CMD_MASK) {
+            Ok(Command::Stop) | Err(_) => break,
+            Ok(Command::Info) => {
+                if idx + INFO.len() > resp.len() {
+                    break;
+                }
+                resp[idx..idx + INFO.len()].copy_from_slice(INFO);
+                idx += INFO.len();
+                0
+            }
+            Ok(Command::Freq) => {
+                if args.len() < 2 {
+                    break;
+                }
+                let khz = u16::from_be_bytes([args[0], args[1]]) as u32;
+                jtag.set_clock(khz.max(1) * 1000);
+                2
+            }
+            Ok(Command::Xfer) => {
+                if args.is_empty() {
+                    break;
+                }
+                let mut bits = args[0] as usize;
+                if cmd & EXTEND_LENGTH != 0 {
+                    bits += 256;
+                }
+                let bits = bits.min(MAX_XFER_BITS);
+                let nbytes = (bits + 7) / 8;
+                if args.len() < 1 + nbytes {
+                    break;
+                }
+                if cmd & NO_READ == 0 && idx + nbytes > resp.len() {
+                    break;
+                }
+
+                // DirtyJTAG shifts MSbit first, the JTAG engine LSbit first.
+                let mut tdi = [0u8; (MAX_XFER_BITS + 7) / 8];
+                for (t, d) in tdi.iter_mut().zip(&args[1..1 + nbytes]) {
+                    *t = d.reverse_bits();
+                }
+                let tms = pins.spi1_mosi.is_high();
+                if cmd & NO_READ != 0 {
+                    jtag.shift(bits, tms, &tdi[..nbytes], None);
+                } else {
+                    let tdo = &mut resp[idx..idx + nbytes];
+                    jtag.shift(bits, tms, &tdi[..nbytes], Some(tdo));
+                    for byte in tdo.iter_mut() {
+                        *byte = byte.reverse_bits();
+                    }
+                    idx += nbytes;
+                }
+                1 + nbytes
+            }
+            Ok(Command::SetSig) => {
+                if args.len() < 2 {
+                    break;
+                }
+                let (mask, value) = (args[0], args[1]);
+                if mask & SIG_TCK != 0 {
+                    pins.spi2_clk.set_bool(value & SIG_TCK != 0);
+                }
+                if mask & SIG_TDI != 0 {
+                    pins.spi2_mosi.set_bool(value & SIG_TDI != 0);
+                }
+                if mask & SIG_TMS != 0 {
+                    pins.spi1_mosi.set_bool(value & SIG_TMS != 0);
+                }
+                if mask & SIG_TRST != 0 {
+                    pins.ntrst.set_bool(value & SIG_TRST != 0);
+                }
+                if mask & SIG_SRST != 0 {
+                    pins.reset.set_bool(value & SIG_SRST != 0);
+                }
+                2
+            }
+            Ok(Command::GetSig) => {
+                if idx >= resp.len() {
+                    break;
+                }
+                let mut signals = 0;
+                if pins.spi2_clk.is_high() {
+                    signals |= SIG_TCK;
+                }
+                if pins.spi2_mosi.is_high() {
+                    signals |= SIG_TDI;
+                }
+                if pins.spi2_miso.is_high() {
+                    signals |= SIG_TDO;
+                }
+                if pins.spi1_mosi.is_high() {
+                    signals |= SIG_TMS;
+                }
+                if pins.ntrst.is_high() {
+                    signals |= SIG_TRST;
+                }
+                if pins.reset.is_high() {
+                    signals |= SIG_SRST;
+                }
+                resp[idx] = signals;
+                idx += 1;
+                0
+            }
+            Ok(Command::Clk) => {
+                if args.len() < 2 {
+                    break;
+                }
+                if cmd & READOUT != 0 && idx >= resp.len() {
+                    break;
+                }
+                let (signals, pulses) = (args[0], args[1]);
+                let tdo = jtag.clock(
+                    pulses as usize,
+                    signals & SIG_TMS != 0,
+                    signals & SIG_TDI != 0,
+                );
+                if cmd & READOUT != 0 {
+                    resp[idx] = tdo as u8;
+                    idx += 1;
+                }
+                2
+            }
+            // The probe has no adjustable target voltage and is updated
+            // through DFU, so these are accepted and ignored.
+            Ok(Command::SetVoltage) => args.len().min(1),
+            Ok(Command::GotoBootloader) => 0,
+        };
+        req = &args[used..];
+    }
+
+    idx
+}
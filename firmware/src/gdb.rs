@@ -0,0 +1,792 @@
+//! Minimal GDB remote serial protocol server on the CDC serial port.
+//!
+//! While the CDC port is set to `GDB_BAUD`, GDB can debug a Cortex-M target
+//! on the selected SWD port with `target extended-remote`, without any probe
+//! software on the host. Core registers, memory, halting, single stepping,
+//! breakpoints through the FPB and `monitor reset` are supported.
+
+use crate::dap::DAP;
+use crate::swd::{self, APnDP, DPRegister};
+
+/// Line coding baud rate which switches the CDC port to the GDB server,
+/// chosen as a rate no real serial link uses.
+pub const GDB_BAUD: u32 = 1_000_002;
+
+/// Largest packet accepted from GDB, as advertised in qSupported.
+const PACKET_SIZE: usize = 1024;
+const PACKET_SIZE_REPLY: &[u8] = b"PacketSize=400;qXfer:features:read+";
+
+/// Largest memory read or write in one packet, hex encoded with some headroom.
+const MAX_MEMORY_LEN: usize = (PACKET_SIZE - 32) / 2;
+
+/// Time between checks of whether a running target has halted.
+const HALT_POLL_MS: u64 = 10;
+
+/// Polls of DHCSR before a register transfer or step is given up on.
+const DHCSR_RETRIES: usize = 1000;
+
+/// Core registers r0-r12, sp, lr, pc and xpsr, numbered as in DCRSR.
+const NUM_REGISTERS: u32 = 17;
+
+const TARGET_XML: &[u8] = b"<?xml version=\"1.0\"?>\
+<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+<target><architecture>arm</architecture>\
+<feature name=\"org.gnu.gdb.arm.m-profile\">\
+<reg name=\"r0\" bitsize=\"32\"/><reg name=\"r1\" bitsize=\"32\"/>\
+<reg name=\"r2\" bitsize=\"32\"/><reg name=\"r3\" bitsize=\"32\"/>\
+<reg name=\"r4\" bitsize=\"32\"/><reg name=\"r5\" bitsize=\"32\"/>\
+<reg name=\"r6\" bitsize=\"32\"/><reg name=\"r7\" bitsize=\"32\"/>\
+<reg name=\"r8\" bitsize=\"32\"/><reg name=\"r9\" bitsize=\"32\"/>\
+<reg name=\"r10\" bitsize=\"32\"/><reg name=\"r11\" bitsize=\"32\"/>\
+<reg name=\"r12\" bitsize=\"32\"/>\
+<reg name=\"sp\" bitsize=\"32\" type=\"data_ptr\"/>\
+<reg name=\"lr\" bitsize=\"32\"/>\
+<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\"/>\
+<reg name=\"xpsr\" bitsize=\"32\"/>\
+</feature></target>";
+
+// Cortex-M debug registers.
+const DHCSR: u32 = 0xE000_EDF0;
+const DCRSR: u32 = 0xE000_EDF4;
+const DCRDR: u32 = 0xE000_EDF8;
+const DEMCR: u32 = 0xE000_EDFC;
+const AIRCR: u32 = 0xE000_ED0C;
+const FP_CTRL: u32 = 0xE000_2000;
+const FP_COMP0: u32 = 0xE000_2008;
+
+const DHCSR_KEY: u32 = 0xA05F << 16;
+const DHCSR_C_DEBUGEN: u32 = 1 << 0;
+const DHCSR_C_HALT: u32 = 1 << 1;
+const DHCSR_C_STEP: u32 = 1 << 2;
+const DHCSR_C_MASKINTS: u32 = 1 << 3;
+const DHCSR_S_REGRDY: u32 = 1 << 16;
+const DHCSR_S_HALT: u32 = 1 << 17;
+const DCRSR_REGWNR: u32 = 1 << 16;
+const DEMCR_VC_CORERESET: u32 = 1 << 0;
+const AIRCR_SYSRESETREQ: u32 = (0x05FA << 16) | (1 << 2);
+
+/// Most FPB code comparators tracked.
+const MAX_BREAKPOINTS: usize = 8;
+
+// DP and MEM-AP registers, by A[3:2].
+const DP_ABORT: u8 = 0;
+const AP_CSW: u8 = 0;
+const AP_TAR: u8 = 1;
+const AP_DRW: u8 = 3;
+
+/// ABORT value clearing all sticky errors.
+const ABORT_CLEAR_ERRORS: u32 = 0b1_1110;
+/// CTRL/STAT CSYSPWRUPREQ and CDBGPWRUPREQ, and their ACKs.
+const CTRLSTAT_PWRUPREQ: u32 = (1 << 30) | (1 << 28);
+const CTRLSTAT_PWRUPACK: u32 = (1 << 31) | (1 << 29);
+/// CSW for 32-bit accesses without address increment, as a privileged debugger.
+const CSW_WORD: u32 = 0x2300_0002;
+
+#[derive(Copy, Clone, Debug, defmt::Format)]
+enum Error {
+    Swd(swd::Error),
+    /// The core did not finish a register transfer or step in time.
+    Timeout,
+}
+
+impl From<swd::Error> for Error {
+    fn from(err: swd::Error) -> Self {
+        Error::Swd(err)
+    }
+}
+
+type Result<T> = core::result::Result<T, Error>;
+
+/// Cortex-M core accessed through MEM-AP 0.
+struct Target<'s, 'a> {
+    swd: &'s swd::SWD<'a>,
+}
+
+impl<'s, 'a> Target<'s, 'a> {
+    /// Switch the target to SWD, power up its debug domain and halt the core.
+    fn attach(&self) -> Result<()> {
+        self.swd.spi_mode();
+        self.swd.jtag_to_swd();
+        let dpidr = self.swd.read_dpidr()?;
+        self.swd.write_dp(DP_ABORT, ABORT_CLEAR_ERRORS)?;
+        self.swd.write_dp(DPRegister::SELECT.into(), 0)?;
+        self.swd
+            .write_dp(DPRegister::CTRLSTAT.into(), CTRLSTAT_PWRUPREQ)?;
+        let mut powered = false;
+        for _ in 0..DHCSR_RETRIES {
+            let ctrlstat = self.swd.read_dp(DPRegister::CTRLSTAT.into())?;
+            if ctrlstat & CTRLSTAT_PWRUPACK == CTRLSTAT_PWRUPACK {
+                powered = true;
+                break;
+            }
+        }
+        if !powered {
+            return Err(Error::Timeout);
+        }
+        self.swd.write(APnDP::AP, AP_CSW, CSW_WORD)?;
+        self.halt()?;
+        info!("GDB server attached, DPIDR {=u32:#010x}", dpidr);
+        Ok(())
+    }
+
+    fn read32(&self, address: u32) -> Result<u32> {
+        self.swd.write(APnDP::AP, AP_TAR, address)?;
+        // AP reads are posted, so collect the result from RDBUFF.
+        self.swd.read_ap(AP_DRW)?;
+        Ok(self.swd.read_dp(DPRegister::RDBUFF.into())?)
+    }
+
+    fn write32(&self, address: u32, value: u32) -> Result<()> {
+        self.swd.write(APnDP::AP, AP_TAR, address)?;
+        Ok(self.swd.write(APnDP::AP, AP_DRW, value)?)
+    }
+
+    fn read_memory(&self, address: u32, buf: &mut [u8]) -> Result<()> {
+        let mut cached = None;
+        let mut word = 0;
+        for (i, byte) in buf.iter_mut().enumerate() {
+            let addr = address.wrapping_add(i as u32);
+            if cached != Some(addr & !3) {
+                word = self.read32(addr & !3)?;
+                cached = Some(addr & !3);
+            }
+            *byte = word.to_le_bytes()[(addr & 3) as usize];
+        }
+        Ok(())
+    }
+
+    /// Write `data` with word accesses, reading back words which are only partly written.
+    fn write_memory(&self, address: u32, data: &[u8]) -> Result<()> {
+        let mut i = 0;
+        while i < data.len() {
+            let addr = address.wrapping_add(i as u32);
+            let offset = (addr & 3) as usize;
+            let n = (4 - offset).min(data.len() - i);
+            let mut bytes = if n == 4 {
+                [0; 4]
+            } else {
+                self.read32(addr & !3)?.to_le_bytes()
+            };
+            bytes[offset..offset + n].copy_from_slice(&data[i..i + n]);
+            self.write32(addr & !3, u32::from_le_bytes(bytes))?;
+            i += n;
+        }
+        Ok(())
+    }
+
+    fn wait_dhcsr(&self, flag: u32) -> Result<()> {
+        for _ in 0..DHCSR_RETRIES {
+            if self.read32(DHCSR)? & flag != 0 {
+                return Ok(());
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    fn read_register(&self, n: u32) -> Result<u32> {
+        self.write32(DCRSR, n)?;
+        self.wait_dhcsr(DHCSR_S_REGRDY)?;
+        self.read32(DCRDR)
+    }
+
+    fn write_register(&self, n: u32, value: u32) -> Result<()> {
+        self.write32(DCRDR, value)?;
+        self.write32(DCRSR, n | DCRSR_REGWNR)?;
+        self.wait_dhcsr(DHCSR_S_REGRDY)
+    }
+
+    fn halt(&self) -> Result<()> {
+        self.write32(DHCSR, DHCSR_KEY | DHCSR_C_DEBUGEN | DHCSR_C_HALT)
+    }
+
+    fn resume(&self) -> Result<()> {
+        self.write32(DHCSR, DHCSR_KEY | DHCSR_C_DEBUGEN)
+    }
+
+    /// Execute one instruction with interrupts masked, waiting for the core to halt again.
+    fn step(&self) -> Result<()> {
+        self.write32(
+            DHCSR,
+            DHCSR_KEY | DHCSR_C_DEBUGEN | DHCSR_C_MASKINTS | DHCSR_C_STEP,
+        )?;
+        self.wait_dhcsr(DHCSR_S_HALT)
+    }
+
+    fn is_halted(&self) -> Result<bool> {
+        Ok(self.read32(DHCSR)? & DHCSR_S_HALT != 0)
+    }
+
+    /// Reset the system, halting the core at the reset vector.
+    fn reset(&self) -> Result<()> {
+        let demcr = self.read32(DEMCR)?;
+        self.write32(DEMCR, demcr | DEMCR_VC_CORERESET)?;
+        // The target may not complete the write before resetting.
+        self.write32(AIRCR, AIRCR_SYSRESETREQ).ok();
+        for _ in 0..DHCSR_RETRIES {
+            if let Ok(true) = self.is_halted() {
+                return self.write32(DEMCR, demcr);
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    /// Comparator value breaking at `address`, or None if the FPB can't.
+    fn fpb_comparator(&self, address: u32) -> Result<Option<u32>> {
+        let ctrl = self.read32(FP_CTRL)?;
+        if ctrl >> 28 == 0 {
+            // FPBv1 only matches the code region, on either halfword of a word.
+            if address >= 0x2000_0000 {
+                return Ok(None);
+            }
+            let replace = if address & 2 == 0 { 1 << 30 } else { 2 << 30 };
+            Ok(Some((address & 0x1FFF_FFFC) | replace | 1))
+        } else {
+            Ok(Some((address & !1) | 1))
+        }
+    }
+
+    fn num_comparators(&self) -> Result<usize> {
+        let ctrl = self.read32(FP_CTRL)?;
+        Ok((((ctrl >> 4) & 0xF) | (((ctrl >> 12) & 0x7) << 4)) as usize)
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Parse {
+    /// Between packets, where GDB sends acknowledgements and interrupts.
+    Idle,
+    Data,
+    Checksum {
+        digits: u8,
+        value: u8,
+    },
+}
+
+/// Response being built and sent to GDB.
+struct Output {
+    buf: [u8; PACKET_SIZE + 8],
+    len: usize,
+    sent: usize,
+    checksum: u8,
+}
+
+impl Output {
+    fn clear(&mut self) {
+        self.len = 0;
+        self.sent = 0;
+    }
+
+    fn raw(&mut self, byte: u8) {
+        if self.len < self.buf.len() {
+            self.buf[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn begin(&mut self) {
+        self.raw(b'$');
+        self.checksum = 0;
+    }
+
+    fn push(&mut self, byte: u8) {
+        // Leave room for the checksum.
+        if self.len < self.buf.len() - 3 {
+            self.raw(byte);
+            self.checksum = self.checksum.wrapping_add(byte);
+        }
+    }
+
+    fn push_str(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.push(byte);
+        }
+    }
+
+    fn push_hex(&mut self, byte: u8) {
+        self.push(hex_digit(byte >> 4));
+        self.push(hex_digit(byte & 0xF));
+    }
+
+    fn end(&mut self) {
+        let checksum = self.checksum;
+        self.raw(b'#');
+        self.raw(hex_digit(checksum >> 4));
+        self.raw(hex_digit(checksum & 0xF));
+    }
+
+    /// Send a complete packet holding `data`.
+    fn packet(&mut self, data: &[u8]) {
+        self.begin();
+        self.push_str(data);
+        self.end();
+    }
+}
+
+pub struct GdbServer {
+    /// Last packet received over USB, and how much of it has been parsed.
+    input: [u8; crate::VCP_PACKET_SIZE as usize],
+    input_len: usize,
+    input_pos: usize,
+    parse: Parse,
+    packet: [u8; PACKET_SIZE],
+    packet_len: usize,
+    checksum: u8,
+    output: Output,
+    attached: bool,
+    running: bool,
+    interrupted: bool,
+    last_halt_poll_ms: u64,
+    breakpoints: [Option<u32>; MAX_BREAKPOINTS],
+}
+
+impl GdbServer {
+    pub const fn new() -> Self {
+        GdbServer {
+            input: [0; crate::VCP_PACKET_SIZE as usize],
+            input_len: 0,
+            input_pos: 0,
+            parse: Parse::Idle,
+            packet: [0; PACKET_SIZE],
+            packet_len: 0,
+            checksum: 0,
+            output: Output {
+                buf: [0; PACKET_SIZE + 8],
+                len: 0,
+                sent: 0,
+                checksum: 0,
+            },
+            attached: false,
+            running: false,
+            interrupted: false,
+            last_halt_poll_ms: 0,
+            breakpoints: [None; MAX_BREAKPOINTS],
+        }
+    }
+
+    /// Start a new session, for when the CDC port switches to the GDB server.
+    pub fn reset(&mut self) {
+        self.input_len = 0;
+        self.input_pos = 0;
+        self.parse = Parse::Idle;
+        self.output.clear();
+        self.attached = false;
+        self.running = false;
+        self.interrupted = false;
+        self.breakpoints = [None; MAX_BREAKPOINTS];
+    }
+
+    /// Check if the last packet from the host has been used up, so another can be received.
+    pub fn is_idle(&self) -> bool {
+        self.input_pos == self.input_len
+    }
+
+    /// Store a packet received from the host. Must only be called when idle.
+    pub fn push(&mut self, data: &[u8]) {
+        let len = data.len().min(self.input.len());
+        self.input[..len].copy_from_slice(&data[..len]);
+        self.input_len = len;
+        self.input_pos = 0;
+    }
+
+    /// Send pending output with `write`, which returns how many bytes it
+    /// accepted, then check on a running target and handle the next packet.
+    pub fn poll(&mut self, dap: &mut DAP, now_ms: u64, mut write: impl FnMut(&[u8]) -> usize) {
+        if self.output.sent < self.output.len {
+            self.output.sent += write(&self.output.buf[self.output.sent..self.output.len]);
+            if self.output.sent < self.output.len {
+                return;
+            }
+        }
+        self.output.clear();
+
+        let complete = self.parse_input();
+
+        if self.running {
+            self.poll_running(dap, now_ms);
+        }
+
+        match complete {
+            Some(true) => {
+                self.output.raw(b'+');
+                let target = Target {
+                    swd: dap.connect_swd(),
+                };
+                self.handle(&target);
+            }
+            Some(false) => self.output.raw(b'-'),
+            None => (),
+        }
+
+        if self.output.len > 0 {
+            self.output.sent = write(&self.output.buf[..self.output.len]);
+        }
+    }
+
+    /// Report a stop once a running target halts, or halt it if GDB interrupted.
+    fn poll_running(&mut self, dap: &mut DAP, now_ms: u64) {
+        if !self.interrupted && now_ms < self.last_halt_poll_ms + HALT_POLL_MS {
+            return;
+        }
+        self.last_halt_poll_ms = now_ms;
+        let target = Target {
+            swd: dap.connect_swd(),
+        };
+        let signal = if self.interrupted {
+            self.interrupted = false;
+            if let Err(e) = target.halt() {
+                warn!("GDB server failed to halt target: {}", e);
+            }
+            b"S02"
+        } else {
+            match target.is_halted() {
+                Ok(true) => b"S05",
+                _ => return,
+            }
+        };
+        self.running = false;
+        self.output.packet(signal);
+    }
+
+    /// Parse input until a packet is complete, returning whether its checksum matched.
+    fn parse_input(&mut self) -> Option<bool> {
+        while self.input_pos < self.input_len {
+            let byte = self.input[self.input_pos];
+            self.input_pos += 1;
+            match self.parse {
+                Parse::Idle => match byte {
+                    b'$' => {
+                        self.parse = Parse::Data;
+                        self.packet_len = 0;
+                        self.checksum = 0;
+                    }
+                    0x03 => self.interrupted = self.running,
+                    // Acknowledgements of our packets, which are not resent.
+                    _ => (),
+                },
+                Parse::Data => {
+                    if byte == b'#' {
+                        self.parse = Parse::Checksum {
+                            digits: 0,
+                            value: 0,
+                        };
+                    } else {
+                        if self.packet_len < self.packet.len() {
+                            self.packet[self.packet_len] = byte;
+                            self.packet_len += 1;
+                        }
+                        self.checksum = self.checksum.wrapping_add(byte);
+                    }
+                }
+                Parse::Checksum { digits, value } => {
+                    let value = (value << 4) | hex_value(byte).unwrap_or(0xFF);
+                    if digits == 0 {
+                        self.parse = Parse::Checksum { digits: 1, value };
+                    } else {
+                        self.parse = Parse::Idle;
+                        return Some(value == self.checksum);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn handle(&mut self, target: &Target) {
+        let len = self.packet_len;
+        let packet = &self.packet[..len];
+        if !self.attached && packet.first() != Some(&b'q') {
+            match target.attach() {
+                Ok(()) => self.attached = true,
+                Err(e) => {
+                    warn!("GDB server failed to attach: {}", e);
+                    self.output.packet(b"E01");
+                    return;
+                }
+            }
+        }
+
+        let result = match packet.first() {
+            Some(b'?') => {
+                self.output.packet(b"S05");
+                Ok(())
+            }
+            Some(b'g') => self.read_registers(target),
+            Some(b'p') => match parse_hex(&packet[1..]) {
+                Some(n) if n < NUM_REGISTERS => target.read_register(n).map(|value| {
+                    self.output.begin();
+                    for byte in value.to_le_bytes().iter() {
+                        self.output.push_hex(*byte);
+                    }
+                    self.output.end();
+                }),
+                _ => {
+                    self.output.packet(b"E01");
+                    Ok(())
+                }
+            },
+            Some(b'P') => {
+                let mut fields = packet[1..].split(|&b| b == b'=');
+                let n = fields.next().and_then(parse_hex);
+                let value = fields.next().and_then(parse_hex_le);
+                match (n, value) {
+                    (Some(n), Some(value)) if n < NUM_REGISTERS => {
+                        target.write_register(n, value).map(|_| self.ok())
+                    }
+                    _ => {
+                        self.output.packet(b"E01");
+                        Ok(())
+                    }
+                }
+            }
+            Some(b'm') => self.read_memory(target),
+            Some(b'M') => self.write_memory(target),
+            Some(b'c') => {
+                // Continuing from an address is not supported, only from the current pc.
+                target.resume().map(|_| self.running = true)
+            }
+            Some(b's') => target.step().map(|_| self.output.packet(b"S05")),
+            Some(b'Z') => self.breakpoint(target, true),
+            Some(b'z') => self.breakpoint(target, false),
+            Some(b'D') => {
+                self.detach(target);
+                self.ok();
+                Ok(())
+            }
+            Some(b'k') => {
+                self.detach(target);
+                Ok(())
+            }
+            Some(b'H') => {
+                self.ok();
+                Ok(())
+            }
+            Some(b'q') => {
+                self.query(target);
+                Ok(())
+            }
+            // Unsupported packets get an empty response.
+            _ => {
+                self.output.packet(b"");
+                Ok(())
+            }
+        };
+
+        if let Err(e) = result {
+            debug!("GDB packet failed: {}", e);
+            self.output.packet(b"E01");
+        }
+    }
+
+    fn ok(&mut self) {
+        self.output.packet(b"OK");
+    }
+
+    fn read_registers(&mut self, target: &Target) -> Result<()> {
+        let mut values = [0u32; NUM_REGISTERS as usize];
+        for (n, value) in values.iter_mut().enumerate() {
+            *value = target.read_register(n as u32)?;
+        }
+        self.output.begin();
+        for value in values.iter() {
+            for byte in value.to_le_bytes().iter() {
+                self.output.push_hex(*byte);
+            }
+        }
+        self.output.end();
+        Ok(())
+    }
+
+    fn read_memory(&mut self, target: &Target) -> Result<()> {
+        let (address, len) = match parse_address_len(&self.packet[1..self.packet_len]) {
+            Some((address, len, _)) => (address, (len as usize).min(MAX_MEMORY_LEN)),
+            None => {
+                self.output.packet(b"E01");
+                return Ok(());
+            }
+        };
+        let mut data = [0; MAX_MEMORY_LEN];
+        target.read_memory(address, &mut data[..len])?;
+        self.output.begin();
+        for &byte in data[..len].iter() {
+            self.output.push_hex(byte);
+        }
+        self.output.end();
+        Ok(())
+    }
+
+    fn write_memory(&mut self, target: &Target) -> Result<()> {
+        let mut data = [0; MAX_MEMORY_LEN];
+        let parsed =
+            parse_address_len(&self.packet[1..self.packet_len]).and_then(|(address, len, hex)| {
+                let len = len as usize;
+                if len > MAX_MEMORY_LEN || hex.len() < 2 * len {
+                    return None;
+                }
+                for (i, byte) in data[..len].iter_mut().enumerate() {
+                    *byte = (hex_value(hex[2 * i])? << 4) | hex_value(hex[2 * i + 1])?;
+                }
+                Some((address, len))
+            });
+        match parsed {
+            Some((address, len)) => target
+                .write_memory(address, &data[..len])
+                .map(|_| self.ok()),
+            None => {
+                self.output.packet(b"E01");
+                Ok(())
+            }
+        }
+    }
+
+    /// Insert or remove a breakpoint, using the FPB for both software and hardware breakpoints.
+    fn breakpoint(&mut self, target: &Target, insert: bool) -> Result<()> {
+        let packet = &self.packet[1..self.packet_len];
+        let mut fields = packet.split(|&b| b == b',');
+        let kind = fields.next();
+        let address = fields.next().and_then(parse_hex);
+        let address = match (kind, address) {
+            (Some(b"0"), Some(address)) | (Some(b"1"), Some(address)) => address,
+            // Watchpoints are not supported.
+            _ => {
+                self.output.packet(b"");
+                return Ok(());
+            }
+        };
+
+        let comparators = target.num_comparators()?.min(MAX_BREAKPOINTS);
+        let slot = if insert {
+            self.breakpoints[..comparators]
+                .iter()
+                .position(|bp| bp.is_none() || *bp == Some(address))
+        } else {
+            self.breakpoints[..comparators]
+                .iter()
+                .position(|bp| *bp == Some(address))
+        };
+        let slot = match slot {
+            Some(slot) => slot,
+            None if insert => {
+                self.output.packet(b"E01");
+                return Ok(());
+            }
+            None => {
+                self.ok();
+                return Ok(());
+            }
+        };
+
+        if insert {
+            let comparator = match target.fpb_comparator(address)? {
+                Some(comparator) => comparator,
+                None => {
+                    self.output.packet(b"E01");
+                    return Ok(());
+                }
+            };
+            // Enable the FPB, with the KEY bit set so the write takes effect.
+            target.write32(FP_CTRL, 0b11)?;
+            target.write32(FP_COMP0 + 4 * slot as u32, comparator)?;
+            self.breakpoints[slot] = Some(address);
+        } else {
+            target.write32(FP_COMP0 + 4 * slot as u32, 0)?;
+            self.breakpoints[slot] = None;
+        }
+        self.ok();
+        Ok(())
+    }
+
+    /// Clear breakpoints and let the target run without the debugger.
+    fn detach(&mut self, target: &Target) {
+        for (slot, bp) in self.breakpoints.iter_mut().enumerate() {
+            if bp.take().is_some() {
+                target.write32(FP_COMP0 + 4 * slot as u32, 0).ok();
+            }
+        }
+        target.write32(DHCSR, DHCSR_KEY).ok();
+        self.attached = false;
+        self.running = false;
+        info!("GDB server detached");
+    }
+
+    fn query(&mut self, target: &Target) {
+        let packet = &self.packet[..self.packet_len];
+        if packet.starts_with(b"qSupported") {
+            self.output.packet(PACKET_SIZE_REPLY);
+        } else if packet.starts_with(b"qAttached") {
+            self.output.packet(b"1");
+        } else if let Some(args) = packet.strip_prefix(b"qXfer:features:read:target.xml:") {
+            let mut fields = args.split(|&b| b == b',');
+            let offset = fields.next().and_then(parse_hex).unwrap_or(0) as usize;
+            let len = fields.next().and_then(parse_hex).unwrap_or(0) as usize;
+            let offset = offset.min(TARGET_XML.len());
+            let end = (offset + len.min(PACKET_SIZE - 8)).min(TARGET_XML.len());
+            self.output.begin();
+            self.output
+                .push(if end == TARGET_XML.len() { b'l' } else { b'm' });
+            self.output.push_str(&TARGET_XML[offset..end]);
+            self.output.end();
+        } else if let Some(hex) = packet.strip_prefix(b"qRcmd,") {
+            if hex == b"7265736574" {
+                // "monitor reset"
+                match target.reset() {
+                    Ok(()) => self.ok(),
+                    Err(e) => {
+                        warn!("GDB server reset failed: {}", e);
+                        self.output.packet(b"E01");
+                    }
+                }
+            } else {
+                self.output.packet(b"");
+            }
+        } else {
+            self.output.packet(b"");
+        }
+    }
+}
+
+fn hex_digit(value: u8) -> u8 {
+    b"0123456789abcdef"[value as usize & 0xF]
+}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Parse a big-endian hex number, as used for addresses and lengths.
+fn parse_hex(digits: &[u8]) -> Option<u32> {
+    if digits.is_empty() || digits.len() > 8 {
+        return None;
+    }
+    digits.iter().try_fold(0u32, |value, &digit| {
+        Some((value << 4) | hex_value(digit)? as u32)
+    })
+}
+
+/// Parse a 32-bit register value, sent as hex bytes in target (little-endian) order.
+fn parse_hex_le(digits: &[u8]) -> Option<u32> {
+    if digits.len() != 8 {
+        return None;
+    }
+    let mut bytes = [0; 4];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = (hex_value(digits[2 * i])? << 4) | hex_value(digits[2 * i + 1])?;
+    }
+    Some(u32::from_le_bytes(bytes))
+}
+
+/// Parse `addr,len`, optionally followed by `:` and data which is returned.
+fn parse_address_len(args: &[u8]) -> Option<(u32, u32, &[u8])> {
+    let (args, data) = match args.iter().position(|&b| b == b':') {
+        Some(idx) => (&args[..idx], &args[idx + 1..]),
+        None => (args, &args[args.len()..]),
+    };
+    let mut fields = args.split(|&b| b == b',');
+    let address = parse_hex(fields.next()?)?;
+    let len = parse_hex(fields.next()?)?;
+    Some((address, len, data))
+}
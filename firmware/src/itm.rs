@@ -0,0 +1,143 @@
+//! Filtering of the ITM packet stream received over SWO.
+//!
+//! When enabled, the stream is parsed on the probe and only instrumentation
+//! packets from the selected stimulus ports are passed on to the host, with
+//! their headers, so host ITM decoders keep working. Synchronisation,
+//! overflow, timestamp, extension and hardware source packets are dropped.
+
+#[derive(Copy, Clone)]
+enum State {
+    /// Expecting a packet header.
+    Header,
+    /// Inside a synchronisation packet, after at least one zero byte.
+    Sync,
+    /// Expecting `remaining` payload bytes of a source packet.
+    Payload { remaining: u8, keep: bool },
+    /// Inside a protocol packet, until a byte with the continuation bit clear.
+    Continuation,
+}
+
+pub struct ItmFilter {
+    ports: u32,
+    state: State,
+}
+
+impl ItmFilter {
+    pub const fn new() -> Self {
+        ItmFilter {
+            ports: 0,
+            state: State::Header,
+        }
+    }
+
+    /// Set the mask of stimulus ports to keep, with bit n for port n.
+    ///
+    /// A mask of 0 disables filtering. Parsing restarts at the next byte,
+    /// which should be a packet header; if it is not, the parser resynchronises
+    /// at the next synchronisation packet the target sends.
+    pub fn set_ports(&mut self, ports: u32) {
+        self.ports = ports;
+        self.reset();
+    }
+
+    /// Restart parsing, for when a new stream starts.
+    pub fn reset(&mut self) {
+        self.state = State::Header;
+    }
+
+    pub fn ports(&self) -> u32 {
+        self.ports
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.ports != 0
+    }
+
+    /// Filter `data` in place, returning the number of bytes kept at its start.
+    ///
+    /// Packets may be split across calls.
+    pub fn filter(&mut self, data: &mut [u8]) -> usize {
+        if !self.is_enabled() {
+            return data.len();
+        }
+
+        let mut kept = 0;
+        for idx in 0..data.len() {
+            let byte = data[idx];
+            if self.keep(byte) {
+                data[kept] = byte;
+                kept += 1;
+            }
+        }
+        kept
+    }
+
+    /// Advance the parser by one byte, returning true if it should be kept.
+    fn keep(&mut self, byte: u8) -> bool {
+        match self.state {
+            State::Header => self.header(byte),
+            State::Sync => match byte {
+                0x00 => false,
+                0x80 => {
+                    self.state = State::Header;
+                    false
+                }
+                // Not a complete synchronisation packet, so treat as a new header.
+                _ => self.header(byte),
+            },
+            State::Payload { remaining, keep } => {
+                self.state = if remaining > 1 {
+                    State::Payload {
+                        remaining: remaining - 1,
+                        keep,
+                    }
+                } else {
+                    State::Header
+                };
+                keep
+            }
+            State::Continuation => {
+                if byte & 0x80 == 0 {
+                    self.state = State::Header;
+                }
+                false
+            }
+        }
+    }
+
+    fn header(&mut self, byte: u8) -> bool {
+        match byte {
+            0x00 => {
+                self.state = State::Sync;
+                false
+            }
+            // Overflow packet.
+            0x70 => {
+                self.state = State::Header;
+                false
+            }
+            // Source packet: bits 1:0 give the payload size, bit 2 is set for
+            // hardware (DWT) sources, and bits 7:3 are the stimulus port.
+            _ if byte & 0x03 != 0 => {
+                let remaining = match byte & 0x03 {
+                    1 => 1,
+                    2 => 2,
+                    _ => 4,
+                };
+                let port = byte >> 3;
+                let keep = byte & 0x04 == 0 && self.ports & (1 << port) != 0;
+                self.state = State::Payload { remaining, keep };
+                keep
+            }
+            // Timestamp and extension packets, continued while bit 7 is set.
+            _ => {
+                self.state = if byte & 0x80 != 0 {
+                    State::Continuation
+                } else {
+                    State::Header
+                };
+                false
+            }
+        }
+    }
+}
@@ -5,14 +5,19 @@ use crate::bsp::delay::Delay;
 use crate::bsp::dma::DMA;
 use crate::bsp::gpio::{Pin, Pins};
 use crate::bsp::spi::SPI;
+use crate::dap;
 use crate::DAP2_PACKET_SIZE;
 use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
+/// Number of polls of RTCK before giving up on the target returning a TCK edge.
+const RTCK_TIMEOUT: u32 = 100_000;
+
 struct JTAGPins<'a> {
     tms: &'a Pin<'a>,
     tck: &'a Pin<'a>,
     tdo: &'a Pin<'a>,
     tdi: &'a Pin<'a>,
+    rtck: &'a Pin<'a>,
 }
 
 #[allow(clippy::upper_case_acronyms)]
@@ -22,7 +27,10 @@ pub struct JTAG<'a> {
     pins: JTAGPins<'a>,
     delay: &'a Delay,
     half_period_ticks: AtomicU32,
+    spi_frequency: AtomicU32,
     use_bitbang: AtomicBool,
+    cjtag: AtomicBool,
+    rtck: AtomicBool,
 }
 
 impl<'a> JTAG<'a> {
@@ -33,6 +41,7 @@ impl<'a> JTAG<'a> {
             tck: &pins.spi2_clk,
             tdo: &pins.spi2_miso,
             tdi: &pins.spi2_mosi,
+            rtck: &pins.usart1_rx,
         };
 
         JTAG {
@@ -41,7 +50,10 @@ impl<'a> JTAG<'a> {
             pins: jtag_pins,
             delay,
             half_period_ticks: AtomicU32::new(10000),
+            spi_frequency: AtomicU32::new(0),
             use_bitbang: AtomicBool::new(true),
+            cjtag: AtomicBool::new(false),
+            rtck: AtomicBool::new(false),
         }
     }
 
@@ -51,12 +63,49 @@ impl<'a> JTAG<'a> {
 
         if let Some(prescaler) = self.spi.calculate_prescaler(max_frequency) {
             self.spi.set_prescaler(prescaler);
+            let frequency = self.spi.prescaler_frequency(prescaler);
+            self.spi_frequency.store(frequency, Ordering::SeqCst);
             self.use_bitbang.store(false, Ordering::SeqCst);
         } else {
             self.use_bitbang.store(true, Ordering::SeqCst);
         }
     }
 
+    /// Actual TCK frequency of SPI-accelerated transfers, or of bitbanged
+    /// transfers if SPI can't be used at the current settings.
+    pub fn frequency(&self) -> u32 {
+        if self.use_bitbang.load(Ordering::SeqCst) || self.is_cjtag() || self.is_rtck() {
+            let half_period_ticks = self.half_period_ticks.load(Ordering::SeqCst);
+            self.delay.ticks_to_frequency(half_period_ticks * 2)
+        } else {
+            self.spi_frequency.load(Ordering::SeqCst)
+        }
+    }
+
+    /// Select between standard four-wire JTAG and two-wire cJTAG (IEEE 1149.7 OScan1).
+    ///
+    /// In cJTAG mode TMS is used as the bidirectional TMSC line and TCK as TCKC,
+    /// while TDI and TDO are unused.
+    pub fn set_cjtag(&self, enabled: bool) {
+        self.cjtag.store(enabled, Ordering::SeqCst);
+    }
+
+    pub fn is_cjtag(&self) -> bool {
+        self.cjtag.load(Ordering::SeqCst)
+    }
+
+    /// Enable adaptive clocking, using the SWO pin as the RTCK input.
+    ///
+    /// Each TCK edge then waits for the target to return it on RTCK before
+    /// proceeding, and transfers are always bitbanged.
+    pub fn set_rtck(&self, enabled: bool) {
+        self.rtck.store(enabled, Ordering::SeqCst);
+    }
+
+    pub fn is_rtck(&self) -> bool {
+        self.rtck.load(Ordering::SeqCst)
+    }
+
     pub fn spi_enable(&self) {
         self.spi.setup_jtag();
     }
@@ -65,13 +114,125 @@ impl<'a> JTAG<'a> {
         self.spi.disable();
     }
 
+    /// Bring the attached TAP.7 controller online in the OScan1 scan format.
+    ///
+    /// Sends a reset escape and a selection escape, followed by the
+    /// online activation code. Must be called after entering JTAG mode
+    /// with cJTAG enabled.
+    pub fn cjtag_activate(&self) {
+        self.bitbang_mode();
+        self.pins.tms.set_high().set_mode_output();
+
+        let half_period_ticks = self.half_period_ticks.load(Ordering::SeqCst);
+        let rtck = self.is_rtck();
+        let mut last = self.delay.get_current();
+        last = self.delay.delay_ticks_from_last(half_period_ticks, last);
+
+        // Reset escape (8 TMSC edges), then selection escape (6 TMSC edges).
+        last = self.cjtag_escape(8, last);
+        last = self.cjtag_escape(6, last);
+
+        // Online Activation Code, Extension Code and Check Packet,
+        // each four bits long, sampled by the target on the rising edge of TCKC.
+        const ACTIVATION: [bool; 12] = [
+            true, true, false, false, // OAC: OScan1
+            true, false, false, false, // EC: short form
+            false, false, false, false, // CP
+        ];
+        for &bit in ACTIVATION.iter() {
+            self.pins.tms.set_bool(bit);
+            last = self.delay.delay_ticks_from_last(half_period_ticks, last);
+            self.pins.tck.set_high();
+            last = self.wait_rtck(rtck, true, last);
+            last = self.delay.delay_ticks_from_last(half_period_ticks, last);
+            self.pins.tck.set_low();
+            last = self.wait_rtck(rtck, false, last);
+        }
+
+        self.pins.tms.set_high();
+    }
+
+    /// Generate a cJTAG escape sequence by toggling TMSC `edges` times while TCKC is high.
+    fn cjtag_escape(&self, edges: usize, mut last: u32) -> u32 {
+        let half_period_ticks = self.half_period_ticks.load(Ordering::SeqCst);
+
+        self.pins.tck.set_high();
+        last = self.delay.delay_ticks_from_last(half_period_ticks, last);
+        for _ in 0..edges {
+            self.pins.tms.toggle();
+            last = self.delay.delay_ticks_from_last(half_period_ticks, last);
+        }
+        self.pins.tck.set_low();
+        self.delay.delay_ticks_from_last(half_period_ticks, last)
+    }
+
+    /// Clock one JTAG bit as an OScan1 packet of three TCKC cycles: nTDI, TMS and TDO.
+    ///
+    /// Returns the TDO bit driven by the target during the third cycle.
+    #[inline(always)]
+    fn oscan1_bit(&self, tms: bool, tdi: bool, mut last: u32) -> (bool, u32) {
+        let half_period_ticks = self.half_period_ticks.load(Ordering::SeqCst);
+        let rtck = self.is_rtck();
+
+        for &bit in [!tdi, tms].iter() {
+            self.pins.tms.set_bool(bit);
+            last = self.delay.delay_ticks_from_last(half_period_ticks, last);
+            self.pins.tck.set_high();
+            last = self.wait_rtck(rtck, true, last);
+            last = self.delay.delay_ticks_from_last(half_period_ticks, last);
+            self.pins.tck.set_low();
+            last = self.wait_rtck(rtck, false, last);
+        }
+
+        // Release TMSC so the target can drive TDO.
+        self.pins.tms.set_mode_input();
+        last = self.delay.delay_ticks_from_last(half_period_ticks, last);
+        self.pins.tck.set_high();
+        let tdo = self.pins.tms.is_high();
+        last = self.delay.delay_ticks_from_last(half_period_ticks, last);
+        self.pins.tck.set_low();
+        self.pins.tms.set_mode_output();
+
+        (tdo, last)
+    }
+
+    /// cJTAG transfer, with optional TDO capture.
+    ///
+    /// Writes `n` bits from successive bytes of `tdi`, LSbit first, with TMS held at `tms`.
+    /// If `tdo` is provided, captured bits are written into successive bytes, LSbit first.
+    #[inline(never)]
+    #[link_section = ".itcm"]
+    fn transfer_oscan1(&self, n: usize, tms: bool, tdi: &[u8], mut tdo: Option<&mut [u8]>) {
+        let mut last = self.delay.get_current();
+
+        for (byte_idx, tdi) in tdi.iter().enumerate() {
+            let mut captured = 0;
+            for bit_idx in 0..8 {
+                if byte_idx * 8 + bit_idx == n {
+                    break;
+                }
+                let (bit, new_last) = self.oscan1_bit(tms, tdi & (1 << bit_idx) != 0, last);
+                last = new_last;
+                if bit {
+                    captured |= 1 << bit_idx;
+                }
+            }
+            if let Some(tdo) = tdo.as_mut() {
+                tdo[byte_idx] = captured;
+            }
+        }
+    }
+
     #[inline(never)]
+    #[link_section = ".itcm"]
     pub fn tms_sequence(&self, data: &[u8], mut bits: usize) {
         self.bitbang_mode();
 
         let half_period_ticks = self.half_period_ticks.load(Ordering::SeqCst);
         let mut last = self.delay.get_current();
         last = self.delay.delay_ticks_from_last(half_period_ticks, last);
+        let cjtag = self.is_cjtag();
+        let rtck = self.is_rtck();
 
         for byte in data {
             let mut byte = *byte;
@@ -80,16 +241,31 @@ impl<'a> JTAG<'a> {
                 let bit = byte & 1;
                 byte >>= 1;
 
+                if cjtag {
+                    // TDI is held high during TMS-only sequences.
+                    last = self.oscan1_bit(bit != 0, true, last).1;
+                    continue;
+                }
+
                 self.pins.tms.set_bool(bit != 0);
                 self.pins.tck.set_low();
+                last = self.wait_rtck(rtck, false, last);
                 last = self.delay.delay_ticks_from_last(half_period_ticks, last);
                 self.pins.tck.set_high();
+                last = self.wait_rtck(rtck, true, last);
                 last = self.delay.delay_ticks_from_last(half_period_ticks, last);
             }
             bits -= frame_bits;
         }
     }
 
+    /// Switch an SWJ-DP from SWD to JTAG, leaving the TAPs in Test-Logic-Reset.
+    pub fn swd_to_jtag(&self) {
+        self.tms_sequence(&[0xFF; 7], 56);
+        self.tms_sequence(&[0x3C, 0xE7], 16);
+        self.tms_sequence(&[0xFF], 5);
+    }
+
     /// Handle a sequence request. The request data follows the CMSIS-DAP
     /// DAP_JTAG_Sequence command:
     /// * First byte contains the number of sequences, then
@@ -108,11 +284,13 @@ impl<'a> JTAG<'a> {
     /// as many TDO bytes will be returned as there were TDI bytes in sequences
     /// with capture enabled.
     ///
-    /// Returns the number of bytes of rxbuf which were written to.
-    pub fn sequences(&self, data: &[u8], rxbuf: &mut [u8]) -> usize {
+    /// Returns the number of bytes of rxbuf which were written to, or None if
+    /// the DAP command's time budget ran out before all sequences were run.
+    #[link_section = ".itcm"]
+    pub fn sequences(&self, data: &[u8], rxbuf: &mut [u8]) -> Option<usize> {
         // Read request header containing number of sequences.
         if data.is_empty() {
-            return 0;
+            return Some(0);
         };
         let mut nseqs = data[0];
         let mut data = &data[1..];
@@ -120,17 +298,26 @@ impl<'a> JTAG<'a> {
 
         // Sanity check
         if nseqs == 0 || data.is_empty() {
-            return 0;
+            return Some(0);
         }
 
         let half_period_ticks = self.half_period_ticks.load(Ordering::SeqCst);
         self.delay.delay_ticks(half_period_ticks);
 
-        // Process alike sequences in one shot
-        // This
-        if !self.use_bitbang.load(Ordering::SeqCst) {
+        let cjtag = self.is_cjtag();
+        let rtck = self.is_rtck();
+
+        // Process runs of alike sequences in one shot each, using SPI with DMA for
+        // both TDI and TDO, until only malformed sequences are left.
+        let use_spi = !self.use_bitbang.load(Ordering::SeqCst) && !cjtag && !rtck;
+        while use_spi && nseqs > 0 && !data.is_empty() {
+            if dap::command_expired() {
+                return None;
+            }
+            let remaining = nseqs;
             let mut buffer = [0u8; DAP2_PACKET_SIZE as usize];
             let mut buffer_idx = 0;
+            let mut remainder = None;
             let transfer_type = data[0] & 0b1100_0000;
             while nseqs > 0 {
                 // Read header byte for this sequence.
@@ -143,10 +330,6 @@ impl<'a> JTAG<'a> {
                     break;
                 }
                 let nbits = header & 0b0011_1111;
-                if nbits & 7 != 0 {
-                    // We can handle only 8*N bit sequences here
-                    break;
-                }
                 let nbits = if nbits == 0 { 64 } else { nbits as usize };
                 let nbytes = Self::bytes_for_bits(nbits);
 
@@ -155,29 +338,73 @@ impl<'a> JTAG<'a> {
                 };
                 data = &data[1..];
 
-                buffer[buffer_idx..buffer_idx + nbytes].copy_from_slice(&data[..nbytes]);
-                buffer_idx += nbytes;
+                // Whole bytes are sent together with the previous sequences. A partial
+                // final byte has to be sent on its own, which ends this run of sequences.
+                let whole = nbits / 8;
+                buffer[buffer_idx..buffer_idx + whole].copy_from_slice(&data[..whole]);
+                buffer_idx += whole;
                 nseqs -= 1;
+                if whole != nbytes {
+                    remainder = Some((nbits % 8, data[whole]));
+                }
                 data = &data[nbytes..];
+                if remainder.is_some() {
+                    break;
+                }
             }
-            if buffer_idx > 0 {
+            if buffer_idx > 0 || remainder.is_some() {
                 let capture = transfer_type & 0b1000_0000;
                 let tms = transfer_type & 0b0100_0000;
 
                 // Set TMS for this transfer.
                 self.pins.tms.set_bool(tms != 0);
 
-                self.spi_mode();
-                self.spi
-                    .jtag_exchange(self.dma, &buffer[..buffer_idx], &mut rxbuf[rxidx..]);
-                if capture != 0 {
-                    rxidx += buffer_idx;
+                // SPI frames can be 4 to 8 bits long, shorter remainders are bitbanged below.
+                if buffer_idx > 0 || remainder.map_or(false, |(bits, _)| bits >= 4) {
+                    let mut last_tdi = self.pins.tdi.is_high();
+                    self.spi_mode();
+                    if buffer_idx > 0 {
+                        // TDO is always received by DMA, into a scratch buffer if not captured.
+                        let mut discard = [0u8; DAP2_PACKET_SIZE as usize];
+                        let rx = if capture != 0 {
+                            &mut rxbuf[rxidx..]
+                        } else {
+                            &mut discard[..]
+                        };
+                        self.spi.jtag_exchange(self.dma, &buffer[..buffer_idx], rx);
+                        if capture != 0 {
+                            rxidx += buffer_idx;
+                        }
+                        last_tdi = (buffer[buffer_idx - 1] >> 7) != 0;
+                    }
+                    if let Some((bits, tdi)) = remainder.filter(|&(bits, _)| bits >= 4) {
+                        let tdo = self.spi.jtag_exchange_bits(tdi, bits as u8);
+                        if capture != 0 {
+                            rxbuf[rxidx] = tdo & ((1 << bits) - 1);
+                            rxidx += 1;
+                        }
+                        last_tdi = (tdi >> (bits - 1)) & 1 != 0;
+                        remainder = None;
+                    }
+
+                    // Set TDI GPIO to the last bit the SPI peripheral transmitted,
+                    // to prevent it changing state when we set it to an output.
+                    self.pins.tdi.set_bool(last_tdi);
+                    self.bitbang_mode();
+                    self.spi.disable();
                 }
-                // Set TDI GPIO to the last bit the SPI peripheral transmitted,
-                // to prevent it changing state when we set it to an output.
-                self.pins.tdi.set_bool((buffer[buffer_idx - 1] >> 7) != 0);
-                self.bitbang_mode();
-                self.spi.disable();
+
+                if let Some((bits, tdi)) = remainder {
+                    if capture != 0 {
+                        self.transfer_rw(bits, &[tdi], &mut rxbuf[rxidx..]);
+                        rxidx += 1;
+                    } else {
+                        self.transfer_wo(bits, &[tdi]);
+                    }
+                }
+            }
+            if nseqs == remaining {
+                break;
             }
         }
 
@@ -187,6 +414,9 @@ impl<'a> JTAG<'a> {
             if data.is_empty() {
                 break;
             };
+            if dap::command_expired() {
+                return None;
+            }
             let header = data[0];
             data = &data[1..];
             let capture = header & 0b1000_0000;
@@ -202,6 +432,17 @@ impl<'a> JTAG<'a> {
             let tdi = &data[..nbytes];
             data = &data[nbytes..];
 
+            // In cJTAG mode TMS is sent as part of every OScan1 packet.
+            if cjtag {
+                if capture != 0 {
+                    self.transfer_oscan1(nbits, tms != 0, tdi, Some(&mut rxbuf[rxidx..]));
+                    rxidx += nbytes;
+                } else {
+                    self.transfer_oscan1(nbits, tms != 0, tdi, None);
+                }
+                continue;
+            }
+
             // Set TMS for this transfer.
             self.pins.tms.set_bool(tms != 0);
 
@@ -214,15 +455,125 @@ impl<'a> JTAG<'a> {
             }
         }
 
-        rxidx
+        Some(rxidx)
+    }
+
+    /// Shift `n` bits of `tdi` with TMS held at `tms`, LSbit first,
+    /// optionally capturing TDO into `tdo`.
+    ///
+    /// Nothing is shifted once the DAP command's time budget has run out.
+    pub fn shift(&self, n: usize, tms: bool, tdi: &[u8], tdo: Option<&mut [u8]>) {
+        if dap::command_expired() {
+            return;
+        }
+        self.bitbang_mode();
+
+        if self.is_cjtag() {
+            self.transfer_oscan1(n, tms, tdi, tdo);
+            return;
+        }
+
+        self.pins.tms.set_bool(tms);
+        match tdo {
+            Some(tdo) => self.transfer_rw(n, tdi, tdo),
+            None => self.transfer_wo(n, tdi),
+        }
+    }
+
+    /// Generate `n` TCK pulses with TMS and TDI held constant, stopping early
+    /// if the DAP command's time budget runs out.
+    ///
+    /// Returns the state of TDO after the final pulse.
+    #[link_section = ".itcm"]
+    pub fn clock(&self, n: usize, tms: bool, tdi: bool) -> bool {
+        self.bitbang_mode();
+
+        let half_period_ticks = self.half_period_ticks.load(Ordering::SeqCst);
+        let mut last = self.delay.get_current();
+        let cjtag = self.is_cjtag();
+        let rtck = self.is_rtck();
+        let mut tdo = false;
+
+        if !cjtag {
+            self.pins.tms.set_bool(tms);
+            self.pins.tdi.set_bool(tdi);
+        }
+        for _ in 0..n {
+            if dap::command_expired() {
+                break;
+            }
+            if cjtag {
+                let (bit, next) = self.oscan1_bit(tms, tdi, last);
+                tdo = bit;
+                last = next;
+                continue;
+            }
+            last = self.delay.delay_ticks_from_last(half_period_ticks, last);
+            self.pins.tck.set_high();
+            last = self.wait_rtck(rtck, true, last);
+            last = self.delay.delay_ticks_from_last(half_period_ticks, last);
+            self.pins.tck.set_low();
+            last = self.wait_rtck(rtck, false, last);
+        }
+
+        if cjtag {
+            tdo
+        } else {
+            self.pins.tdo.is_high()
+        }
+    }
+
+    /// Reset all TAPs and shift out the DR chain, which then holds either an
+    /// IDCODE or a single BYPASS bit for each device.
+    ///
+    /// IDCODEs are written to `ids` in chain order starting nearest TDO, with 0
+    /// for devices without an IDCODE register. The TAPs are left in Run-Test/Idle.
+    ///
+    /// Returns the number of devices found, or None if the end of the chain
+    /// was not seen within `ids.len()` devices.
+    pub fn scan_chain(&self, ids: &mut [u32]) -> Option<usize> {
+        // Test-Logic-Reset, Run-Test/Idle, Select-DR-Scan, Capture-DR, Shift-DR.
+        self.clock(5, true, true);
+        self.clock(1, false, true);
+        self.clock(1, true, true);
+        self.clock(2, false, true);
+
+        // Shift in ones: once they reach TDO, a full IDCODE of all ones marks the end.
+        let mut n = 0;
+        let count = loop {
+            let mut first = [0u8; 1];
+            self.shift(1, false, &[0xFF], Some(&mut first));
+            let id = if first[0] & 1 == 0 {
+                0
+            } else {
+                let mut rest = [0u8; 4];
+                self.shift(31, false, &[0xFF; 4], Some(&mut rest));
+                (u32::from_le_bytes(rest) << 1) | 1
+            };
+
+            if id == 0xFFFF_FFFF {
+                break Some(n);
+            }
+            if n == ids.len() {
+                break None;
+            }
+            ids[n] = id;
+            n += 1;
+        };
+
+        self.clock(5, true, true);
+        self.clock(1, false, true);
+        count
     }
 
     /// Write-only JTAG transfer without capturing TDO.
     ///
     /// Writes `n` bits from successive bytes of `tdi`, LSbit first.
     #[inline(never)]
+    #[link_section = ".itcm"]
     fn transfer_wo(&self, n: usize, tdi: &[u8]) {
         let half_period_ticks = self.half_period_ticks.load(Ordering::SeqCst);
+        let rtck = self.is_rtck();
         let mut last = self.delay.get_current();
 
         for (byte_idx, byte) in tdi.iter().enumerate() {
@@ -236,8 +587,10 @@ impl<'a> JTAG<'a> {
                 self.pins.tdi.set_bool(byte & (1 << bit_idx) != 0);
                 last = self.delay.delay_ticks_from_last(half_period_ticks, last);
                 self.pins.tck.set_high();
+                last = self.wait_rtck(rtck, true, last);
                 last = self.delay.delay_ticks_from_last(half_period_ticks, last);
                 self.pins.tck.set_low();
+                last = self.wait_rtck(rtck, false, last);
             }
         }
     }
@@ -247,8 +600,10 @@ impl<'a> JTAG<'a> {
     /// Writes `n` bits from successive bytes of `tdi`, LSbit first.
     /// Captures `n` bits from TDO and writes into successive bytes of `tdo`, LSbit first.
     #[inline(never)]
+    #[link_section = ".itcm"]
     fn transfer_rw(&self, n: usize, tdi: &[u8], tdo: &mut [u8]) {
         let half_period_ticks = self.half_period_ticks.load(Ordering::SeqCst);
+        let rtck = self.is_rtck();
         let mut last = self.delay.get_current();
 
         for (byte_idx, (tdi, tdo)) in tdi.iter().zip(tdo.iter_mut()).enumerate() {
@@ -265,13 +620,31 @@ impl<'a> JTAG<'a> {
                 self.pins.tdi.set_bool(tdi & (1 << bit_idx) != 0);
                 last = self.delay.delay_ticks_from_last(half_period_ticks, last);
                 self.pins.tck.set_high();
+                last = self.wait_rtck(rtck, true, last);
                 last = self.delay.delay_ticks_from_last(half_period_ticks, last);
                 if self.pins.tdo.is_high() {
                     *tdo |= 1 << bit_idx;
                 }
                 self.pins.tck.set_low();
+                last = self.wait_rtck(rtck, false, last);
+            }
+        }
+    }
+
+    /// With adaptive clocking enabled, wait for RTCK to follow TCK to `high`.
+    ///
+    /// Returns the SysTick value to time the rest of the TCK cycle from.
+    #[inline(always)]
+    fn wait_rtck(&self, rtck: bool, high: bool, last: u32) -> u32 {
+        if !rtck {
+            return last;
+        }
+        for _ in 0..RTCK_TIMEOUT {
+            if self.pins.rtck.is_high() == high {
+                break;
             }
         }
+        self.delay.get_current()
     }
 
     /// Compute required number of bytes to store a number of bits.
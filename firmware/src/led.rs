@@ -0,0 +1,44 @@
+//! Activity indication for the VCP and SWO data paths.
+//!
+//! The red and green LEDs show the DAP connection state, set by DAP_HostStatus,
+//! so data traffic is shown separately on the blue LED.
+
+use crate::bsp::gpio::Pin;
+
+/// Time the LED stays on after the last activity, so single bytes are visible.
+const ACTIVITY_MS: u64 = 20;
+
+pub struct ActivityLed<'a> {
+    pin: &'a Pin<'a>,
+    off_at_ms: Option<u64>,
+}
+
+impl<'a> ActivityLed<'a> {
+    pub fn new(pin: &'a Pin<'a>) -> Self {
+        ActivityLed {
+            pin,
+            off_at_ms: None,
+        }
+    }
+
+    /// Turn the LED on for data transferred at `now_ms`.
+    pub fn activity(&mut self, now_ms: u64) {
+        // Active low.
+        self.pin.set_low();
+        self.off_at_ms = Some(now_ms + ACTIVITY_MS);
+    }
+
+    /// Turn the LED off once there has been no activity for a while.
+    pub fn poll(&mut self, now_ms: u64) {
+        if let Some(off_at_ms) = self.off_at_ms {
+            if now_ms >= off_at_ms {
+                self.off();
+            }
+        }
+    }
+
+    pub fn off(&mut self) {
+        self.pin.set_high();
+        self.off_at_ms = None;
+    }
+}
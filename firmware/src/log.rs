@@ -0,0 +1,200 @@
+//! Logging with defmt, filtered by a log level which can be changed at runtime.
+//!
+//! All levels are compiled in (see `DEFMT_LOG` in `.cargo/config`), and the
+//! macros in this module skip messages above the current level, which the
+//! host can change with the LogLevel vendor configuration option.
+//!
+//! Encoded log frames are written both to RTT and to a ring buffer, which is
+//! streamed to the host over the USB log interface.
+
+use crate::bsp::cortex_m;
+use crate::buffers::LOG_BUFFER_SIZE;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use num_enum::TryFromPrimitive;
+use rtt_target::{rtt_init, UpChannel};
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Level {
+    Off = 0,
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+/// Set the log level, returning false if `level` is not a valid level.
+pub fn set_level(level: u8) -> bool {
+    match Level::try_from_primitive(level) {
+        Ok(level) => {
+            LEVEL.store(level as u8, Ordering::Relaxed);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+pub fn level() -> u8 {
+    LEVEL.load(Ordering::Relaxed)
+}
+
+/// Returns true if messages at `level` should be logged.
+#[inline(always)]
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Ring buffer of encoded log frames, dropping new data while full.
+///
+/// Frames are delimited, so a host decoder resynchronises after dropped data.
+pub struct LogBuffer {
+    buf: [u8; LOG_BUFFER_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl LogBuffer {
+    fn push(&mut self, data: &[u8]) {
+        for &byte in data {
+            if self.len == LOG_BUFFER_SIZE {
+                return;
+            }
+            self.buf[(self.head + self.len) % LOG_BUFFER_SIZE] = byte;
+            self.len += 1;
+        }
+    }
+
+    /// Oldest contiguous run of buffered data.
+    fn front(&self) -> &[u8] {
+        let end = (self.head + self.len).min(LOG_BUFFER_SIZE);
+        &self.buf[self.head..end]
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.head = (self.head + n) % LOG_BUFFER_SIZE;
+        self.len -= n;
+    }
+}
+
+static mut LOG_BUFFER: LogBuffer = LogBuffer {
+    buf: [0; LOG_BUFFER_SIZE],
+    head: 0,
+    len: 0,
+};
+static mut RTT: Option<UpChannel> = None;
+
+/// Set up the RTT channel for log output.
+///
+/// Must be called once at startup, before anything is logged.
+pub fn init() {
+    let channels = rtt_init! {
+        up: {
+            0: {
+                size: 1024
+                mode: NoBlockSkip
+                name: "defmt"
+            }
+        }
+    };
+    cortex_m::interrupt::free(|_| unsafe { RTT = Some(channels.up.0) });
+}
+
+/// Pass the oldest buffered log data to `write`, which returns how many bytes it sent.
+pub fn drain(write: impl FnOnce(&[u8]) -> usize) {
+    cortex_m::interrupt::free(|_| unsafe {
+        let data = LOG_BUFFER.front();
+        if !data.is_empty() {
+            let n = write(data);
+            LOG_BUFFER.consume(n);
+        }
+    })
+}
+
+fn write_frame(data: &[u8]) {
+    // Safety: only called by the logger, with interrupts disabled.
+    unsafe {
+        if let Some(rtt) = RTT.as_mut() {
+            rtt.write(data);
+        }
+        LOG_BUFFER.push(data);
+    }
+}
+
+#[defmt::global_logger]
+struct Logger;
+
+static TAKEN: AtomicBool = AtomicBool::new(false);
+static mut INTERRUPTS_ENABLED: bool = false;
+static mut ENCODER: defmt::Encoder = defmt::Encoder::new();
+
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {
+        let primask = cortex_m::register::primask::read();
+        cortex_m::interrupt::disable();
+        if TAKEN.load(Ordering::Relaxed) {
+            panic!("defmt logger taken reentrantly");
+        }
+        TAKEN.store(true, Ordering::Relaxed);
+        unsafe {
+            INTERRUPTS_ENABLED = primask.is_active();
+            ENCODER.start_frame(write_frame);
+        }
+    }
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {
+        ENCODER.end_frame(write_frame);
+        TAKEN.store(false, Ordering::Relaxed);
+        if INTERRUPTS_ENABLED {
+            cortex_m::interrupt::enable();
+        }
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        ENCODER.write(bytes, write_frame);
+    }
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Error) {
+            defmt::error!($($arg)*);
+        }
+    };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Warn) {
+            defmt::warn!($($arg)*);
+        }
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Info) {
+            defmt::info!($($arg)*);
+        }
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Debug) {
+            defmt::debug!($($arg)*);
+        }
+    };
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Trace) {
+            defmt::trace!($($arg)*);
+        }
+    };
+}
@@ -1,24 +1,48 @@
 #![no_std]
 #![no_main]
+// The AVRISP, DirtyJTAG and data stream interfaces are left out to make FIFO
+// room for 1024 byte HID reports, leaving the code behind them unused.
+#![cfg_attr(feature = "hid-1024", allow(dead_code))]
 
 use bsp::{cortex_m, stm32ral};
-use cortex_m_rt::{entry, pre_init};
+use cortex_m_rt::{entry, exception, pre_init};
 use git_version::git_version;
 pub use hs_probe_bsp as bsp;
-use panic_rtt_target as _;
-use rtt_target::{rprintln, rtt_init_print};
-use stm32_device_signature::device_id_hex;
+use panic_probe as _;
+use stm32ral::interrupt;
 
 const GIT_VERSION: &str = git_version!();
 
-const DAP1_PACKET_SIZE: u16 = 64;
+const DAP1_PACKET_SIZE: u16 = if cfg!(feature = "hid-1024") { 1024 } else { 64 };
 const DAP2_PACKET_SIZE: u16 = 512;
 const VCP_PACKET_SIZE: u16 = 512;
+const AVRISP_PACKET_SIZE: u16 = 512;
+const DIRTYJTAG_PACKET_SIZE: u16 = 512;
+const STREAM_PACKET_SIZE: u16 = 512;
+
+/// USART1, shared by SWO and UPDI.
+type SwoUart<'a> =
+    bsp::uart::UART<'a, { buffers::SWO_BUFFER_SIZE }, { buffers::SWO_TX_BUFFER_SIZE }>;
+
+#[macro_use]
+mod log;
 
 mod app;
+mod avrisp;
+mod buffers;
+mod calibration;
+mod config;
 mod dap;
+mod dirtyjtag;
+mod gdb;
+mod itm;
 mod jtag;
+mod led;
 mod swd;
+mod swim;
+mod trace;
+mod tunnel;
+mod updi;
 mod usb;
 mod vcp;
 
@@ -34,15 +58,28 @@ unsafe fn pre_init() {
     // It must be called from pre_init as otherwise the
     // flag is overwritten when statics are initialised.
     bsp::bootload::check();
+
+    // Copy SWD/JTAG hot paths into ITCM before anything can call them.
+    bsp::itcm::init();
+
+    // Fill the unused stack, to measure how much is used later.
+    bsp::stack::paint();
 }
 
 #[entry]
 fn main() -> ! {
-    rtt_init_print!();
+    log::init();
 
-    // Enable I-cache
+    // Enable I-cache and D-cache. DMA buffers are kept in DTCM, which is not
+    // cached, by placing the stack there in memory.x, or in non-cacheable SRAM2.
     let mut cp = cortex_m::Peripherals::take().unwrap();
     cp.SCB.enable_icache();
+    cp.SCB.enable_dcache(&mut cp.CPUID);
+
+    // Enable the cycle counter, used to time DAP commands when tracing.
+    cp.DCB.enable_trace();
+    cortex_m::peripheral::DWT::unlock();
+    cp.DWT.enable_cycle_counter();
 
     let rcc = bsp::rcc::RCC::new(stm32ral::rcc::RCC::take().unwrap());
 
@@ -58,59 +95,111 @@ fn main() -> ! {
     );
     let spi1 = bsp::spi::SPI::new(stm32ral::spi::SPI1::take().unwrap());
     let spi2 = bsp::spi::SPI::new(stm32ral::spi::SPI2::take().unwrap());
-    let mut uart1 = bsp::uart::UART::new(stm32ral::usart::USART1::take().unwrap(), &dma);
+    let mut uart1 = SwoUart::new(stm32ral::usart::USART1::take().unwrap(), &dma);
     let uart2 = stm32ral::usart::USART2::take().unwrap();
+    let adc = bsp::adc::ADC::new(
+        stm32ral::adc::ADC1::take().unwrap(),
+        stm32ral::adc_common::ADC_Common::take().unwrap(),
+    );
 
-    let _gpioa = bsp::gpio::GPIO::new(stm32ral::gpio::GPIOA::take().unwrap());
-    let gpiob = bsp::gpio::GPIO::new(stm32ral::gpio::GPIOB::take().unwrap());
-    let gpioc = bsp::gpio::GPIO::new(stm32ral::gpio::GPIOC::take().unwrap());
-    let gpiod = bsp::gpio::GPIO::new(stm32ral::gpio::GPIOD::take().unwrap());
-    let gpioe = bsp::gpio::GPIO::new(stm32ral::gpio::GPIOE::take().unwrap());
-    let gpiog = bsp::gpio::GPIO::new(stm32ral::gpio::GPIOG::take().unwrap());
-    let gpioi = bsp::gpio::GPIO::new(stm32ral::gpio::GPIOI::take().unwrap());
-
-    let pins = bsp::gpio::Pins {
-        led_red: gpioc.pin(10),
-        led_green: gpiob.pin(8),
-        led_blue: gpioe.pin(0),
-        t5v_en: gpiob.pin(1),
-        tvcc_en: gpioe.pin(2),
-        reset: gpiog.pin(13),
-        gnd_detect: gpiog.pin(14),
-        usart1_rx: gpiob.pin(7),
-        usart2_rx: gpiod.pin(6),
-        usart2_tx: gpiod.pin(5),
-        spi1_clk: gpiob.pin(3),
-        spi1_miso: gpiob.pin(4),
-        spi1_mosi: gpiob.pin(5),
-        spi2_clk: gpioi.pin(1),
-        spi2_miso: gpioi.pin(2),
-        spi2_mosi: gpioi.pin(3),
-        usb_dm: gpiob.pin(14),
-        usb_dp: gpiob.pin(15),
-        usb_sel: gpiob.pin(10),
-    };
+    let board = bsp::board::Board::current();
+    let ports = bsp::board::Ports::take().unwrap();
+    let pins = board.pins(&ports);
+
+    let flash = bsp::flash::Flash::new(stm32ral::flash::FLASH::take().unwrap());
+    let config = config::ConfigStore::new(&flash);
+
+    let pwm = bsp::tim::Pwm::new(stm32ral::tim9::TIM9::take().unwrap());
+    let capture = bsp::tim::Capture::new(stm32ral::tim2::TIM2::take().unwrap());
 
     let syst = stm32ral::syst::SYST::take().unwrap();
     let delay = bsp::delay::Delay::new(syst);
 
-    let swd = swd::SWD::new(&spi1, &pins, &delay);
+    let swd = swd::SWD::new(&spi1, pins.swd_pins(), &delay);
+    let swd2 = swd::SWD::new(&spi2, pins.swd2_pins(), &delay);
     let jtag = jtag::JTAG::new(&spi2, &dma, &pins, &delay);
-    let mut dap = dap::DAP::new(swd, jtag, &mut uart1, &pins);
+    let swim = swim::SWIM::new(&pins, &delay);
+    let mut dap = dap::DAP::new(
+        swd, swd2, jtag, swim, &mut uart1, &pins, &adc, &delay, &pwm, &capture, &config,
+    );
     let mut vcp = vcp::VCP::new(uart2, &pins, &dma);
+    let mut avrisp = avrisp::AVRISP::new(&spi2, &pins, &delay);
 
     // Create App instance with the HAL instances
     let mut app = app::App::new(
-        &rcc, &dma, &pins, &spi1, &spi2, &mut usb, &mut dap, &mut vcp, &delay,
+        &rcc,
+        &dma,
+        &pins,
+        &spi1,
+        &spi2,
+        &mut usb,
+        &mut dap,
+        &mut vcp,
+        &mut avrisp,
+        &delay,
+        &config,
     );
 
-    rprintln!("Starting...");
+    info!(
+        "Starting on {=str}, firmware {=str}",
+        board.name, GIT_VERSION
+    );
+    buffers::log_usage();
 
     // Initialise application, including system peripherals
-    unsafe { app.setup(device_id_hex()) };
+    unsafe { app.setup(bsp::signature::unique_id_hex()) };
+
+    // Receive DAPv2 requests from the USB interrupt while others are processed
+    unsafe { cortex_m::peripheral::NVIC::unmask(stm32ral::Interrupt::OTG_HS) };
+    // Read the next VCP packet from the host as soon as the last one is sent
+    unsafe { cortex_m::peripheral::NVIC::unmask(stm32ral::Interrupt::DMA1_Stream6) };
+    // Stream SWO data as it is received
+    unsafe { cortex_m::peripheral::NVIC::unmask(stm32ral::Interrupt::DMA2_Stream5) };
+    unsafe { cortex_m::peripheral::NVIC::unmask(stm32ral::Interrupt::USART1) };
 
     loop {
         // Process events
         app.poll();
     }
 }
+
+#[interrupt]
+fn OTG_HS() {
+    usb::USB::on_interrupt();
+}
+
+#[interrupt]
+fn DMA1_Stream6() {
+    bsp::dma::DMA::on_usart2_tx_interrupt();
+    vcp::on_tx_complete();
+    usb::USB::on_vcp_tx_complete();
+}
+
+#[interrupt]
+fn DMA2_Stream5() {
+    bsp::dma::DMA::on_usart1_rx_interrupt();
+    dap::notify_swo();
+}
+
+#[interrupt]
+fn USART1() {
+    bsp::uart::on_usart1_interrupt();
+    dap::notify_swo();
+}
+
+#[exception]
+fn MemoryManagement() {
+    // A stack overflow into the guard region usually locks up the core
+    // instead, as the exception can't be stacked.
+    let scb = unsafe { &*cortex_m::peripheral::SCB::PTR };
+    panic!(
+        "MemManage fault, CFSR {:#x}, MMFAR {:#x}",
+        scb.cfsr.read(),
+        scb.mmfar.read()
+    );
+}
+
+#[exception]
+fn SysTick() {
+    bsp::delay::Delay::on_systick();
+}
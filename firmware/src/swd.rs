@@ -1,11 +1,12 @@
 // Copyright 2019-2020 Adam Greig
 // Dual licensed under the Apache 2.0 and MIT licenses.
 
-use crate::bsp::{delay::Delay, gpio::Pins, spi::SPI};
-use core::sync::atomic::{AtomicU32, Ordering};
+use crate::bsp::{delay::Delay, gpio::SWDPins, spi::SPI};
+use crate::dap;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use num_enum::IntoPrimitive;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, defmt::Format)]
 pub enum Error {
     BadParity,
     AckWait,
@@ -16,6 +17,10 @@ pub enum Error {
 
 pub type Result<T> = core::result::Result<T, Error>;
 
+/// DP registers by A[3:2], as seen with DPBANKSEL 0.
+///
+/// On DPv3 (ADIv6) reads at 0x0 are banked too, and with DPBANKSEL 5 address
+/// 0x4 is SELECT1, which holds bits 63:32 of the AP address.
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, IntoPrimitive)]
 #[allow(clippy::upper_case_acronyms)]
@@ -26,14 +31,57 @@ pub enum DPRegister {
     RDBUFF = 3,
 }
 
+/// DPBANKSEL field of SELECT.
+const SELECT_DPBANKSEL: u32 = 0xF;
+/// DPBANKSEL selecting SELECT1 at address 0x4 on DPv3.
+const DPBANK_SELECT1: u32 = 5;
+/// Reads at address 0x8 return RESEND, the data of the last AP or RDBUFF read.
+const DP_RESEND: u8 = 2;
+
+/// Upper limit for re-reads after a parity error.
+const MAX_PARITY_RETRIES: usize = 3;
+
 #[allow(clippy::upper_case_acronyms)]
 pub struct SWD<'a> {
     spi: &'a SPI,
-    pins: &'a Pins<'a>,
+    pins: SWDPins<'a>,
     delay: &'a Delay,
     half_period_ticks: AtomicU32,
+    use_bitbang: AtomicBool,
+    frequency: AtomicU32,
 
     wait_retries: usize,
+    wait_idle_cycles: usize,
+    wait_delay_us: u32,
+    turnaround: usize,
+    data_phase: bool,
+    fault_retry: bool,
+    parity_retries: usize,
+    /// Last values written to SELECT and SELECT1 through this port.
+    select: AtomicU32,
+    select1: AtomicU32,
+}
+
+/// Upper limit for the delay between WAIT retries, in µs.
+const MAX_WAIT_DELAY_US: u32 = 5_000;
+
+/// ABORT value clearing STICKYORUN, WDATAERR, STICKYERR and STICKYCMP.
+const ABORT_CLEAR_ERRORS: u32 = 0b1_1110;
+
+/// SWD request bytes, indexed by APnDP | RnW << 1 | A[3:2] << 2.
+const REQUESTS: [u8; 16] = make_requests();
+
+/// Build every request byte: start bit, APnDP, RnW, A[3:2], parity, stop and park.
+const fn make_requests() -> [u8; 16] {
+    let mut requests = [0; 16];
+    let mut i = 0;
+    while i < requests.len() {
+        let req = 1 | ((i as u8) << 1) | (1 << 7);
+        let parity = (req.count_ones() & 1) as u8;
+        requests[i] = req | (parity << 5);
+        i += 1;
+    }
+    requests
 }
 
 #[repr(u8)]
@@ -83,26 +131,53 @@ impl ACK {
 }
 
 impl<'a> SWD<'a> {
-    pub fn new(spi: &'a SPI, pins: &'a Pins, delay: &'a Delay) -> Self {
+    pub fn new(spi: &'a SPI, pins: SWDPins<'a>, delay: &'a Delay) -> Self {
         SWD {
             spi,
             pins,
             delay,
             half_period_ticks: AtomicU32::new(10000),
+            use_bitbang: AtomicBool::new(false),
+            frequency: AtomicU32::new(0),
             wait_retries: 8,
+            wait_idle_cycles: 0,
+            wait_delay_us: 0,
+            turnaround: 1,
+            data_phase: false,
+            fault_retry: false,
+            parity_retries: 0,
+            select: AtomicU32::new(0),
+            select1: AtomicU32::new(0),
         }
     }
 
+    /// Set the SWCLK frequency to at most `max_frequency`.
+    ///
+    /// Transfers use SPI at the fastest prescaler not above `max_frequency`,
+    /// or are bitbanged when it is below the slowest SPI clock.
     pub fn set_clock(&self, max_frequency: u32) -> bool {
+        if max_frequency == 0 {
+            return false;
+        }
+
         let period = self.delay.calc_period_ticks(max_frequency);
         self.half_period_ticks.store(period / 2, Ordering::SeqCst);
 
-        if let Some(prescaler) = self.spi.calculate_prescaler(max_frequency) {
+        let frequency = if let Some(prescaler) = self.spi.calculate_prescaler(max_frequency) {
             self.spi.set_prescaler(prescaler);
-            true
+            self.use_bitbang.store(false, Ordering::SeqCst);
+            self.spi.prescaler_frequency(prescaler)
         } else {
-            false
-        }
+            self.use_bitbang.store(true, Ordering::SeqCst);
+            self.delay.ticks_to_frequency(period / 2 * 2)
+        };
+        self.frequency.store(frequency, Ordering::SeqCst);
+        true
+    }
+
+    /// Actual SWCLK frequency selected by the last `set_clock` call.
+    pub fn frequency(&self) -> u32 {
+        self.frequency.load(Ordering::SeqCst)
     }
 
     pub fn spi_enable(&self) {
@@ -113,11 +188,93 @@ impl<'a> SWD<'a> {
         self.spi.disable();
     }
 
+    /// Return SWCLK and SWDIO to SPI control, in case they've been used as
+    /// outputs by the SWJ_Pins command.
+    pub fn spi_mode(&self) {
+        self.pins.swd_clk_spi();
+        self.pins.swd_tx();
+    }
+
     pub fn set_wait_retries(&mut self, wait_retries: usize) {
         self.wait_retries = wait_retries;
     }
 
-    pub fn tx_sequence(&self, data: &[u8], mut bits: usize) {
+    /// Set the number of idle clock cycles, rounded up to a multiple of 8,
+    /// inserted before retrying a transfer which received a WAIT ACK.
+    pub fn set_wait_idle_cycles(&mut self, cycles: usize) {
+        self.wait_idle_cycles = cycles;
+    }
+
+    pub fn wait_idle_cycles(&self) -> usize {
+        self.wait_idle_cycles
+    }
+
+    /// Set the delay in µs before the first retry after a WAIT ACK.
+    ///
+    /// The delay doubles for each further retry, up to 5ms.
+    pub fn set_wait_delay_us(&mut self, delay_us: u32) {
+        self.wait_delay_us = delay_us;
+    }
+
+    pub fn wait_delay_us(&self) -> u32 {
+        self.wait_delay_us
+    }
+
+    /// Set the number of turnaround clock cycles, from 1 to 4.
+    pub fn set_turnaround(&mut self, turnaround: usize) {
+        self.turnaround = turnaround;
+    }
+
+    /// Set whether sticky errors are cleared through ABORT after a FAULT ACK,
+    /// and the access retried once.
+    pub fn set_fault_retry(&mut self, enabled: bool) {
+        self.fault_retry = enabled;
+    }
+
+    pub fn fault_retry(&self) -> bool {
+        self.fault_retry
+    }
+
+    /// Set how many times a read with bad parity is repeated, up to 3.
+    pub fn set_parity_retries(&mut self, retries: usize) {
+        self.parity_retries = retries.min(MAX_PARITY_RETRIES);
+    }
+
+    pub fn parity_retries(&self) -> usize {
+        self.parity_retries
+    }
+
+    /// Set whether a data phase is generated even after a WAIT or FAULT ACK.
+    pub fn set_data_phase(&mut self, data_phase: bool) {
+        self.data_phase = data_phase;
+    }
+
+    #[link_section = ".itcm"]
+    pub fn tx_sequence(&self, mut data: &[u8], mut bits: usize) {
+        // Whole bytes and a remainder of at least 4 bits are sent by SPI,
+        // unless the clock is below the slowest SPI rate.
+        if !self.use_bitbang.load(Ordering::SeqCst) {
+            self.pins.swd_tx();
+            self.pins.swd_clk_spi();
+            let nbytes = bits / 8;
+            self.spi.tx_bytes(&data[..nbytes]);
+            data = &data[nbytes..];
+            bits -= nbytes * 8;
+            if bits >= 4 {
+                self.spi.tx_bits(data[0], bits as u8);
+                bits = 0;
+            }
+            self.spi.wait_busy();
+        }
+
+        if bits > 0 {
+            self.tx_sequence_bitbang(data, bits);
+        }
+    }
+
+    /// Bitbang the first `bits` bits of `data` onto SWDIO, LSbit first.
+    #[link_section = ".itcm"]
+    fn tx_sequence_bitbang(&self, data: &[u8], mut bits: usize) {
         self.pins.swd_tx_direct();
         self.pins.swd_clk_direct();
 
@@ -131,10 +288,10 @@ impl<'a> SWD<'a> {
             for _ in 0..frame_bits {
                 let bit = byte & 1;
                 byte >>= 1;
-                self.pins.spi1_mosi.set_bool(bit != 0);
-                self.pins.spi1_clk.set_low();
+                self.pins.swdio_out.set_bool(bit != 0);
+                self.pins.swclk.set_low();
                 last = self.delay.delay_ticks_from_last(half_period_ticks, last);
-                self.pins.spi1_clk.set_high();
+                self.pins.swclk.set_high();
                 last = self.delay.delay_ticks_from_last(half_period_ticks, last);
             }
             bits -= frame_bits;
@@ -143,6 +300,64 @@ impl<'a> SWD<'a> {
         self.pins.swd_clk_spi();
     }
 
+    /// Switch an SWJ-DP from JTAG to SWD: line reset, the JTAG-to-SWD sequence,
+    /// another line reset, then idle cycles, ready for a DPIDR read.
+    pub fn jtag_to_swd(&self) {
+        self.tx_sequence(&[0xFF; 7], 56);
+        self.tx_sequence(&[0x9E, 0xE7], 16);
+        self.tx_sequence(&[0xFF; 7], 56);
+        self.tx_sequence(&[0x00], 8);
+    }
+
+    /// Recover the SWD link after errors: send a line reset, read DPIDR to
+    /// leave the reset state, then clear all sticky errors through ABORT.
+    pub fn recover(&self) -> Result<()> {
+        self.tx_sequence(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00], 64);
+        self.read_dp(DPRegister::DPIDR.into())?;
+        self.write_dp(0, ABORT_CLEAR_ERRORS)
+    }
+
+    /// Read DPIDR, as the first access after a line reset.
+    ///
+    /// DPv3 banks reads at address 0x0 by DPBANKSEL, so if the last SELECT
+    /// written has a non-zero DPBANKSEL, DPBANKSEL is cleared to read DPIDR
+    /// again and SELECT is then restored, leaving it as the host expects.
+    pub fn read_dpidr(&self) -> Result<u32> {
+        let value = self.read_dp(DPRegister::DPIDR.into())?;
+        let select = self.select();
+        if select & SELECT_DPBANKSEL == 0 {
+            return Ok(value);
+        }
+        self.write_dp(DPRegister::SELECT.into(), select & !SELECT_DPBANKSEL)?;
+        let dpidr = self.read_dp(DPRegister::DPIDR.into());
+        self.write_dp(DPRegister::SELECT.into(), select)?;
+        dpidr
+    }
+
+    /// Last value written to SELECT.
+    pub fn select(&self) -> u32 {
+        self.select.load(Ordering::Relaxed)
+    }
+
+    /// Last value written to SELECT1 on a DPv3, bits 63:32 of the AP address.
+    pub fn select1(&self) -> u32 {
+        self.select1.load(Ordering::Relaxed)
+    }
+
+    /// Keep track of SELECT and SELECT1 after a successful DP write.
+    fn track_select(&self, apndp: APnDP, a: u8, data: u32) {
+        if let APnDP::AP = apndp {
+            return;
+        }
+        if a == DPRegister::SELECT as u8 {
+            self.select.store(data, Ordering::Relaxed);
+        } else if a == DPRegister::CTRLSTAT as u8
+            && self.select() & SELECT_DPBANKSEL == DPBANK_SELECT1
+        {
+            self.select1.store(data, Ordering::Relaxed);
+        }
+    }
+
     pub fn idle_low(&self) {
         self.spi.tx4(0x0);
     }
@@ -160,26 +375,137 @@ impl<'a> SWD<'a> {
     }
 
     pub fn read(&self, apndp: APnDP, a: u8) -> Result<u32> {
-        for _ in 0..self.wait_retries {
+        let result = match self.read_waiting(apndp, a) {
+            Err(Error::AckFault) if self.retry_after_fault(apndp, a, RnW::R) => {
+                self.read_waiting(apndp, a)
+            }
+            x => x,
+        };
+        self.retry_parity(apndp, a, result)
+    }
+
+    pub fn write(&self, apndp: APnDP, a: u8, data: u32) -> Result<()> {
+        match self.write_waiting(apndp, a, data) {
+            Err(Error::AckFault) if self.retry_after_fault(apndp, a, RnW::W) => {
+                self.write_waiting(apndp, a, data)
+            }
+            x => x,
+        }
+    }
+
+    /// Clear sticky errors through ABORT after a FAULT, if enabled, returning
+    /// true if the access should be retried.
+    ///
+    /// Reads of RDBUFF are not retried, as they return the result of an earlier
+    /// posted AP read, which is lost once its error is cleared.
+    fn retry_after_fault(&self, apndp: APnDP, a: u8, rnw: RnW) -> bool {
+        if !self.fault_retry
+            || matches!((apndp, rnw), (APnDP::DP, RnW::R) if a == DPRegister::RDBUFF as u8)
+        {
+            return false;
+        }
+        debug!("Clearing sticky errors after FAULT");
+        self.write_waiting(APnDP::DP, 0, ABORT_CLEAR_ERRORS).is_ok()
+    }
+
+    /// Read data which failed its parity check again, up to the configured
+    /// number of times.
+    ///
+    /// AP and RDBUFF reads are recovered through RESEND, which returns the data
+    /// again without repeating the access, and other DP registers are re-read.
+    fn retry_parity(&self, apndp: APnDP, a: u8, mut result: Result<u32>) -> Result<u32> {
+        let resend = matches!(apndp, APnDP::AP) || a == DPRegister::RDBUFF as u8;
+        for _ in 0..self.parity_retries {
+            if !matches!(result, Err(Error::BadParity)) {
+                break;
+            }
+            debug!("Reading {=u8:#x} again after bad parity", a);
+            result = if resend {
+                self.read_waiting(APnDP::DP, DP_RESEND)
+            } else {
+                self.read_waiting(apndp, a)
+            };
+        }
+        result
+    }
+
+    /// Read a register, retrying while the target replies WAIT.
+    fn read_waiting(&self, apndp: APnDP, a: u8) -> Result<u32> {
+        for retry in 0..self.wait_retries {
+            if retry > 0 {
+                if dap::command_expired() {
+                    break;
+                }
+                self.wait_backoff(retry - 1);
+            }
             match self.read_inner(apndp, a) {
                 Err(Error::AckWait) => continue,
+                Err(e) => {
+                    debug!("SWD read {=u8:#x} failed: {}", a, e);
+                    return Err(e);
+                }
                 x => return x,
             }
         }
+        debug!("SWD read {=u8:#x} still WAIT after retries", a);
         Err(Error::AckWait)
     }
 
-    pub fn write(&self, apndp: APnDP, a: u8, data: u32) -> Result<()> {
-        for _ in 0..self.wait_retries {
+    /// Write a register, retrying while the target replies WAIT.
+    fn write_waiting(&self, apndp: APnDP, a: u8, data: u32) -> Result<()> {
+        for retry in 0..self.wait_retries {
+            if retry > 0 {
+                if dap::command_expired() {
+                    break;
+                }
+                self.wait_backoff(retry - 1);
+            }
             match self.write_inner(apndp, a, data) {
+                Ok(()) => {
+                    self.track_select(apndp, a, data);
+                    return Ok(());
+                }
                 Err(Error::AckWait) => continue,
-                x => return x,
+                Err(e) => {
+                    debug!("SWD write {=u8:#x} failed: {}", a, e);
+                    return Err(e);
+                }
             }
         }
+        debug!("SWD write {=u8:#x} still WAIT after retries", a);
         Err(Error::AckWait)
     }
 
+    /// Give a target which replied WAIT time to finish, before retry number `retry`.
+    fn wait_backoff(&self, retry: usize) {
+        if self.wait_idle_cycles > 0 {
+            if self.use_bitbang.load(Ordering::SeqCst) {
+                self.pins.swdio_out.set_low();
+                self.pins.swd_tx_direct();
+                self.pins.swd_clk_direct();
+                let last = self.delay.get_current();
+                self.bitbang_write(0, self.wait_idle_cycles, last);
+                self.pins.swd_tx();
+                self.pins.swd_clk_spi();
+            } else {
+                for _ in 0..(self.wait_idle_cycles + 7) / 8 {
+                    self.spi.tx8(0);
+                }
+                self.spi.wait_busy();
+            }
+        }
+        if self.wait_delay_us > 0 {
+            let delay = self.wait_delay_us.saturating_mul(1 << retry.min(16));
+            self.delay.delay_us(delay.min(MAX_WAIT_DELAY_US));
+        }
+    }
+
+    #[link_section = ".itcm"]
     fn read_inner(&self, apndp: APnDP, a: u8) -> Result<u32> {
+        if self.use_bitbang.load(Ordering::SeqCst) {
+            return self.read_inner_bitbang(apndp, a);
+        }
+
         let req = Self::make_request(apndp, RnW::R, a);
 
         self.spi.tx8(req);
@@ -187,11 +513,19 @@ impl<'a> SWD<'a> {
         self.spi.drain();
         self.pins.swd_rx();
 
-        // 1 clock for turnaround and 3 for ACK
-        let ack = self.spi.rx4() >> 1;
+        // Clocks for turnaround and 3 for ACK
+        let turnaround = self.turnaround;
+        let ack = (self.spi.rx_bits(turnaround as u8 + 3) >> turnaround) & 0b111;
         match ACK::try_ok(ack as u8) {
             Ok(_) => (),
             Err(e) => {
+                if self.data_phase && matches!(e, Error::AckWait | Error::AckFault) {
+                    // Clock through the data phase the target isn't driving.
+                    self.spi.swd_rdata_phase(&self.pins, turnaround);
+                    self.pins.swd_tx();
+                    return Err(e);
+                }
+
                 // On non-OK ACK, target has released the bus but
                 // is still expecting a turnaround clock before
                 // the next request, and we need to take over the bus.
@@ -201,10 +535,9 @@ impl<'a> SWD<'a> {
             }
         }
 
-        // Read 8x4=32 bits of data and 8x1=8 bits for parity+turnaround+trailing.
-        // Doing a batch of 5 8-bit reads is the quickest option as we keep the FIFO
-        // hot.
-        let (data, parity) = self.spi.swd_rdata_phase(self.pins);
+        // Read 8x4=32 bits of data as two packed 16-bit reads, then parity and
+        // turnaround. Queuing all four data words at once keeps the FIFO hot.
+        let (data, parity) = self.spi.swd_rdata_phase(&self.pins, turnaround);
         let parity = (parity & 1) as u32;
 
         // Back to driving SWDIO to ensure it doesn't float high
@@ -217,7 +550,12 @@ impl<'a> SWD<'a> {
         }
     }
 
+    #[link_section = ".itcm"]
     fn write_inner(&self, apndp: APnDP, a: u8, data: u32) -> Result<()> {
+        if self.use_bitbang.load(Ordering::SeqCst) {
+            return self.write_inner_bitbang(apndp, a, data);
+        }
+
         let req = Self::make_request(apndp, RnW::W, a);
         let parity = data.count_ones() & 1;
 
@@ -226,12 +564,20 @@ impl<'a> SWD<'a> {
         self.spi.drain();
         self.pins.swd_rx();
 
-        // 1 clock for turnaround and 3 for ACK and 1 for turnaround
-        let ack = (self.spi.rx5() >> 1) & 0b111;
+        // Clocks for turnaround, 3 for ACK, then clocks for turnaround again
+        let turnaround = self.turnaround;
+        let ack = (self.spi.rx_bits(2 * turnaround as u8 + 3) >> turnaround) & 0b111;
         self.pins.swd_tx();
         match ACK::try_ok(ack as u8) {
             Ok(_) => (),
-            Err(e) => return Err(e),
+            Err(e) => {
+                if self.data_phase && matches!(e, Error::AckWait | Error::AckFault) {
+                    // Send an all-zero data phase, which the target ignores.
+                    self.spi.swd_wdata_phase(0, 0);
+                    self.spi.wait_busy();
+                }
+                return Err(e);
+            }
         }
 
         // Write 8x4=32 bits of data and 8x1=8 bits for parity+trailing idle.
@@ -246,9 +592,127 @@ impl<'a> SWD<'a> {
         Ok(())
     }
 
+    /// Bitbanged read transfer, for SWCLK frequencies below the slowest SPI clock.
+    #[link_section = ".itcm"]
+    fn read_inner_bitbang(&self, apndp: APnDP, a: u8) -> Result<u32> {
+        let req = Self::make_request(apndp, RnW::R, a);
+        let turnaround = self.turnaround;
+
+        self.pins.swd_tx_direct();
+        self.pins.swd_clk_direct();
+        let mut last = self.delay.get_current();
+
+        last = self.bitbang_write(req as u32, 8, last);
+        self.pins.swd_rx();
+        last = self.bitbang_read(turnaround, last).1;
+        let (ack, mut last) = self.bitbang_read(3, last);
+        if let Err(e) = ACK::try_ok(ack as u8) {
+            if self.data_phase && matches!(e, Error::AckWait | Error::AckFault) {
+                last = self.bitbang_read(32, last).1;
+                last = self.bitbang_read(1, last).1;
+            }
+            last = self.bitbang_read(turnaround, last).1;
+            self.pins.swdio_out.set_low();
+            self.pins.swd_tx_direct();
+            self.bitbang_write(0, 4, last);
+            self.pins.swd_tx();
+            self.pins.swd_clk_spi();
+            return Err(e);
+        }
+
+        let (data, last) = self.bitbang_read(32, last);
+        let (parity, last) = self.bitbang_read(1, last);
+        let last = self.bitbang_read(turnaround, last).1;
+
+        // Back to driving SWDIO, with trailing idle cycles as on the SPI path.
+        self.pins.swdio_out.set_low();
+        self.pins.swd_tx_direct();
+        self.bitbang_write(0, 8, last);
+        self.pins.swd_tx();
+        self.pins.swd_clk_spi();
+
+        if parity == (data.count_ones() & 1) {
+            Ok(data)
+        } else {
+            Err(Error::BadParity)
+        }
+    }
+
+    /// Bitbanged write transfer, for SWCLK frequencies below the slowest SPI clock.
+    #[link_section = ".itcm"]
+    fn write_inner_bitbang(&self, apndp: APnDP, a: u8, data: u32) -> Result<()> {
+        let req = Self::make_request(apndp, RnW::W, a);
+        let parity = data.count_ones() & 1;
+        let turnaround = self.turnaround;
+
+        self.pins.swd_tx_direct();
+        self.pins.swd_clk_direct();
+        let mut last = self.delay.get_current();
+
+        last = self.bitbang_write(req as u32, 8, last);
+        self.pins.swd_rx();
+        last = self.bitbang_read(turnaround, last).1;
+        let (ack, mut last) = self.bitbang_read(3, last);
+        last = self.bitbang_read(turnaround, last).1;
+        self.pins.swdio_out.set_low();
+        self.pins.swd_tx_direct();
+
+        let result = match ACK::try_ok(ack as u8) {
+            Ok(_) => {
+                last = self.bitbang_write(data, 32, last);
+                self.bitbang_write(parity, 1, last);
+                Ok(())
+            }
+            Err(e) => {
+                if self.data_phase && matches!(e, Error::AckWait | Error::AckFault) {
+                    last = self.bitbang_write(0, 32, last);
+                    self.bitbang_write(0, 1, last);
+                }
+                Err(e)
+            }
+        };
+
+        // Trailing idle cycles to make the write effective, as on the SPI path.
+        self.bitbang_write(0, 8, self.delay.get_current());
+        self.pins.swd_tx();
+        self.pins.swd_clk_spi();
+        result
+    }
+
+    /// Clock out `bits` bits of `data` on SWDIO, LSbit first.
+    #[link_section = ".itcm"]
+    fn bitbang_write(&self, mut data: u32, bits: usize, mut last: u32) -> u32 {
+        let half_period_ticks = self.half_period_ticks.load(Ordering::SeqCst);
+        for _ in 0..bits {
+            self.pins.swdio_out.set_bool(data & 1 != 0);
+            data >>= 1;
+            self.pins.swclk.set_low();
+            last = self.delay.delay_ticks_from_last(half_period_ticks, last);
+            self.pins.swclk.set_high();
+            last = self.delay.delay_ticks_from_last(half_period_ticks, last);
+        }
+        last
+    }
+
+    /// Clock in up to 32 bits from SWDIO, LSbit first.
+    #[link_section = ".itcm"]
+    fn bitbang_read(&self, bits: usize, mut last: u32) -> (u32, u32) {
+        let half_period_ticks = self.half_period_ticks.load(Ordering::SeqCst);
+        let mut data = 0;
+        for bit in 0..bits {
+            self.pins.swclk.set_low();
+            last = self.delay.delay_ticks_from_last(half_period_ticks, last);
+            if self.pins.swdio_in.is_high() {
+                data |= 1 << bit;
+            }
+            self.pins.swclk.set_high();
+            last = self.delay.delay_ticks_from_last(half_period_ticks, last);
+        }
+        (data, last)
+    }
+
+    #[inline(always)]
     fn make_request(apndp: APnDP, rnw: RnW, a: u8) -> u8 {
-        let req = 1 | ((apndp as u8) << 1) | ((rnw as u8) << 2) | (a << 3) | (1 << 7);
-        let parity = (req.count_ones() & 1) as u8;
-        req | (parity << 5)
+        REQUESTS[(apndp as usize) | ((rnw as usize) << 1) | (((a & 0b11) as usize) << 2)]
     }
 }
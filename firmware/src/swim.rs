@@ -0,0 +1,223 @@
+use crate::bsp::{cortex_m, delay::Delay, gpio::Pins};
+
+#[derive(Copy, Clone, Debug)]
+pub enum Error {
+    NoSync,
+    Timeout,
+    Nack,
+    BadParity,
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[allow(unused)]
+mod command {
+    pub const SRST: u8 = 0b000;
+    pub const ROTF: u8 = 0b001;
+    pub const WOTF: u8 = 0b010;
+}
+
+const SWIM_CSR: u32 = 0x0000_7F80;
+const CSR_SAFE_MASK: u8 = 1 << 7;
+const CSR_SWIM_DM: u8 = 1 << 5;
+
+/// SWIM clock in low speed mode, derived from the target's 8MHz HSI.
+const SWIM_CLOCK: u32 = 8_000_000;
+
+/// Number of times a frame is resent after the target replies with NACK.
+const FRAME_RETRIES: usize = 8;
+
+/// Number of SWIM clocks to wait for the target to drive a bit.
+const BIT_TIMEOUT_CLOCKS: u32 = 2048;
+
+/// Single Wire Interface Module driver for STM8 targets.
+///
+/// SWIM is bitbanged in low speed mode on the SWDIO pin, which is switched
+/// to open-drain with a pull-up while SWIM is active. Bits are timed using
+/// SysTick, so interrupts are disabled while each frame is transferred.
+#[allow(clippy::upper_case_acronyms)]
+pub struct SWIM<'a> {
+    pins: &'a Pins<'a>,
+    delay: &'a Delay,
+    clock_ticks: u32,
+    active: bool,
+}
+
+impl<'a> SWIM<'a> {
+    pub fn new(pins: &'a Pins, delay: &'a Delay) -> Self {
+        SWIM {
+            pins,
+            delay,
+            clock_ticks: 9,
+            active: false,
+        }
+    }
+
+    /// Hold the target in reset, send the SWIM entry sequence and
+    /// enable debug access through SWIM_CSR, then release reset.
+    pub fn enter(&mut self) -> Result<()> {
+        self.clock_ticks = self.delay.calc_period_ticks(SWIM_CLOCK);
+        self.pins.swim_mode();
+        self.active = true;
+        self.pins.reset.set_low();
+        self.delay.delay_us(1000);
+
+        // Activation: 16µs low, then four pulses at 1kHz and four at 2kHz.
+        let swim = &self.pins.spi1_mosi;
+        swim.set_low();
+        self.delay.delay_us(16);
+        for &half_period in [500, 500, 500, 500, 250, 250, 250, 250].iter() {
+            swim.set_high();
+            self.delay.delay_us(half_period);
+            swim.set_low();
+            self.delay.delay_us(half_period);
+        }
+        swim.set_high();
+
+        // The target acknowledges with a 16µs low synchronisation pulse.
+        let timeout = self.delay.calc_period_ticks(1000);
+        self.wait_level(false, timeout).ok_or(Error::NoSync)?;
+        self.wait_level(true, timeout).ok_or(Error::NoSync)?;
+        self.delay.delay_us(300);
+
+        self.write(SWIM_CSR, &[CSR_SAFE_MASK | CSR_SWIM_DM])?;
+        self.pins.reset.set_high();
+        self.delay.delay_us(1000);
+        Ok(())
+    }
+
+    /// Release the SWIM and reset lines, if SWIM is active.
+    pub fn exit(&mut self) {
+        if self.active {
+            self.pins.reset.set_high();
+            self.pins.swim_exit();
+            self.active = false;
+        }
+    }
+
+    /// Issue a SWIM software reset of the target.
+    pub fn srst(&self) -> Result<()> {
+        self.write_frame(command::SRST, 3)
+    }
+
+    /// Read `buf.len()` bytes, at most 255, starting at `addr`.
+    pub fn read(&self, addr: u32, buf: &mut [u8]) -> Result<()> {
+        self.write_header(command::ROTF, buf.len() as u8, addr)?;
+        for byte in buf.iter_mut() {
+            *byte = self.read_frame()?;
+        }
+        Ok(())
+    }
+
+    /// Write `data`, at most 255 bytes, starting at `addr`.
+    pub fn write(&self, addr: u32, data: &[u8]) -> Result<()> {
+        self.write_header(command::WOTF, data.len() as u8, addr)?;
+        for &byte in data {
+            self.write_frame(byte, 8)?;
+        }
+        Ok(())
+    }
+
+    fn write_header(&self, command: u8, count: u8, addr: u32) -> Result<()> {
+        self.write_frame(command, 3)?;
+        self.write_frame(count, 8)?;
+        self.write_frame((addr >> 16) as u8, 8)?;
+        self.write_frame((addr >> 8) as u8, 8)?;
+        self.write_frame(addr as u8, 8)
+    }
+
+    /// Send a host frame of `bits` bits, MSbit first, with start and parity bits,
+    /// retrying while the target replies with NACK.
+    fn write_frame(&self, value: u8, bits: u32) -> Result<()> {
+        for _ in 0..FRAME_RETRIES {
+            let ack = cortex_m::interrupt::free(|_| {
+                // Host frames start with a 0 bit.
+                self.write_bit(false);
+                let mut parity = false;
+                for i in (0..bits).rev() {
+                    let bit = (value >> i) & 1 != 0;
+                    parity ^= bit;
+                    self.write_bit(bit);
+                }
+                self.write_bit(parity);
+                self.read_bit()
+            })?;
+
+            if ack {
+                return Ok(());
+            }
+        }
+        Err(Error::Nack)
+    }
+
+    /// Receive a target frame of 8 bits and acknowledge it.
+    fn read_frame(&self) -> Result<u8> {
+        cortex_m::interrupt::free(|_| self.read_frame_inner())
+    }
+
+    fn read_frame_inner(&self) -> Result<u8> {
+        // Target frames start with a 1 bit.
+        if !self.read_bit()? {
+            return Err(Error::BadParity);
+        }
+        let mut value = 0u8;
+        let mut parity = false;
+        for _ in 0..8 {
+            let bit = self.read_bit()?;
+            parity ^= bit;
+            value = (value << 1) | (bit as u8);
+        }
+        let parity_ok = self.read_bit()? == parity;
+
+        // Leave the line idle briefly before sending ACK or NACK.
+        self.delay.delay_ticks(self.clock_ticks * 4);
+        self.write_bit(parity_ok);
+
+        if parity_ok {
+            Ok(value)
+        } else {
+            Err(Error::BadParity)
+        }
+    }
+
+    /// Send a bit: 1 is 2 SWIM clocks low then 20 high, 0 is 20 low then 2 high.
+    fn write_bit(&self, bit: bool) {
+        let (low, high) = if bit { (2, 20) } else { (20, 2) };
+        let swim = &self.pins.spi1_mosi;
+        let mut last = self.delay.get_current();
+        swim.set_low();
+        last = self
+            .delay
+            .delay_ticks_from_last(low * self.clock_ticks, last);
+        swim.set_high();
+        self.delay
+            .delay_ticks_from_last(high * self.clock_ticks, last);
+    }
+
+    /// Receive a bit driven by the target, decoded from the length of its low pulse.
+    fn read_bit(&self) -> Result<bool> {
+        let timeout = BIT_TIMEOUT_CLOCKS * self.clock_ticks;
+        let fall = self.wait_level(false, timeout).ok_or(Error::Timeout)?;
+        let rise = self.wait_level(true, timeout).ok_or(Error::Timeout)?;
+
+        // SysTick counts down, and a 1 is much shorter than a 0.
+        let low_ticks = fall.wrapping_sub(rise) & 0xFF_FFFF;
+        Ok(low_ticks < 11 * self.clock_ticks)
+    }
+
+    /// Wait for the SWIM line to reach the given level.
+    ///
+    /// Returns the SysTick value when it did, or None after `timeout` ticks.
+    fn wait_level(&self, high: bool, timeout: u32) -> Option<u32> {
+        let start = self.delay.get_current();
+        loop {
+            let now = self.delay.get_current();
+            if self.pins.spi1_mosi.is_high() == high {
+                return Some(now);
+            }
+            if (start.wrapping_sub(now) & 0xFF_FFFF) > timeout {
+                return None;
+            }
+        }
+    }
+}
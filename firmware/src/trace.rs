@@ -0,0 +1,114 @@
+use crate::bsp::cortex_m::peripheral::DWT;
+
+/// Number of DAP commands kept in the trace buffer.
+pub const TRACE_LEN: usize = 64;
+
+/// Size of one entry when written into a response.
+pub const ENTRY_SIZE: usize = 14;
+
+/// One processed DAP command.
+#[derive(Copy, Clone)]
+struct TraceEntry {
+    /// Cycle counter when processing started.
+    start: u32,
+    /// CPU cycles spent processing the command.
+    cycles: u32,
+    command: u8,
+    /// First request bytes after the command ID, zero padded.
+    args: [u8; 3],
+    /// First response bytes after the command ID, zero padded.
+    response: [u8; 2],
+}
+
+impl TraceEntry {
+    const EMPTY: TraceEntry = TraceEntry {
+        start: 0,
+        cycles: 0,
+        command: 0,
+        args: [0; 3],
+        response: [0; 2],
+    };
+
+    fn write_to(&self, buf: &mut [u8]) {
+        buf[0..4].copy_from_slice(&self.start.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.cycles.to_le_bytes());
+        buf[8] = self.command;
+        buf[9..12].copy_from_slice(&self.args);
+        buf[12..14].copy_from_slice(&self.response);
+    }
+}
+
+/// Ring buffer of the most recent DAP commands, their responses and timing.
+pub struct Trace {
+    entries: [TraceEntry; TRACE_LEN],
+    next: usize,
+    count: usize,
+    enabled: bool,
+}
+
+impl Trace {
+    pub const fn new() -> Self {
+        Trace {
+            entries: [TraceEntry::EMPTY; TRACE_LEN],
+            next: 0,
+            count: 0,
+            enabled: false,
+        }
+    }
+
+    /// Enable or disable tracing. The buffer is cleared when tracing is enabled.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if enabled && !self.enabled {
+            self.next = 0;
+            self.count = 0;
+        }
+        self.enabled = enabled;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Number of entries recorded, up to `TRACE_LEN`.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    /// Record a command `request` which produced `response`,
+    /// with processing started at cycle counter value `start`.
+    pub fn record(&mut self, request: &[u8], response: &[u8], start: u32) {
+        let cycles = DWT::cycle_count().wrapping_sub(start);
+        let mut entry = TraceEntry {
+            start,
+            cycles,
+            command: request[0],
+            ..TraceEntry::EMPTY
+        };
+        copy_prefix(&mut entry.args, &request[1..]);
+        if !response.is_empty() {
+            copy_prefix(&mut entry.response, &response[1..]);
+        }
+
+        self.entries[self.next] = entry;
+        self.next = (self.next + 1) % TRACE_LEN;
+        self.count = (self.count + 1).min(TRACE_LEN);
+    }
+
+    /// Write entries from index `first`, counting from the oldest, into `buf`.
+    ///
+    /// Returns the number of entries written.
+    pub fn read(&self, first: usize, buf: &mut [u8]) -> usize {
+        let oldest = (self.next + TRACE_LEN - self.count) % TRACE_LEN;
+        let mut n = 0;
+        for (idx, chunk) in (first..self.count).zip(buf.chunks_exact_mut(ENTRY_SIZE)) {
+            self.entries[(oldest + idx) % TRACE_LEN].write_to(chunk);
+            n += 1;
+        }
+        n
+    }
+}
+
+fn copy_prefix(dst: &mut [u8], src: &[u8]) {
+    let n = dst.len().min(src.len());
+    dst[..n].copy_from_slice(&src[..n]);
+}
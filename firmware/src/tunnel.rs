@@ -0,0 +1,133 @@
+//! CMSIS-DAP commands tunnelled over the CDC serial port.
+//!
+//! For hosts which can't open the DAP interfaces, such as restricted VMs or
+//! CI runners, the CDC port carries DAP commands instead of VCP data while it
+//! is set to `TUNNEL_BAUD`. Each command and response is framed as a u16
+//! little-endian length followed by that many bytes.
+
+use crate::{DAP2_PACKET_SIZE, VCP_PACKET_SIZE};
+
+/// Line coding baud rate which switches the CDC port to the DAP tunnel,
+/// chosen as a rate no real serial link uses.
+pub const TUNNEL_BAUD: u32 = 1_000_001;
+
+const FRAME_SIZE: usize = DAP2_PACKET_SIZE as usize;
+
+pub struct DapTunnel {
+    /// Last packet received from the host, and how much of it has been parsed.
+    input: [u8; VCP_PACKET_SIZE as usize],
+    input_len: usize,
+    input_pos: usize,
+    /// Request being received, with its length once both length bytes are in.
+    request: [u8; FRAME_SIZE],
+    header: [u8; 2],
+    header_len: usize,
+    request_len: usize,
+    received: usize,
+    /// Framed response, and how much of it the host has accepted.
+    response: [u8; FRAME_SIZE + 2],
+    response_len: usize,
+    sent: usize,
+}
+
+impl DapTunnel {
+    pub const fn new() -> Self {
+        DapTunnel {
+            input: [0; VCP_PACKET_SIZE as usize],
+            input_len: 0,
+            input_pos: 0,
+            request: [0; FRAME_SIZE],
+            header: [0; 2],
+            header_len: 0,
+            request_len: 0,
+            received: 0,
+            response: [0; FRAME_SIZE + 2],
+            response_len: 0,
+            sent: 0,
+        }
+    }
+
+    /// Drop any partial request and unsent response, for when the tunnel is opened.
+    pub fn reset(&mut self) {
+        self.input_len = 0;
+        self.input_pos = 0;
+        self.header_len = 0;
+        self.received = 0;
+        self.response_len = 0;
+        self.sent = 0;
+    }
+
+    /// Check if the last packet from the host has been used up, so another can be received.
+    pub fn is_idle(&self) -> bool {
+        self.input_pos == self.input_len
+    }
+
+    /// Store a packet received from the host. Must only be called when idle.
+    pub fn push(&mut self, data: &[u8]) {
+        let len = data.len().min(self.input.len());
+        self.input[..len].copy_from_slice(&data[..len]);
+        self.input_len = len;
+        self.input_pos = 0;
+    }
+
+    /// Send any pending response with `write`, which returns how many bytes
+    /// it accepted, then process the next complete request with `process`,
+    /// which returns the length of the response it writes.
+    ///
+    /// At most one request is processed per call, and none until the
+    /// previous response has been accepted.
+    pub fn poll(
+        &mut self,
+        process: impl FnOnce(&[u8], &mut [u8]) -> usize,
+        mut write: impl FnMut(&[u8]) -> usize,
+    ) {
+        if self.sent < self.response_len {
+            self.sent += write(&self.response[self.sent..self.response_len]);
+            if self.sent < self.response_len {
+                return;
+            }
+        }
+
+        if !self.parse() {
+            return;
+        }
+        let len = process(&self.request[..self.request_len], &mut self.response[2..]);
+        self.response[..2].copy_from_slice(&(len as u16).to_le_bytes());
+        self.response_len = len + 2;
+        self.sent = write(&self.response[..self.response_len]);
+    }
+
+    /// Parse input until a request is complete, returning true if one is.
+    fn parse(&mut self) -> bool {
+        while self.input_pos < self.input_len {
+            let byte = self.input[self.input_pos];
+            self.input_pos += 1;
+
+            if self.header_len < 2 {
+                self.header[self.header_len] = byte;
+                self.header_len += 1;
+                if self.header_len == 2 {
+                    self.request_len = u16::from_le_bytes(self.header) as usize;
+                    self.received = 0;
+                    if self.request_len == 0 || self.request_len > FRAME_SIZE {
+                        // Framing is lost, so drop the rest of this packet and
+                        // expect the host to start a new frame with the next one.
+                        warn!("Invalid DAP tunnel frame length {=usize}", self.request_len);
+                        self.header_len = 0;
+                        self.input_pos = self.input_len;
+                        return false;
+                    }
+                }
+                continue;
+            }
+
+            self.request[self.received] = byte;
+            self.received += 1;
+            if self.received == self.request_len {
+                self.header_len = 0;
+                return true;
+            }
+        }
+        false
+    }
+}
@@ -0,0 +1,124 @@
+use crate::bsp::{cortex_m, gpio::Pin};
+use crate::SwoUart;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Error {
+    Timeout,
+    BadEcho,
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Number of polls to wait for each received byte before timing out.
+const TIMEOUT: u32 = 100_000;
+
+/// Number of CPU cycles to hold the line low for a break,
+/// at least 24.6ms even when running at 216MHz.
+const BREAK_CYCLES: u32 = 216_000 * 25;
+
+#[allow(unused)]
+mod instruction {
+    pub const SYNC: u8 = 0x55;
+    pub const LDS: u8 = 0x00;
+    pub const STS: u8 = 0x40;
+    pub const LDCS: u8 = 0x80;
+    pub const STCS: u8 = 0xC0;
+    pub const KEY: u8 = 0xE0;
+}
+
+#[allow(unused)]
+mod cs {
+    pub const STATUSA: u8 = 0x00;
+    pub const STATUSB: u8 = 0x01;
+    pub const CTRLA: u8 = 0x02;
+    pub const CTRLB: u8 = 0x03;
+
+    pub const CTRLA_IBDLY: u8 = 1 << 7;
+    pub const CTRLB_CCDETDIS: u8 = 1 << 3;
+    pub const CTRLB_UPDIDIS: u8 = 1 << 2;
+}
+
+/// UPDI link layer, using USART1 in single-wire half-duplex mode on the SWO pin.
+#[allow(clippy::upper_case_acronyms)]
+pub struct UPDI<'a> {
+    uart: &'a SwoUart<'a>,
+    pin: &'a Pin<'a>,
+}
+
+impl<'a> UPDI<'a> {
+    pub fn new(uart: &'a SwoUart<'a>, pin: &'a Pin<'a>) -> Self {
+        UPDI { uart, pin }
+    }
+
+    /// Enable the half-duplex UART at `baud` and initialise the target's UPDI.
+    ///
+    /// Returns the contents of the UPDI STATUSA register on success.
+    pub fn start(&self, baud: u32) -> Result<u8> {
+        self.uart.start_half_duplex(baud);
+        self.double_break();
+
+        // Disable collision detection and add an inter-byte delay
+        // so we don't miss responses from the target.
+        self.stcs(cs::CTRLB, cs::CTRLB_CCDETDIS)?;
+        self.stcs(cs::CTRLA, cs::CTRLA_IBDLY)?;
+        self.ldcs(cs::STATUSA)
+    }
+
+    /// Disable the target's UPDI and leave half-duplex mode.
+    pub fn stop(&self) {
+        if self.uart.is_half_duplex() {
+            self.stcs(cs::CTRLB, cs::CTRLB_UPDIDIS).ok();
+            self.uart.stop_half_duplex();
+        }
+    }
+
+    /// Send a double break, resetting the target's UPDI link.
+    pub fn double_break(&self) {
+        for _ in 0..2 {
+            self.pin.set_low().set_mode_output();
+            cortex_m::asm::delay(BREAK_CYCLES);
+            self.pin.set_high().set_mode_alternate();
+            cortex_m::asm::delay(BREAK_CYCLES / 25);
+        }
+        self.uart.hd_flush();
+    }
+
+    /// Load a UPDI control/status register.
+    pub fn ldcs(&self, addr: u8) -> Result<u8> {
+        self.send(&[instruction::SYNC, instruction::LDCS | (addr & 0xF)])?;
+        self.receive()
+    }
+
+    /// Store a UPDI control/status register.
+    pub fn stcs(&self, addr: u8, value: u8) -> Result<()> {
+        self.send(&[instruction::SYNC, instruction::STCS | (addr & 0xF), value])
+    }
+
+    /// Transmit raw UPDI bytes from `tx`, then receive `rx.len()` bytes into `rx`.
+    ///
+    /// The host is responsible for including the SYNC character.
+    pub fn transfer(&self, tx: &[u8], rx: &mut [u8]) -> Result<()> {
+        self.send(tx)?;
+        for byte in rx.iter_mut() {
+            *byte = self.receive()?;
+        }
+        Ok(())
+    }
+
+    fn send(&self, data: &[u8]) -> Result<()> {
+        for &byte in data {
+            self.uart.hd_write(byte);
+            // Every transmitted byte is echoed back on the single wire.
+            match self.uart.hd_read(TIMEOUT) {
+                Some(echo) if echo == byte => (),
+                Some(_) => return Err(Error::BadEcho),
+                None => return Err(Error::Timeout),
+            }
+        }
+        Ok(())
+    }
+
+    fn receive(&self) -> Result<u8> {
+        self.uart.hd_read(TIMEOUT).ok_or(Error::Timeout)
+    }
+}
@@ -0,0 +1,60 @@
+use super::endpoints;
+use super::names::{self, Name};
+use crate::app::Request;
+use crate::AVRISP_PACKET_SIZE;
+use usb_device::class_prelude::*;
+use usb_device::Result;
+
+/// Vendor-specific bulk interface carrying STK500v2 messages,
+/// in the same way as the AVRISP mkII.
+pub struct AvrIsp<'a, B: UsbBus> {
+    interface: InterfaceNumber,
+    name: StringIndex,
+    read_ep: EndpointOut<'a, B>,
+    write_ep: EndpointIn<'a, B>,
+}
+
+impl<B: UsbBus> AvrIsp<'_, B> {
+    pub fn new(alloc: &UsbBusAllocator<B>) -> AvrIsp<B> {
+        AvrIsp {
+            interface: alloc.interface(),
+            name: alloc.string(),
+            read_ep: endpoints::bulk(alloc, endpoints::AVRISP_OUT, AVRISP_PACKET_SIZE),
+            write_ep: endpoints::bulk(alloc, endpoints::AVRISP_IN, AVRISP_PACKET_SIZE),
+        }
+    }
+
+    pub fn process(&mut self) -> Option<Request> {
+        let mut buf = [0u8; AVRISP_PACKET_SIZE as usize];
+        match self.read_ep.read(&mut buf) {
+            Ok(size) if size > 0 => Some(Request::AVRISPCommand((buf, size))),
+            _ => None,
+        }
+    }
+
+    pub fn write_packet(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() > self.write_ep.max_packet_size() as usize {
+            return Err(UsbError::BufferOverflow);
+        }
+        self.write_ep.write(data).map(|_| ())
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for AvrIsp<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface_alt(self.interface, 0, 0xff, 0, 0, Some(self.name))?;
+
+        super::write_endpoint(writer, &self.read_ep)?;
+        super::write_endpoint(writer, &self.write_ep)?;
+
+        Ok(())
+    }
+
+    fn get_string(&self, index: StringIndex, _lang_id: u16) -> Option<&str> {
+        if index == self.name {
+            Some(names::get(Name::AvrIsp))
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, RequestType};
+
+/// CDC PSTN requests which change the state of the serial port.
+const SET_LINE_CODING: u8 = 0x20;
+const SET_CONTROL_LINE_STATE: u8 = 0x22;
+
+/// Notes CDC-ACM requests changing the line coding or the control line state,
+/// so the firmware hears about each change once instead of polling the serial port.
+///
+/// Requests are only offered to classes until one accepts them, so this class
+/// must be polled before the serial port, and never accepts them itself.
+#[derive(Default)]
+pub struct CdcEvents {
+    line_coding: bool,
+    control_lines: bool,
+}
+
+impl CdcEvents {
+    /// Check whether SET_LINE_CODING was received since the last call.
+    pub fn take_line_coding(&mut self) -> bool {
+        core::mem::replace(&mut self.line_coding, false)
+    }
+
+    /// Check whether SET_CONTROL_LINE_STATE was received since the last call.
+    pub fn take_control_lines(&mut self) -> bool {
+        core::mem::replace(&mut self.control_lines, false)
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for CdcEvents {
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = xfer.request();
+        if !(req.request_type == RequestType::Class && req.recipient == Recipient::Interface) {
+            return;
+        }
+
+        match req.request {
+            SET_LINE_CODING => self.line_coding = true,
+            SET_CONTROL_LINE_STATE => self.control_lines = true,
+            _ => (),
+        }
+    }
+}
@@ -1,3 +1,7 @@
+use super::endpoints;
+use super::names::{self, Name};
+use super::queue::RequestQueue;
+use super::stats::STATS;
 use crate::app::Request;
 use crate::DAP1_PACKET_SIZE;
 use usb_device::control::{Recipient, RequestType};
@@ -6,6 +10,14 @@ use usb_device::{class_prelude::*, device};
 
 const INTERFACE_CLASS_HID: u8 = 0x03;
 
+/// Polling interval of the HID endpoints. The interval is 2^(bInterval-1)
+/// microframes at high speed and bInterval frames at full speed, so 1 polls
+/// every 125 µs at high speed, as often as the host allows.
+///
+/// Reports are 64 bytes, or 1024 bytes with the `hid-1024` feature, which
+/// leaves out other interfaces to free the OTG_HS FIFO RAM they need.
+const HID_INTERVAL: u8 = 1;
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum DescriptorType {
@@ -13,6 +25,7 @@ pub enum DescriptorType {
     Report = 0x22,
 }
 
+#[cfg(not(feature = "hid-1024"))]
 const REPORT_DESCRIPTOR: &[u8] = &[
     0x06, 0x00, 0xFF, // Usage Page (Vendor Defined 0xFF00)
     0x09, 0x01, // Usage (0x01)
@@ -36,36 +49,101 @@ const REPORT_DESCRIPTOR: &[u8] = &[
           // 32 bytes
 ];
 
+#[cfg(feature = "hid-1024")]
+const REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x00, 0xFF, // Usage Page (Vendor Defined 0xFF00)
+    0x09, 0x01, // Usage (0x01)
+    0xA1, 0x01, // Collection (Application)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0xFF, //   Logical Maximum (255)
+    0x75, 0x08, //   Report Size (8)
+    0x96, 0x00, 0x04, //   Report Count (1024)
+    0x09, 0x01, //   Usage (0x01)
+    0x81, 0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x96, 0x00, 0x04, //   Report Count (1024)
+    0x09, 0x01, //   Usage (0x01)
+    0x91,
+    0x02, //   Output (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+    0x95, 0x01, //   Report Count (1)
+    0x09, 0x01, //   Usage (0x01)
+    0xB1,
+    0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+    0xC0, // End Collection
+
+          // 34 bytes
+];
+
 pub struct CmsisDapV1<'a, B: UsbBus> {
     interface: InterfaceNumber,
     name: StringIndex,
     read_ep: EndpointOut<'a, B>,
     write_ep: EndpointIn<'a, B>,
+    requests: &'static RequestQueue<{ DAP1_PACKET_SIZE as usize }>,
 }
 
 impl<B: UsbBus> CmsisDapV1<'_, B> {
-    pub fn new(alloc: &UsbBusAllocator<B>) -> CmsisDapV1<B> {
+    pub fn new(
+        alloc: &UsbBusAllocator<B>,
+        requests: &'static RequestQueue<{ DAP1_PACKET_SIZE as usize }>,
+    ) -> CmsisDapV1<B> {
         CmsisDapV1 {
             interface: alloc.interface(),
             name: alloc.string(),
-            read_ep: alloc.interrupt(DAP1_PACKET_SIZE, 1),
-            write_ep: alloc.interrupt(DAP1_PACKET_SIZE, 1),
+            read_ep: endpoints::interrupt(
+                alloc,
+                endpoints::DAP_V1_OUT,
+                DAP1_PACKET_SIZE,
+                HID_INTERVAL,
+            ),
+            write_ep: endpoints::interrupt(
+                alloc,
+                endpoints::DAP_V1_IN,
+                DAP1_PACKET_SIZE,
+                HID_INTERVAL,
+            ),
+            requests,
         }
     }
 
-    pub fn process(&mut self) -> Option<Request> {
-        let mut buf = [0u8; DAP1_PACKET_SIZE as usize];
-        match self.read_ep.read(&mut buf) {
-            Ok(size) if size > 0 => Some(Request::DAP1Command((buf, size))),
+    /// Read a pending request into the request queue, if there is space.
+    pub fn receive(&mut self) {
+        if self.requests.is_full() {
+            STATS.queue_was_full();
+            return;
+        }
+        let read_ep = &self.read_ep;
+        self.requests.push_with(|buf| match read_ep.read(buf) {
+            Ok(size) if size > 0 => {
+                STATS.received(size);
+                Some(size)
+            }
             _ => None,
+        });
+    }
+
+    pub fn process(&mut self) -> Option<Request> {
+        self.receive();
+        if self.requests.is_empty() {
+            None
+        } else {
+            Some(Request::DAP1Command)
         }
     }
 
-    pub fn write_packet(&mut self, data: &[u8]) -> Result<()> {
-        if data.len() > self.write_ep.max_packet_size() as usize {
+    /// Transmit the first `len` bytes of the response buffer, if any,
+    /// and release the request it answers.
+    pub fn write_response(&mut self, len: usize) -> Result<()> {
+        if len > self.write_ep.max_packet_size() as usize {
             return Err(UsbError::BufferOverflow);
         }
-        self.write_ep.write(data).map(|_| ())
+        if len > 0 {
+            // Safety: only called from the main context.
+            let response = unsafe { self.requests.response() };
+            self.write_ep.write(&response[..len])?;
+            STATS.sent(len);
+        }
+        self.requests.pop();
+        Ok(())
     }
 }
 
@@ -106,7 +184,7 @@ impl<B: UsbBus> UsbClass<B> for CmsisDapV1<'_, B> {
 
     fn get_string(&self, index: StringIndex, _lang_id: u16) -> Option<&str> {
         if index == self.name {
-            Some("HS-Probe CMSIS-DAP v1 Interface")
+            Some(names::get(Name::DapV1))
         } else {
             None
         }
@@ -1,4 +1,9 @@
+use super::endpoints;
+use super::names::{self, Name};
+use super::queue::RequestQueue;
+use super::stats::STATS;
 use crate::app::Request;
+use crate::dap;
 use crate::DAP2_PACKET_SIZE;
 use usb_device::class_prelude::*;
 use usb_device::Result;
@@ -8,35 +13,86 @@ pub struct CmsisDapV2<'a, B: UsbBus> {
     name: StringIndex,
     read_ep: EndpointOut<'a, B>,
     write_ep: EndpointIn<'a, B>,
+    /// Set from writing a response until the host has read it.
+    write_busy: bool,
+    requests: &'static RequestQueue<{ DAP2_PACKET_SIZE as usize }>,
     trace_ep: EndpointIn<'a, B>,
     trace_busy: bool,
 }
 
 impl<B: UsbBus> CmsisDapV2<'_, B> {
-    pub fn new(alloc: &UsbBusAllocator<B>) -> CmsisDapV2<B> {
+    pub fn new(
+        alloc: &UsbBusAllocator<B>,
+        requests: &'static RequestQueue<{ DAP2_PACKET_SIZE as usize }>,
+    ) -> CmsisDapV2<B> {
+        #[cfg(not(feature = "swo-interrupt"))]
+        let trace_ep = endpoints::bulk(alloc, endpoints::DAP_V2_TRACE_IN, DAP2_PACKET_SIZE);
+        // Polled every microframe, so the host reserves bandwidth for SWO data
+        // which bulk transfers on the command endpoints can't take away.
+        #[cfg(feature = "swo-interrupt")]
+        let trace_ep = endpoints::interrupt(alloc, endpoints::DAP_V2_TRACE_IN, DAP2_PACKET_SIZE, 1);
+
         CmsisDapV2 {
             interface: alloc.interface(),
             name: alloc.string(),
-            read_ep: alloc.bulk(DAP2_PACKET_SIZE),
-            write_ep: alloc.bulk(DAP2_PACKET_SIZE),
-            trace_ep: alloc.bulk(DAP2_PACKET_SIZE),
+            read_ep: endpoints::bulk(alloc, endpoints::DAP_V2_OUT, DAP2_PACKET_SIZE),
+            write_ep: endpoints::bulk(alloc, endpoints::DAP_V2_IN, DAP2_PACKET_SIZE),
+            write_busy: false,
+            trace_ep,
+            requests,
             trace_busy: false,
         }
     }
 
-    pub fn process(&mut self) -> Option<Request> {
-        let mut buf = [0u8; DAP2_PACKET_SIZE as usize];
-        match self.read_ep.read(&mut buf) {
-            Ok(size) if size > 0 => Some(Request::DAP2Command((buf, size))),
+    /// Read a pending request into the request queue, if there is space.
+    ///
+    /// A DAP_TransferAbort request aborts the transfer in progress immediately,
+    /// and is then queued like any other request.
+    pub fn receive(&mut self) {
+        if self.requests.is_full() {
+            STATS.queue_was_full();
+            return;
+        }
+        let read_ep = &self.read_ep;
+        self.requests.push_with(|buf| match read_ep.read(buf) {
+            Ok(size) if size > 0 => {
+                STATS.received(size);
+                if buf[0] == dap::DAP_TRANSFER_ABORT {
+                    dap::abort_transfer();
+                }
+                Some(size)
+            }
             _ => None,
+        });
+    }
+
+    /// Get the next request to process, once the response to the previous
+    /// one has been read by the host, so a response is never written while
+    /// the endpoint still holds the last one.
+    pub fn process(&mut self) -> Option<Request> {
+        self.receive();
+        if self.requests.is_empty() || self.write_busy {
+            None
+        } else {
+            Some(Request::DAP2Command)
         }
     }
 
-    pub fn write_packet(&mut self, data: &[u8]) -> Result<()> {
-        if data.len() > self.write_ep.max_packet_size() as usize {
+    /// Transmit the first `len` bytes of the response buffer, if any,
+    /// and release the request it answers.
+    pub fn write_response(&mut self, len: usize) -> Result<()> {
+        if len > super::max_packet_size(self.write_ep.max_packet_size()) as usize {
             return Err(UsbError::BufferOverflow);
         }
-        self.write_ep.write(data).map(|_| ())
+        if len > 0 {
+            // Safety: only called from the main context.
+            let response = unsafe { self.requests.response() };
+            self.write_ep.write(&response[..len])?;
+            self.write_busy = true;
+            STATS.sent(len);
+        }
+        self.requests.pop();
+        Ok(())
     }
 
     pub fn trace_busy(&self) -> bool {
@@ -44,7 +100,7 @@ impl<B: UsbBus> CmsisDapV2<'_, B> {
     }
 
     pub fn trace_write(&mut self, data: &[u8]) -> Result<()> {
-        if data.len() > self.trace_ep.max_packet_size() as usize {
+        if data.len() > super::max_packet_size(self.trace_ep.max_packet_size()) as usize {
             return Err(UsbError::BufferOverflow);
         }
         self.trace_ep.write(data).map(|_| ())?;
@@ -57,28 +113,32 @@ impl<B: UsbBus> UsbClass<B> for CmsisDapV2<'_, B> {
     fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
         writer.interface_alt(self.interface, 0, 0xff, 0, 0, Some(self.name))?;
 
-        writer.endpoint(&self.read_ep)?;
-        writer.endpoint(&self.write_ep)?;
-        writer.endpoint(&self.trace_ep)?;
+        super::write_endpoint(writer, &self.read_ep)?;
+        super::write_endpoint(writer, &self.write_ep)?;
+        super::write_endpoint(writer, &self.trace_ep)?;
 
         Ok(())
     }
 
     fn get_string(&self, index: StringIndex, _lang_id: u16) -> Option<&str> {
         if index == self.name {
-            Some("HS-probe CMSIS-DAP v2 Interface")
+            Some(names::get(Name::DapV2))
         } else {
             None
         }
     }
 
     fn reset(&mut self) {
+        self.write_busy = false;
         self.trace_busy = false;
     }
 
     fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
-        if addr == self.trace_ep.address() {
+        if addr == self.write_ep.address() {
+            self.write_busy = false;
+        } else if addr == self.trace_ep.address() {
             self.trace_busy = false;
+            dap::notify_swo();
         }
     }
 }
@@ -0,0 +1,51 @@
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, Request, RequestType};
+
+const DEVICE_QUALIFIER: u8 = 6;
+
+/// Device class, subclass and protocol of a composite device whose functions
+/// are grouped by interface association descriptors, as set by
+/// `UsbDeviceBuilder::composite_with_iads`.
+///
+/// The CDC-ACM class writes an IAD grouping its two interfaces, so hosts bind
+/// cdc_acm to the pair. The other functions each have a single interface and
+/// keep their own class codes.
+const CLASS_MISC: u8 = 0xEF;
+const SUBCLASS_COMMON: u8 = 0x02;
+const PROTOCOL_IAD: u8 = 0x01;
+
+/// Answers GET_DESCRIPTOR(DEVICE_QUALIFIER), which usb-device leaves unhandled.
+///
+/// High-speed capable devices must return it, and some hosts treat a stall as
+/// a device which is not, or retry enumeration. It repeats the composite class
+/// codes of the device descriptor, as they are the same at either speed.
+pub struct DeviceQualifier;
+
+impl<B: UsbBus> UsbClass<B> for DeviceQualifier {
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = xfer.request();
+        if !(req.request_type == RequestType::Standard
+            && req.recipient == Recipient::Device
+            && req.request == Request::GET_DESCRIPTOR)
+        {
+            return;
+        }
+
+        let (dtype, _index) = req.descriptor_type_index();
+        if dtype == DEVICE_QUALIFIER {
+            xfer.accept_with_static(&[
+                10,               // bLength
+                DEVICE_QUALIFIER, // bDescriptorType
+                0x00,             // bcdUSB.lower
+                0x02,             // bcdUSB.upper
+                CLASS_MISC,       // bDeviceClass
+                SUBCLASS_COMMON,  // bDeviceSubClass
+                PROTOCOL_IAD,     // bDeviceProtocol
+                64,               // bMaxPacketSize0
+                1,                // bNumConfigurations
+                0,                // bReserved
+            ])
+            .ok();
+        }
+    }
+}
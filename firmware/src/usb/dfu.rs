@@ -1,3 +1,6 @@
+use super::names::{self, Name};
+use crate::bsp::cortex_m::peripheral::DWT;
+use core::sync::atomic::{AtomicBool, Ordering};
 use usb_device::control::{Recipient, RequestType};
 use usb_device::Result;
 use usb_device::{class_prelude::*, device};
@@ -13,16 +16,71 @@ mod request {
     pub const DFU_ABORT: u8 = 6;
 }
 
+/// Set when DFU_DETACH must be confirmed with the Restart vendor command.
+static DETACH_LOCKED: AtomicBool = AtomicBool::new(false);
+/// Set while bootloader entry has been requested with the Restart vendor command.
+static DETACH_ARMED: AtomicBool = AtomicBool::new(false);
+
+/// Only accept DFU_DETACH while bootloader entry is armed, so stray requests
+/// cannot restart a probe which must stay attached.
+pub fn set_detach_lock(locked: bool) {
+    DETACH_LOCKED.store(locked, Ordering::Relaxed);
+}
+
+/// Accept DFU_DETACH even when locked, until disarmed.
+pub fn arm_detach(armed: bool) {
+    DETACH_ARMED.store(armed, Ordering::Relaxed);
+}
+
+/// DFU class-specific descriptor type.
+const DFU_FUNCTIONAL: u8 = 0x21;
+
+/// bmAttributes of the system bootloader: it can download and upload firmware,
+/// and the host must reset the bus to leave appDETACH.
+const ATTRIBUTES: u8 = 0x03;
+/// Longest time to wait in appDETACH for the bus reset, in ms.
+const DETACH_TIMEOUT_MS: u16 = 255;
+/// Size of a download or upload block in the system bootloader.
+const TRANSFER_SIZE: u16 = 2048;
+/// DFU 1.1.
+const DFU_VERSION: u16 = 0x0110;
+
+/// Runtime states reported by DFU_GETSTATUS and DFU_GETSTATE.
+#[derive(Copy, Clone)]
+enum State {
+    AppIdle,
+    /// DFU_DETACH was received at the given cycle count, with a timeout in cycles.
+    AppDetach {
+        start: u32,
+        timeout: u32,
+    },
+}
+
+impl State {
+    fn bstate(self) -> u8 {
+        match self {
+            State::AppIdle => 0,
+            State::AppDetach { .. } => 1,
+        }
+    }
+}
+
+/// DFU runtime interface, which restarts into the system bootloader when the
+/// bus is reset after a DFU_DETACH request, so that dfu-util can update the firmware.
 pub struct DfuRuntime {
     interface: InterfaceNumber,
     name: StringIndex,
+    hclk: u32,
+    state: State,
 }
 
 impl DfuRuntime {
-    pub fn new<B: UsbBus>(alloc: &UsbBusAllocator<B>) -> DfuRuntime {
+    pub fn new<B: UsbBus>(alloc: &UsbBusAllocator<B>, hclk: u32) -> DfuRuntime {
         DfuRuntime {
             interface: alloc.interface(),
             name: alloc.string(),
+            hclk,
+            state: State::AppIdle,
         }
     }
 }
@@ -38,14 +96,13 @@ impl<B: UsbBus> UsbClass<B> for DfuRuntime {
             Some(self.name),
         )?;
 
-        // DFU Functional Descriptor
+        let [timeout_lo, timeout_hi] = DETACH_TIMEOUT_MS.to_le_bytes();
+        let [size_lo, size_hi] = TRANSFER_SIZE.to_le_bytes();
+        let [version_lo, version_hi] = DFU_VERSION.to_le_bytes();
         writer.write(
-            0x21, // Functional descriptor type
+            DFU_FUNCTIONAL,
             &[
-                0x0F, // bmAttributes
-                0xFF, 0x00, // wDetachTimeOut
-                0x08, 0x00, // wTransferSize
-                0x00, 0x01, // bcdDFUVersion
+                ATTRIBUTES, timeout_lo, timeout_hi, size_lo, size_hi, version_lo, version_hi,
             ],
         )?;
 
@@ -54,12 +111,28 @@ impl<B: UsbBus> UsbClass<B> for DfuRuntime {
 
     fn get_string(&self, index: StringIndex, _lang_id: u16) -> Option<&str> {
         if index == self.name {
-            Some("HS-Probe DFU")
+            Some(names::get(Name::Dfu))
         } else {
             None
         }
     }
 
+    fn reset(&mut self) {
+        if let State::AppDetach { .. } = self.state {
+            hs_probe_bsp::bootload::bootload();
+        }
+    }
+
+    fn poll(&mut self) {
+        // Without the bus reset in time, stay in the application.
+        if let State::AppDetach { start, timeout } = self.state {
+            if DWT::cycle_count().wrapping_sub(start) > timeout {
+                info!("DFU detach timed out");
+                self.state = State::AppIdle;
+            }
+        }
+    }
+
     fn control_in(&mut self, xfer: ControlIn<B>) {
         let req = xfer.request();
         if !(req.request_type == RequestType::Class
@@ -71,7 +144,12 @@ impl<B: UsbBus> UsbClass<B> for DfuRuntime {
 
         match req.request {
             request::DFU_GETSTATUS => {
-                xfer.accept_with_static(&[0x00; 6]).ok();
+                // bStatus OK, bwPollTimeout 0, bState, iString 0
+                let status = [0, 0, 0, 0, self.state.bstate(), 0];
+                xfer.accept_with(&status).ok();
+            }
+            request::DFU_GETSTATE => {
+                xfer.accept_with(&[self.state.bstate()]).ok();
             }
             _ => {
                 xfer.reject().ok();
@@ -88,9 +166,23 @@ impl<B: UsbBus> UsbClass<B> for DfuRuntime {
             return;
         }
 
-        match req.request {
-            request::DFU_DETACH => {
-                hs_probe_bsp::bootload::bootload();
+        match (req.request, self.state) {
+            (request::DFU_DETACH, State::AppIdle)
+                if DETACH_LOCKED.load(Ordering::Relaxed)
+                    && !DETACH_ARMED.load(Ordering::Relaxed) =>
+            {
+                warn!("Ignoring DFU detach, bootloader entry is locked");
+                xfer.reject().ok();
+            }
+            (request::DFU_DETACH, State::AppIdle) => {
+                // wValue is the longest time the host will take to reset the bus.
+                let timeout_ms = req.value.min(DETACH_TIMEOUT_MS) as u32;
+                self.state = State::AppDetach {
+                    start: DWT::cycle_count(),
+                    timeout: timeout_ms * (self.hclk / 1000),
+                };
+                info!("DFU detach, waiting {=u32} ms for bus reset", timeout_ms);
+                xfer.accept().ok();
             }
             _ => {
                 xfer.reject().ok();
@@ -0,0 +1,59 @@
+use super::endpoints;
+use super::names::{self, Name};
+use crate::app::Request;
+use crate::DIRTYJTAG_PACKET_SIZE;
+use usb_device::class_prelude::*;
+use usb_device::Result;
+
+/// Vendor-specific bulk interface carrying DirtyJTAG command packets.
+pub struct DirtyJtag<'a, B: UsbBus> {
+    interface: InterfaceNumber,
+    name: StringIndex,
+    read_ep: EndpointOut<'a, B>,
+    write_ep: EndpointIn<'a, B>,
+}
+
+impl<B: UsbBus> DirtyJtag<'_, B> {
+    pub fn new(alloc: &UsbBusAllocator<B>) -> DirtyJtag<B> {
+        DirtyJtag {
+            interface: alloc.interface(),
+            name: alloc.string(),
+            read_ep: endpoints::bulk(alloc, endpoints::DIRTYJTAG_OUT, DIRTYJTAG_PACKET_SIZE),
+            write_ep: endpoints::bulk(alloc, endpoints::DIRTYJTAG_IN, DIRTYJTAG_PACKET_SIZE),
+        }
+    }
+
+    pub fn process(&mut self) -> Option<Request> {
+        let mut buf = [0u8; DIRTYJTAG_PACKET_SIZE as usize];
+        match self.read_ep.read(&mut buf) {
+            Ok(size) if size > 0 => Some(Request::DirtyJTAGCommand((buf, size))),
+            _ => None,
+        }
+    }
+
+    pub fn write_packet(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() > self.write_ep.max_packet_size() as usize {
+            return Err(UsbError::BufferOverflow);
+        }
+        self.write_ep.write(data).map(|_| ())
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for DirtyJtag<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface_alt(self.interface, 0, 0xff, 0, 0, Some(self.name))?;
+
+        super::write_endpoint(writer, &self.read_ep)?;
+        super::write_endpoint(writer, &self.write_ep)?;
+
+        Ok(())
+    }
+
+    fn get_string(&self, index: StringIndex, _lang_id: u16) -> Option<&str> {
+        if index == self.name {
+            Some(names::get(Name::DirtyJtag))
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! Endpoint addresses of every interface, allocated explicitly so that adding
+//! an interface cannot silently move the existing endpoints or collide with them.
+//!
+//! The CDC-ACM class of the VCP allocates its own endpoints. It is created
+//! first, so it takes the lowest free ones, which are reserved here.
+//!
+//! The endpoints' packet sizes are also checked against the OTG_HS FIFO RAM,
+//! as the USB stack otherwise only finds it too small when allocating them at boot.
+
+#[cfg(not(feature = "hid-1024"))]
+use crate::{AVRISP_PACKET_SIZE, DIRTYJTAG_PACKET_SIZE, STREAM_PACKET_SIZE};
+use crate::{DAP1_PACKET_SIZE, DAP2_PACKET_SIZE, VCP_PACKET_SIZE};
+use usb_device::class_prelude::*;
+use usb_device::endpoint::{Endpoint, EndpointDirection, EndpointType};
+use usb_device::UsbDirection;
+
+/// Endpoints in each direction besides EP0, as for `ENDPOINT_COUNT` of the OTG_HS peripheral.
+const MAX_ENDPOINT: u8 = 8;
+
+/// Words of OTG_HS FIFO RAM, as for `FIFO_DEPTH_WORDS` of the OTG_HS peripheral.
+pub const FIFO_DEPTH_WORDS: usize = 1024;
+
+/// Packet size of EP0, also set in the device descriptor.
+const EP0_PACKET_SIZE: u16 = 64;
+
+/// Packet size of the CDC notification endpoint, at most this large.
+const VCP_COMM_PACKET_SIZE: u16 = 64;
+
+pub const VCP_COMM_IN: u8 = 0x81;
+pub const VCP_DATA_OUT: u8 = 0x01;
+pub const VCP_DATA_IN: u8 = 0x82;
+pub const DAP_V1_OUT: u8 = 0x02;
+pub const DAP_V1_IN: u8 = 0x83;
+pub const DAP_V2_TRACE_IN: u8 = 0x84;
+pub const DAP_V2_OUT: u8 = 0x03;
+pub const DAP_V2_IN: u8 = 0x85;
+#[cfg(not(feature = "hid-1024"))]
+pub const AVRISP_OUT: u8 = 0x04;
+#[cfg(not(feature = "hid-1024"))]
+pub const AVRISP_IN: u8 = 0x86;
+#[cfg(not(feature = "hid-1024"))]
+pub const DIRTYJTAG_OUT: u8 = 0x05;
+#[cfg(not(feature = "hid-1024"))]
+pub const DIRTYJTAG_IN: u8 = 0x87;
+#[cfg(not(feature = "hid-1024"))]
+pub const STREAM_OUT: u8 = 0x06;
+#[cfg(not(feature = "hid-1024"))]
+pub const STREAM_IN: u8 = 0x88;
+
+/// Every endpoint allocated, with its maximum packet size.
+const ALL: &[(u8, u16)] = &[
+    (VCP_COMM_IN, VCP_COMM_PACKET_SIZE),
+    (VCP_DATA_OUT, VCP_PACKET_SIZE),
+    (VCP_DATA_IN, VCP_PACKET_SIZE),
+    (DAP_V1_OUT, DAP1_PACKET_SIZE),
+    (DAP_V1_IN, DAP1_PACKET_SIZE),
+    (DAP_V2_TRACE_IN, DAP2_PACKET_SIZE),
+    (DAP_V2_OUT, DAP2_PACKET_SIZE),
+    (DAP_V2_IN, DAP2_PACKET_SIZE),
+    #[cfg(not(feature = "hid-1024"))]
+    (AVRISP_OUT, AVRISP_PACKET_SIZE),
+    #[cfg(not(feature = "hid-1024"))]
+    (AVRISP_IN, AVRISP_PACKET_SIZE),
+    #[cfg(not(feature = "hid-1024"))]
+    (DIRTYJTAG_OUT, DIRTYJTAG_PACKET_SIZE),
+    #[cfg(not(feature = "hid-1024"))]
+    (DIRTYJTAG_IN, DIRTYJTAG_PACKET_SIZE),
+    #[cfg(not(feature = "hid-1024"))]
+    (STREAM_OUT, STREAM_PACKET_SIZE),
+    #[cfg(not(feature = "hid-1024"))]
+    (STREAM_IN, STREAM_PACKET_SIZE),
+];
+
+/// Check every address is an endpoint the peripheral has, other than EP0, and is used once.
+const fn valid(eps: &[(u8, u16)]) -> bool {
+    let mut i = 0;
+    while i < eps.len() {
+        let number = eps[i].0 & 0x7F;
+        if number == 0 || number > MAX_ENDPOINT {
+            return false;
+        }
+        let mut j = i + 1;
+        while j < eps.len() {
+            if eps[i].0 == eps[j].0 {
+                return false;
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    true
+}
+
+const fn words(packet_size: u16) -> usize {
+    (packet_size as usize + 3) / 4
+}
+
+const fn tx_fifo_words(packet_size: u16) -> usize {
+    let size = words(packet_size);
+    if size < 16 {
+        16
+    } else {
+        size
+    }
+}
+
+/// Words of FIFO RAM the endpoints take, sized as synopsys-usb-otg does at high speed:
+/// a shared RX FIFO of the largest OUT packet plus 30 words, and for each IN endpoint,
+/// including EP0, a TX FIFO of its packet size, at least 16 words.
+const fn fifo_words(eps: &[(u8, u16)]) -> usize {
+    let mut largest_out = words(EP0_PACKET_SIZE);
+    let mut tx = tx_fifo_words(EP0_PACKET_SIZE);
+    let mut i = 0;
+    while i < eps.len() {
+        let (addr, packet_size) = eps[i];
+        if addr & 0x80 != 0 {
+            tx += tx_fifo_words(packet_size);
+        } else if words(packet_size) > largest_out {
+            largest_out = words(packet_size);
+        }
+        i += 1;
+    }
+    largest_out + 30 + tx
+}
+
+/// Words of FIFO RAM taken by the endpoints of this build.
+pub const FIFO_WORDS: usize = fifo_words(ALL);
+
+const _: () = assert!(
+    valid(ALL),
+    "USB endpoint addresses collide or are out of range"
+);
+
+const _: () = assert!(
+    FIFO_WORDS <= FIFO_DEPTH_WORDS,
+    "USB endpoints need more FIFO RAM than OTG_HS has"
+);
+
+fn allocate<B: UsbBus, D: EndpointDirection>(
+    alloc: &UsbBusAllocator<B>,
+    addr: u8,
+    ep_type: EndpointType,
+    max_packet_size: u16,
+    interval: u8,
+) -> Endpoint<'_, B, D> {
+    assert_eq!(addr & 0x80 != 0, D::DIRECTION == UsbDirection::In);
+    alloc
+        .alloc(Some(addr.into()), ep_type, max_packet_size, interval)
+        .expect("alloc_ep failed")
+}
+
+/// Allocate the bulk endpoint at `addr`, which must be listed above.
+pub fn bulk<B: UsbBus, D: EndpointDirection>(
+    alloc: &UsbBusAllocator<B>,
+    addr: u8,
+    max_packet_size: u16,
+) -> Endpoint<'_, B, D> {
+    allocate(alloc, addr, EndpointType::Bulk, max_packet_size, 0)
+}
+
+/// Allocate the interrupt endpoint at `addr`, which must be listed above.
+pub fn interrupt<B: UsbBus, D: EndpointDirection>(
+    alloc: &UsbBusAllocator<B>,
+    addr: u8,
+    max_packet_size: u16,
+    interval: u8,
+) -> Endpoint<'_, B, D> {
+    allocate(
+        alloc,
+        addr,
+        EndpointType::Interrupt,
+        max_packet_size,
+        interval,
+    )
+}
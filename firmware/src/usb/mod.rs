@@ -1,23 +1,58 @@
 use crate::app::Request;
 use crate::bsp::cortex_m;
 use crate::bsp::stm32ral::{otg_hs_device, otg_hs_global, otg_hs_pwrclk, usbphyc};
-use crate::VCP_PACKET_SIZE;
-use hs_probe_bsp::otg_hs::{UsbBus, UsbBusType};
+use crate::vcp::VcpConfig;
+use crate::{DAP1_PACKET_SIZE, DAP2_PACKET_SIZE, VCP_PACKET_SIZE};
+use hs_probe_bsp::otg_hs::{self, Speed, UsbBus, UsbBusType};
 use hs_probe_bsp::rcc::Clocks;
 use usb_device::bus::UsbBusAllocator;
+use usb_device::descriptor::{descriptor_type, DescriptorWriter};
+use usb_device::endpoint::{Endpoint, EndpointDirection};
 use usb_device::prelude::*;
-use usbd_serial::{LineCoding, SerialPort};
+use usbd_serial::SerialPort;
 
+#[cfg(not(feature = "hid-1024"))]
+mod avrisp;
+mod cdc_events;
 mod dap_v1;
 mod dap_v2;
+mod device_qualifier;
 mod dfu;
+#[cfg(not(feature = "hid-1024"))]
+mod dirtyjtag;
+mod endpoints;
+pub mod names;
+mod queue;
+pub mod stats;
+#[cfg(not(feature = "hid-1024"))]
+mod stream;
+mod test_mode;
 mod winusb;
 
+#[cfg(not(feature = "hid-1024"))]
+use avrisp::AvrIsp;
+use cdc_events::CdcEvents;
 use dap_v1::CmsisDapV1;
 use dap_v2::CmsisDapV2;
+use device_qualifier::DeviceQualifier;
 use dfu::DfuRuntime;
+#[cfg(not(feature = "hid-1024"))]
+use dirtyjtag::DirtyJtag;
+use names::Name;
+use queue::{RequestQueue, ResponseBuffer};
+#[cfg(not(feature = "hid-1024"))]
+use stream::DataStream;
+use test_mode::TestMode;
 use winusb::MicrosoftDescriptors;
 
+pub use dfu::{arm_detach, set_detach_lock};
+pub use endpoints::{FIFO_DEPTH_WORDS, FIFO_WORDS};
+
+pub type Dap1Requests = RequestQueue<{ DAP1_PACKET_SIZE as usize }>;
+pub type Dap2Requests = RequestQueue<{ DAP2_PACKET_SIZE as usize }>;
+pub type Dap1Response = ResponseBuffer<{ DAP1_PACKET_SIZE as usize }>;
+pub type Dap2Response = ResponseBuffer<{ DAP2_PACKET_SIZE as usize }>;
+
 struct UninitializedUSB {
     phy: usbphyc::Instance,
     global: otg_hs_global::Instance,
@@ -25,44 +60,94 @@ struct UninitializedUSB {
     pwrclk: otg_hs_pwrclk::Instance,
 }
 
-struct InitializedUSB {
+pub struct InitializedUSB {
     device: UsbDevice<'static, UsbBusType>,
     device_state: UsbDeviceState,
     winusb: MicrosoftDescriptors,
+    device_qualifier: DeviceQualifier,
     dap_v1: CmsisDapV1<'static, UsbBusType>,
     dap_v2: CmsisDapV2<'static, UsbBusType>,
     serial: SerialPort<'static, UsbBusType>,
+    cdc_events: CdcEvents,
     dfu: DfuRuntime,
+    #[cfg(not(feature = "hid-1024"))]
+    avrisp: AvrIsp<'static, UsbBusType>,
+    #[cfg(not(feature = "hid-1024"))]
+    dirtyjtag: DirtyJtag<'static, UsbBusType>,
+    #[cfg(not(feature = "hid-1024"))]
+    stream: DataStream<'static, UsbBusType>,
+    test_mode: TestMode,
+    /// CDC packet read after the last VCP transmission completed, not yet processed.
+    vcp_packet: Option<([u8; VCP_PACKET_SIZE as usize], usize)>,
 }
 
-#[allow(clippy::large_enum_variant)]
 enum State {
     Uninitialized(UninitializedUSB),
-    Initialized(InitializedUSB),
+    Initialized,
     Initializing,
 }
 
-impl State {
-    pub fn as_initialized(&self) -> &InitializedUSB {
-        if let State::Initialized(initialized) = self {
-            initialized
-        } else {
-            panic!("USB is not initialized yet");
+static mut USB_BUS: Option<UsbBusAllocator<UsbBusType>> = None;
+static mut USB_STATE: Option<InitializedUSB> = None;
+#[link_section = ".sram2"]
+static DAP1_RESPONSE: Dap1Response = ResponseBuffer::new();
+#[link_section = ".sram2"]
+static DAP2_RESPONSE: Dap2Response = ResponseBuffer::new();
+static DAP1_REQUESTS: Dap1Requests = RequestQueue::new(&DAP1_RESPONSE);
+static DAP2_REQUESTS: Dap2Requests = RequestQueue::new(&DAP2_RESPONSE);
+
+/// Run `f` on the initialised USB stack with interrupts disabled,
+/// so that it cannot be preempted by the USB interrupt handler.
+fn with_usb<R>(f: impl FnOnce(&mut InitializedUSB) -> R) -> R {
+    cortex_m::interrupt::free(|_| {
+        let usb = unsafe { USB_STATE.as_mut() }.expect("USB is not initialized yet");
+        f(usb)
+    })
+}
+
+impl InitializedUSB {
+    fn poll(&mut self) -> bool {
+        self.device.poll(&mut [
+            &mut self.winusb,
+            &mut self.device_qualifier,
+            &mut self.cdc_events,
+            &mut self.serial,
+            &mut self.dap_v1,
+            &mut self.dap_v2,
+            &mut self.dfu,
+            #[cfg(not(feature = "hid-1024"))]
+            &mut self.avrisp,
+            #[cfg(not(feature = "hid-1024"))]
+            &mut self.dirtyjtag,
+            #[cfg(not(feature = "hid-1024"))]
+            &mut self.stream,
+            &mut self.test_mode,
+        ])
+    }
+
+    /// Grab the current UART parameters from the CDC-ACM stack
+    fn vcp_config(&self) -> VcpConfig {
+        let line_coding = self.serial.line_coding();
+        VcpConfig {
+            stop_bits: line_coding.stop_bits(),
+            data_bits: line_coding.data_bits(),
+            parity_type: line_coding.parity_type(),
+            data_rate: line_coding.data_rate(),
         }
     }
 
-    pub fn as_initialized_mut(&mut self) -> &mut InitializedUSB {
-        if let State::Initialized(initialized) = self {
-            initialized
-        } else {
-            panic!("USB is not initialized yet");
+    /// Read the next CDC packet from the host, unless one is already waiting.
+    fn read_vcp(&mut self) {
+        if self.vcp_packet.is_none() {
+            let mut buf = [0; VCP_PACKET_SIZE as usize];
+            // discard error?
+            if let Ok(x) = self.serial.read(&mut buf) {
+                self.vcp_packet = Some((buf, x));
+            }
         }
     }
 }
 
-static mut EP_MEMORY: [u32; 4096] = [0; 4096];
-static mut USB_BUS: Option<UsbBusAllocator<UsbBusType>> = None;
-
 /// USB stack interface
 #[allow(clippy::upper_case_acronyms)]
 pub struct USB {
@@ -89,7 +174,9 @@ impl USB {
     }
 
     /// Initialise the USB peripheral ready to start processing packets
-    pub fn setup(&mut self, clocks: &Clocks, serial_string: &'static str) {
+    ///
+    /// `label` is appended to the product and interface names, unless empty.
+    pub fn setup(&mut self, clocks: &Clocks, serial_string: &'static str, label: &[u8]) {
         let state = core::mem::replace(&mut self.state, State::Initializing);
         if let State::Uninitialized(usb) = state {
             cortex_m::interrupt::free(|_| unsafe {
@@ -99,24 +186,36 @@ impl USB {
                     usb_device: usb.device,
                     usb_pwrclk: usb.pwrclk,
                     hclk: clocks.hclk(),
+                    hse: clocks.hse(),
                 };
 
-                let usb_bus = UsbBus::new(usb, &mut EP_MEMORY);
+                names::build(label);
+
+                let usb_bus = UsbBus::new(usb, &mut crate::buffers::EP_MEMORY);
                 USB_BUS = Some(usb_bus);
                 let usb_bus = USB_BUS.as_ref().unwrap();
 
                 let winusb = MicrosoftDescriptors;
 
-                // Order of these calls is important, if the interface numbers for CmsisDapV2 or DfuRuntime change,
-                // definitions in winusb.rs (DAP_V2_INTERFACE, DFU_INTERFACE) have to be adapted!
+                // SerialPort must be created first, to take the endpoints reserved for it in
+                // endpoints.rs. Order of these calls is also important, if the interface numbers for CmsisDapV2, DfuRuntime,
+                // AvrIsp, DirtyJtag or DataStream change, definitions in winusb.rs (DAP_V2_INTERFACE,
+                // DFU_INTERFACE, AVRISP_INTERFACE, DIRTYJTAG_INTERFACE, STREAM_INTERFACE) have to be adapted!
                 let serial = SerialPort::new(usb_bus);
-                let dap_v1 = CmsisDapV1::new(usb_bus);
-                let dap_v2 = CmsisDapV2::new(usb_bus);
-                let dfu = DfuRuntime::new(usb_bus);
+                let dap_v1 = CmsisDapV1::new(usb_bus, &DAP1_REQUESTS);
+                let dap_v2 = CmsisDapV2::new(usb_bus, &DAP2_REQUESTS);
+                let dfu = DfuRuntime::new(usb_bus, clocks.hclk());
+                #[cfg(not(feature = "hid-1024"))]
+                let avrisp = AvrIsp::new(usb_bus);
+                #[cfg(not(feature = "hid-1024"))]
+                let dirtyjtag = DirtyJtag::new(usb_bus);
+                #[cfg(not(feature = "hid-1024"))]
+                let stream = DataStream::new(usb_bus);
+                let test_mode = TestMode::new(clocks.hclk());
 
                 let device = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x1209, 0x4853))
                     .manufacturer("Probe-rs development team")
-                    .product("HS-Probe with CMSIS-DAP Support")
+                    .product(names::get(Name::Product))
                     .serial_number(serial_string)
                     .composite_with_iads()
                     .max_packet_size_0(64)
@@ -129,44 +228,85 @@ impl USB {
                     device,
                     device_state,
                     winusb,
+                    device_qualifier: DeviceQualifier,
                     dap_v1,
                     dap_v2,
                     serial,
+                    cdc_events: CdcEvents::default(),
                     dfu,
+                    #[cfg(not(feature = "hid-1024"))]
+                    avrisp,
+                    #[cfg(not(feature = "hid-1024"))]
+                    dirtyjtag,
+                    #[cfg(not(feature = "hid-1024"))]
+                    stream,
+                    test_mode,
+                    vcp_packet: None,
                 };
-                self.state = State::Initialized(usb)
+                USB_STATE = Some(usb);
+                self.state = State::Initialized;
             });
         } else {
             panic!("Invalid state");
         }
     }
 
-    /// Process a pending USB interrupt.
+    /// Handle the USB interrupt.
     ///
-    /// Call this function when a USB interrupt occurs.
+    /// Call this function from the OTG_HS interrupt handler. DAPv2 requests
+    /// are received into a queue here, so the OUT endpoint is re-armed while
+    /// the previous request is still being processed.
+    pub fn on_interrupt() {
+        // Safety: the main context only accesses the USB stack through
+        // `with_usb`, which disables interrupts.
+        if let Some(usb) = unsafe { USB_STATE.as_mut() } {
+            usb.poll();
+            usb.dap_v2.receive();
+        }
+    }
+
+    /// Handle the VCP transmit DMA completing.
+    ///
+    /// Call this function from the USART2 TX DMA interrupt handler. The next
+    /// CDC packet is read here, so the OUT endpoint is re-armed as soon as the
+    /// previous packet has been sent, rather than at the next main loop poll.
+    pub fn on_vcp_tx_complete() {
+        // Safety: the main context only accesses the USB stack through
+        // `with_usb`, which disables interrupts.
+        if let Some(usb) = unsafe { USB_STATE.as_mut() } {
+            usb.read_vcp();
+        }
+    }
+
+    /// Process pending USB events.
     ///
     /// Returns Some(Request) if a new request has been received
     /// from the host.
-    ///
-    /// This function will clear the interrupt bits of all interrupts
-    /// it processes; if any are unprocessed the USB interrupt keeps
-    /// triggering until all are processed.
     pub fn interrupt(&mut self, vcp_idle: bool) -> Option<Request> {
-        let usb = self.state.as_initialized_mut();
-        if usb.device.poll(&mut [
-            &mut usb.winusb,
-            &mut usb.serial,
-            &mut usb.dap_v1,
-            &mut usb.dap_v2,
-            &mut usb.dfu,
-        ]) {
+        with_usb(|usb| {
+            usb.poll();
+
+            // Events may already have been polled in the interrupt handler,
+            // so check the state and all classes for new data every time.
             let old_state = usb.device_state;
             let new_state = usb.device.state();
             usb.device_state = new_state;
+            if old_state != new_state {
+                info!("USB state changed to {=str}", state_name(new_state));
+            }
+            if old_state == UsbDeviceState::Suspend && new_state == UsbDeviceState::Configured {
+                return Some(Request::Resume);
+            }
             if (old_state != new_state) && (new_state != UsbDeviceState::Configured) {
                 return Some(Request::Suspend);
             }
 
+            #[cfg(not(feature = "hid-1024"))]
+            if new_state == UsbDeviceState::Configured {
+                usb.stream.flush();
+                usb.stream.process();
+            }
+
             let r = usb.dap_v1.process();
             if r.is_some() {
                 return r;
@@ -177,58 +317,161 @@ impl USB {
                 return r;
             }
 
+            #[cfg(not(feature = "hid-1024"))]
+            {
+                let r = usb.avrisp.process();
+                if r.is_some() {
+                    return r;
+                }
+
+                let r = usb.dirtyjtag.process();
+                if r.is_some() {
+                    return r;
+                }
+            }
+
+            // Report changes before reading more data, which the host sends
+            // after changing the line coding.
+            if usb.cdc_events.take_line_coding() {
+                return Some(Request::VCPLineCoding(usb.vcp_config()));
+            }
+            if usb.cdc_events.take_control_lines() {
+                return Some(Request::VCPControlLines {
+                    dtr: usb.serial.dtr(),
+                    rts: usb.serial.rts(),
+                });
+            }
+
             if vcp_idle {
-                let mut buf = [0; VCP_PACKET_SIZE as usize];
-                let serialdata = usb.serial.read(&mut buf);
-                match serialdata {
-                    Ok(x) => {
-                        return Some(Request::VCPPacket((buf, x)));
-                    }
-                    // discard error?
-                    Err(_e) => (),
+                usb.read_vcp();
+                if let Some(packet) = usb.vcp_packet.take() {
+                    return Some(Request::VCPPacket(packet));
                 }
             }
-        }
-        None
+            None
+        })
+    }
+
+    /// Get the last DAPv1 request and the buffer to write its response into
+    pub fn dap1_buffers(&mut self) -> (&[u8], &mut [u8]) {
+        // Safety: only the main context holds `&mut USB`, and the slices
+        // borrow it until the request is released in `dap1_reply`.
+        unsafe { DAP1_REQUESTS.front() }.expect("No DAPv1 request pending")
     }
 
-    /// Transmit a DAP report back over the DAPv1 HID interface
-    pub fn dap1_reply(&mut self, data: &[u8]) {
-        let usb = self.state.as_initialized_mut();
-        usb.dap_v1
-            .write_packet(data)
-            .expect("DAPv1 EP write failed");
+    /// Transmit `len` bytes of the response buffer, if any, back over the DAPv1 HID interface
+    /// and release the request buffer
+    pub fn dap1_reply(&mut self, len: usize) {
+        with_usb(|usb| {
+            usb.dap_v1
+                .write_response(len)
+                .expect("DAPv1 EP write failed");
+        })
     }
 
-    /// Transmit a DAP report back over the DAPv2 bulk interface
-    pub fn dap2_reply(&mut self, data: &[u8]) {
-        let usb = self.state.as_initialized_mut();
-        usb.dap_v2
-            .write_packet(data)
-            .expect("DAPv2 EP write failed");
+    /// Get the last DAPv2 request and the buffer to write its response into
+    pub fn dap2_buffers(&mut self) -> (&[u8], &mut [u8]) {
+        // Safety: only the main context holds `&mut USB`, and the slices
+        // borrow it until the request is released in `dap2_reply`.
+        unsafe { DAP2_REQUESTS.front() }.expect("No DAPv2 request pending")
+    }
+
+    /// Transmit `len` bytes of the response buffer, if any, back over the DAPv2 bulk interface
+    /// and release the request buffer
+    pub fn dap2_reply(&mut self, len: usize) {
+        with_usb(|usb| {
+            usb.dap_v2
+                .write_response(len)
+                .expect("DAPv2 EP write failed");
+        })
+    }
+
+    /// Transmit an STK500v2 response back over the AVRISP bulk interface
+    #[cfg(not(feature = "hid-1024"))]
+    pub fn avrisp_reply(&mut self, data: &[u8]) {
+        with_usb(|usb| {
+            usb.avrisp
+                .write_packet(data)
+                .expect("AVRISP EP write failed");
+        })
+    }
+
+    /// Transmit a DirtyJTAG response back over the DirtyJTAG bulk interface
+    #[cfg(not(feature = "hid-1024"))]
+    pub fn dirtyjtag_reply(&mut self, data: &[u8]) {
+        with_usb(|usb| {
+            usb.dirtyjtag
+                .write_packet(data)
+                .expect("DirtyJTAG EP write failed");
+        })
     }
 
     /// Check if SWO endpoint is currently busy transmitting data
     pub fn dap2_swo_is_busy(&self) -> bool {
-        let usb = self.state.as_initialized();
-        usb.dap_v2.trace_busy()
+        with_usb(|usb| usb.dap_v2.trace_busy())
     }
 
     /// Transmit SWO streaming data back over the DAPv2 bulk interface
     pub fn dap2_stream_swo(&mut self, data: &[u8]) {
-        let usb = self.state.as_initialized_mut();
-        usb.dap_v2.trace_write(data).expect("trace EP write failed");
+        with_usb(|usb| {
+            usb.dap_v2.trace_write(data).expect("trace EP write failed");
+        })
     }
 
-    /// Grab the current LineCoding (UART parameters) from the CDC-ACM stack
-    pub fn serial_line_encoding(&self) -> &LineCoding {
-        let usb = self.state.as_initialized();
-        usb.serial.line_coding()
+    /// Return UART data to host trough USB, returning the number of bytes accepted.
+    ///
+    /// Fewer bytes are accepted when the host is not reading the serial port
+    /// and its buffer is full.
+    pub fn serial_return(&mut self, data: &[u8]) -> usize {
+        with_usb(|usb| usb.serial.write(data).unwrap_or(0))
     }
+}
+
+/// Largest bulk and interrupt packet size allowed at full speed.
+const FULL_SPEED_PACKET_SIZE: u16 = 64;
+
+/// Limit a maximum packet size chosen for high speed to what is allowed
+/// at the bus speed negotiated with the host.
+///
+/// A hub or cable problem can make the probe enumerate at full speed.
+pub fn max_packet_size(size: u16) -> u16 {
+    match otg_hs::enumerated_speed() {
+        Speed::High => size,
+        Speed::Full => size.min(FULL_SPEED_PACKET_SIZE),
+    }
+}
+
+/// Bus speed negotiated with the host.
+pub fn speed() -> Speed {
+    otg_hs::enumerated_speed()
+}
+
+/// Write the descriptor for `ep`, with its maximum packet size limited by `max_packet_size`.
+///
+/// Descriptors are requested after the reset which negotiates the bus speed,
+/// so they always match the current speed.
+fn write_endpoint<B: usb_device::bus::UsbBus, D: EndpointDirection>(
+    writer: &mut DescriptorWriter,
+    ep: &Endpoint<B, D>,
+) -> usb_device::Result<()> {
+    let mps = max_packet_size(ep.max_packet_size());
+    writer.write(
+        descriptor_type::ENDPOINT,
+        &[
+            ep.address().into(),
+            ep.ep_type() as u8,
+            mps as u8,
+            (mps >> 8) as u8,
+            ep.interval(),
+        ],
+    )
+}
 
-    /// Return UART data to host trough USB
-    pub fn serial_return(&mut self, data: &[u8]) {
-        let usb = self.state.as_initialized_mut();
-        usb.serial.write(data).expect("Serial EP write failed");
+fn state_name(state: UsbDeviceState) -> &'static str {
+    match state {
+        UsbDeviceState::Default => "Default",
+        UsbDeviceState::Addressed => "Addressed",
+        UsbDeviceState::Configured => "Configured",
+        UsbDeviceState::Suspend => "Suspend",
     }
 }
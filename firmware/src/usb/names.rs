@@ -0,0 +1,72 @@
+//! Product and interface names, with an optional label appended so that
+//! several probes connected to one host can be told apart.
+//!
+//! The names are built once when the USB stack is set up, as the string
+//! descriptors must live as long as the device.
+
+/// Longest label, keeping every name well within a string descriptor.
+pub const MAX_LABEL_LEN: usize = 32;
+
+const MAX_NAME_LEN: usize = 72;
+
+#[derive(Copy, Clone)]
+pub enum Name {
+    Product,
+    DapV1,
+    DapV2,
+    Dfu,
+    AvrIsp,
+    DirtyJtag,
+    Stream,
+}
+
+/// Names without a label, in the order of `Name`. The CMSIS-DAP interface
+/// names must keep containing "CMSIS-DAP" for host tools to find them.
+const BASE_NAMES: [&str; 7] = [
+    "HS-Probe with CMSIS-DAP Support",
+    "HS-Probe CMSIS-DAP v1 Interface",
+    "HS-Probe CMSIS-DAP v2 Interface",
+    "HS-Probe DFU Interface",
+    "HS-Probe AVRISP Interface",
+    "HS-Probe DirtyJTAG Interface",
+    "HS-Probe Data Stream Interface",
+];
+
+/// Each string descriptor's name, and its length.
+pub type NameTable = [([u8; MAX_NAME_LEN], usize); 7];
+
+static mut NAMES: NameTable = [([0; MAX_NAME_LEN], 0); 7];
+
+/// Check a label only holds printable ASCII and is short enough.
+pub fn valid_label(label: &[u8]) -> bool {
+    label.len() <= MAX_LABEL_LEN && label.iter().all(|b| (0x20..0x7F).contains(b))
+}
+
+/// Build the names, appending `label` in brackets unless it is empty or invalid.
+///
+/// Safety: must only be called before the USB stack is created,
+/// as it overwrites names returned by `get`.
+pub unsafe fn build(label: &[u8]) {
+    let label = if valid_label(label) { label } else { &[] };
+    for (base, (buf, len)) in BASE_NAMES.iter().zip(NAMES.iter_mut()) {
+        let parts: [&[u8]; 4] = [base.as_bytes(), b" (", label, b")"];
+        let parts = if label.is_empty() {
+            &parts[..1]
+        } else {
+            &parts[..]
+        };
+        let mut n = 0;
+        for part in parts {
+            buf[n..n + part.len()].copy_from_slice(part);
+            n += part.len();
+        }
+        *len = n;
+    }
+}
+
+/// Get a name, as built by the last call to `build`.
+pub fn get(name: Name) -> &'static str {
+    let (buf, len) = unsafe { &NAMES[name as usize] };
+    // Only ASCII is ever written, so this cannot fail.
+    core::str::from_utf8(&buf[..*len]).unwrap_or(BASE_NAMES[name as usize])
+}
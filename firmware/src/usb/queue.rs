@@ -0,0 +1,113 @@
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of requests buffered, so one can be received while another is processed.
+pub const QUEUE_LEN: usize = 2;
+
+/// Buffer responses are written into, only accessed by the main context.
+///
+/// Responses can be written by DMA, such as TDO captured by DAP_JTAG_Sequence,
+/// so the buffer must be placed in the non-cacheable `.sram2` section.
+pub struct ResponseBuffer<const N: usize>(UnsafeCell<[u8; N]>);
+
+// Safety: only accessed by the main context, through the queue's `front` and `response`.
+unsafe impl<const N: usize> Sync for ResponseBuffer<N> {}
+
+impl<const N: usize> ResponseBuffer<N> {
+    pub const fn new() -> Self {
+        ResponseBuffer(UnsafeCell::new([0; N]))
+    }
+}
+
+/// Single producer, single consumer queue of USB request packets with a response buffer.
+///
+/// Requests are written by the USB stack, possibly from the USB interrupt,
+/// and processed in order by the main context, which also owns the response
+/// buffer. A request slot is not reused until its response has been sent.
+pub struct RequestQueue<const N: usize> {
+    requests: [UnsafeCell<[u8; N]>; QUEUE_LEN],
+    lens: [AtomicUsize; QUEUE_LEN],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    response: &'static ResponseBuffer<N>,
+}
+
+// Safety: each request slot is only accessed by the producer until `head`
+// is advanced past it, and then only by the consumer until `tail` is.
+unsafe impl<const N: usize> Sync for RequestQueue<N> {}
+
+impl<const N: usize> RequestQueue<N> {
+    pub const fn new(response: &'static ResponseBuffer<N>) -> Self {
+        RequestQueue {
+            requests: [UnsafeCell::new([0; N]), UnsafeCell::new([0; N])],
+            lens: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            response,
+        }
+    }
+
+    /// Check if there is an unprocessed request.
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire) == self.tail.load(Ordering::Acquire)
+    }
+
+    /// Check if all request slots are waiting to be processed.
+    pub fn is_full(&self) -> bool {
+        let head = self.head.load(Ordering::Acquire);
+        head.wrapping_sub(self.tail.load(Ordering::Acquire)) >= QUEUE_LEN
+    }
+
+    /// Fill the next free request slot using `read`, which returns the number of
+    /// bytes written or None if no packet was available.
+    ///
+    /// Returns false without calling `read` if the queue is full.
+    ///
+    /// Must only be called from a single context at a time.
+    pub fn push_with(&self, read: impl FnOnce(&mut [u8]) -> Option<usize>) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        if head.wrapping_sub(self.tail.load(Ordering::Acquire)) >= QUEUE_LEN {
+            return false;
+        }
+        let slot = head % QUEUE_LEN;
+        let buf = unsafe { &mut *self.requests[slot].get() };
+        match read(buf) {
+            Some(len) => {
+                self.lens[slot].store(len, Ordering::Relaxed);
+                self.head.store(head.wrapping_add(1), Ordering::Release);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Get the oldest request and the response buffer.
+    ///
+    /// Must only be called from the main context, and the returned
+    /// slices must be dropped before the request is popped.
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn front(&self) -> Option<(&[u8], &mut [u8])> {
+        if self.is_empty() {
+            return None;
+        }
+        let slot = self.tail.load(Ordering::Relaxed) % QUEUE_LEN;
+        let len = self.lens[slot].load(Ordering::Relaxed);
+        let request = &(*self.requests[slot].get())[..len];
+        Some((request, &mut *self.response.0.get()))
+    }
+
+    /// Get the response buffer.
+    ///
+    /// Must only be called from the main context.
+    pub unsafe fn response(&self) -> &[u8] {
+        &*self.response.0.get()
+    }
+
+    /// Release the oldest request slot so it can receive a new request.
+    pub fn pop(&self) {
+        if !self.is_empty() {
+            let tail = self.tail.load(Ordering::Relaxed);
+            self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Counters for DAP traffic over USB, updated from both the USB interrupt and main context.
+pub struct UsbStats {
+    /// DAP request packets received.
+    pub rx_packets: AtomicU32,
+    /// DAP request bytes received.
+    pub rx_bytes: AtomicU32,
+    /// DAP response packets sent.
+    pub tx_packets: AtomicU32,
+    /// DAP response bytes sent.
+    pub tx_bytes: AtomicU32,
+    /// USB events which found a DAP request queue full, so further
+    /// packets from the host were NAKed until a request was processed.
+    pub queue_full: AtomicU32,
+    /// DAP commands processed.
+    pub commands: AtomicU32,
+    /// Total CPU cycles spent processing DAP commands.
+    pub command_cycles: AtomicU32,
+    /// Longest time spent processing one DAP command, in CPU cycles.
+    pub max_command_cycles: AtomicU32,
+}
+
+pub static STATS: UsbStats = UsbStats::new();
+
+impl UsbStats {
+    const fn new() -> Self {
+        UsbStats {
+            rx_packets: AtomicU32::new(0),
+            rx_bytes: AtomicU32::new(0),
+            tx_packets: AtomicU32::new(0),
+            tx_bytes: AtomicU32::new(0),
+            queue_full: AtomicU32::new(0),
+            commands: AtomicU32::new(0),
+            command_cycles: AtomicU32::new(0),
+            max_command_cycles: AtomicU32::new(0),
+        }
+    }
+
+    fn counters(&self) -> [&AtomicU32; 8] {
+        [
+            &self.rx_packets,
+            &self.rx_bytes,
+            &self.tx_packets,
+            &self.tx_bytes,
+            &self.queue_full,
+            &self.commands,
+            &self.command_cycles,
+            &self.max_command_cycles,
+        ]
+    }
+
+    pub(super) fn received(&self, len: usize) {
+        self.rx_packets.fetch_add(1, Ordering::Relaxed);
+        self.rx_bytes.fetch_add(len as u32, Ordering::Relaxed);
+    }
+
+    pub(super) fn queue_was_full(&self) {
+        self.queue_full.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn sent(&self, len: usize) {
+        self.tx_packets.fetch_add(1, Ordering::Relaxed);
+        self.tx_bytes.fetch_add(len as u32, Ordering::Relaxed);
+    }
+
+    /// Record a DAP command which took `cycles` CPU cycles to process.
+    pub fn command(&self, cycles: u32) {
+        self.commands.fetch_add(1, Ordering::Relaxed);
+        self.command_cycles.fetch_add(cycles, Ordering::Relaxed);
+        self.max_command_cycles.fetch_max(cycles, Ordering::Relaxed);
+    }
+
+    /// Current counter values, in the order of the fields.
+    pub fn values(&self) -> [u32; 8] {
+        let mut values = [0; 8];
+        for (value, counter) in values.iter_mut().zip(self.counters().iter()) {
+            *value = counter.load(Ordering::Relaxed);
+        }
+        values
+    }
+
+    pub fn reset(&self) {
+        for counter in self.counters().iter() {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
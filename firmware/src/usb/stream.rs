@@ -0,0 +1,105 @@
+use super::endpoints;
+use super::names::{self, Name};
+use crate::STREAM_PACKET_SIZE;
+use core::convert::TryFrom;
+use num_enum::TryFromPrimitive;
+use usb_device::class_prelude::*;
+use usb_device::Result;
+
+/// Bytes of the header before each frame: the stream ID, then the u16 payload length.
+const HEADER_LEN: usize = 3;
+
+/// Streams carried by the interface, each identified by the first byte of its frames.
+#[derive(Copy, Clone, TryFromPrimitive)]
+#[repr(u8)]
+pub enum StreamId {
+    /// The firmware's defmt log, sent to the host.
+    Log = 0x01,
+}
+
+/// Vendor-specific bulk interface shared by all high-rate data streams,
+/// so that new streams do not each need endpoints of their own.
+///
+/// Each packet holds one or more whole frames, each a header followed by
+/// the payload. A frame never spans two packets.
+pub struct DataStream<'a, B: UsbBus> {
+    interface: InterfaceNumber,
+    name: StringIndex,
+    read_ep: EndpointOut<'a, B>,
+    write_ep: EndpointIn<'a, B>,
+}
+
+impl<B: UsbBus> DataStream<'_, B> {
+    pub fn new(alloc: &UsbBusAllocator<B>) -> DataStream<B> {
+        DataStream {
+            interface: alloc.interface(),
+            name: alloc.string(),
+            read_ep: endpoints::bulk(alloc, endpoints::STREAM_OUT, STREAM_PACKET_SIZE),
+            write_ep: endpoints::bulk(alloc, endpoints::STREAM_IN, STREAM_PACKET_SIZE),
+        }
+    }
+
+    /// Send the next frame of buffered log data, if the endpoint is free.
+    ///
+    /// Data is only removed from the log buffer once it has been accepted,
+    /// so nothing is lost while the host is not reading the endpoint.
+    pub fn flush(&mut self) {
+        let write_ep = &self.write_ep;
+        crate::log::drain(|data| {
+            let mut packet = [0; STREAM_PACKET_SIZE as usize];
+            let mps = super::max_packet_size(STREAM_PACKET_SIZE) as usize;
+            let len = data.len().min(mps - HEADER_LEN);
+            packet[0] = StreamId::Log as u8;
+            packet[1..HEADER_LEN].copy_from_slice(&(len as u16).to_le_bytes());
+            packet[HEADER_LEN..HEADER_LEN + len].copy_from_slice(&data[..len]);
+            match write_ep.write(&packet[..HEADER_LEN + len]) {
+                Ok(_) => len,
+                Err(_) => 0,
+            }
+        });
+    }
+
+    /// Read a packet from the host and pass each frame to its stream.
+    pub fn process(&mut self) {
+        let mut packet = [0; STREAM_PACKET_SIZE as usize];
+        let size = match self.read_ep.read(&mut packet) {
+            Ok(size) => size,
+            Err(_) => return,
+        };
+
+        let mut frames = &packet[..size];
+        while frames.len() >= HEADER_LEN {
+            let len = u16::from_le_bytes([frames[1], frames[2]]) as usize;
+            if frames.len() < HEADER_LEN + len {
+                warn!("Data stream frame longer than its packet");
+                return;
+            }
+            match StreamId::try_from(frames[0]) {
+                // The log is only sent to the host.
+                Ok(StreamId::Log) | Err(_) => {
+                    warn!("Dropping frame for stream {=u8:#x}", frames[0]);
+                }
+            }
+            frames = &frames[HEADER_LEN + len..];
+        }
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for DataStream<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface_alt(self.interface, 0, 0xff, 0, 0, Some(self.name))?;
+
+        super::write_endpoint(writer, &self.read_ep)?;
+        super::write_endpoint(writer, &self.write_ep)?;
+
+        Ok(())
+    }
+
+    fn get_string(&self, index: StringIndex, _lang_id: u16) -> Option<&str> {
+        if index == self.name {
+            Some(names::get(Name::Stream))
+        } else {
+            None
+        }
+    }
+}
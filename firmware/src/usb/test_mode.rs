@@ -0,0 +1,62 @@
+use crate::bsp::cortex_m::peripheral::DWT;
+use hs_probe_bsp::otg_hs;
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, Request, RequestType};
+
+/// Feature selector of the TEST_MODE device feature.
+const TEST_MODE: u16 = 2;
+
+/// Test selectors a device must support, from USB 2.0 section 9.4.9.
+/// Test_Force_Enable (5) only applies to hubs.
+const TEST_J: u8 = 1;
+const TEST_PACKET: u8 = 4;
+
+/// Handles SET_FEATURE(TEST_MODE), for USB-IF high-speed electrical compliance testing.
+///
+/// The test mode is entered once the status stage of the request has had time
+/// to complete, and is only left by power cycling the probe.
+pub struct TestMode {
+    hclk: u32,
+    pending: Option<(u8, u32)>,
+}
+
+impl TestMode {
+    pub fn new(hclk: u32) -> Self {
+        TestMode {
+            hclk,
+            pending: None,
+        }
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for TestMode {
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = xfer.request();
+        if !(req.request_type == RequestType::Standard
+            && req.recipient == Recipient::Device
+            && req.request == Request::SET_FEATURE
+            && req.value == TEST_MODE)
+        {
+            return;
+        }
+
+        let [low, selector] = req.index.to_le_bytes();
+        if low == 0 && (TEST_J..=TEST_PACKET).contains(&selector) {
+            self.pending = Some((selector, DWT::cycle_count()));
+            xfer.accept().ok();
+        } else {
+            xfer.reject().ok();
+        }
+    }
+
+    fn poll(&mut self) {
+        // The device has 3ms after the status stage to enter test mode,
+        // so wait 1ms for the status stage to be sent.
+        if let Some((selector, start)) = self.pending {
+            if DWT::cycle_count().wrapping_sub(start) > self.hclk / 1000 {
+                otg_hs::enter_test_mode(selector);
+                self.pending = None;
+            }
+        }
+    }
+}
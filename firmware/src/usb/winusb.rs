@@ -19,12 +19,28 @@ pub enum OSFeatureDescriptorType {
     Descriptor = 7,
 }
 
-const LEN: u16 = 330;
+/// Length of each function subset, of the header, compatible ID and registry property.
+const FUNCTION_LEN: u16 = 8 + 20 + 132;
+
+/// Function subsets in `MS_OS_DESCRIPTOR`, the last three left out with `hid-1024`,
+/// which has no AVRISP, DirtyJTAG or data stream interface.
+const ALL_FUNCTIONS: u16 = 5;
+const FUNCTIONS: u16 = if cfg!(feature = "hid-1024") {
+    2
+} else {
+    ALL_FUNCTIONS
+};
+
+const FULL_LEN: u16 = 10 + ALL_FUNCTIONS * FUNCTION_LEN;
+const LEN: u16 = 10 + FUNCTIONS * FUNCTION_LEN;
 
 const VENDOR_CODE: u8 = 0x41;
 
 const DAP_V2_INTERFACE: u8 = 3;
 const DFU_INTERFACE: u8 = 4;
+const AVRISP_INTERFACE: u8 = 5;
+const DIRTYJTAG_INTERFACE: u8 = 6;
+const STREAM_INTERFACE: u8 = 7;
 
 enum MsDescriptorTypes {
     Header = 0x0,
@@ -43,7 +59,19 @@ enum MsDescriptorTypes {
 /// For interface ['DFU_INTERFACE']:
 /// - compatible ID 'WinUSB'
 /// - registry property DeviceInterfaceGUIDs = ['{A5DCBF10-6530-11D2-901F-00C04FB951ED}']
-const MS_OS_DESCRIPTOR: [u8; LEN as usize] = [
+///
+/// For interface ['AVRISP_INTERFACE']:
+/// - compatible ID 'WinUSB'
+/// - registry property DeviceInterfaceGUIDs = ['{6B4A4B78-B4B7-49EC-AC20-502150F1BB01}']
+///
+/// For interface ['DIRTYJTAG_INTERFACE']:
+/// - compatible ID 'WinUSB'
+/// - registry property DeviceInterfaceGUIDs = ['{3F2E5C1A-8D47-4B6E-9A0C-71D2E4B8C5F3}']
+///
+/// For interface ['STREAM_INTERFACE']:
+/// - compatible ID 'WinUSB'
+/// - registry property DeviceInterfaceGUIDs = ['{9E4D2A61-7C35-4F08-B1E6-58A3C0D7F294}']
+static MS_OS_DESCRIPTOR: [u8; FULL_LEN as usize] = [
     0xa,
     0x00, // Length 10 bytes
     MsDescriptorTypes::Header as u8,
@@ -380,6 +408,495 @@ const MS_OS_DESCRIPTOR: [u8; LEN as usize] = [
     0,
     0,
     0,
+    // Function header,
+    0x8,
+    0x0, // Length 8
+    MsDescriptorTypes::HeaderFunction as u8,
+    0x00,
+    AVRISP_INTERFACE, // First interface (avrisp)
+    0x0,              // reserved
+    8 + 20 + 132,     // Header + compatible ID
+    0x00,             // Subset length, including header
+    // compatible ID descriptor
+    20,
+    0x00, // length 20
+    MsDescriptorTypes::CompatibleId as u8,
+    0x00,
+    b'W',
+    b'I',
+    b'N',
+    b'U',
+    b'S',
+    b'B',
+    0x00,
+    0x00, // Compatible ID: 8 bytes ASCII
+    0x00,
+    0x00,
+    0x00,
+    0x00,
+    0x00,
+    0x00,
+    0x00,
+    0x00, // Sub-Compatible ID: 8 bytes ASCII
+    // Registry property
+    80 + 2 + 42 + 2 + 2 + 2 + 2,
+    0x00, // length
+    MsDescriptorTypes::RegistryProperty as u8,
+    0x00,
+    7,
+    0, // Data type: multi sz
+    42,
+    0x00, // property name length,
+    b'D',
+    0,
+    b'e',
+    0,
+    b'v',
+    0,
+    b'i',
+    0,
+    b'c',
+    0,
+    b'e',
+    0,
+    b'I',
+    0,
+    b'n',
+    0,
+    b't',
+    0,
+    b'e',
+    0,
+    b'r',
+    0,
+    b'f',
+    0,
+    b'a',
+    0,
+    b'c',
+    0,
+    b'e',
+    0,
+    b'G',
+    0,
+    b'U',
+    0,
+    b'I',
+    0,
+    b'D',
+    0,
+    b's',
+    0,
+    0,
+    0,
+    80,
+    0x00, // data length
+    b'{',
+    0,
+    b'6',
+    0,
+    b'B',
+    0,
+    b'4',
+    0,
+    b'A',
+    0,
+    b'4',
+    0,
+    b'B',
+    0,
+    b'7',
+    0,
+    b'8',
+    0,
+    b'-',
+    0,
+    b'B',
+    0,
+    b'4',
+    0,
+    b'B',
+    0,
+    b'7',
+    0,
+    b'-',
+    0,
+    b'4',
+    0,
+    b'9',
+    0,
+    b'E',
+    0,
+    b'C',
+    0,
+    b'-',
+    0,
+    b'A',
+    0,
+    b'C',
+    0,
+    b'2',
+    0,
+    b'0',
+    0,
+    b'-',
+    0,
+    b'5',
+    0,
+    b'0',
+    0,
+    b'2',
+    0,
+    b'1',
+    0,
+    b'5',
+    0,
+    b'0',
+    0,
+    b'F',
+    0,
+    b'1',
+    0,
+    b'B',
+    0,
+    b'B',
+    0,
+    b'0',
+    0,
+    b'1',
+    0,
+    b'}',
+    0,
+    0,
+    0,
+    0,
+    0,
+    // Function header,
+    0x8,
+    0x0, // Length 8
+    MsDescriptorTypes::HeaderFunction as u8,
+    0x00,
+    DIRTYJTAG_INTERFACE, // First interface (dirtyjtag)
+    0x0,                 // reserved
+    8 + 20 + 132,        // Header + compatible ID
+    0x00,                // Subset length, including header
+    // compatible ID descriptor
+    20,
+    0x00, // length 20
+    MsDescriptorTypes::CompatibleId as u8,
+    0x00,
+    b'W',
+    b'I',
+    b'N',
+    b'U',
+    b'S',
+    b'B',
+    0x00,
+    0x00, // Compatible ID: 8 bytes ASCII
+    0x00,
+    0x00,
+    0x00,
+    0x00,
+    0x00,
+    0x00,
+    0x00,
+    0x00, // Sub-Compatible ID: 8 bytes ASCII
+    // Registry property
+    80 + 2 + 42 + 2 + 2 + 2 + 2,
+    0x00, // length
+    MsDescriptorTypes::RegistryProperty as u8,
+    0x00,
+    7,
+    0, // Data type: multi sz
+    42,
+    0x00, // property name length,
+    b'D',
+    0,
+    b'e',
+    0,
+    b'v',
+    0,
+    b'i',
+    0,
+    b'c',
+    0,
+    b'e',
+    0,
+    b'I',
+    0,
+    b'n',
+    0,
+    b't',
+    0,
+    b'e',
+    0,
+    b'r',
+    0,
+    b'f',
+    0,
+    b'a',
+    0,
+    b'c',
+    0,
+    b'e',
+    0,
+    b'G',
+    0,
+    b'U',
+    0,
+    b'I',
+    0,
+    b'D',
+    0,
+    b's',
+    0,
+    0,
+    0,
+    80,
+    0x00, // data length
+    b'{',
+    0,
+    b'3',
+    0,
+    b'F',
+    0,
+    b'2',
+    0,
+    b'E',
+    0,
+    b'5',
+    0,
+    b'C',
+    0,
+    b'1',
+    0,
+    b'A',
+    0,
+    b'-',
+    0,
+    b'8',
+    0,
+    b'D',
+    0,
+    b'4',
+    0,
+    b'7',
+    0,
+    b'-',
+    0,
+    b'4',
+    0,
+    b'B',
+    0,
+    b'6',
+    0,
+    b'E',
+    0,
+    b'-',
+    0,
+    b'9',
+    0,
+    b'A',
+    0,
+    b'0',
+    0,
+    b'C',
+    0,
+    b'-',
+    0,
+    b'7',
+    0,
+    b'1',
+    0,
+    b'D',
+    0,
+    b'2',
+    0,
+    b'E',
+    0,
+    b'4',
+    0,
+    b'B',
+    0,
+    b'8',
+    0,
+    b'C',
+    0,
+    b'5',
+    0,
+    b'F',
+    0,
+    b'3',
+    0,
+    b'}',
+    0,
+    0,
+    0,
+    0,
+    0,
+    // Function header,
+    0x8,
+    0x0, // Length 8
+    MsDescriptorTypes::HeaderFunction as u8,
+    0x00,
+    STREAM_INTERFACE, // First interface (data stream)
+    0x0,              // reserved
+    8 + 20 + 132,     // Header + compatible ID
+    0x00,             // Subset length, including header
+    // compatible ID descriptor
+    20,
+    0x00, // length 20
+    MsDescriptorTypes::CompatibleId as u8,
+    0x00,
+    b'W',
+    b'I',
+    b'N',
+    b'U',
+    b'S',
+    b'B',
+    0x00,
+    0x00, // Compatible ID: 8 bytes ASCII
+    0x00,
+    0x00,
+    0x00,
+    0x00,
+    0x00,
+    0x00,
+    0x00,
+    0x00, // Sub-Compatible ID: 8 bytes ASCII
+    // Registry property
+    80 + 2 + 42 + 2 + 2 + 2 + 2,
+    0x00, // length
+    MsDescriptorTypes::RegistryProperty as u8,
+    0x00,
+    7,
+    0, // Data type: multi sz
+    42,
+    0x00, // property name length,
+    b'D',
+    0,
+    b'e',
+    0,
+    b'v',
+    0,
+    b'i',
+    0,
+    b'c',
+    0,
+    b'e',
+    0,
+    b'I',
+    0,
+    b'n',
+    0,
+    b't',
+    0,
+    b'e',
+    0,
+    b'r',
+    0,
+    b'f',
+    0,
+    b'a',
+    0,
+    b'c',
+    0,
+    b'e',
+    0,
+    b'G',
+    0,
+    b'U',
+    0,
+    b'I',
+    0,
+    b'D',
+    0,
+    b's',
+    0,
+    0,
+    0,
+    80,
+    0x00, // data length
+    b'{',
+    0,
+    b'9',
+    0,
+    b'E',
+    0,
+    b'4',
+    0,
+    b'D',
+    0,
+    b'2',
+    0,
+    b'A',
+    0,
+    b'6',
+    0,
+    b'1',
+    0,
+    b'-',
+    0,
+    b'7',
+    0,
+    b'C',
+    0,
+    b'3',
+    0,
+    b'5',
+    0,
+    b'-',
+    0,
+    b'4',
+    0,
+    b'F',
+    0,
+    b'0',
+    0,
+    b'8',
+    0,
+    b'-',
+    0,
+    b'B',
+    0,
+    b'1',
+    0,
+    b'E',
+    0,
+    b'6',
+    0,
+    b'-',
+    0,
+    b'5',
+    0,
+    b'8',
+    0,
+    b'A',
+    0,
+    b'3',
+    0,
+    b'C',
+    0,
+    b'0',
+    0,
+    b'D',
+    0,
+    b'7',
+    0,
+    b'F',
+    0,
+    b'2',
+    0,
+    b'9',
+    0,
+    b'4',
+    0,
+    b'}',
+    0,
+    0,
+    0,
+    0,
+    0,
 ];
 
 pub struct MicrosoftDescriptors;
@@ -428,7 +945,8 @@ impl<B: UsbBus> UsbClass<B> for MicrosoftDescriptors {
         // is returned in the BOS descriptor.
         if req.request == VENDOR_CODE {
             if req.index == 0x7 {
-                xfer.accept_with_static(&MS_OS_DESCRIPTOR).ok();
+                xfer.accept_with_static(&MS_OS_DESCRIPTOR[..LEN as usize])
+                    .ok();
             } else {
                 xfer.reject().ok();
             }
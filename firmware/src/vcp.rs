@@ -1,15 +1,18 @@
 // Copyright 2019-2022 Alexis Marquet
 // Dual licensed under the Apache 2.0 and MIT licenses.
 
-use core::cmp::Ordering;
-
-use crate::{
-    bsp::{dma::DMA, gpio::Pins, rcc::Clocks, stm32ral},
-    VCP_PACKET_SIZE,
+use crate::bsp::{
+    dma::DMA,
+    gpio::Pins,
+    rcc::Clocks,
+    stm32ral::usart,
+    uart::{self, UART},
 };
-
-use stm32ral::usart;
-use stm32ral::{modify_reg, write_reg};
+use crate::buffers::{VCP_RX_BUFFER_SIZE, VCP_TX_BUFFER_SIZE};
+use crate::VCP_PACKET_SIZE;
+use core::convert::{TryFrom, TryInto};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use num_enum::TryFromPrimitive;
 use usbd_serial::{ParityType, StopBits};
 
 /// UART configuration struct
@@ -32,37 +35,202 @@ impl Default for VcpConfig {
     }
 }
 
+/// Counters for VCP traffic and receive errors.
+pub struct VcpStats {
+    /// Bytes received from the target.
+    pub rx_bytes: AtomicU32,
+    /// Bytes transmitted to the target.
+    pub tx_bytes: AtomicU32,
+    /// Bytes received from the target but dropped, as the host was not reading them.
+    pub dropped_bytes: AtomicU32,
+    /// Polls which found each USART receive error flag set.
+    pub parity_errors: AtomicU32,
+    pub framing_errors: AtomicU32,
+    pub noise_errors: AtomicU32,
+    pub overrun_errors: AtomicU32,
+}
+
+pub static VCP_STATS: VcpStats = VcpStats::new();
+
+const CONTROL_LINE_DTR: u32 = 1 << 0;
+const CONTROL_LINE_RTS: u32 = 1 << 1;
+
+/// Set when the transmit DMA completes a transfer, until the main loop handles it.
+static TX_COMPLETE: AtomicBool = AtomicBool::new(false);
+
+/// Note the transmit DMA completed a transfer.
+///
+/// Call this function from the USART2 TX DMA interrupt handler.
+pub fn on_tx_complete() {
+    TX_COMPLETE.store(true, Ordering::Release);
+}
+
+/// Check whether the transmit DMA completed a transfer since the last call.
+pub fn take_tx_complete() -> bool {
+    TX_COMPLETE.swap(false, Ordering::Acquire)
+}
+
+/// CDC control line state last set by the host, as `CONTROL_LINE_*` bits.
+static CONTROL_LINES: AtomicU32 = AtomicU32::new(0);
+
+/// Record the CDC control line state set by the host.
+pub fn set_control_lines(dtr: bool, rts: bool) {
+    let mut lines = 0;
+    if dtr {
+        lines |= CONTROL_LINE_DTR;
+    }
+    if rts {
+        lines |= CONTROL_LINE_RTS;
+    }
+    CONTROL_LINES.store(lines, Ordering::Relaxed);
+}
+
+/// CDC control line state as a `u32`, bit 0 DTR and bit 1 RTS.
+pub fn control_lines() -> u32 {
+    CONTROL_LINES.load(Ordering::Relaxed)
+}
+
+/// Check whether the host asserts DTR, which terminals do while they have the port open.
+pub fn dtr() -> bool {
+    control_lines() & CONTROL_LINE_DTR != 0
+}
+
+/// Check whether the host asserts RTS.
+pub fn rts() -> bool {
+    control_lines() & CONTROL_LINE_RTS != 0
+}
+
+impl VcpStats {
+    const fn new() -> Self {
+        VcpStats {
+            rx_bytes: AtomicU32::new(0),
+            tx_bytes: AtomicU32::new(0),
+            dropped_bytes: AtomicU32::new(0),
+            parity_errors: AtomicU32::new(0),
+            framing_errors: AtomicU32::new(0),
+            noise_errors: AtomicU32::new(0),
+            overrun_errors: AtomicU32::new(0),
+        }
+    }
+
+    fn counters(&self) -> [&AtomicU32; 7] {
+        [
+            &self.rx_bytes,
+            &self.tx_bytes,
+            &self.dropped_bytes,
+            &self.parity_errors,
+            &self.framing_errors,
+            &self.noise_errors,
+            &self.overrun_errors,
+        ]
+    }
+
+    /// Record `len` bytes from the target which could not be sent to the host.
+    pub fn dropped(&self, len: usize) {
+        self.dropped_bytes.fetch_add(len as u32, Ordering::Relaxed);
+    }
+
+    fn errors(&self, errors: uart::RxErrors) {
+        let flags = [
+            (errors.parity, &self.parity_errors),
+            (errors.framing, &self.framing_errors),
+            (errors.noise, &self.noise_errors),
+            (errors.overrun, &self.overrun_errors),
+        ];
+        for (flag, counter) in flags.iter() {
+            if *flag {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Current counter values, in the order of the fields.
+    pub fn values(&self) -> [u32; 7] {
+        let mut values = [0; 7];
+        for (value, counter) in values.iter_mut().zip(self.counters().iter()) {
+            *value = counter.load(Ordering::Relaxed);
+        }
+        values
+    }
+
+    pub fn reset(&self) {
+        for counter in self.counters().iter() {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl VcpConfig {
+    /// Encode as a CDC line coding structure, for storing.
+    pub fn to_bytes(&self) -> [u8; 7] {
+        let rate = self.data_rate.to_le_bytes();
+        [
+            rate[0],
+            rate[1],
+            rate[2],
+            rate[3],
+            self.stop_bits as u8,
+            self.parity_type as u8,
+            self.data_bits,
+        ]
+    }
+
+    /// Decode a CDC line coding structure written by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 7 {
+            return None;
+        }
+        let data_rate = u32::from_le_bytes(bytes[0..4].try_into().ok()?);
+        if data_rate == 0 {
+            return None;
+        }
+        Some(VcpConfig {
+            stop_bits: bytes[4].into(),
+            data_bits: bytes[6],
+            parity_type: bytes[5].into(),
+            data_rate,
+        })
+    }
+}
+
+/// Loopback used by the VCP self test.
+#[derive(Copy, Clone, TryFromPrimitive)]
+#[repr(u8)]
+enum Loopback {
+    /// USART2 in single-wire half-duplex mode, receiving what it sends on the TX pin.
+    Internal = 0,
+    /// TX wired to RX outside the probe, such as at the target connector.
+    External = 1,
+}
+
+/// Polls of the receive flag before giving up on a looped back byte.
+const SELF_TEST_TIMEOUT: u32 = 1_000_000;
+
 #[allow(clippy::upper_case_acronyms)]
 pub struct VCP<'a> {
-    uart: usart::Instance,
+    uart: UART<'a, VCP_RX_BUFFER_SIZE, VCP_TX_BUFFER_SIZE>,
     pins: &'a Pins<'a>,
-    dma: &'a DMA,
-    rx_buffer: [u8; VCP_PACKET_SIZE as usize],
-    tx_buffer: [u8; VCP_PACKET_SIZE as usize],
-    last_idx_rx: usize,
-    last_idx_tx: usize,
-    fck: u32,
+    config: Option<VcpConfig>,
+    running: bool,
+    port_open: bool,
 }
 
 impl<'a> VCP<'a> {
     pub fn new(uart: usart::Instance, pins: &'a Pins, dma: &'a DMA) -> Self {
         VCP {
-            uart,
+            uart: UART::new(uart, dma),
             pins,
-            dma,
-            rx_buffer: [0; VCP_PACKET_SIZE as usize],
-            tx_buffer: [0; VCP_PACKET_SIZE as usize],
-            last_idx_rx: 0,
-            last_idx_tx: 0,
-            fck: 72_000_000,
+            config: None,
+            running: false,
+            port_open: false,
         }
     }
 
     /// Call with the system clock speeds to configure peripherals that require timing information.
     ///
-    /// Currently this only configures the pins & DMA RX
+    /// Currently this only configures the pins & UART clock
     pub fn setup(&mut self, clocks: &Clocks) {
-        self.fck = clocks.pclk1();
+        self.uart.setup(clocks);
 
         self.pins.usart2_tx.set_ospeed_veryhigh();
         self.pins.usart2_tx.set_otype_pushpull();
@@ -75,52 +243,48 @@ impl<'a> VCP<'a> {
         self.pins.usart2_rx.set_pull_up();
         self.pins.usart2_rx.set_mode_alternate();
         self.pins.usart2_rx.set_af(7);
-
-        self.dma.usart2_start_rx(&mut self.rx_buffer);
     }
 
     /// Start the VCP function.
     ///
-    /// This enables both TX & RX.
+    /// This enables TX, and RX if the host has the port open.
     pub fn start(&mut self) {
-        self.last_idx_rx = 0;
-        self.last_idx_tx = 0;
-        write_reg!(usart, self.uart, CR3, DMAR: Enabled, DMAT: Enabled);
-
-        write_reg!(
-            usart,
-            self.uart,
-            CR1,
-            OVER8: Oversampling8,
-            RE: Enabled,
-            TE: Enabled,
-            UE: Enabled
-        );
+        self.uart.start();
+        self.uart.start_tx();
+        if !self.port_open {
+            self.uart.stop();
+        }
+        self.running = true;
     }
 
     /// Disable UART.
-    pub fn stop(&self) {
-        modify_reg!(
-            usart,
-            self.uart,
-            CR1,
-            RE: Disabled,
-            TE: Disabled,
-            UE: Disabled
-        );
+    pub fn stop(&mut self) {
+        self.uart.stop_tx();
+        self.uart.stop();
+        self.running = false;
+    }
+
+    /// Receive from the target only while the host has the port open, as
+    /// signalled by DTR, so a newly opened terminal doesn't show old data.
+    pub fn set_port_open(&mut self, open: bool) {
+        if open == self.port_open {
+            return;
+        }
+        self.port_open = open;
+        if self.running {
+            if open {
+                self.uart.restart_rx();
+            } else {
+                self.uart.stop();
+            }
+        }
     }
 
     /// Fetch current number of bytes available.
     ///
     /// Subsequent calls to read() may return a different amount of data.
     pub fn rx_bytes_available(&self) -> usize {
-        // length of the buffer minus the remainder of the dma transfer
-        let dma_idx = self.rx_buffer.len() - self.dma.usart2_rx_ndtr();
-        if dma_idx >= self.last_idx_rx {
-            dma_idx - self.last_idx_rx
-        } else {
-            (self.rx_buffer.len() - self.last_idx_rx) + dma_idx
-        }
+        self.uart.bytes_available()
     }
 
     /// Read new UART data.
@@ -131,55 +295,14 @@ impl<'a> VCP<'a> {
     /// Remaining data will be read on the next call, so long as the internal buffer
     /// doesn't overflow, which is not detected.
     pub fn read(&mut self, rx: &mut [u8]) -> usize {
-        // See what index the DMA is going to write next, and copy out
-        // all prior data. Even if the DMA writes new data while we're
-        // processing we won't get out of sync and will handle the new
-        // data next time read() is called.
-        let dma_idx = self.rx_buffer.len() - self.dma.usart2_rx_ndtr();
-        match dma_idx.cmp(&self.last_idx_rx) {
-            Ordering::Equal => {
-                // No action required if no data has been received.
-                0
-            }
-            Ordering::Less => {
-                // Wraparound occurred:
-                // Copy from last_idx to end, and from start to new dma_idx.
-                let mut n1 = self.rx_buffer.len() - self.last_idx_rx;
-                let mut n2 = dma_idx;
-                let mut new_last_idx = dma_idx;
-
-                // Ensure we don't overflow rx buffer
-                if n1 > rx.len() {
-                    n1 = rx.len();
-                    n2 = 0;
-                    new_last_idx = self.last_idx_rx + n1;
-                } else if (n1 + n2) > rx.len() {
-                    n2 = rx.len() - n1;
-                    new_last_idx = n2;
-                }
-
-                rx[..n1].copy_from_slice(&self.rx_buffer[self.last_idx_rx..self.last_idx_rx + n1]);
-                rx[n1..(n1 + n2)].copy_from_slice(&self.rx_buffer[..n2]);
-
-                self.last_idx_rx = new_last_idx;
-                n1 + n2
-            }
-            Ordering::Greater => {
-                // New data, no wraparound:
-                // Copy from last_idx to new dma_idx.
-                let mut n = dma_idx - self.last_idx_rx;
-
-                // Ensure we don't overflow rx buffer
-                if n > rx.len() {
-                    n = rx.len();
-                }
-
-                rx[..n].copy_from_slice(&self.rx_buffer[self.last_idx_rx..self.last_idx_rx + n]);
+        let len = self.uart.read(rx);
+        VCP_STATS.rx_bytes.fetch_add(len as u32, Ordering::Relaxed);
+        len
+    }
 
-                self.last_idx_rx += n;
-                n
-            }
-        }
+    /// Count receive errors flagged by the USART since the last call.
+    pub fn poll_errors(&self) {
+        VCP_STATS.errors(self.uart.take_errors());
     }
 
     /// Setup the USART line config.
@@ -187,51 +310,110 @@ impl<'a> VCP<'a> {
     /// This should be done between a `stop()` and a `start` call since
     /// configuring this requires the UE bit to be `0b0`.
     pub fn set_config(&mut self, coding: VcpConfig) {
-        // Find closest divider which is also an even integer >= 16.
-        // The baud rate is (2*fck)/BRR.
-        let mut div = (2 * self.fck) / coding.data_rate;
-        div &= 0xffff_fffe;
-        if div < 16 {
-            div = 16;
-        }
+        let stop_bits = match coding.stop_bits {
+            StopBits::One => uart::StopBits::One,
+            StopBits::OnePointFive => uart::StopBits::OnePointFive,
+            StopBits::Two => uart::StopBits::Two,
+        };
+        let parity = match coding.parity_type {
+            ParityType::Odd => uart::Parity::Odd,
+            ParityType::Event => uart::Parity::Even,
+            // Mark and space parity are unsupported
+            _ => uart::Parity::None,
+        };
+        self.uart.set_format(coding.data_bits, stop_bits, parity);
+        self.uart.set_baud(coding.data_rate);
+        self.config = Some(coding);
+    }
 
-        // Write BRR value based on div.
-        // Since we are OVERSAMPLE8, shift bottom 4 bits down by 1.
-        let brr = (div & 0xffff_fff0) | ((div & 0xf) >> 1);
-        write_reg!(usart, self.uart, BRR, brr);
-
-        // configure data bits
-        match coding.data_bits {
-            7 => modify_reg!(usart, self.uart, CR1, M1: 1, M0: 0),
-            8 => modify_reg!(usart, self.uart, CR1, M1: 0, M0: 0),
-            9 => modify_reg!(usart, self.uart, CR1, M1: 0, M0: 1),
-            _ => panic!(),
-        }
+    /// Handle the VCP self test vendor command, returning the response length.
+    ///
+    /// Request: loopback (0 internal, 1 external), u32 baud rate.
+    /// Response: status, u16 bytes sent, u16 bytes received back correctly.
+    pub fn process_self_test(&mut self, report: &[u8], resp: &mut [u8]) -> usize {
+        resp[0] = report[0];
+        let loopback = report.get(1).and_then(|&l| Loopback::try_from(l).ok());
+        let baud = report
+            .get(2..6)
+            .and_then(|b| b.try_into().ok())
+            .map(u32::from_le_bytes);
+        let (loopback, baud) = match (loopback, baud) {
+            (Some(loopback), Some(baud)) if baud != 0 => (loopback, baud),
+            _ => {
+                // DAP_ERROR
+                resp[1] = 0xFF;
+                return 2;
+            }
+        };
+
+        let (sent, matched) = self.self_test(loopback, baud);
+        resp[1] = 0;
+        resp[2..4].copy_from_slice(&sent.to_le_bytes());
+        resp[4..6].copy_from_slice(&matched.to_le_bytes());
+        6
+    }
 
-        // configure stop bits
-        match coding.stop_bits {
-            StopBits::One => modify_reg!(usart, self.uart, CR2, STOP: 0b00),
-            StopBits::OnePointFive => modify_reg!(usart, self.uart, CR2, STOP: 0b11),
-            StopBits::Two => modify_reg!(usart, self.uart, CR2, STOP: 0b10),
+    /// Send every byte value through `loopback` at `baud`, returning the number
+    /// of bytes sent and the number received back correctly.
+    ///
+    /// The test stops at the first byte which is not received at all.
+    /// Normal VCP operation is suspended during the test and restored after it.
+    fn self_test(&mut self, loopback: Loopback, baud: u32) -> (u16, u16) {
+        let running = self.running;
+        self.stop();
+        self.uart
+            .start_loopback(baud, matches!(loopback, Loopback::Internal));
+
+        let (mut sent, mut matched) = (0, 0);
+        for byte in 0..=255u8 {
+            self.uart.hd_write(byte);
+            sent += 1;
+            match self.uart.hd_read(SELF_TEST_TIMEOUT) {
+                Some(received) if received == byte => matched += 1,
+                Some(_) => {}
+                None => break,
+            }
         }
 
-        // configure parity type
-        match coding.parity_type {
-            ParityType::None => modify_reg!(usart, self.uart, CR1, PCE: 0),
-            ParityType::Odd => modify_reg!(usart, self.uart, CR1, PCE:1, PS: 1),
-            ParityType::Event => modify_reg!(usart, self.uart, CR1, PCE:1, PS: 0),
-            ParityType::Mark => (),  // unsupported?
-            ParityType::Space => (), // unsupported?
+        self.uart.stop_half_duplex();
+        if let Some(config) = self.config {
+            self.set_config(config);
         }
+        if running {
+            self.start();
+        }
+        info!(
+            "VCP self test: {=u16} of {=u16} bytes received",
+            matched, sent
+        );
+        (sent, matched)
     }
 
-    /// Check state of TX Dma transfer
-    pub fn is_tx_idle(&self) -> bool {
-        self.dma.usart2_tx_ndtr() == 0
+    /// Check there is space to queue a whole packet from the host, continuing
+    /// transmission of data already queued.
+    ///
+    /// Packets are only read from the host while this is true, so the CDC OUT
+    /// endpoint is NAKed rather than data being dropped when the target's
+    /// baud rate can't keep up.
+    pub fn is_tx_ready(&mut self) -> bool {
+        self.uart.poll_tx();
+        self.uart.tx_space() >= VCP_PACKET_SIZE as usize
+    }
+
+    /// Start sending the next chunk of queued data, after a transfer completes.
+    pub fn poll_tx(&mut self) {
+        self.uart.poll_tx();
     }
-    /// Start DMA transfer from buffer to TX Shift register.
-    pub fn write(&mut self, tx: &[u8], len: usize) {
-        self.tx_buffer[0..len].copy_from_slice(tx);
-        self.dma.usart2_start_tx_transfer(&self.tx_buffer, len);
+
+    /// Queue data for transmission to the target by DMA.
+    pub fn write(&mut self, tx: &[u8]) {
+        let len = self.uart.write(tx);
+        VCP_STATS.tx_bytes.fetch_add(len as u32, Ordering::Relaxed);
+        if len < tx.len() {
+            warn!(
+                "VCP transmit buffer full, dropped {=usize} bytes",
+                tx.len() - len
+            );
+        }
     }
 }
@@ -0,0 +1,79 @@
+use stm32ral::{adc, adc_common};
+use stm32ral::{modify_reg, read_reg, write_reg};
+
+/// ADC channel connected to the internal temperature sensor.
+pub const TEMPERATURE_CHANNEL: u8 = 18;
+
+/// ADC channel connected to the internal voltage reference.
+pub const VREFINT_CHANNEL: u8 = 17;
+
+/// Factory temperature sensor readings at 30°C and 110°C, taken with VDDA at 3.3V.
+const TS_CAL1: *const u16 = 0x1FF0_7A2C as *const u16;
+const TS_CAL2: *const u16 = 0x1FF0_7A2E as *const u16;
+/// Factory internal reference reading, taken with VDDA at 3.3V.
+const VREFINT_CAL: *const u16 = 0x1FF0_7A2A as *const u16;
+
+/// Single conversions on demand from one ADC instance.
+pub struct ADC {
+    adc: adc::Instance,
+    common: adc_common::Instance,
+}
+
+impl ADC {
+    pub fn new(adc: adc::Instance, common: adc_common::Instance) -> Self {
+        ADC { adc, common }
+    }
+
+    /// Power up the ADC and enable the temperature sensor.
+    ///
+    /// The ADC clock is PCLK2/4, at most 27MHz, and the temperature sensor and
+    /// internal reference channels are sampled for 480 cycles to meet their
+    /// minimum sampling time.
+    pub fn setup(&self) {
+        modify_reg!(adc_common, self.common, CCR, ADCPRE: 0b01, TSVREFE: 1);
+        modify_reg!(adc, self.adc, SMPR1, SMP17: 0b111, SMP18: 0b111);
+        write_reg!(adc, self.adc, SQR1, L: 0);
+        write_reg!(adc, self.adc, CR2, ADON: 1);
+    }
+
+    /// Convert `channel` once, returning the 12-bit result.
+    pub fn read_channel(&self, channel: u8) -> u16 {
+        write_reg!(adc, self.adc, SQR3, SQ1: channel as u32);
+        modify_reg!(adc, self.adc, CR2, SWSTART: 1);
+        while read_reg!(adc, self.adc, SR, EOC) == 0 {}
+        read_reg!(adc, self.adc, DR) as u16
+    }
+
+    /// Sample `channel` for 480 cycles, for inputs behind a high impedance
+    /// such as a resistor divider.
+    pub fn set_slow_sampling(&self, channel: u8) {
+        let channel = channel as u32;
+        if channel < 10 {
+            modify_reg!(adc, self.adc, SMPR2, |r| r | (0b111 << (3 * channel)));
+        } else {
+            modify_reg!(adc, self.adc, SMPR1, |r| r
+                | (0b111 << (3 * (channel - 10))));
+        }
+    }
+
+    /// Convert `channel` once, returning the input voltage in mV, with VDDA
+    /// measured against the internal reference.
+    pub fn read_millivolts(&self, channel: u8) -> u32 {
+        let vrefint = self.read_channel(VREFINT_CHANNEL).max(1) as u64;
+        let cal = unsafe { core::ptr::read_volatile(VREFINT_CAL) } as u64;
+        let raw = self.read_channel(channel) as u64;
+        (3300 * cal * raw / (vrefint * 4095)) as u32
+    }
+
+    /// Read the internal temperature sensor, in hundredths of a degree Celsius.
+    pub fn temperature(&self) -> i32 {
+        let raw = self.read_channel(TEMPERATURE_CHANNEL) as i32;
+        let (cal1, cal2) = unsafe {
+            (
+                core::ptr::read_volatile(TS_CAL1) as i32,
+                core::ptr::read_volatile(TS_CAL2) as i32,
+            )
+        };
+        3000 + (raw - cal1) * (11000 - 3000) / (cal2 - cal1).max(1)
+    }
+}
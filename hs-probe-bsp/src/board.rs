@@ -0,0 +1,246 @@
+use crate::gpio::{DirectionPin, Pin, Pins, SensePin, GPIO, MAX_SPARE_PINS};
+
+/// GPIO ports used by HS-Probe boards.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Port {
+    A,
+    B,
+    C,
+    D,
+    E,
+    G,
+    I,
+}
+
+/// Location of a pin, as its port and pin number.
+#[derive(Copy, Clone)]
+pub struct PinId {
+    pub port: Port,
+    pub n: u8,
+}
+
+/// Analog input measuring a voltage through a divider, as `mul / div` of the voltage.
+#[derive(Copy, Clone)]
+pub struct SenseId {
+    pub pin: PinId,
+    /// ADC channel of the pin.
+    pub channel: u8,
+    pub mul: u32,
+    pub div: u32,
+}
+
+/// Direction pin of a level shifter, and the level at which it drives the target.
+#[derive(Copy, Clone)]
+pub struct DirectionId {
+    pub pin: PinId,
+    pub output_level: bool,
+}
+
+const fn pin(port: Port, n: u8) -> PinId {
+    PinId { port, n }
+}
+
+/// Pin assignments of one hardware revision.
+///
+/// See `Pins` for what each pin is used for.
+pub struct Board {
+    pub name: &'static str,
+
+    pub led_red: PinId,
+    pub led_green: PinId,
+    pub led_blue: PinId,
+
+    pub t5v_en: PinId,
+    pub tvcc_en: PinId,
+    pub reset: PinId,
+    pub ntrst: PinId,
+    pub gnd_detect: PinId,
+
+    pub usart1_rx: PinId,
+    pub usart1_tx: PinId,
+    pub usart2_rx: PinId,
+    pub usart2_tx: PinId,
+
+    pub spi1_clk: PinId,
+    pub spi1_miso: PinId,
+    pub spi1_mosi: PinId,
+    pub spi2_clk: PinId,
+    pub spi2_miso: PinId,
+    pub spi2_mosi: PinId,
+
+    pub usb_dm: PinId,
+    pub usb_dp: PinId,
+    pub usb_sel: PinId,
+    /// Input sensing VBUS from the USB connector, if the board has one.
+    pub vbus: Option<PinId>,
+    /// Analog input measuring the target reference voltage, if the board has one.
+    pub tvcc_sense: Option<SenseId>,
+
+    /// Spare pin with TIM9 channel 1 on alternate function 3, for PWM output.
+    pub pwm: Option<PinId>,
+    /// Spare pin with TIM2 channel 1 on alternate function 1, for measuring frequency.
+    pub freq: Option<PinId>,
+
+    /// Pins not used by the firmware, which the host can drive and read with
+    /// the GPIO vendor command. At most `MAX_SPARE_PINS`.
+    pub spare: &'static [PinId],
+
+    /// Direction pin of an external level shifter on SWDIO/TMS, for derived
+    /// boards which buffer the target pins. It is turned around with SWDIO
+    /// in SWD mode and drives the target in JTAG mode. SWIM, which needs an
+    /// open-drain SWDIO, is not supported through such a shifter.
+    pub swdio_dir: Option<DirectionId>,
+}
+
+/// The original HS-Probe.
+pub const HS_PROBE: Board = Board {
+    name: "HS-Probe",
+
+    led_red: pin(Port::C, 10),
+    led_green: pin(Port::B, 8),
+    led_blue: pin(Port::E, 0),
+
+    t5v_en: pin(Port::B, 1),
+    tvcc_en: pin(Port::E, 2),
+    reset: pin(Port::G, 13),
+    ntrst: pin(Port::G, 12),
+    gnd_detect: pin(Port::G, 14),
+
+    usart1_rx: pin(Port::B, 7),
+    usart1_tx: pin(Port::B, 6),
+    usart2_rx: pin(Port::D, 6),
+    usart2_tx: pin(Port::D, 5),
+
+    spi1_clk: pin(Port::B, 3),
+    spi1_miso: pin(Port::B, 4),
+    spi1_mosi: pin(Port::B, 5),
+    spi2_clk: pin(Port::I, 1),
+    spi2_miso: pin(Port::I, 2),
+    spi2_mosi: pin(Port::I, 3),
+
+    usb_dm: pin(Port::B, 14),
+    usb_dp: pin(Port::B, 15),
+    usb_sel: pin(Port::B, 10),
+    vbus: None,
+    tvcc_sense: None,
+
+    pwm: Some(pin(Port::E, 5)),
+    freq: Some(pin(Port::A, 0)),
+    spare: &[
+        pin(Port::D, 0),
+        pin(Port::D, 1),
+        pin(Port::D, 2),
+        pin(Port::A, 0),
+        pin(Port::E, 3),
+        pin(Port::E, 4),
+        pin(Port::E, 5),
+        pin(Port::E, 6),
+    ],
+
+    swdio_dir: None,
+};
+
+impl Board {
+    /// Get the description of the board the firmware is running on.
+    ///
+    /// Only one revision exists so far. Later revisions should be told apart
+    /// here, by cargo feature or by reading strap pins, before any other pin
+    /// is configured.
+    pub fn current() -> &'static Board {
+        &HS_PROBE
+    }
+
+    /// Create the `Pins` for this board.
+    pub fn pins<'a>(&self, ports: &'a Ports) -> Pins<'a> {
+        let spare = |i: usize| self.spare.get(i).map(|&id| ports.pin(id));
+        Pins {
+            led_red: ports.pin(self.led_red),
+            led_green: ports.pin(self.led_green),
+            led_blue: ports.pin(self.led_blue),
+            t5v_en: ports.pin(self.t5v_en),
+            tvcc_en: ports.pin(self.tvcc_en),
+            reset: ports.pin(self.reset),
+            ntrst: ports.pin(self.ntrst),
+            gnd_detect: ports.pin(self.gnd_detect),
+            usart1_rx: ports.pin(self.usart1_rx),
+            usart1_tx: ports.pin(self.usart1_tx),
+            usart2_rx: ports.pin(self.usart2_rx),
+            usart2_tx: ports.pin(self.usart2_tx),
+            spi1_clk: ports.pin(self.spi1_clk),
+            spi1_miso: ports.pin(self.spi1_miso),
+            spi1_mosi: ports.pin(self.spi1_mosi),
+            spi2_clk: ports.pin(self.spi2_clk),
+            spi2_miso: ports.pin(self.spi2_miso),
+            spi2_mosi: ports.pin(self.spi2_mosi),
+            usb_dm: ports.pin(self.usb_dm),
+            usb_dp: ports.pin(self.usb_dp),
+            usb_sel: ports.pin(self.usb_sel),
+            vbus: self.vbus.map(|id| ports.pin(id)),
+            tvcc_sense: self.tvcc_sense.map(|sense| SensePin {
+                pin: ports.pin(sense.pin),
+                channel: sense.channel,
+                mul: sense.mul,
+                div: sense.div,
+            }),
+            pwm: self.pwm.map(|id| ports.pin(id)),
+            freq: self.freq.map(|id| ports.pin(id)),
+            spare: [
+                spare(0),
+                spare(1),
+                spare(2),
+                spare(3),
+                spare(4),
+                spare(5),
+                spare(6),
+                spare(7),
+            ],
+            swdio_dir: self.swdio_dir.map(|dir| DirectionPin {
+                pin: ports.pin(dir.pin),
+                output_level: dir.output_level,
+            }),
+        }
+    }
+}
+
+/// All GPIO ports a board may assign pins on.
+pub struct Ports {
+    pub a: GPIO,
+    pub b: GPIO,
+    pub c: GPIO,
+    pub d: GPIO,
+    pub e: GPIO,
+    pub g: GPIO,
+    pub i: GPIO,
+}
+
+impl Ports {
+    /// Take all GPIO port instances.
+    pub fn take() -> Option<Self> {
+        use stm32ral::gpio;
+        Some(Ports {
+            a: GPIO::new(gpio::GPIOA::take()?),
+            b: GPIO::new(gpio::GPIOB::take()?),
+            c: GPIO::new(gpio::GPIOC::take()?),
+            d: GPIO::new(gpio::GPIOD::take()?),
+            e: GPIO::new(gpio::GPIOE::take()?),
+            g: GPIO::new(gpio::GPIOG::take()?),
+            i: GPIO::new(gpio::GPIOI::take()?),
+        })
+    }
+
+    pub fn port(&self, port: Port) -> &GPIO {
+        match port {
+            Port::A => &self.a,
+            Port::B => &self.b,
+            Port::C => &self.c,
+            Port::D => &self.d,
+            Port::E => &self.e,
+            Port::G => &self.g,
+            Port::I => &self.i,
+        }
+    }
+
+    pub fn pin(&self, id: PinId) -> Pin {
+        self.port(id.port).pin(id.n)
+    }
+}
@@ -4,8 +4,12 @@ use stm32ral::syst;
 use stm32ral::{modify_reg, read_reg, write_reg};
 
 const SYST_CSR_ENABLE: u32 = 1 << 0;
+const SYST_CSR_TICKINT: u32 = 1 << 1;
 const SYST_CSR_CLKSOURCE: u32 = 1 << 2;
 
+/// Number of times SysTick has wrapped, counted once `enable_uptime` is called.
+static WRAPS: AtomicU32 = AtomicU32::new(0);
+
 pub struct Delay {
     systick: syst::Instance,
     base_clock: AtomicU32,
@@ -50,6 +54,13 @@ impl Delay {
         base_clock / frequency
     }
 
+    /// Frequency with a period of `ticks` ticks.
+    pub fn ticks_to_frequency(&self, ticks: u32) -> u32 {
+        let base_clock = self.base_clock.load(Ordering::SeqCst);
+        base_clock / ticks.max(1)
+    }
+
+    #[link_section = ".itcm"]
     pub fn delay_ticks(&self, mut ticks: u32) {
         let mut last = self.get_current();
         loop {
@@ -65,6 +76,7 @@ impl Delay {
         }
     }
 
+    #[link_section = ".itcm"]
     pub fn delay_ticks_from_last(&self, mut ticks: u32, mut last: u32) -> u32 {
         loop {
             let now = self.get_current();
@@ -83,4 +95,40 @@ impl Delay {
     pub fn get_current(&self) -> u32 {
         read_reg!(syst, self.systick, CVR)
     }
+
+    /// Count SysTick wraps to keep track of uptime.
+    ///
+    /// `on_systick()` must be called from the SysTick exception handler.
+    pub fn enable_uptime(&self) {
+        modify_reg!(syst, self.systick, CSR, |r| (r | SYST_CSR_TICKINT));
+    }
+
+    /// Call this function from the SysTick exception handler.
+    pub fn on_systick() {
+        WRAPS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Time since `enable_uptime` was called, in milliseconds.
+    pub fn uptime_ms(&self) -> u64 {
+        let base_clock = self.base_clock.load(Ordering::SeqCst) as u64;
+        self.uptime_ticks() * 1000 / base_clock.max(1)
+    }
+
+    /// Time since `enable_uptime` was called, in microseconds.
+    pub fn uptime_us(&self) -> u64 {
+        let base_clock = self.base_clock.load(Ordering::SeqCst) as u64;
+        self.uptime_ticks() * 1_000_000 / base_clock.max(1)
+    }
+
+    fn uptime_ticks(&self) -> u64 {
+        // Read the wrap count on both sides of the counter, in case it wrapped in between.
+        let (wraps, current) = loop {
+            let wraps = WRAPS.load(Ordering::Relaxed);
+            let current = self.get_current();
+            if WRAPS.load(Ordering::Relaxed) == wraps {
+                break (wraps, current);
+            }
+        };
+        ((wraps as u64) << 24) + (0xffffff - current) as u64
+    }
 }
@@ -10,6 +10,7 @@ SPI1_TX: DMA2, stream 3, channel 3
 SPI2_RX: DMA1, stream 3, channel 0
 SPI2_TX: DMA1, stream 4, channel 0
 USART1_RX: DMA2, stream 5, channel 4
+USART1_TX: DMA2, stream 7, channel 4
 USART2_RX: DMA1, stream 5, channel 4
 USART2_TX: DMA1, stream 6, channel 4
 */
@@ -18,6 +19,30 @@ const SPI_DR_OFFSET: u32 = 0x0C;
 const UART_RDR_OFFSET: u32 = 0x24;
 const UART_TDR_OFFSET: u32 = 0x28;
 
+/// End of DTCM. DMA buffers must lie below this address, either in flash
+/// or DTCM, as neither is cached and so both stay coherent with D-cache,
+/// or else in SRAM2, which `mpu::setup()` makes non-cacheable.
+const DTCM_END: usize = 0x2001_0000;
+const SRAM2_START: usize = 0x2003_C000;
+const SRAM2_END: usize = 0x2004_0000;
+
+#[inline(always)]
+fn check_buffer(buf: &[u8]) {
+    let start = buf.as_ptr() as usize;
+    let end = start + buf.len();
+    debug_assert!(
+        end <= DTCM_END || (start >= SRAM2_START && end <= SRAM2_END),
+        "DMA buffer not in DTCM or SRAM2"
+    );
+}
+
+/// USART instances with DMA streams assigned for reception and transmission.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum UartStreams {
+    USART1,
+    USART2,
+}
+
 pub struct DMA {
     dma1: dma::Instance,
     dma2: dma::Instance,
@@ -130,6 +155,8 @@ impl DMA {
             PINC: Fixed,
             CIRC: Enabled,
             DIR: PeripheralToMemory,
+            HTIE: Enabled,
+            TCIE: Enabled,
             EN: Disabled
         );
         write_reg!(
@@ -139,6 +166,28 @@ impl DMA {
             stm32ral::usart::USART1 as u32 + UART_RDR_OFFSET
         );
 
+        // Set up DMA2 stream 7, channel 4 for USART1_TX
+        write_reg!(
+            dma,
+            self.dma2,
+            CR7,
+            CHSEL: 4,
+            PL: High,
+            MSIZE: Bits8,
+            PSIZE: Bits8,
+            MINC: Incremented,
+            PINC: Fixed,
+            CIRC: Disabled,
+            DIR: MemoryToPeripheral,
+            EN: Disabled
+        );
+        write_reg!(
+            dma,
+            self.dma2,
+            PAR7,
+            stm32ral::usart::USART1 as u32 + UART_TDR_OFFSET
+        );
+
         // Set up DMA1 stream 5, channel 4 for USART2_RX
         write_reg!(
             dma,
@@ -174,6 +223,7 @@ impl DMA {
             PINC: Fixed,
             CIRC: Disabled,
             DIR: MemoryToPeripheral,
+            TCIE: Enabled,
             EN: Disabled
         );
         write_reg!(
@@ -186,6 +236,8 @@ impl DMA {
 
     /// Sets up and enables a DMA transmit/receive for SPI1 (streams 2 and 3, channel 3)
     pub fn spi1_enable(&self, tx: &[u8], rx: &mut [u8]) {
+        check_buffer(tx);
+        check_buffer(rx);
         write_reg!(
             dma,
             self.dma2,
@@ -205,6 +257,8 @@ impl DMA {
         write_reg!(dma, self.dma2, NDTR3, tx.len() as u32);
         write_reg!(dma, self.dma2, M0AR2, rx.as_mut_ptr() as u32);
         write_reg!(dma, self.dma2, M0AR3, tx.as_ptr() as u32);
+        // Drain stores to the buffers before the transfer starts.
+        cortex_m::asm::dsb();
         modify_reg!(dma, self.dma2, CR2, EN: Enabled);
         modify_reg!(dma, self.dma2, CR3, EN: Enabled);
     }
@@ -218,10 +272,14 @@ impl DMA {
     pub fn spi1_disable(&self) {
         modify_reg!(dma, self.dma2, CR2, EN: Disabled);
         modify_reg!(dma, self.dma2, CR3, EN: Disabled);
+        // Order reads of the received data after the transfer completed.
+        cortex_m::asm::dmb();
     }
 
     /// Sets up and enables a DMA transmit/receive for SPI2 (streams 3 and 4, channel 0)
     pub fn spi2_enable(&self, tx: &[u8], rx: &mut [u8]) {
+        check_buffer(tx);
+        check_buffer(rx);
         write_reg!(
             dma,
             self.dma1,
@@ -246,6 +304,8 @@ impl DMA {
         write_reg!(dma, self.dma1, NDTR4, tx.len() as u32);
         write_reg!(dma, self.dma1, M0AR3, rx.as_mut_ptr() as u32);
         write_reg!(dma, self.dma1, M0AR4, tx.as_ptr() as u32);
+        // Drain stores to the buffers before the transfer starts.
+        cortex_m::asm::dsb();
         modify_reg!(dma, self.dma1, CR3, EN: Enabled);
         modify_reg!(dma, self.dma1, CR4, EN: Enabled);
     }
@@ -259,10 +319,13 @@ impl DMA {
     pub fn spi2_disable(&self) {
         modify_reg!(dma, self.dma1, CR3, EN: Disabled);
         modify_reg!(dma, self.dma1, CR4, EN: Disabled);
+        // Order reads of the received data after the transfer completed.
+        cortex_m::asm::dmb();
     }
 
     /// Start USART1 reception into provided buffer
     pub fn usart1_start(&self, rx: &mut [u8]) {
+        check_buffer(rx);
         write_reg!(
             dma,
             self.dma2,
@@ -275,9 +338,20 @@ impl DMA {
         );
         write_reg!(dma, self.dma2, NDTR5, rx.len() as u32);
         write_reg!(dma, self.dma2, M0AR5, rx.as_mut_ptr() as u32);
+        cortex_m::asm::dsb();
         modify_reg!(dma, self.dma2, CR5, EN: Enabled);
     }
 
+    /// Clear the USART1 RX half and fully complete flags, set each time
+    /// the DMA reaches the middle and the end of the circular buffer.
+    ///
+    /// Call this function from the DMA2 stream 5 interrupt handler.
+    pub fn on_usart1_rx_interrupt() {
+        // Safety: only the flags of stream 5 are written, which are not otherwise used.
+        let dma2 = unsafe { &*dma::DMA2 };
+        write_reg!(dma, dma2, HIFCR, CHTIF5: Clear, CTCIF5: Clear);
+    }
+
     /// Return how many bytes are left to transfer for USART1
     pub fn usart1_ndtr(&self) -> usize {
         read_reg!(dma, self.dma2, NDTR5) as usize
@@ -288,8 +362,40 @@ impl DMA {
         modify_reg!(dma, self.dma2, CR5, EN: Disabled);
     }
 
+    /// Return how many bytes are left to transfer for USART1 TX
+    pub fn usart1_tx_ndtr(&self) -> usize {
+        read_reg!(dma, self.dma2, NDTR7) as usize
+    }
+
+    /// Start a DMA transfer for USART1 TX
+    pub fn usart1_start_tx_transfer(&self, tx: &[u8], len: usize) {
+        check_buffer(tx);
+        write_reg!(
+            dma,
+            self.dma2,
+            HIFCR,
+            CTCIF7: Clear,
+            CHTIF7: Clear,
+            CTEIF7: Clear,
+            CDMEIF7: Clear,
+            CFEIF7: Clear
+        );
+
+        modify_reg!(dma, self.dma2, CR7, EN: Disabled);
+        write_reg!(dma, self.dma2, NDTR7, len as u32);
+        write_reg!(dma, self.dma2, M0AR7, tx.as_ptr() as u32);
+        cortex_m::asm::dsb();
+        modify_reg!(dma, self.dma2, CR7, EN: Enabled);
+    }
+
+    /// Stop USART1 TX DMA
+    pub fn usart1_stop_tx(&self) {
+        modify_reg!(dma, self.dma2, CR7, EN: Disabled);
+    }
+
     /// Start USART2 reception into provided buffer
     pub fn usart2_start_rx(&self, rx: &mut [u8]) {
+        check_buffer(rx);
         write_reg!(
             dma,
             self.dma1,
@@ -302,6 +408,7 @@ impl DMA {
         );
         write_reg!(dma, self.dma1, NDTR5, rx.len() as u32);
         write_reg!(dma, self.dma1, M0AR5, rx.as_mut_ptr() as u32);
+        cortex_m::asm::dsb();
         modify_reg!(dma, self.dma1, CR5, EN: Enabled);
     }
 
@@ -316,6 +423,7 @@ impl DMA {
 
     /// Start a DMA transfer for USART2 TX
     pub fn usart2_start_tx_transfer(&self, tx: &[u8], len: usize) {
+        check_buffer(tx);
         write_reg!(
             dma,
             self.dma1,
@@ -337,9 +445,71 @@ impl DMA {
         modify_reg!(dma, self.dma1, CR6, EN: Enabled);
     }
 
-    /// Stop USART2 DMA
-    pub fn usart2_stop(&self) {
+    /// Clear the USART2 TX transfer complete flag.
+    ///
+    /// Call this function from the DMA1 stream 6 interrupt handler.
+    pub fn on_usart2_tx_interrupt() {
+        // Safety: only the transfer complete flag of stream 6 is written,
+        // which is not otherwise used.
+        let dma1 = unsafe { &*dma::DMA1 };
+        write_reg!(dma, dma1, HIFCR, CTCIF6: Clear);
+    }
+
+    /// Stop USART2 RX DMA
+    pub fn usart2_stop_rx(&self) {
         modify_reg!(dma, self.dma1, CR5, EN: Disabled);
+    }
+
+    /// Stop USART2 TX DMA
+    pub fn usart2_stop_tx(&self) {
         modify_reg!(dma, self.dma1, CR6, EN: Disabled);
     }
+
+    /// Start circular reception into the provided buffer for the given USART
+    pub fn uart_start_rx(&self, uart: UartStreams, rx: &mut [u8]) {
+        match uart {
+            UartStreams::USART1 => self.usart1_start(rx),
+            UartStreams::USART2 => self.usart2_start_rx(rx),
+        }
+    }
+
+    /// Return how many bytes are left to receive before the RX buffer wraps around
+    pub fn uart_rx_ndtr(&self, uart: UartStreams) -> usize {
+        match uart {
+            UartStreams::USART1 => self.usart1_ndtr(),
+            UartStreams::USART2 => self.usart2_rx_ndtr(),
+        }
+    }
+
+    /// Stop RX DMA for the given USART
+    pub fn uart_stop_rx(&self, uart: UartStreams) {
+        match uart {
+            UartStreams::USART1 => self.usart1_stop(),
+            UartStreams::USART2 => self.usart2_stop_rx(),
+        }
+    }
+
+    /// Start a TX DMA transfer of `len` bytes for the given USART
+    pub fn uart_start_tx(&self, uart: UartStreams, tx: &[u8], len: usize) {
+        match uart {
+            UartStreams::USART1 => self.usart1_start_tx_transfer(tx, len),
+            UartStreams::USART2 => self.usart2_start_tx_transfer(tx, len),
+        }
+    }
+
+    /// Return how many bytes are left to transmit for the given USART
+    pub fn uart_tx_ndtr(&self, uart: UartStreams) -> usize {
+        match uart {
+            UartStreams::USART1 => self.usart1_tx_ndtr(),
+            UartStreams::USART2 => self.usart2_tx_ndtr(),
+        }
+    }
+
+    /// Stop TX DMA for the given USART
+    pub fn uart_stop_tx(&self, uart: UartStreams) {
+        match uart {
+            UartStreams::USART1 => self.usart1_stop_tx(),
+            UartStreams::USART2 => self.usart2_stop_tx(),
+        }
+    }
 }
@@ -0,0 +1,109 @@
+//! Erasing and programming the internal flash
+
+use stm32ral::{flash, modify_reg, read_reg, write_reg};
+
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xCDEF_89AB;
+
+/// SR error flags: OPERR, WRPERR, PGAERR, PGPERR and ERSERR.
+const SR_ERRORS: u32 = (1 << 1) | (0b1111 << 4);
+/// SR end of operation flag.
+const SR_EOP: u32 = 1 << 0;
+
+/// Program in 32-bit units, which requires a 2.7V to 3.6V supply.
+const PSIZE_X32: u32 = 0b10;
+
+/// Offset from the AXIM flash address to the same flash on the ITCM interface.
+///
+/// Reading through the ITCM interface bypasses the D-cache, so data is never
+/// stale after the flash has been changed.
+const ITCM_OFFSET: u32 = 0x0800_0000 - 0x0020_0000;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Error {
+    /// The flash reported an error flag, given as the SR value.
+    Flash(u32),
+    /// The address is not word aligned.
+    Alignment,
+}
+
+pub struct Flash {
+    flash: flash::Instance,
+}
+
+impl Flash {
+    pub fn new(flash: flash::Instance) -> Self {
+        Flash { flash }
+    }
+
+    /// Erase sector `sector`.
+    ///
+    /// Code keeps running from flash, but stalls on flash accesses until the
+    /// erase has finished, which can take over a second for a 128KB sector.
+    pub fn erase_sector(&self, sector: u32) -> Result<(), Error> {
+        self.unlock();
+        modify_reg!(flash, self.flash, CR, PG: 0, SER: 1, SNB: sector, PSIZE: PSIZE_X32);
+        modify_reg!(flash, self.flash, CR, STRT: 1);
+        let result = self.wait();
+        modify_reg!(flash, self.flash, CR, SER: 0);
+        self.lock();
+        result
+    }
+
+    /// Program `data` starting at the word aligned AXIM `address`, which must have been erased.
+    ///
+    /// Only the configuration store sector is writable once the MPU is set up.
+    /// Host builds have no `mpu` module, and leave the MPU alone.
+    pub fn program(&self, address: u32, data: &[u32]) -> Result<(), Error> {
+        if address % 4 != 0 {
+            return Err(Error::Alignment);
+        }
+        self.unlock();
+        #[cfg(not(feature = "host"))]
+        crate::mpu::set_config_writable(true);
+        modify_reg!(flash, self.flash, CR, SER: 0, PG: 1, PSIZE: PSIZE_X32);
+        let mut result = Ok(());
+        for (i, &word) in data.iter().enumerate() {
+            let ptr = (address as usize + i * 4) as *mut u32;
+            unsafe { core::ptr::write_volatile(ptr, word) };
+            cortex_m::asm::dsb();
+            result = self.wait();
+            if result.is_err() {
+                break;
+            }
+        }
+        modify_reg!(flash, self.flash, CR, PG: 0);
+        #[cfg(not(feature = "host"))]
+        crate::mpu::set_config_writable(false);
+        self.lock();
+        result
+    }
+
+    /// Read the word at the AXIM flash `address`, bypassing the D-cache.
+    pub fn read_word(&self, address: u32) -> u32 {
+        unsafe { core::ptr::read_volatile((address - ITCM_OFFSET) as *const u32) }
+    }
+
+    fn unlock(&self) {
+        if read_reg!(flash, self.flash, CR, LOCK) != 0 {
+            write_reg!(flash, self.flash, KEYR, KEY1);
+            write_reg!(flash, self.flash, KEYR, KEY2);
+        }
+    }
+
+    fn lock(&self) {
+        modify_reg!(flash, self.flash, CR, LOCK: 1);
+    }
+
+    /// Wait for the current operation to finish, then clear and check its flags.
+    fn wait(&self) -> Result<(), Error> {
+        while read_reg!(flash, self.flash, SR, BSY) != 0 {}
+        let sr = read_reg!(flash, self.flash, SR);
+        write_reg!(flash, self.flash, SR, sr & (SR_ERRORS | SR_EOP));
+        if sr & SR_ERRORS != 0 {
+            Err(Error::Flash(sr))
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -196,6 +196,53 @@ impl<'a> GPIO {
         let n = n as u8;
         (self.get_idr() & (1 << n)) >> n
     }
+
+    /// Set every pin in `mask` to the matching bit of `value` with a single
+    /// atomic BSRR write, leaving all other pins unchanged.
+    #[inline(always)]
+    pub fn write_port(&'a self, mask: u16, value: u16) -> &Self {
+        let set = (value & mask) as u32;
+        let reset = (!value & mask) as u32;
+        write_reg!(gpio, self.p, BSRR, set | (reset << 16));
+        self
+    }
+
+    /// Read the input state of all 16 pins at once.
+    #[inline(always)]
+    pub fn read_port(&'a self) -> u16 {
+        read_reg!(gpio, self.p, IDR) as u16
+    }
+
+    /// Read the output state of all 16 pins at once.
+    #[inline(always)]
+    pub fn read_port_output(&'a self) -> u16 {
+        read_reg!(gpio, self.p, ODR) as u16
+    }
+
+    /// Lock the configuration of every pin in `mask` until the next reset.
+    ///
+    /// The mode, output type, speed, pull and alternate function of locked pins
+    /// can no longer be changed, but their output level can. A port can only be
+    /// locked once, so all pins to lock on it must be given together.
+    ///
+    /// Returns true if the lock was applied.
+    pub fn lock(&'a self, mask: u16) -> bool {
+        const LCKK: u32 = 1 << 16;
+        let mask = mask as u32;
+        write_reg!(gpio, self.p, LCKR, LCKK | mask);
+        write_reg!(gpio, self.p, LCKR, mask);
+        write_reg!(gpio, self.p, LCKR, LCKK | mask);
+        let _ = read_reg!(gpio, self.p, LCKR);
+        read_reg!(gpio, self.p, LCKR) & LCKK != 0
+    }
+
+    /// Fill `samples` with back-to-back snapshots of the input state of all pins.
+    #[inline]
+    pub fn sample_port(&'a self, samples: &mut [u16]) {
+        for sample in samples.iter_mut() {
+            *sample = self.read_port();
+        }
+    }
 }
 
 /// Stores a pre-computed mask and value for quickly changing pin mode
@@ -238,6 +285,18 @@ pub struct Pin<'a> {
 }
 
 impl<'a> Pin<'a> {
+    /// Bit mask of this pin within its port, for use with `GPIO::write_port`.
+    #[inline(always)]
+    pub fn mask(&self) -> u16 {
+        1 << (self.n as u8)
+    }
+
+    /// The port this pin belongs to.
+    #[inline(always)]
+    pub fn port(&self) -> &'a GPIO {
+        self.port
+    }
+
     #[inline(always)]
     pub fn set_high(&self) -> &Self {
         self.port.set_high(self.n);
@@ -291,6 +350,13 @@ impl<'a> Pin<'a> {
         }
     }
 
+    /// Returns true if the output data register is set high for this pin,
+    /// which for an open-drain output may differ from the line state.
+    #[inline(always)]
+    pub fn is_set_high(&self) -> bool {
+        self.port.read_port_output() & self.mask() != 0
+    }
+
     #[inline(always)]
     pub fn toggle(&'a self) -> &Self {
         self.port.toggle(self.n);
@@ -404,6 +470,9 @@ impl<'a> Pin<'a> {
     }
 }
 
+/// Number of spare pins a board can expose to the host.
+pub const MAX_SPARE_PINS: usize = 8;
+
 pub struct Pins<'a> {
     pub led_red: Pin<'a>,
     pub led_green: Pin<'a>,
@@ -412,10 +481,13 @@ pub struct Pins<'a> {
     pub t5v_en: Pin<'a>,
     pub tvcc_en: Pin<'a>,
     pub reset: Pin<'a>,
+    pub ntrst: Pin<'a>,
     pub gnd_detect: Pin<'a>,
 
     // Used for SWO in SWD mode
     pub usart1_rx: Pin<'a>,
+    // Spare USART1 transmitter
+    pub usart1_tx: Pin<'a>,
 
     // Used for external serial interface
     pub usart2_rx: Pin<'a>,
@@ -435,6 +507,22 @@ pub struct Pins<'a> {
     pub usb_dm: Pin<'a>,
     pub usb_dp: Pin<'a>,
     pub usb_sel: Pin<'a>,
+
+    // VBUS sense input, on boards which have one
+    pub vbus: Option<Pin<'a>>,
+    // tVCC voltage measurement input, on boards which have one
+    pub tvcc_sense: Option<SensePin<'a>>,
+
+    // Spare pin which can output PWM from TIM9, also in `spare`
+    pub pwm: Option<Pin<'a>>,
+    // Spare pin whose frequency can be measured with TIM2, also in `spare`
+    pub freq: Option<Pin<'a>>,
+
+    // Pins unused by the firmware, for the host to drive fixture signals
+    pub spare: [Option<Pin<'a>>; MAX_SPARE_PINS],
+
+    // Direction of a level shifter on SWDIO/TMS, on boards which have one
+    pub swdio_dir: Option<DirectionPin<'a>>,
 }
 
 impl<'a> Pins<'a> {
@@ -480,12 +568,26 @@ impl<'a> Pins<'a> {
             .set_ospeed_high()
             .set_mode_output();
 
+        // Open-drain output to JTAG nTRST line (active low).
+        self.ntrst
+            .set_high()
+            .set_otype_opendrain()
+            .set_ospeed_high()
+            .set_mode_output();
+
         // Input for GNDDetect
         self.gnd_detect.set_pull_up().set_mode_input();
 
         // Used for SWO in SWD mode. Starts high-impedance.
         self.usart1_rx.set_af(7).set_mode_input();
 
+        // Push-pull output for USART1 TX. Starts high-impedance.
+        self.usart1_tx
+            .set_af(7)
+            .set_otype_pushpull()
+            .set_ospeed_high()
+            .set_mode_input();
+
         // VCP pins
         self.usart2_rx.set_af(7).set_pull_up().set_mode_alternate();
         self.usart2_tx
@@ -544,6 +646,129 @@ impl<'a> Pins<'a> {
             .set_otype_pushpull()
             .set_ospeed_low()
             .set_mode_output();
+
+        // Input for VBUS sensing, pulled down so it reads low without VBUS.
+        if let Some(vbus) = &self.vbus {
+            vbus.set_pull_down().set_mode_input();
+        }
+
+        // Analog input for tVCC measurement.
+        if let Some(sense) = &self.tvcc_sense {
+            sense.pin.set_pull_floating().set_mode_analog();
+        }
+
+        // Spare pins start as floating inputs, until the host configures them.
+        for pin in self.spare.iter().flatten() {
+            pin.set_pull_floating().set_mode_input();
+        }
+
+        // Push-pull output to the SWDIO level shifter direction, starting
+        // towards the probe as SWDIO starts high-impedance.
+        if let Some(dir) = &self.swdio_dir {
+            dir.input();
+            dir.pin
+                .set_otype_pushpull()
+                .set_ospeed_high()
+                .set_mode_output();
+        }
+    }
+
+    /// Set the output speed of the SWCLK/TCK, SWDIO/TMS and TDI outputs, from
+    /// 0 (low) to 3 (very high, the default), trading edge rate against ringing.
+    pub fn set_debug_ospeed(&self, ospeed: u32) {
+        let pins = [
+            &self.spi1_clk,
+            &self.spi1_mosi,
+            &self.spi2_clk,
+            &self.spi2_mosi,
+        ];
+        for pin in pins.iter() {
+            pin.port.set_ospeed(pin.n, ospeed & 0b11);
+        }
+    }
+
+    /// Set the internal pulls of the SWDIO, TDO, TDI and nRESET pins, for
+    /// targets without external pulls. `pulls` has two bits per pin in that
+    /// order from bit 0, 0 for none, 1 for pull-up and 2 for pull-down.
+    pub fn set_target_pulls(&self, pulls: u32) {
+        let pins = [
+            &self.spi1_miso,
+            &self.spi2_miso,
+            &self.spi2_mosi,
+            &self.reset,
+        ];
+        for (i, pin) in pins.iter().enumerate() {
+            pin.port.set_pull(pin.n, (pulls >> (2 * i)) & 0b11);
+        }
+    }
+
+    /// Connect the PWM pin to the TIM9 output.
+    pub fn pwm_mode(&self) {
+        if let Some(pwm) = &self.pwm {
+            pwm.set_af(3)
+                .set_otype_pushpull()
+                .set_ospeed_low()
+                .set_mode_alternate();
+        }
+    }
+
+    /// Return the PWM pin to a floating input.
+    pub fn pwm_release(&self) {
+        if let Some(pwm) = &self.pwm {
+            pwm.set_mode_input();
+        }
+    }
+
+    /// Connect the frequency measurement pin to the TIM2 input.
+    pub fn freq_mode(&self) {
+        if let Some(freq) = &self.freq {
+            freq.set_af(1).set_pull_floating().set_mode_alternate();
+        }
+    }
+
+    /// Return the frequency measurement pin to a floating input.
+    pub fn freq_release(&self) {
+        if let Some(freq) = &self.freq {
+            freq.set_mode_input();
+        }
+    }
+
+    /// Number of spare pins on this board.
+    pub fn spare_count(&self) -> usize {
+        self.spare.iter().filter(|pin| pin.is_some()).count()
+    }
+
+    /// Check if VBUS is present, or None if the board can't sense it.
+    pub fn vbus_present(&self) -> Option<bool> {
+        self.vbus.as_ref().map(|vbus| vbus.is_high())
+    }
+
+    /// Lock the configuration of the USB and target power enable pins,
+    /// so no later bug can reconfigure them. Call once after `setup()`.
+    ///
+    /// Returns true if all locks were applied.
+    pub fn lock_critical(&self) -> bool {
+        let pins = [
+            &self.usb_dm,
+            &self.usb_dp,
+            &self.usb_sel,
+            &self.t5v_en,
+            &self.tvcc_en,
+        ];
+        let mut locked = true;
+        for (i, pin) in pins.iter().enumerate() {
+            // Each port is locked once, with the pins of all later entries on it.
+            let port = pin.port();
+            if pins[..i].iter().any(|p| core::ptr::eq(p.port(), port)) {
+                continue;
+            }
+            let mask = pins[i..]
+                .iter()
+                .filter(|p| core::ptr::eq(p.port(), port))
+                .fold(0, |mask, p| mask | p.mask());
+            locked &= port.lock(mask);
+        }
+        locked
     }
 
     /// Place SPI pins into high-impedance mode
@@ -551,12 +776,54 @@ impl<'a> Pins<'a> {
     pub fn high_impedance_mode(&self) {
         self.reset.set_high().set_mode_output();
         self.usart1_rx.set_mode_input();
+        self.usart1_tx.set_mode_input();
         self.spi1_clk.set_mode_input();
         self.spi1_miso.set_mode_input();
         self.spi1_mosi.set_mode_input();
         self.spi2_clk.set_mode_input();
         self.spi2_miso.set_mode_input();
         self.spi2_mosi.set_mode_input();
+        self.swdio_release();
+    }
+
+    /// Set the SWDIO level shifter, if any, to drive the target.
+    #[inline]
+    fn swdio_drive(&self) {
+        if let Some(dir) = &self.swdio_dir {
+            dir.output();
+        }
+    }
+
+    /// Set the SWDIO level shifter, if any, to pass the target's level to the probe.
+    #[inline]
+    fn swdio_release(&self) {
+        if let Some(dir) = &self.swdio_dir {
+            dir.input();
+        }
+    }
+
+    /// Place USART1 RX pin into single-wire half-duplex mode, for UPDI
+    #[inline]
+    pub fn usart1_half_duplex_mode(&self) {
+        self.usart1_rx
+            .set_otype_opendrain()
+            .set_pull_up()
+            .set_mode_alternate();
+    }
+
+    /// Return USART1 RX pin from half-duplex mode to a high-impedance input
+    #[inline]
+    pub fn usart1_rx_mode(&self) {
+        self.usart1_rx
+            .set_otype_pushpull()
+            .set_pull_floating()
+            .set_mode_input();
+    }
+
+    /// Connect USART1 TX pin to the USART1 transmitter
+    #[inline]
+    pub fn usart1_tx_mode(&self) {
+        self.usart1_tx.set_mode_alternate();
     }
 
     /// Place SPI pins into JTAG mode
@@ -566,12 +833,53 @@ impl<'a> Pins<'a> {
         self.usart1_rx.set_mode_input();
         self.spi1_clk.set_mode_input();
         self.spi1_miso.set_mode_input();
+        self.swdio_drive();
         self.spi1_mosi.set_mode_output();
         self.spi2_clk.set_mode_output();
         self.spi2_miso.set_mode_input();
         self.spi2_mosi.set_mode_output();
     }
 
+    /// Place SPI2 pins into AVR ISP mode, with SPI2 driving SCK/MOSI
+    #[inline]
+    pub fn avrisp_mode(&self) {
+        self.reset.set_mode_output();
+        self.usart1_rx.set_mode_input();
+        self.spi1_clk.set_mode_input();
+        self.spi1_miso.set_mode_input();
+        self.spi1_mosi.set_mode_input();
+        self.swdio_release();
+        self.spi2_clk.set_mode_alternate();
+        self.spi2_miso.set_mode_alternate();
+        self.spi2_mosi.set_mode_alternate();
+    }
+
+    /// Place SWDIO pin into open-drain output mode for STM8 SWIM
+    #[inline]
+    pub fn swim_mode(&self) {
+        self.reset.set_high().set_mode_output();
+        self.usart1_rx.set_mode_input();
+        self.spi1_clk.set_mode_input();
+        self.spi1_miso.set_mode_input();
+        self.spi1_mosi
+            .set_high()
+            .set_otype_opendrain()
+            .set_pull_up()
+            .set_mode_output();
+        self.spi2_clk.set_mode_input();
+        self.spi2_miso.set_mode_input();
+        self.spi2_mosi.set_mode_input();
+    }
+
+    /// Return SWDIO pin from SWIM mode to a high-impedance input
+    #[inline]
+    pub fn swim_exit(&self) {
+        self.spi1_mosi
+            .set_otype_pushpull()
+            .set_pull_floating()
+            .set_mode_input();
+    }
+
     /// Place SPI pins into SWD mode
     #[inline]
     pub fn swd_mode(&self) {
@@ -582,36 +890,139 @@ impl<'a> Pins<'a> {
         self.spi2_mosi.set_mode_input();
         self.spi1_clk.set_mode_alternate();
         self.spi1_miso.set_mode_alternate();
+        self.swdio_drive();
         self.spi1_mosi.set_mode_alternate();
     }
 
-    /// Disconnect SPI1_MOSI from SWDIO, target drives the bus
+    /// Place SPI pins into SWD mode for the second SWD port, on the JTAG pins.
+    ///
+    /// SWCLK is shared, as SPI1_CLK and SPI2_CLK are connected, so SPI2 drives
+    /// it while the first port's SWDIO is held low, which the first target
+    /// sees as idle cycles. The second target's SWDIO must be connected to
+    /// both TDI and TDO.
+    #[inline]
+    pub fn swd2_mode(&self) {
+        self.reset.set_mode_output();
+        self.usart1_rx.set_mode_alternate();
+        self.spi1_clk.set_mode_input();
+        self.spi1_miso.set_mode_input();
+        self.swdio_drive();
+        self.spi1_mosi.set_low().set_mode_output();
+        self.spi2_clk.set_mode_alternate();
+        self.spi2_miso.set_mode_alternate();
+        self.spi2_mosi.set_mode_alternate();
+    }
+
+    /// Hold the second SWD port's SWDIO low while the first port is in use,
+    /// so the second target sees idle cycles rather than a line reset.
+    #[inline]
+    pub fn swd2_park(&self) {
+        self.spi2_mosi.set_low().set_mode_output();
+    }
+
+    /// Pins of the first SWD port, on SPI1.
+    pub fn swd_pins(&self) -> SWDPins {
+        SWDPins {
+            swclk: &self.spi1_clk,
+            swdio_in: &self.spi1_miso,
+            swdio_out: &self.spi1_mosi,
+            swdio_dir: self.swdio_dir.as_ref(),
+        }
+    }
+
+    /// Pins of the second SWD port, on SPI2.
+    pub fn swd2_pins(&self) -> SWDPins {
+        SWDPins {
+            swclk: &self.spi2_clk,
+            swdio_in: &self.spi2_miso,
+            swdio_out: &self.spi2_mosi,
+            // TDI and TDO each only go one way through a level shifter.
+            swdio_dir: None,
+        }
+    }
+}
+
+/// Analog input measuring a voltage through a divider.
+pub struct SensePin<'a> {
+    pub pin: Pin<'a>,
+    /// ADC channel of the pin.
+    pub channel: u8,
+    /// The measured voltage is `mul / div` of the voltage at the pin.
+    pub mul: u32,
+    pub div: u32,
+}
+
+/// Direction control of an external level shifter on a bidirectional target pin.
+pub struct DirectionPin<'a> {
+    pub pin: Pin<'a>,
+    /// Level which makes the shifter drive from the probe towards the target.
+    pub output_level: bool,
+}
+
+impl<'a> DirectionPin<'a> {
+    /// Make the shifter drive the probe's output onto the target pin.
+    #[inline(always)]
+    pub fn output(&self) {
+        self.pin.set_bool(self.output_level);
+    }
+
+    /// Make the shifter pass the target pin's level to the probe.
+    #[inline(always)]
+    pub fn input(&self) {
+        self.pin.set_bool(!self.output_level);
+    }
+}
+
+/// SPI pins of one SWD port, with MOSI and MISO both connected to SWDIO.
+///
+/// On boards with a direction-controlled level shifter on SWDIO, `swdio_dir`
+/// is switched along with MOSI, so the bus turnaround also turns the shifter
+/// around: towards the probe only after MOSI stops driving, and towards the
+/// target before MOSI starts.
+pub struct SWDPins<'a> {
+    pub swclk: &'a Pin<'a>,
+    pub swdio_in: &'a Pin<'a>,
+    pub swdio_out: &'a Pin<'a>,
+    pub swdio_dir: Option<&'a DirectionPin<'a>>,
+}
+
+impl<'a> SWDPins<'a> {
+    /// Disconnect MOSI from SWDIO, target drives the bus
     #[inline]
     pub fn swd_rx(&self) {
-        self.spi1_mosi.set_mode_input();
+        self.swdio_out.set_mode_input();
+        if let Some(dir) = self.swdio_dir {
+            dir.input();
+        }
     }
 
-    /// Connect SPI1_MOSI to SWDIO, SPI drives the bus
+    /// Connect MOSI to SWDIO, SPI drives the bus
     #[inline]
     pub fn swd_tx(&self) {
-        self.spi1_mosi.set_mode_alternate();
+        if let Some(dir) = self.swdio_dir {
+            dir.output();
+        }
+        self.swdio_out.set_mode_alternate();
     }
 
-    /// Connect SPI1_MOSI to SWDIO, manual bitbanging
+    /// Connect MOSI to SWDIO, manual bitbanging
     #[inline]
     pub fn swd_tx_direct(&self) {
-        self.spi1_mosi.set_mode_output();
+        if let Some(dir) = self.swdio_dir {
+            dir.output();
+        }
+        self.swdio_out.set_mode_output();
     }
 
-    /// Swap SPI1_CLK pin to direct output mode for manual driving
+    /// Swap the SPI clock pin to direct output mode for manual driving
     #[inline]
     pub fn swd_clk_direct(&self) {
-        self.spi1_clk.set_mode_output();
+        self.swclk.set_mode_output();
     }
 
-    /// Swap SPI1_CLK pin back to alternate mode for SPI use
+    /// Swap the SPI clock pin back to alternate mode for SPI use
     #[inline]
     pub fn swd_clk_spi(&self) {
-        self.spi1_clk.set_mode_alternate();
+        self.swclk.set_mode_alternate();
     }
 }
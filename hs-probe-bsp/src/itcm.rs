@@ -0,0 +1,24 @@
+extern "C" {
+    static mut __sitcm: u32;
+    static mut __eitcm: u32;
+    static __siitcm: u32;
+}
+
+/// Call this function at boot in pre_init, before any code in ITCM is run.
+///
+/// Copies functions placed in the `.itcm` link section from their load
+/// address in flash into ITCM RAM. The linker script must provide the
+/// `__sitcm`, `__eitcm` and `__siitcm` symbols.
+pub fn init() {
+    unsafe {
+        let start = &mut __sitcm as *mut u32;
+        let end = &mut __eitcm as *mut u32;
+        let load = &__siitcm as *const u32;
+        let words = (end as usize - start as usize) / 4;
+        for i in 0..words {
+            core::ptr::write_volatile(start.add(i), core::ptr::read(load.add(i)));
+        }
+    }
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+}
@@ -3,11 +3,32 @@
 pub use cortex_m;
 pub use stm32ral;
 
+#[cfg(feature = "adc")]
+pub mod adc;
+pub mod board;
+#[cfg(not(feature = "host"))]
 pub mod bootload;
 pub mod delay;
+#[cfg(feature = "dma")]
 pub mod dma;
+#[cfg(feature = "flash")]
+pub mod flash;
 pub mod gpio;
+#[cfg(not(feature = "host"))]
+pub mod itcm;
+#[cfg(not(feature = "host"))]
+pub mod mpu;
+#[cfg(feature = "usb")]
 pub mod otg_hs;
 pub mod rcc;
+pub mod ring;
+#[cfg(not(feature = "host"))]
+pub mod signature;
+#[cfg(feature = "spi")]
 pub mod spi;
+#[cfg(not(feature = "host"))]
+pub mod stack;
+#[cfg(feature = "tim")]
+pub mod tim;
+#[cfg(feature = "uart")]
 pub mod uart;
@@ -0,0 +1,122 @@
+//! Memory protection unit regions.
+//!
+//! Without the MPU, a stray write with flash programming enabled can corrupt
+//! the firmware, and a stack overflow runs off the bottom of DTCM. The
+//! regions below turn both into MemManage faults, and make SRAM2 a
+//! non-cacheable area for DMA buffers which can't live on the stack in DTCM.
+
+use cortex_m::peripheral::MPU;
+
+/// Flash on the AXIM interface, read-only so that a stray write with PG set can't program it.
+const FLASH_BASE: u32 = 0x0800_0000;
+const FLASH_SIZE_LOG2: u32 = 19;
+
+/// The last sector, holding the configuration store, which is made writable while it is programmed.
+const CONFIG_BASE: u32 = 0x0806_0000;
+const CONFIG_SIZE_LOG2: u32 = 17;
+
+/// SRAM2, which memory.x keeps for the `.sram2` section of non-cacheable DMA buffers.
+const SRAM2_BASE: u32 = 0x2003_C000;
+const SRAM2_SIZE_LOG2: u32 = 14;
+
+/// Bottom of DTCM, where the stack ends, with no access so an overflow faults.
+pub const STACK_GUARD_BASE: u32 = 0x2000_0000;
+const STACK_GUARD_SIZE_LOG2: u32 = 5;
+/// Size of the stack guard, which the stack can't use.
+pub const STACK_GUARD_SIZE: u32 = 1 << STACK_GUARD_SIZE_LOG2;
+
+/// Regions in priority order, as a higher region number takes precedence.
+const FLASH_REGION: u32 = 0;
+const CONFIG_REGION: u32 = 1;
+const SRAM2_REGION: u32 = 2;
+const STACK_GUARD_REGION: u32 = 3;
+
+// RASR fields
+const RASR_ENABLE: u32 = 1;
+const RASR_XN: u32 = 1 << 28;
+const AP_NONE: u32 = 0;
+const AP_RW: u32 = 0b011 << 24;
+const AP_RO: u32 = 0b110 << 24;
+/// Normal memory, write-through, as flash is cached by default: TEX 0, C 1, B 0.
+const NORMAL_WT: u32 = 1 << 17;
+/// Normal memory, non-cacheable and shareable: TEX 1, C 0, B 0, S 1.
+const NORMAL_NC: u32 = 1 << 19 | 1 << 18;
+
+// CTRL fields
+const CTRL_ENABLE: u32 = 1 << 0;
+const CTRL_PRIVDEFENA: u32 = 1 << 2;
+
+/// SHCSR bit enabling MemManage faults, which otherwise escalate to HardFault.
+const SHCSR_MEMFAULTENA: u32 = 1 << 16;
+
+fn rasr(size_log2: u32, attrs: u32) -> u32 {
+    attrs | (size_log2 - 1) << 1 | RASR_ENABLE
+}
+
+unsafe fn set_region(
+    mpu: &cortex_m::peripheral::mpu::RegisterBlock,
+    region: u32,
+    base: u32,
+    rasr: u32,
+) {
+    mpu.rnr.write(region);
+    mpu.rbar.write(base);
+    mpu.rasr.write(rasr);
+}
+
+/// Configure and enable the MPU.
+///
+/// Other addresses keep the default memory map.
+///
+/// Unsafety: this function should be called from the main context,
+/// before anything is placed in the `.sram2` section.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn setup() {
+    let mpu = &*MPU::PTR;
+    cortex_m::asm::dmb();
+    mpu.ctrl.write(0);
+
+    set_region(
+        mpu,
+        FLASH_REGION,
+        FLASH_BASE,
+        rasr(FLASH_SIZE_LOG2, AP_RO | NORMAL_WT),
+    );
+    set_region(
+        mpu,
+        CONFIG_REGION,
+        CONFIG_BASE,
+        rasr(CONFIG_SIZE_LOG2, AP_RO | RASR_XN | NORMAL_WT),
+    );
+    set_region(
+        mpu,
+        SRAM2_REGION,
+        SRAM2_BASE,
+        rasr(SRAM2_SIZE_LOG2, AP_RW | RASR_XN | NORMAL_NC),
+    );
+    set_region(
+        mpu,
+        STACK_GUARD_REGION,
+        STACK_GUARD_BASE,
+        rasr(STACK_GUARD_SIZE_LOG2, AP_NONE | RASR_XN | NORMAL_NC),
+    );
+
+    let scb = &*cortex_m::peripheral::SCB::PTR;
+    scb.shcsr.modify(|r| r | SHCSR_MEMFAULTENA);
+    mpu.ctrl.write(CTRL_ENABLE | CTRL_PRIVDEFENA);
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+}
+
+/// Allow or forbid CPU writes to the configuration store sector, for programming it.
+pub fn set_config_writable(writable: bool) {
+    let ap = if writable { AP_RW } else { AP_RO };
+    cortex_m::interrupt::free(|_| unsafe {
+        let mpu = &*MPU::PTR;
+        mpu.rnr.write(CONFIG_REGION);
+        mpu.rasr
+            .write(rasr(CONFIG_SIZE_LOG2, ap | RASR_XN | NORMAL_WT));
+    });
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+}
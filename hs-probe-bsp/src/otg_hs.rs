@@ -11,8 +11,38 @@ pub struct USB {
     pub usb_device: otg_hs_device::Instance,
     pub usb_pwrclk: otg_hs_pwrclk::Instance,
     pub hclk: u32,
+    /// HSE frequency, which the HS PHY PLL is configured for.
+    pub hse: u32,
 }
 
+/// PLL1SEL value of the HS PHY for an HSE frequency, or None if the PHY can't use it.
+pub const fn phy_pll_sel(hse: u32) -> Option<u32> {
+    match hse {
+        12_000_000 => Some(0b000),
+        12_500_000 => Some(0b001),
+        16_000_000 => Some(0b011),
+        24_000_000 => Some(0b100),
+        25_000_000 => Some(0b101),
+        _ => None,
+    }
+}
+
+const fn phy_supports_all_hse() -> bool {
+    let mut i = 0;
+    while i < crate::rcc::HSE_FREQUENCIES.len() {
+        if phy_pll_sel(crate::rcc::HSE_FREQUENCIES[i]).is_none() {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+const _: () = assert!(
+    phy_supports_all_hse(),
+    "Every HSE frequency in rcc::HSE_FREQUENCIES must be supported by the HS PHY"
+);
+
 // We only store peripheral instances to enforce ownership,
 // so it's safe to share the USB object
 unsafe impl Send for USB {}
@@ -61,9 +91,8 @@ unsafe impl UsbPeripheral for USB {
         while read_reg!(usbphyc, phy, LDO, LDO_STATUS) == 0 {}
 
         // Setup PLL
-        write_reg!(usbphyc, phy, PLL1,
-            PLL1SEL: 0b000 // A value for 12MHz HSE
-        );
+        let pll1sel = phy_pll_sel(self.hse).expect("HSE frequency not supported by the HS PHY");
+        write_reg!(usbphyc, phy, PLL1, PLL1SEL: pll1sel);
         modify_reg!(usbphyc, phy, TUNE, |r| r | 0xF13);
         modify_reg!(usbphyc, phy, PLL1, PLL1EN: 1);
 
@@ -73,3 +102,33 @@ unsafe impl UsbPeripheral for USB {
 }
 
 pub type UsbBusType = UsbBus<USB>;
+
+/// Bus speed negotiated with the host.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Speed {
+    High = 0,
+    Full = 1,
+}
+
+/// Put the transceiver into a USB 2.0 high-speed test mode, given its test selector:
+/// 1 for Test_J, 2 for Test_K, 3 for Test_SE0_NAK and 4 for Test_Packet.
+///
+/// The test mode can only be left by resetting the peripheral.
+pub fn enter_test_mode(selector: u8) {
+    // Safety: TCTL is only used for test modes, which the bus driver does not touch.
+    let device = unsafe { &*otg_hs_device::OTG_HS_DEVICE };
+    modify_reg!(otg_hs_device, device, DCTL, TCTL: selector as u32);
+}
+
+/// Read the bus speed negotiated during the last USB reset.
+///
+/// Only meaningful once the host has reset the device to start enumeration.
+pub fn enumerated_speed() -> Speed {
+    // Safety: DSTS is a read-only status register, so reading it while
+    // the bus driver owns the peripheral has no side effects.
+    let device = unsafe { &*otg_hs_device::OTG_HS_DEVICE };
+    match read_reg!(otg_hs_device, device, DSTS, ENUMSPD) {
+        0b00 => Speed::High,
+        _ => Speed::Full,
+    }
+}
@@ -1,5 +1,26 @@
-use stm32ral::{flash, pwr, rcc};
-use stm32ral::{modify_reg, read_reg, reset_reg};
+use stm32ral::{flash, pwr, rcc, tim11};
+use stm32ral::{modify_reg, read_reg, reset_reg, write_reg};
+
+/// HSE frequencies boards may use, one of which is picked from the measurement.
+///
+/// The HS PHY PLL only accepts 12, 12.5, 16, 24 or 25MHz, and 12.5MHz can't be
+/// divided to the 1MHz or 2MHz PLL input, which leaves these.
+pub(crate) const HSE_FREQUENCIES: [u32; 4] = [12_000_000, 16_000_000, 24_000_000, 25_000_000];
+
+/// Divider from HSE to HSE_RTC, which is the TIM11 input used to measure HSE.
+const HSE_RTC_DIV: u32 = 25;
+
+/// Edges of HSE_RTC between two captures, set by the input capture prescaler.
+const HSE_RTC_EDGES: u32 = 8;
+
+/// Clock required by the USB FS peripheral and its PHY, and by SDMMC and the RNG.
+const CK48: u32 = 48_000_000;
+
+/// HSI frequency, which clocks TIM11 while HSE is measured.
+const HSI: u32 = 16_000_000;
+
+/// Polls of HSERDY before giving up on one HSE mode, well over the 2ms a crystal needs at HSI.
+const HSE_STARTUP_POLLS: u32 = 100_000;
 
 pub struct RCC {
     rcc: rcc::Instance,
@@ -10,6 +31,11 @@ impl RCC {
         RCC { rcc }
     }
 
+    /// Trim HSI, which HSE is measured against in `setup`, from 0 to 31 with 16 the default.
+    pub fn set_hsi_trim(&self, trim: u8) {
+        modify_reg!(rcc, self.rcc, CR, HSITRIM: trim as u32);
+    }
+
     /// Set up the device, enabling all required clocks
     ///
     /// Unsafety: this function should be called from the main context.
@@ -42,12 +68,12 @@ impl RCC {
         reset_reg!(rcc, self.rcc, RCC, APB1ENR);
         reset_reg!(rcc, self.rcc, RCC, APB2ENR);
 
-        // Configure HSE in bypass mode
-        modify_reg!(rcc, self.rcc, CR, HSEBYP: Bypassed);
-        // Start HSE
-        modify_reg!(rcc, self.rcc, CR, HSEON: On);
-        // Wait for HSE to be ready
-        while read_reg!(rcc, self.rcc, CR, HSERDY == NotReady) {}
+        // Start HSE, which is an oscillator on HS-Probe, but may be a crystal
+        // on derivative boards, and measure its frequency against HSI.
+        if !self.start_hse(true) && !self.start_hse(false) {
+            panic!("HSE failed to start");
+        }
+        let hse = self.measure_hse();
 
         // Calculate prescalers
         let ppre1;
@@ -70,35 +96,42 @@ impl RCC {
         modify_reg!(rcc, self.rcc, CFGR, HPRE: Div1, PPRE1: ppre1, PPRE2: ppre2);
 
         // Calculate PLL parameters and flash latency
-        let pllm = 6;
-        let plln;
+        let vco;
         let pllp;
-        let pllq;
         let flash_latency;
         let sysclk;
         match frequency {
             CoreFrequency::F48MHz => {
-                plln = 96;
+                vco = 192_000_000;
                 pllp = 0b01; // /4
-                pllq = 4;
                 flash_latency = 0b0001;
                 sysclk = 48_000_000;
             }
             CoreFrequency::F72MHz => {
-                plln = 144;
+                vco = 288_000_000;
                 pllp = 0b01; // /4
-                pllq = 6;
                 flash_latency = 0b0010;
                 sysclk = 72_000_000;
             }
             CoreFrequency::F216MHz => {
-                plln = 216;
+                vco = 432_000_000;
                 pllp = 0b00; // /2
-                pllq = 9;
                 flash_latency = 0b0111;
                 sysclk = 216_000_000;
             }
         }
+        // Divide HSE to the recommended 2MHz PLL input where possible, else 1MHz.
+        let pll_input = if hse % 2_000_000 == 0 {
+            2_000_000
+        } else {
+            1_000_000
+        };
+        let pllm = hse / pll_input;
+        let plln = vco / pll_input;
+        // Every VCO frequency above is a multiple of 48MHz, so PLLQ always
+        // provides CK48 and PLLSAI is not needed for it.
+        let pllq = vco / CK48;
+        debug_assert_eq!(vco % CK48, 0);
 
         // Configure PLL from HSE
         modify_reg!(
@@ -112,6 +145,9 @@ impl RCC {
             PLLQ: pllq
         );
 
+        // Take CK48 from PLLQ rather than PLLSAIP
+        modify_reg!(rcc, self.rcc, DCKCFGR2, CK48MSEL: 0);
+
         // Enable PWR domain and setup voltage scale and overdrive options
         modify_reg!(rcc, self.rcc, APB1ENR, PWREN: Enabled);
 
@@ -173,10 +209,82 @@ impl RCC {
             DMA1EN: Enabled,
             DMA2EN: Enabled
         );
-        modify_reg!(rcc, self.rcc, APB1ENR, SPI2EN: Enabled, USART2EN: Enabled);
-        modify_reg!(rcc, self.rcc, APB2ENR, SPI1EN: Enabled, USART1EN: Enabled);
+        modify_reg!(
+            rcc,
+            self.rcc,
+            APB1ENR,
+            SPI2EN: Enabled,
+            SPI3EN: Enabled,
+            TIM2EN: Enabled,
+            USART2EN: Enabled
+        );
+        modify_reg!(
+            rcc,
+            self.rcc,
+            APB2ENR,
+            ADC1EN: Enabled,
+            SPI1EN: Enabled,
+            SPI4EN: Enabled,
+            SPI5EN: Enabled,
+            TIM9EN: Enabled,
+            USART1EN: Enabled
+        );
 
-        Clocks { sysclk }
+        Clocks { sysclk, hse }
+    }
+
+    /// Start HSE, either in bypass mode for an external oscillator or for a crystal.
+    ///
+    /// Returns false, leaving HSE off, if it does not become ready.
+    unsafe fn start_hse(&self, bypass: bool) -> bool {
+        modify_reg!(rcc, self.rcc, CR, HSEON: Off);
+        while read_reg!(rcc, self.rcc, CR, HSERDY == Ready) {}
+        modify_reg!(rcc, self.rcc, CR, HSEBYP: bypass as u32);
+        modify_reg!(rcc, self.rcc, CR, HSEON: On);
+        for _ in 0..HSE_STARTUP_POLLS {
+            if read_reg!(rcc, self.rcc, CR, HSERDY == Ready) {
+                return true;
+            }
+        }
+        modify_reg!(rcc, self.rcc, CR, HSEON: Off);
+        false
+    }
+
+    /// Measure HSE with TIM11, which can capture HSE_RTC, while running from HSI.
+    ///
+    /// Returns the nearest frequency in `HSE_FREQUENCIES`.
+    unsafe fn measure_hse(&self) -> u32 {
+        // Run TIM11 from undivided HSI and capture every eighth edge of HSE_RTC.
+        modify_reg!(rcc, self.rcc, CFGR, HPRE: Div1, PPRE2: 0b000, RTCPRE: HSE_RTC_DIV);
+        modify_reg!(rcc, self.rcc, APB2ENR, TIM11EN: Enabled);
+        let tim = &*tim11::TIM11;
+        write_reg!(tim11, tim, PSC, 0);
+        write_reg!(tim11, tim, ARR, 0xFFFF);
+        write_reg!(tim11, tim, OR, RMP: 0b11);
+        write_reg!(tim11, tim, CCMR1_Input, CC1S: 0b01, IC1PSC: 0b11);
+        write_reg!(tim11, tim, CCER, CC1E: 1);
+        write_reg!(tim11, tim, SR, 0);
+        write_reg!(tim11, tim, CR1, CEN: 1);
+
+        // Reading CCR1 clears CC1IF, so discard the first capture, which may be stale.
+        let mut captures = [0; 3];
+        for capture in captures.iter_mut() {
+            while read_reg!(tim11, tim, SR, CC1IF) == 0 {}
+            *capture = read_reg!(tim11, tim, CCR1);
+        }
+        let ticks = captures[2].wrapping_sub(captures[1]) & 0xFFFF;
+
+        write_reg!(tim11, tim, CR1, 0);
+        write_reg!(tim11, tim, CCER, 0);
+        write_reg!(tim11, tim, OR, 0);
+        modify_reg!(rcc, self.rcc, APB2ENR, TIM11EN: Disabled);
+
+        let measured =
+            (HSI as u64 * (HSE_RTC_EDGES * HSE_RTC_DIV) as u64 / ticks.max(1) as u64) as u32;
+        *HSE_FREQUENCIES
+            .iter()
+            .min_by_key(|&&f| (f as i64 - measured as i64).abs())
+            .unwrap()
     }
 }
 
@@ -189,9 +297,20 @@ pub enum CoreFrequency {
 
 pub struct Clocks {
     sysclk: u32,
+    hse: u32,
 }
 
 impl Clocks {
+    /// HSE frequency, as measured at startup.
+    pub fn hse(&self) -> u32 {
+        self.hse
+    }
+
+    /// 48MHz clock for the USB FS peripheral, from PLLQ at every `CoreFrequency`.
+    pub fn ck48(&self) -> u32 {
+        CK48
+    }
+
     pub fn hclk(&self) -> u32 {
         let rcc = unsafe { &*rcc::RCC };
         let hpre = read_reg!(rcc, rcc, CFGR, HPRE);
@@ -235,4 +354,22 @@ impl Clocks {
             _ => hclk,
         }
     }
+
+    /// Kernel clock of the timers on APB1, which is twice PCLK1 when APB1 is divided.
+    pub fn timclk1(&self) -> u32 {
+        let rcc = unsafe { &*rcc::RCC };
+        match read_reg!(rcc, rcc, CFGR, PPRE1) {
+            0b100..=0b111 => self.pclk1() * 2,
+            _ => self.pclk1(),
+        }
+    }
+
+    /// Kernel clock of the timers on APB2, which is twice PCLK2 when APB2 is divided.
+    pub fn timclk2(&self) -> u32 {
+        let rcc = unsafe { &*rcc::RCC };
+        match read_reg!(rcc, rcc, CFGR, PPRE2) {
+            0b100..=0b111 => self.pclk2() * 2,
+            _ => self.pclk2(),
+        }
+    }
 }
@@ -0,0 +1,308 @@
+// Copyright 2020 Adam Greig
+// Dual licensed under the Apache 2.0 and MIT licenses.
+
+use core::cmp::Ordering;
+
+/// Read side of a circular buffer written by a DMA stream in circular mode.
+///
+/// This only keeps track of what has been read, and takes the index the DMA
+/// will write next on each call, so it does not touch any registers and
+/// can be built and exercised on the host.
+pub struct RxRing<const N: usize> {
+    buffer: [u8; N],
+    last_idx: usize,
+}
+
+impl<const N: usize> RxRing<N> {
+    pub const fn new() -> Self {
+        RxRing {
+            buffer: [0; N],
+            last_idx: 0,
+        }
+    }
+
+    /// Get the buffer for the DMA to write into, and restart reading from its start.
+    pub fn reset(&mut self) -> &mut [u8] {
+        self.last_idx = 0;
+        &mut self.buffer
+    }
+
+    /// Return length of the buffer
+    pub fn len(&self) -> usize {
+        N
+    }
+
+    /// Return true if the buffer has zero length
+    pub fn is_empty(&self) -> bool {
+        N == 0
+    }
+
+    /// Number of bytes written up to `dma_idx` which have not been read yet.
+    pub fn available(&self, dma_idx: usize) -> usize {
+        if dma_idx >= self.last_idx {
+            dma_idx - self.last_idx
+        } else {
+            (N - self.last_idx) + dma_idx
+        }
+    }
+
+    /// Read data written up to `dma_idx`, the index the DMA is going to write next.
+    ///
+    /// Returns number of bytes written to `rx`.
+    ///
+    /// Reads at most rx.len() new bytes, which may be less than what was received.
+    /// Remaining data will be read on the next call, so long as the buffer
+    /// doesn't overflow, which is not detected.
+    pub fn read(&mut self, dma_idx: usize, rx: &mut [u8]) -> usize {
+        match dma_idx.cmp(&self.last_idx) {
+            Ordering::Equal => {
+                // No action required if no data has been received.
+                0
+            }
+            Ordering::Less => {
+                // Wraparound occurred:
+                // Copy from last_idx to end, and from start to new dma_idx.
+                let mut n1 = N - self.last_idx;
+                let mut n2 = dma_idx;
+                let mut new_last_idx = dma_idx;
+
+                // Ensure we don't overflow rx buffer
+                if n1 > rx.len() {
+                    n1 = rx.len();
+                    n2 = 0;
+                    new_last_idx = self.last_idx + n1;
+                } else if (n1 + n2) > rx.len() {
+                    n2 = rx.len() - n1;
+                    new_last_idx = n2;
+                }
+
+                rx[..n1].copy_from_slice(&self.buffer[self.last_idx..self.last_idx + n1]);
+                rx[n1..(n1 + n2)].copy_from_slice(&self.buffer[..n2]);
+
+                self.last_idx = new_last_idx;
+                n1 + n2
+            }
+            Ordering::Greater => {
+                // New data, no wraparound:
+                // Copy from last_idx to new dma_idx.
+                let mut n = dma_idx - self.last_idx;
+
+                // Ensure we don't overflow rx buffer
+                if n > rx.len() {
+                    n = rx.len();
+                }
+
+                rx[..n].copy_from_slice(&self.buffer[self.last_idx..self.last_idx + n]);
+
+                self.last_idx += n;
+                n
+            }
+        }
+    }
+}
+
+/// Circular buffer of data waiting to be sent by a DMA stream in normal mode.
+///
+/// Data is pushed at the tail and sent from the head one contiguous chunk at
+/// a time, so a transfer never wraps around the end of the buffer. Like
+/// `RxRing`, it does not touch any registers.
+pub struct TxRing<const N: usize> {
+    buffer: [u8; N],
+    head: usize,
+    /// Bytes waiting, including those being sent.
+    len: usize,
+    /// Bytes in the chunk being sent.
+    sending: usize,
+}
+
+impl<const N: usize> TxRing<N> {
+    pub const fn new() -> Self {
+        TxRing {
+            buffer: [0; N],
+            head: 0,
+            len: 0,
+            sending: 0,
+        }
+    }
+
+    /// Discard all data, including any chunk being sent.
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+        self.sending = 0;
+    }
+
+    /// Number of bytes which can be pushed.
+    pub fn free(&self) -> usize {
+        N - self.len
+    }
+
+    /// Copy as much of `data` as fits into the buffer.
+    ///
+    /// Returns number of bytes copied.
+    pub fn push(&mut self, data: &[u8]) -> usize {
+        let n = data.len().min(self.free());
+        let tail = (self.head + self.len) % N;
+        let n1 = n.min(N - tail);
+        self.buffer[tail..tail + n1].copy_from_slice(&data[..n1]);
+        self.buffer[..n - n1].copy_from_slice(&data[n1..n]);
+        self.len += n;
+        n
+    }
+
+    /// Release the chunk returned by the last call to `next_chunk`, once it has been sent.
+    pub fn complete(&mut self) {
+        self.head = (self.head + self.sending) % N;
+        self.len -= self.sending;
+        self.sending = 0;
+    }
+
+    /// Get the next contiguous chunk of data to send, or None if there is none.
+    ///
+    /// `complete` must be called once it has been sent, before the next call.
+    pub fn next_chunk(&mut self) -> Option<&[u8]> {
+        if self.len == 0 {
+            return None;
+        }
+        self.sending = self.len.min(N - self.head);
+        Some(&self.buffer[self.head..self.head + self.sending])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RxRing;
+
+    /// Write `data` the way the DMA would, starting at `idx`, and return the
+    /// index it will write next.
+    fn dma_write<const N: usize>(ring: &mut RxRing<N>, mut idx: usize, data: &[u8]) -> usize {
+        for &byte in data {
+            ring.buffer[idx] = byte;
+            idx = (idx + 1) % N;
+        }
+        idx
+    }
+
+    #[test]
+    fn read_without_wraparound() {
+        let mut ring = RxRing::<8>::new();
+        let mut rx = [0; 8];
+        assert_eq!(ring.read(0, &mut rx), 0);
+
+        let idx = dma_write(&mut ring, 0, &[1, 2, 3]);
+        assert_eq!(ring.available(idx), 3);
+        assert_eq!(ring.read(idx, &mut rx), 3);
+        assert_eq!(&rx[..3], &[1, 2, 3]);
+        assert_eq!(ring.available(idx), 0);
+        assert_eq!(ring.read(idx, &mut rx), 0);
+    }
+
+    #[test]
+    fn read_with_wraparound() {
+        let mut ring = RxRing::<8>::new();
+        let mut rx = [0; 8];
+        let idx = dma_write(&mut ring, 0, &[1, 2, 3, 4, 5, 6]);
+        assert_eq!(ring.read(idx, &mut rx), 6);
+
+        let idx = dma_write(&mut ring, idx, &[7, 8, 9, 10]);
+        assert_eq!(idx, 2);
+        assert_eq!(ring.available(idx), 4);
+        assert_eq!(ring.read(idx, &mut rx), 4);
+        assert_eq!(&rx[..4], &[7, 8, 9, 10]);
+        assert_eq!(ring.available(idx), 0);
+    }
+
+    #[test]
+    fn read_ending_at_buffer_end() {
+        let mut ring = RxRing::<8>::new();
+        let mut rx = [0; 8];
+        let idx = dma_write(&mut ring, 0, &[1, 2, 3, 4, 5]);
+        assert_eq!(ring.read(idx, &mut rx), 5);
+
+        // The DMA index wraps to 0 once the last byte is written.
+        let idx = dma_write(&mut ring, idx, &[6, 7, 8]);
+        assert_eq!(idx, 0);
+        assert_eq!(ring.available(idx), 3);
+        assert_eq!(ring.read(idx, &mut rx), 3);
+        assert_eq!(&rx[..3], &[6, 7, 8]);
+    }
+
+    #[test]
+    fn short_rx_without_wraparound() {
+        let mut ring = RxRing::<8>::new();
+        let mut rx = [0; 2];
+        let idx = dma_write(&mut ring, 0, &[1, 2, 3, 4, 5]);
+
+        assert_eq!(ring.read(idx, &mut rx), 2);
+        assert_eq!(rx, [1, 2]);
+        assert_eq!(ring.available(idx), 3);
+        assert_eq!(ring.read(idx, &mut rx), 2);
+        assert_eq!(rx, [3, 4]);
+        assert_eq!(ring.read(idx, &mut rx), 1);
+        assert_eq!(rx[0], 5);
+    }
+
+    #[test]
+    fn short_rx_before_end() {
+        let mut ring = RxRing::<8>::new();
+        let mut rx = [0; 8];
+        let idx = dma_write(&mut ring, 0, &[1, 2, 3, 4]);
+        assert_eq!(ring.read(idx, &mut rx), 4);
+
+        // rx is filled before the end of the buffer is reached.
+        let idx = dma_write(&mut ring, idx, &[5, 6, 7, 8, 9]);
+        let mut rx = [0; 3];
+        assert_eq!(ring.read(idx, &mut rx), 3);
+        assert_eq!(rx, [5, 6, 7]);
+        assert_eq!(ring.available(idx), 2);
+        assert_eq!(ring.read(idx, &mut rx), 2);
+        assert_eq!(&rx[..2], &[8, 9]);
+    }
+
+    #[test]
+    fn short_rx_across_end() {
+        let mut ring = RxRing::<8>::new();
+        let mut rx = [0; 8];
+        let idx = dma_write(&mut ring, 0, &[1, 2, 3, 4, 5, 6]);
+        assert_eq!(ring.read(idx, &mut rx), 6);
+
+        // rx is filled after wrapping, but before reaching the DMA index.
+        let idx = dma_write(&mut ring, idx, &[7, 8, 9, 10, 11]);
+        let mut rx = [0; 3];
+        assert_eq!(ring.read(idx, &mut rx), 3);
+        assert_eq!(rx, [7, 8, 9]);
+        assert_eq!(ring.available(idx), 2);
+        assert_eq!(ring.read(idx, &mut rx), 2);
+        assert_eq!(&rx[..2], &[10, 11]);
+    }
+
+    #[test]
+    fn overrun_is_not_detected() {
+        let mut ring = RxRing::<8>::new();
+        let mut rx = [0; 8];
+        let idx = dma_write(&mut ring, 0, &[1, 2]);
+        assert_eq!(ring.read(idx, &mut rx), 2);
+
+        // Ten bytes arrive before the next read, lapping the reader, so only
+        // the two bytes past the last read index are seen, and they are the
+        // newest ones.
+        let idx = dma_write(&mut ring, idx, &[3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+        assert_eq!(ring.available(idx), 2);
+        assert_eq!(ring.read(idx, &mut rx), 2);
+        assert_eq!(&rx[..2], &[11, 12]);
+    }
+
+    #[test]
+    fn reset_restarts_from_start() {
+        let mut ring = RxRing::<8>::new();
+        let mut rx = [0; 8];
+        let idx = dma_write(&mut ring, 0, &[1, 2, 3]);
+        assert_eq!(ring.read(idx, &mut rx), 3);
+
+        assert_eq!(ring.reset().len(), 8);
+        assert_eq!(ring.available(0), 0);
+        let idx = dma_write(&mut ring, 0, &[4]);
+        assert_eq!(ring.read(idx, &mut rx), 1);
+        assert_eq!(rx[0], 4);
+    }
+}
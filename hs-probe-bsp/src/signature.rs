@@ -0,0 +1,56 @@
+//! Device electronic signature: unique ID, flash size, package and revision.
+//!
+//! Addresses are those of the STM32F72x/F73x, from RM0431 section 41.
+
+use core::ptr::read_volatile;
+
+const UID: *const u8 = 0x1FF0_7A10 as *const u8;
+const FLASH_SIZE: *const u16 = 0x1FF0_7A22 as *const u16;
+const PACKAGE: *const u16 = 0x1FF0_7BF0 as *const u16;
+const DBGMCU_IDCODE: *const u32 = 0xE004_2000 as *const u32;
+
+/// 96-bit unique device ID, in memory order.
+pub fn unique_id() -> [u8; 12] {
+    let mut id = [0; 12];
+    for (i, byte) in id.iter_mut().enumerate() {
+        *byte = unsafe { read_volatile(UID.add(i)) };
+    }
+    id
+}
+
+/// Unique device ID as 24 lowercase hex digits, as used for the USB serial number.
+pub fn unique_id_hex() -> &'static str {
+    static mut UID_HEX: [u8; 24] = [0; 24];
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+
+    cortex_m::interrupt::free(|_| unsafe {
+        if UID_HEX[0] == 0 {
+            for (i, byte) in unique_id().iter().enumerate() {
+                UID_HEX[2 * i] = HEX[(byte >> 4) as usize];
+                UID_HEX[2 * i + 1] = HEX[(byte & 0xF) as usize];
+            }
+        }
+        // Only hex digits are ever written.
+        core::str::from_utf8_unchecked(&UID_HEX)
+    })
+}
+
+/// Size of the flash memory in KiB.
+pub fn flash_size_kb() -> u16 {
+    unsafe { read_volatile(FLASH_SIZE) }
+}
+
+/// Package code, from 0 to 7, as listed for the package data register.
+pub fn package() -> u8 {
+    ((unsafe { read_volatile(PACKAGE) } >> 8) & 0b111) as u8
+}
+
+/// Device ID, 0x452 for the STM32F72x/F73x.
+pub fn dev_id() -> u16 {
+    (unsafe { read_volatile(DBGMCU_IDCODE) } & 0xFFF) as u16
+}
+
+/// Silicon revision ID.
+pub fn rev_id() -> u16 {
+    (unsafe { read_volatile(DBGMCU_IDCODE) } >> 16) as u16
+}
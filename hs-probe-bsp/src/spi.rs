@@ -6,7 +6,7 @@ use stm32ral::spi;
 use stm32ral::{modify_reg, read_reg, write_reg};
 
 use super::dma::DMA;
-use super::gpio::Pins;
+use super::gpio::SWDPins;
 use crate::rcc::Clocks;
 use core::ops::Deref;
 
@@ -36,11 +36,13 @@ impl SPI {
         }
     }
 
+    /// Set the SPI kernel clock, from APB2 for SPI1, SPI4 and SPI5 or APB1 for SPI2 and SPI3.
     pub fn set_base_clock(&self, clocks: &Clocks) {
-        if self.spi.deref() as *const _ == spi::SPI1 {
+        let instance = self.spi.deref() as *const _;
+        if instance == spi::SPI1 || instance == spi::SPI4 || instance == spi::SPI5 {
             self.base_clock.store(clocks.pclk2(), Ordering::SeqCst);
         }
-        if self.spi.deref() as *const _ == spi::SPI2 {
+        if instance == spi::SPI2 || instance == spi::SPI3 {
             self.base_clock.store(clocks.pclk1(), Ordering::SeqCst);
         }
     }
@@ -96,6 +98,29 @@ impl SPI {
         );
     }
 
+    /// Set up SPI peripheral for AVR ISP: mode 0, MSB first, 8-bit frames.
+    ///
+    /// Defaults to the slowest clock, use `set_prescaler` to change it.
+    pub fn setup_avrisp(&self) {
+        write_reg!(
+            spi,
+            self.spi,
+            CR1,
+            BIDIMODE: Unidirectional,
+            CRCEN: Disabled,
+            RXONLY: FullDuplex,
+            SSM: Enabled,
+            SSI: SlaveNotSelected,
+            LSBFIRST: MSBFirst,
+            BR: Div256,
+            MSTR: Master,
+            CPOL: IdleLow,
+            CPHA: FirstEdge,
+            SPE: Enabled
+        );
+        write_reg!(spi, self.spi, CR2, FRXTH: Quarter, DS: EightBit);
+    }
+
     pub fn calculate_prescaler(&self, max_frequency: u32) -> Option<SPIPrescaler> {
         let base_clock = self.base_clock.load(Ordering::SeqCst);
         if base_clock == 0 {
@@ -129,6 +154,11 @@ impl SPI {
         None
     }
 
+    /// SPI clock frequency resulting from `prescaler`
+    pub fn prescaler_frequency(&self, prescaler: SPIPrescaler) -> u32 {
+        self.base_clock.load(Ordering::SeqCst) >> (prescaler as u32 + 1)
+    }
+
     /// Change SPI clock rate to one of the SPIClock variants
     pub fn set_prescaler(&self, prescaler: SPIPrescaler) {
         modify_reg!(spi, self.spi, CR1, BR: prescaler as u32);
@@ -141,23 +171,58 @@ impl SPI {
     }
 
     /// Transmit `txdata` and write the same number of bytes into `rxdata`.
+    #[link_section = ".itcm"]
     pub fn jtag_exchange(&self, dma: &DMA, txdata: &[u8], rxdata: &mut [u8]) {
-        debug_assert!(rxdata.len() >= 64);
+        debug_assert!(rxdata.len() >= txdata.len());
+
+        // Only SPI1 and SPI2 have DMA streams assigned
+        let spi1 = self.spi.deref() as *const _ == spi::SPI1;
+        debug_assert!(spi1 || self.spi.deref() as *const _ == spi::SPI2);
 
         // Set up DMA transfer (configures NDTR and MAR and enables streams)
-        dma.spi2_enable(txdata, &mut rxdata[..txdata.len()]);
+        if spi1 {
+            dma.spi1_enable(txdata, &mut rxdata[..txdata.len()]);
+        } else {
+            dma.spi2_enable(txdata, &mut rxdata[..txdata.len()]);
+        }
 
         // Start SPI transfer
         modify_reg!(spi, self.spi, CR1, SPE: Enabled);
 
         // Busy wait for RX DMA completion (at most 43µs)
-        while dma.spi2_busy() {}
+        if spi1 {
+            while dma.spi1_busy() {}
+            dma.spi1_disable();
+        } else {
+            while dma.spi2_busy() {}
+            dma.spi2_disable();
+        }
+    }
 
-        // Disable DMA
-        dma.spi2_disable();
+    /// Transmit and receive a single JTAG frame of 4 to 8 bits, LSbit first.
+    ///
+    /// Must be called after `setup_jtag`, and leaves the SPI set up
+    /// for further `jtag_exchange` calls.
+    #[link_section = ".itcm"]
+    pub fn jtag_exchange_bits(&self, data: u8, bits: u8) -> u8 {
+        debug_assert!((4..=8).contains(&bits));
+        write_reg!(spi, self.spi, CR2, FRXTH: Quarter, DS: (bits - 1) as u32);
+        modify_reg!(spi, self.spi, CR1, SPE: Enabled);
+        let rx = self.exchange8(data);
+        write_reg!(
+            spi,
+            self.spi,
+            CR2,
+            FRXTH: Quarter,
+            DS: EightBit,
+            TXDMAEN: Enabled,
+            RXDMAEN: Enabled
+        );
+        rx
     }
 
     /// Transmit 4 bits
+    #[link_section = ".itcm"]
     pub fn tx4(&self, data: u8) {
         write_reg!(spi, self.spi, CR2, FRXTH: Quarter, DS: FourBit);
         self.write_dr_u8(data);
@@ -165,6 +230,7 @@ impl SPI {
     }
 
     /// Transmit 8 bits
+    #[link_section = ".itcm"]
     pub fn tx8(&self, data: u8) {
         write_reg!(spi, self.spi, CR2, FRXTH: Quarter, DS: EightBit);
         self.write_dr_u8(data);
@@ -172,16 +238,53 @@ impl SPI {
     }
 
     /// Transmit 16 bits
+    #[link_section = ".itcm"]
     pub fn tx16(&self, data: u16) {
         write_reg!(spi, self.spi, CR2, FRXTH: Quarter, DS: EightBit);
         self.write_dr_u16(data);
         self.wait_txe();
     }
 
+    /// Transmit whole bytes, discarding received data as it arrives
+    /// so the RX FIFO never overruns while the TX FIFO is kept full.
+    #[link_section = ".itcm"]
+    pub fn tx_bytes(&self, data: &[u8]) {
+        write_reg!(spi, self.spi, CR2, FRXTH: Quarter, DS: EightBit);
+        let mut received = 0;
+        for &byte in data {
+            self.wait_txe();
+            self.write_dr_u8(byte);
+            while read_reg!(spi, self.spi, SR, RXNE != Empty) {
+                self.read_dr_u8();
+                received += 1;
+            }
+        }
+        while received < data.len() {
+            self.wait_rxne();
+            self.read_dr_u8();
+            received += 1;
+        }
+    }
+
+    /// Transmit between 4 and 8 bits, waiting for the transfer to complete
+    #[link_section = ".itcm"]
+    pub fn tx_bits(&self, data: u8, bits: u8) {
+        debug_assert!((4..=8).contains(&bits));
+        write_reg!(spi, self.spi, CR2, FRXTH: Quarter, DS: (bits - 1) as u32);
+        self.write_dr_u8(data);
+        self.wait_rxne();
+        self.read_dr_u8();
+    }
+
     /// Transmit an SWD WDATA phase, with 32 bits of data and 1 bit of parity.
     ///
     /// We transmit an extra 7 trailing idle bits after the parity bit because
     /// it's much quicker to do that than reconfigure SPI to a smaller data size.
+    ///
+    /// The data is packed into two 16-bit writes which fill the FIFO, and the
+    /// parity word is queued as soon as half of it is free, so the FIFO never
+    /// drains before the final word.
+    #[link_section = ".itcm"]
     pub fn swd_wdata_phase(&self, data: u32, parity: u8) {
         write_reg!(spi, self.spi, CR2, FRXTH: Quarter, DS: EightBit);
         // Trigger 4 words, filling the FIFO
@@ -192,7 +295,16 @@ impl SPI {
         self.write_dr_u8(parity & 1);
     }
 
+    /// Transmit and receive 8 bits
+    #[link_section = ".itcm"]
+    pub fn exchange8(&self, data: u8) -> u8 {
+        self.write_dr_u8(data);
+        self.wait_rxne();
+        self.read_dr_u8()
+    }
+
     /// Receive 4 bits
+    #[link_section = ".itcm"]
     pub fn rx4(&self) -> u8 {
         write_reg!(spi, self.spi, CR2, FRXTH: Quarter, DS: FourBit);
         self.write_dr_u8(0);
@@ -201,6 +313,7 @@ impl SPI {
     }
 
     /// Receive 5 bits
+    #[link_section = ".itcm"]
     pub fn rx5(&self) -> u8 {
         write_reg!(spi, self.spi, CR2, FRXTH: Quarter, DS: FiveBit);
         self.write_dr_u8(0);
@@ -208,36 +321,54 @@ impl SPI {
         self.read_dr_u8()
     }
 
+    /// Receive between 4 and 16 bits
+    #[link_section = ".itcm"]
+    pub fn rx_bits(&self, bits: u8) -> u16 {
+        debug_assert!((4..=16).contains(&bits));
+        if bits > 8 {
+            write_reg!(spi, self.spi, CR2, FRXTH: Half, DS: (bits - 1) as u32);
+            self.write_dr_u16(0);
+            self.wait_rxne();
+            self.read_dr_u16()
+        } else {
+            write_reg!(spi, self.spi, CR2, FRXTH: Quarter, DS: (bits - 1) as u32);
+            self.write_dr_u8(0);
+            self.wait_rxne();
+            self.read_dr_u8() as u16
+        }
+    }
+
     /// Receive an SWD RDATA phase, with 32 bits of data and 1 bit of parity.
     ///
-    /// This method requires `Pins` be passed in so it can directly control
-    /// the SWD lines at the end of RDATA in order to correctly sample PARITY
-    /// and then resume driving SWDIO.
-    pub fn swd_rdata_phase(&self, pins: &Pins) -> (u32, u8) {
-        write_reg!(spi, self.spi, CR2, FRXTH: Quarter, DS: EightBit);
+    /// This method requires `SWDPins` be passed in so it can directly control
+    /// the SWD lines at the end of RDATA in order to correctly sample PARITY,
+    /// generate `turnaround` clocks, and then resume driving SWDIO.
+    #[link_section = ".itcm"]
+    pub fn swd_rdata_phase(&self, pins: &SWDPins, turnaround: usize) -> (u32, u8) {
+        // RXNE is set once two words are received, so they can be read together.
+        write_reg!(spi, self.spi, CR2, FRXTH: Half, DS: EightBit);
         // Trigger 4 words, filling the FIFO
         self.write_dr_u16(0);
         self.write_dr_u16(0);
+        // Read the first two words while the final two are being received
         self.wait_rxne();
-        let mut data = self.read_dr_u8() as u32;
-        self.wait_rxne();
-        data |= (self.read_dr_u8() as u32) << 8;
-        self.wait_rxne();
-        data |= (self.read_dr_u8() as u32) << 16;
+        let mut data = self.read_dr_u16() as u32;
 
-        // While we wait for the final word to be available in the RXFIFO,
+        // While we wait for the final two words to be available in the RXFIFO,
         // handle the parity bit. First wait for current transaction to complete.
         self.wait_rxne();
 
         // The parity bit is currently being driven onto the bus by the target.
         // On the next rising edge, the target will release the bus, and we need
         // to then start driving it before sending any more clocks to avoid a false START.
-        let parity = pins.spi1_miso.is_high() as u8;
+        let parity = pins.swdio_in.is_high() as u8;
         // Take direct control of SWCLK
         pins.swd_clk_direct();
-        // Send one clock pulse. Target releases bus after rising edge.
-        pins.spi1_clk.set_low();
-        pins.spi1_clk.set_high();
+        // Send turnaround clock pulses. Target releases bus after the first rising edge.
+        for _ in 0..turnaround {
+            pins.swclk.set_low();
+            pins.swclk.set_high();
+        }
         // Drive bus ourselves with 0 (all our SPI read transactions transmitted 0s)
         pins.swd_tx();
         // Restore SWCLK to SPI control
@@ -247,13 +378,14 @@ impl SPI {
         write_reg!(spi, self.spi, CR2, FRXTH: Quarter, DS: FourBit);
         self.write_dr_u8(0);
 
-        // Now read the final data word that was waiting in RXFIFO
-        data |= (self.read_dr_u8() as u32) << 24;
+        // Now read the final two data words that were waiting in RXFIFO
+        data |= (self.read_dr_u16() as u32) << 16;
 
         (data, parity)
     }
 
     /// Empty the receive FIFO
+    #[link_section = ".itcm"]
     pub fn drain(&self) {
         // FIFO is 32 bits so ideally we'd make two 16-bit reads, but that screws
         // up the SPI's FIFO pointers and wrecks subsequent reads on later operations.
@@ -288,6 +420,12 @@ impl SPI {
         unsafe { core::ptr::read_volatile(&self.spi.DR as *const _ as *const u8) }
     }
 
+    /// Perform a 16-bit read from DR
+    #[inline(always)]
+    fn read_dr_u16(&self) -> u16 {
+        unsafe { core::ptr::read_volatile(&self.spi.DR as *const _ as *const u16) }
+    }
+
     /// Perform an 8-bit write to DR
     #[inline(always)]
     fn write_dr_u8(&self, data: u8) {
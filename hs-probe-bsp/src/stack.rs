@@ -0,0 +1,63 @@
+//! Stack usage measurement.
+//!
+//! The unused stack is filled with a pattern at boot, and the lowest word
+//! which no longer holds it marks the most stack ever used.
+
+use crate::mpu::{STACK_GUARD_BASE, STACK_GUARD_SIZE};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+const PAINT: u32 = 0x5AC3_5AC3;
+
+/// Stack left unpainted below the stack pointer, for the frame of `paint` itself.
+const PAINT_MARGIN: u32 = 64;
+
+/// Lowest stack address found used so far, or 0 before the first measurement.
+static LOW_WATER: AtomicU32 = AtomicU32::new(0);
+
+extern "C" {
+    static _stack_start: u32;
+}
+
+fn top() -> u32 {
+    unsafe { &_stack_start as *const u32 as u32 }
+}
+
+/// Lowest address the stack can use, just above the MPU guard region.
+fn bottom() -> u32 {
+    STACK_GUARD_BASE + STACK_GUARD_SIZE
+}
+
+/// Call this function at boot in pre_init, to fill the unused stack with the pattern.
+pub fn paint() {
+    let end = cortex_m::register::msp::read() - PAINT_MARGIN;
+    let mut addr = bottom();
+    while addr < end {
+        unsafe { core::ptr::write_volatile(addr as *mut u32, PAINT) };
+        addr += 4;
+    }
+}
+
+/// Find how much of the stack has been used, and return the most ever used in bytes.
+///
+/// Scans up from the bottom of the stack, so call it occasionally rather than often.
+pub fn measure() -> u32 {
+    let mut addr = bottom();
+    while addr < top() && unsafe { core::ptr::read_volatile(addr as *const u32) } == PAINT {
+        addr += 4;
+    }
+    LOW_WATER.store(addr, Ordering::Relaxed);
+    top() - addr
+}
+
+/// Most stack used in bytes, as of the last call to `measure`.
+pub fn high_water() -> u32 {
+    match LOW_WATER.load(Ordering::Relaxed) {
+        0 => 0,
+        low => top() - low,
+    }
+}
+
+/// Size of the stack in bytes, excluding the guard region.
+pub fn size() -> u32 {
+    top() - bottom()
+}
@@ -0,0 +1,108 @@
+//! General purpose timers, for PWM output and frequency measurement on spare pins
+
+use stm32ral::{read_reg, tim2, tim9, write_reg};
+
+/// Duty cycle of 100%, in hundredths of a percent.
+pub const DUTY_MAX: u16 = 10_000;
+
+/// PWM output on channel 1 of TIM9.
+pub struct Pwm {
+    tim: tim9::Instance,
+}
+
+impl Pwm {
+    pub fn new(tim: tim9::Instance) -> Self {
+        Pwm { tim }
+    }
+
+    /// Start PWM at `frequency` Hz with `duty` in hundredths of a percent,
+    /// from a timer kernel clock of `clock` Hz.
+    ///
+    /// Returns the actual frequency, which is `clock` divided by a whole
+    /// number, or None if the frequency is out of range.
+    pub fn start(&self, clock: u32, frequency: u32, duty: u16) -> Option<u32> {
+        if frequency == 0 || duty > DUTY_MAX {
+            return None;
+        }
+        let total = clock / frequency;
+        if total < 2 {
+            return None;
+        }
+
+        // Use the smallest prescaler which fits the period in 16 bits,
+        // for the finest duty cycle resolution.
+        let psc = (total - 1) / 65536;
+        let period = total / (psc + 1);
+        let ccr = (period as u64 * duty as u64 / DUTY_MAX as u64) as u32;
+
+        write_reg!(tim9, self.tim, CR1, 0);
+        write_reg!(tim9, self.tim, PSC, psc);
+        write_reg!(tim9, self.tim, ARR, period - 1);
+        write_reg!(tim9, self.tim, CCR1, ccr);
+        // PWM mode 1, high while the count is below CCR1.
+        write_reg!(tim9, self.tim, CCMR1_Output, OC1M: 0b110, OC1PE: 1);
+        write_reg!(tim9, self.tim, CCER, CC1E: 1);
+        write_reg!(tim9, self.tim, EGR, UG: 1);
+        write_reg!(tim9, self.tim, CR1, ARPE: 1, CEN: 1);
+
+        Some(clock / ((psc + 1) * period))
+    }
+
+    /// Stop the timer and disable its output.
+    pub fn stop(&self) {
+        write_reg!(tim9, self.tim, CR1, 0);
+        write_reg!(tim9, self.tim, CCER, 0);
+    }
+}
+
+/// Period and high time measurement of the signal on channel 1 of TIM2.
+///
+/// The 32-bit counter runs from the timer kernel clock and is reset by each
+/// rising edge, after capturing its value into CCR1, while CCR2 captures the
+/// count at the falling edge.
+pub struct Capture {
+    tim: tim2::Instance,
+}
+
+impl Capture {
+    pub fn new(tim: tim2::Instance) -> Self {
+        Capture { tim }
+    }
+
+    /// Start capturing edges.
+    pub fn start(&self) {
+        write_reg!(tim2, self.tim, CR1, 0);
+        write_reg!(tim2, self.tim, PSC, 0);
+        write_reg!(tim2, self.tim, ARR, 0xFFFF_FFFF);
+        // IC1 captures TI1 rising edges and IC2 captures TI1 falling edges.
+        write_reg!(tim2, self.tim, CCMR1_Input, CC1S: 0b01, CC2S: 0b10);
+        write_reg!(tim2, self.tim, CCER, CC1E: 1, CC2E: 1, CC2P: 1);
+        // Reset the counter on each rising edge of TI1FP1.
+        write_reg!(tim2, self.tim, SMCR, TS: 0b101, SMS: 0b100);
+        write_reg!(tim2, self.tim, EGR, UG: 1);
+        write_reg!(tim2, self.tim, SR, 0);
+        // Only counter overflow sets UIF, not the resets on each edge.
+        write_reg!(tim2, self.tim, CR1, URS: 1, CEN: 1);
+    }
+
+    /// Check for a rising edge since the last call, returning the cycles since
+    /// the previous rising edge and the cycles the signal was high for.
+    ///
+    /// The first edge after `start` only starts the count, so its values are
+    /// not meaningful. Returns None if there was no new edge.
+    pub fn capture(&self) -> Option<(u32, u32)> {
+        if read_reg!(tim2, self.tim, SR, CC1IF) == 0 {
+            return None;
+        }
+        // Reading CCR1 clears CC1IF.
+        let high = read_reg!(tim2, self.tim, CCR2);
+        let period = read_reg!(tim2, self.tim, CCR1);
+        Some((period, high))
+    }
+
+    pub fn stop(&self) {
+        write_reg!(tim2, self.tim, CR1, 0);
+        write_reg!(tim2, self.tim, CCER, 0);
+        write_reg!(tim2, self.tim, SMCR, 0);
+    }
+}
@@ -1,58 +1,188 @@
 // Copyright 2020 Adam Greig
 // Dual licensed under the Apache 2.0 and MIT licenses.
 
-use core::cmp::Ordering;
+use core::ops::Deref;
 use stm32ral::usart;
 use stm32ral::{modify_reg, read_reg, write_reg};
 
-use super::dma::DMA;
+use super::dma::{UartStreams, DMA};
 use super::rcc::Clocks;
+use super::ring::{RxRing, TxRing};
 
-pub struct UART<'a> {
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum StopBits {
+    One,
+    OnePointFive,
+    Two,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+/// Receive errors flagged by the USART.
+#[derive(Copy, Clone, Default)]
+pub struct RxErrors {
+    pub parity: bool,
+    pub framing: bool,
+    pub noise: bool,
+    /// A byte was received before the previous one was read.
+    pub overrun: bool,
+}
+
+/// Clear the USART1 idle line flag.
+///
+/// Call this function from the USART1 interrupt handler.
+pub fn on_usart1_interrupt() {
+    // Safety: only the idle line flag is cleared, which is not otherwise used.
+    let usart1 = unsafe { &*usart::USART1 };
+    write_reg!(usart, usart1, ICR, IDLECF: 1);
+}
+
+/// DMA driven UART, for any USART instance with DMA streams assigned in `DMA`.
+///
+/// `RX` and `TX` set the sizes of the circular receive and transmit buffers.
+pub struct UART<'a, const RX: usize, const TX: usize> {
     uart: usart::Instance,
     dma: &'a DMA,
-    buffer: [u8; 256],
-    last_idx: usize,
+    streams: UartStreams,
+    rx: RxRing<RX>,
+    tx: TxRing<TX>,
     fck: u32,
 }
 
-impl<'a> UART<'a> {
+impl<'a, const RX: usize, const TX: usize> UART<'a, RX, TX> {
     pub fn new(uart: usart::Instance, dma: &'a DMA) -> Self {
+        let streams = if uart.deref() as *const _ == usart::USART1 {
+            UartStreams::USART1
+        } else if uart.deref() as *const _ == usart::USART2 {
+            UartStreams::USART2
+        } else {
+            panic!("No DMA streams assigned to this USART");
+        };
         UART {
             uart,
             dma,
-            buffer: [0; 256],
-            last_idx: 0,
+            streams,
+            rx: RxRing::new(),
+            tx: TxRing::new(),
             fck: 72_000_000,
         }
     }
 
     /// Set the UART peripheral clock speed, used for baud rate calculation.
     pub fn setup(&mut self, clocks: &Clocks) {
-        self.fck = clocks.pclk2();
+        self.fck = match self.streams {
+            UartStreams::USART1 => clocks.pclk2(),
+            UartStreams::USART2 => clocks.pclk1(),
+        };
     }
 
     /// Begin UART reception into buffer.
     ///
-    /// UART::poll must be called regularly after starting.
+    /// UART::poll must be called regularly after starting. The USART interrupt
+    /// is raised when the line goes idle after receiving, for handlers which
+    /// forward data as it arrives.
     pub fn start(&mut self) {
-        self.last_idx = 0;
+        self.dma.uart_stop_rx(self.streams);
         write_reg!(usart, self.uart, CR3, DMAR: Enabled);
-        write_reg!(
+        modify_reg!(
             usart,
             self.uart,
             CR1,
             OVER8: Oversampling8,
+            IDLEIE: Enabled,
             RE: Enabled,
+            TE: Disabled,
             UE: Enabled
         );
-        self.dma.usart1_start(&mut self.buffer);
+        self.dma.uart_start_rx(self.streams, self.rx.reset());
+    }
+
+    /// Resume reception stopped by `stop`, into an empty buffer, leaving
+    /// transmission unchanged.
+    ///
+    /// `start` must have been called first to configure the USART.
+    pub fn restart_rx(&mut self) {
+        self.dma.uart_stop_rx(self.streams);
+        modify_reg!(usart, self.uart, CR3, DMAR: Enabled);
+        modify_reg!(usart, self.uart, CR1, IDLEIE: Enabled, RE: Enabled);
+        self.dma.uart_start_rx(self.streams, self.rx.reset());
     }
 
     /// End UART reception.
     pub fn stop(&self) {
-        self.dma.usart1_stop();
-        modify_reg!(usart, self.uart, CR1, RE: Disabled);
+        self.dma.uart_stop_rx(self.streams);
+        modify_reg!(usart, self.uart, CR1, IDLEIE: Disabled, RE: Disabled);
+    }
+
+    /// Enable UART transmission by DMA, in addition to reception.
+    ///
+    /// Must be called after `start()`, which resets the transmitter configuration.
+    /// The TX pin must be placed into alternate mode separately.
+    pub fn start_tx(&mut self) {
+        self.dma.uart_stop_tx(self.streams);
+        self.tx.clear();
+        modify_reg!(usart, self.uart, CR3, DMAT: Enabled);
+        modify_reg!(usart, self.uart, CR1, TE: Enabled, UE: Enabled);
+    }
+
+    /// End UART transmission, aborting any transfer in progress.
+    pub fn stop_tx(&self) {
+        self.dma.uart_stop_tx(self.streams);
+        modify_reg!(usart, self.uart, CR1, TE: Disabled);
+        modify_reg!(usart, self.uart, CR3, DMAT: Disabled);
+    }
+
+    /// Returns true if no TX DMA transfer is in progress
+    pub fn is_tx_idle(&self) -> bool {
+        self.dma.uart_tx_ndtr(self.streams) == 0
+    }
+
+    /// Number of bytes which can be queued for transmission.
+    ///
+    /// Call `poll_tx` first to release the space of a completed transfer.
+    pub fn tx_space(&self) -> usize {
+        self.tx.free()
+    }
+
+    /// Queue as much of `data` as fits for transmission by DMA.
+    ///
+    /// Returns the number of bytes queued.
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        let len = self.tx.push(data);
+        self.poll_tx();
+        len
+    }
+
+    /// Start transmitting the next chunk of queued data, once the previous transfer has completed.
+    ///
+    /// Must be called regularly while data is queued.
+    pub fn poll_tx(&mut self) {
+        if self.is_tx_idle() {
+            self.tx.complete();
+            if let Some(chunk) = self.tx.next_chunk() {
+                self.dma.uart_start_tx(self.streams, chunk, chunk.len());
+            }
+        }
+    }
+
+    /// Read and clear the receive error flags.
+    ///
+    /// Each flag stays set until cleared, so it shows at least one error
+    /// of its kind since the last call, not how many there were.
+    pub fn take_errors(&self) -> RxErrors {
+        let (pe, fe, nf, ore) = read_reg!(usart, self.uart, ISR, PE, FE, NF, ORE);
+        write_reg!(usart, self.uart, ICR, PECF: pe, FECF: fe, NCF: nf, ORECF: ore);
+        RxErrors {
+            parity: pe != 0,
+            framing: fe != 0,
+            noise: nf != 0,
+            overrun: ore != 0,
+        }
     }
 
     /// Returns true if UART currently enabled
@@ -62,7 +192,7 @@ impl<'a> UART<'a> {
 
     /// Return length of internal buffer
     pub fn buffer_len(&self) -> usize {
-        self.buffer.len()
+        self.rx.len()
     }
 
     /// Request a target baud rate. Returns actual baud rate set.
@@ -84,16 +214,134 @@ impl<'a> UART<'a> {
         (2 * self.fck) / div
     }
 
+    /// Set the frame format.
+    ///
+    /// The UART is disabled, and must be started again afterwards.
+    /// Unsupported numbers of data bits select 8 data bits.
+    pub fn set_format(&self, data_bits: u8, stop_bits: StopBits, parity: Parity) {
+        modify_reg!(usart, self.uart, CR1, UE: Disabled);
+
+        match data_bits {
+            7 => modify_reg!(usart, self.uart, CR1, M1: 1, M0: 0),
+            9 => modify_reg!(usart, self.uart, CR1, M1: 0, M0: 1),
+            _ => modify_reg!(usart, self.uart, CR1, M1: 0, M0: 0),
+        }
+
+        match stop_bits {
+            StopBits::One => modify_reg!(usart, self.uart, CR2, STOP: 0b00),
+            StopBits::OnePointFive => modify_reg!(usart, self.uart, CR2, STOP: 0b11),
+            StopBits::Two => modify_reg!(usart, self.uart, CR2, STOP: 0b10),
+        }
+
+        match parity {
+            Parity::None => modify_reg!(usart, self.uart, CR1, PCE: 0),
+            Parity::Odd => modify_reg!(usart, self.uart, CR1, PCE: 1, PS: 1),
+            Parity::Even => modify_reg!(usart, self.uart, CR1, PCE: 1, PS: 0),
+        }
+    }
+
+    /// Configure the USART for single-wire half-duplex operation, as used by UPDI.
+    ///
+    /// TX and RX are swapped so the transmitter drives the RX pin, frames are
+    /// 8 data bits with even parity and two stop bits, and DMA is not used.
+    ///
+    /// Returns actual baud rate set.
+    pub fn start_half_duplex(&self, baud: u32) -> u32 {
+        self.dma.uart_stop_rx(self.streams);
+        write_reg!(usart, self.uart, CR1, 0);
+        let actual = self.set_baud(baud);
+        write_reg!(usart, self.uart, CR2, STOP: 0b10, SWAP: 1);
+        write_reg!(usart, self.uart, CR3, HDSEL: 1);
+        write_reg!(
+            usart,
+            self.uart,
+            CR1,
+            OVER8: Oversampling8,
+            M1: 0,
+            M0: 1,
+            PCE: 1,
+            PS: 0,
+            RE: Enabled,
+            TE: Enabled,
+            UE: Enabled
+        );
+        actual
+    }
+
+    /// Configure the USART for a polled loopback test at `baud`, with 8N1 frames and no DMA.
+    ///
+    /// With `internal`, single-wire half-duplex mode receives what is sent on
+    /// the TX pin without any wiring, otherwise TX must be wired to RX.
+    /// Bytes are exchanged with `hd_write` and `hd_read`, and `stop_half_duplex`
+    /// returns the USART to its reset configuration.
+    ///
+    /// Returns actual baud rate set.
+    pub fn start_loopback(&self, baud: u32, internal: bool) -> u32 {
+        self.dma.uart_stop_rx(self.streams);
+        self.dma.uart_stop_tx(self.streams);
+        write_reg!(usart, self.uart, CR1, 0);
+        let actual = self.set_baud(baud);
+        write_reg!(usart, self.uart, CR2, 0);
+        write_reg!(usart, self.uart, CR3, HDSEL: internal as u32);
+        write_reg!(
+            usart,
+            self.uart,
+            CR1,
+            OVER8: Oversampling8,
+            RE: Enabled,
+            TE: Enabled,
+            UE: Enabled
+        );
+        self.hd_flush();
+        actual
+    }
+
+    /// Leave half-duplex mode, returning the USART to its reset configuration.
+    pub fn stop_half_duplex(&self) {
+        write_reg!(usart, self.uart, CR1, 0);
+        write_reg!(usart, self.uart, CR2, 0);
+        write_reg!(usart, self.uart, CR3, 0);
+    }
+
+    /// Returns true if UART is currently in half-duplex mode
+    pub fn is_half_duplex(&self) -> bool {
+        read_reg!(usart, self.uart, CR3, HDSEL) != 0
+    }
+
+    /// Transmit one byte in half-duplex mode.
+    ///
+    /// The transmitted byte is also received, so it must be read back with `hd_read`.
+    pub fn hd_write(&self, byte: u8) {
+        while read_reg!(usart, self.uart, ISR, TXE) == 0 {}
+        write_reg!(usart, self.uart, TDR, byte as u32);
+    }
+
+    /// Receive one byte in half-duplex mode, giving up after `timeout` polls.
+    pub fn hd_read(&self, timeout: u32) -> Option<u8> {
+        for _ in 0..timeout {
+            if read_reg!(usart, self.uart, ISR, RXNE) != 0 {
+                return Some(read_reg!(usart, self.uart, RDR) as u8);
+            }
+        }
+        None
+    }
+
+    /// Discard any received data and clear error flags in half-duplex mode.
+    pub fn hd_flush(&self) {
+        write_reg!(usart, self.uart, ICR, ORECF: 1, FECF: 1, PECF: 1, NCF: 1);
+        write_reg!(usart, self.uart, RQR, RXFRQ: 1);
+    }
+
+    /// Index in the receive buffer the DMA is going to write next.
+    fn dma_idx(&self) -> usize {
+        RX - self.dma.uart_rx_ndtr(self.streams)
+    }
+
     /// Fetch current number of bytes available.
     ///
     /// Subsequent calls to read() may return a different amount of data.
     pub fn bytes_available(&self) -> usize {
-        let dma_idx = self.buffer.len() - self.dma.usart1_ndtr();
-        if dma_idx >= self.last_idx {
-            dma_idx - self.last_idx
-        } else {
-            (self.buffer.len() - self.last_idx) + dma_idx
-        }
+        self.rx.available(self.dma_idx())
     }
 
     /// Read new UART data.
@@ -108,51 +356,7 @@ impl<'a> UART<'a> {
         // all prior data. Even if the DMA writes new data while we're
         // processing we won't get out of sync and will handle the new
         // data next time read() is called.
-        let dma_idx = self.buffer.len() - self.dma.usart1_ndtr();
-
-        match dma_idx.cmp(&self.last_idx) {
-            Ordering::Equal => {
-                // No action required if no data has been received.
-                0
-            }
-            Ordering::Less => {
-                // Wraparound occurred:
-                // Copy from last_idx to end, and from start to new dma_idx.
-                let mut n1 = self.buffer.len() - self.last_idx;
-                let mut n2 = dma_idx;
-                let mut new_last_idx = dma_idx;
-
-                // Ensure we don't overflow rx buffer
-                if n1 > rx.len() {
-                    n1 = rx.len();
-                    n2 = 0;
-                    new_last_idx = self.last_idx + n1;
-                } else if (n1 + n2) > rx.len() {
-                    n2 = rx.len() - n1;
-                    new_last_idx = n2;
-                }
-
-                rx[..n1].copy_from_slice(&self.buffer[self.last_idx..self.last_idx + n1]);
-                rx[n1..(n1 + n2)].copy_from_slice(&self.buffer[..n2]);
-
-                self.last_idx = new_last_idx;
-                n1 + n2
-            }
-            Ordering::Greater => {
-                // New data, no wraparound:
-                // Copy from last_idx to new dma_idx.
-                let mut n = dma_idx - self.last_idx;
-
-                // Ensure we don't overflow rx buffer
-                if n > rx.len() {
-                    n = rx.len();
-                }
-
-                rx[..n].copy_from_slice(&self.buffer[self.last_idx..self.last_idx + n]);
-
-                self.last_idx += n;
-                n
-            }
-        }
+        let dma_idx = self.dma_idx();
+        self.rx.read(dma_idx, rx)
     }
 }